@@ -0,0 +1,8 @@
+fn main() {
+    // Link the native runtime into the compiler itself so JIT-compiled code can call it
+    cc::Build::new()
+        .file("src/runtime.c")
+        .warnings(false)
+        .compile("razen_runtime");
+    println!("cargo:rerun-if-changed=src/runtime.c");
+}
@@ -22,9 +22,25 @@ impl Program {
     }
 }
 
+// Span is the stretch of source a node was parsed from: the file it belongs to (an index into
+// the compiler's file names), its start and end byte offsets, and the line and column it starts at
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    pub file: usize,
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
 // Statement represents a statement in the program
 #[derive(Debug, Clone, PartialEq)]
 pub enum Statement {
+    // A statement tagged with the source it was parsed from
+    Located {
+        span: Span,
+        statement: Box<Statement>,
+    },
     VariableDeclaration {
         var_type: String,     // let, take, hold, put
         name: String,
@@ -103,7 +119,8 @@ pub enum Statement {
     // OOP (Section 12)
     ClassDeclaration {
         name: String,
-        body: Vec<Statement>,
+        parent: Option<String>,  // Class it extends
+        body: Vec<Statement>,    // Field declarations and methods
     },
     // API Integration (Section 13)
     ApiDeclaration {
@@ -214,6 +231,7 @@ pub enum Statement {
     },
     FinalClassDeclaration {
         name: String,
+        parent: Option<String>,
         body: Vec<Statement>,
     },
     VolatileDeclaration {
@@ -221,11 +239,91 @@ pub enum Statement {
         name: String,
         value: Option<Expression>,
     },
+    // Pattern matching
+    WhenStatement {
+        value: Expression,
+        arms: Vec<WhenArm>,
+    },
+}
+
+// WhenArm is one `pattern [if guard] => { ... }` branch of a when statement
+#[derive(Debug, Clone, PartialEq)]
+pub struct WhenArm {
+    pub pattern: Pattern,
+    pub guard: Option<Expression>,
+    pub body: Vec<Statement>,
+}
+
+// Pattern is what a when arm matches its value against
+#[derive(Debug, Clone, PartialEq)]
+pub enum Pattern {
+    Wildcard,                   // _
+    Binding(String),            // name, binds the value
+    Literal(Expression),        // 1, "text", true, null
+    Range {                     // 1..5, 1..=5
+        start: Expression,
+        end: Expression,
+        inclusive: bool,
+    },
+    EnumVariant {               // Shape.CIRCLE
+        enum_name: String,
+        variant: String,
+    },
+    Array {                     // [first, 0, ..rest]
+        elements: Vec<Pattern>,
+        rest: Option<Box<Pattern>>,
+    },
+}
+
+impl Pattern {
+    // Whether the pattern matches every value
+    pub fn is_irrefutable(&self) -> bool {
+        matches!(self, Pattern::Wildcard | Pattern::Binding(_))
+    }
+}
+
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Pattern::Wildcard => write!(f, "_"),
+            Pattern::Binding(name) => write!(f, "{}", name),
+            Pattern::Literal(value) => write!(f, "{}", Node::Expression(value.clone())),
+            Pattern::Range { start, end, inclusive } => {
+                let op = if *inclusive { "..=" } else { ".." };
+                write!(f, "{}{}{}", Node::Expression(start.clone()), op, Node::Expression(end.clone()))
+            },
+            Pattern::EnumVariant { enum_name, variant } => write!(f, "{}.{}", enum_name, variant),
+            Pattern::Array { elements, rest } => {
+                let mut parts: Vec<String> = elements.iter().map(|p| p.to_string()).collect();
+                match rest.as_deref() {
+                    Some(Pattern::Wildcard) => parts.push("..".to_string()),
+                    Some(rest) => parts.push(format!("..{}", rest)),
+                    None => {}
+                }
+                write!(f, "[{}]", parts.join(", "))
+            },
+        }
+    }
+}
+
+impl Statement {
+    // The statement itself, looking through any source span tag
+    pub fn unlocated(&self) -> &Statement {
+        match self {
+            Statement::Located { statement, .. } => statement.unlocated(),
+            other => other,
+        }
+    }
 }
 
 // Expression represents an expression in the program
 #[derive(Debug, Clone, PartialEq)]
 pub enum Expression {
+    // An expression tagged with the source it was parsed from
+    Located {
+        span: Span,
+        expression: Box<Expression>,
+    },
     Identifier(String),
     StringLiteral(String),
     NumberLiteral(f64),
@@ -269,6 +367,36 @@ pub enum Expression {
         function: String,
         arguments: Vec<Expression>,
     },
+    FunctionLiteral {
+        parameters: Vec<String>,
+        body: Vec<Statement>,
+    },
+}
+
+impl Expression {
+    // The expression itself, looking through any source span tag
+    pub fn unlocated(&self) -> &Expression {
+        match self {
+            Expression::Located { expression, .. } => expression.unlocated(),
+            other => other,
+        }
+    }
+
+    // Where the expression came from, if the parser recorded it
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            Expression::Located { span, .. } => Some(*span),
+            _ => None,
+        }
+    }
+
+    // Owned version of `unlocated`, for matches that take the expression apart
+    pub fn into_unlocated(self) -> Expression {
+        match self {
+            Expression::Located { expression, .. } => expression.into_unlocated(),
+            other => other,
+        }
+    }
 }
 
 impl fmt::Display for Node {
@@ -283,6 +411,9 @@ impl fmt::Display for Node {
             },
             Node::Statement(stmt) => {
                 match stmt {
+                    Statement::Located { statement, .. } => {
+                        write!(f, "{}", Node::Statement(*statement.clone()))
+                    },
                     Statement::VariableDeclaration { var_type, name, value } => {
                         if let Some(val) = value {
                             write!(f, "{} {} = {};", var_type, name, Node::Expression(val.clone()))
@@ -486,14 +617,15 @@ impl fmt::Display for Node {
                         write!(f, "trace {};", Node::Expression(value.clone()))
                     },
                     // OOP (Section 12)
-                    Statement::ClassDeclaration { name, body } => {
+                    Statement::ClassDeclaration { name, parent, body } => {
                         let mut body_str = String::new();
                         for stmt in body {
                             body_str.push_str(&format!("{}", Node::Statement(stmt.clone())));
                         }
-                        write!(f, "class {} {{
+                        let extends = parent.as_ref().map(|p| format!(" extends {}", p)).unwrap_or_default();
+                        write!(f, "class {}{} {{
 {}
-}}", name, body_str)
+}}", name, extends, body_str)
                     },
                     // API Integration (Section 13)
                     Statement::ApiDeclaration { name, url } => {
@@ -562,8 +694,9 @@ impl fmt::Display for Node {
                         result.push_str("\n}");
                         write!(f, "{}", result)
                     },
-                    Statement::FinalClassDeclaration { name, body } => {
-                        let mut result = format!("final class {} {{\n", name);
+                    Statement::FinalClassDeclaration { name, parent, body } => {
+                        let extends = parent.as_ref().map(|p| format!(" extends {}", p)).unwrap_or_default();
+                        let mut result = format!("final class {}{} {{\n", name, extends);
                         for stmt in body {
                             result.push_str(&format!("    {}", Node::Statement(stmt.clone())));
                         }
@@ -577,10 +710,27 @@ impl fmt::Display for Node {
                             write!(f, "volatile {} {};", var_type, name)
                         }
                     },
+                    Statement::WhenStatement { value, arms } => {
+                        let mut result = format!("when {} {{\n", Node::Expression(value.clone()));
+                        for arm in arms {
+                            result.push_str(&format!("    {}", arm.pattern));
+                            if let Some(guard) = &arm.guard {
+                                result.push_str(&format!(" if {}", Node::Expression(guard.clone())));
+                            }
+                            result.push_str(" => {\n");
+                            for stmt in &arm.body {
+                                result.push_str(&format!("        {}", Node::Statement(stmt.clone())));
+                            }
+                            result.push_str("\n    },\n");
+                        }
+                        result.push_str("}");
+                        write!(f, "{}", result)
+                    },
                 }
             },
             Node::Expression(expr) => {
                 match expr {
+                    Expression::Located { expression, .. } => write!(f, "{}", Node::Expression(*expression.clone())),
                     Expression::Identifier(name) => write!(f, "{}", name),
                     Expression::StringLiteral(value) => write!(f, "\"{}\"", value),
                     Expression::NumberLiteral(value) => write!(f, "{}", value),
@@ -638,6 +788,13 @@ impl fmt::Display for Node {
                         let args_str = args.join(", ");
                         write!(f, "{}::{}({})", namespace, function, args_str)
                     },
+                    Expression::FunctionLiteral { parameters, body } => {
+                        let mut body_str = String::new();
+                        for stmt in body {
+                            body_str.push_str(&format!("{}", Node::Statement(stmt.clone())));
+                        }
+                        write!(f, "fun ({}) {{ {} }}", parameters.join(", "), body_str)
+                    },
                 }
             },
         }
@@ -0,0 +1,534 @@
+// Serialized bytecode container (.rzb) for compiled Razen programs.
+//
+// Layout, all integers little-endian:
+//   header     "RZB\0", u16 version, u16 reserved
+//   constants  u32 count, f64 numbers
+//   strings    u32 count, (u32 length, UTF-8 bytes) per string
+//   functions  u32 count, (u32 name, u32 address, u32 param count, u32 slot count, u32 slot
+//              names) per function, parameters in the first slots
+//   top level  u32 slot count, u32 slot names of the top-level frame
+//   files      u32 count, u32 name per source file, indexed by the file ids of spans
+//   jumps      u32 count, u32 instruction index per resolved jump target
+//   code       u32 count, u8 opcode and its operands per instruction
+//   spans      u32 file, start byte, end byte, line and column per instruction, line 0 when unknown
+// Operands are indexes into the tables above, except counts and booleans. A closure's
+// captures follow its count as a u8 kind (0 slot, 1 capture) and a u32 index each. A class's
+// parent follows its name as a u8 flag and, when set, a u32 string.
+
+use std::collections::HashMap;
+
+use crate::ast::Span;
+use crate::compiler::{Capture, IR, TOP_LEVEL_FRAME};
+
+const MAGIC: &[u8; 4] = b"RZB\0";
+// Bumped whenever the layout or the opcode numbering changes
+pub const VERSION: u16 = 6;
+
+// A program loaded from a bytecode file
+#[derive(Debug, Clone)]
+pub struct Bytecode {
+    pub ir: Vec<IR>,
+    pub source_map: Vec<Option<Span>>,
+    pub files: Vec<String>,
+    pub function_param_names: HashMap<String, Vec<String>>,
+    pub function_locals: HashMap<String, Vec<String>>,
+}
+
+// Deduplicating table of values, in order of first use
+struct Table<K> {
+    indexes: HashMap<K, u32>,
+    count: u32,
+}
+
+impl<K: std::hash::Hash + Eq> Table<K> {
+    fn new() -> Self {
+        Table { indexes: HashMap::new(), count: 0 }
+    }
+
+    fn index(&mut self, key: K, bytes: &mut Vec<u8>, write: impl FnOnce(&K, &mut Vec<u8>)) -> u32 {
+        if let Some(index) = self.indexes.get(&key) {
+            return *index;
+        }
+        write(&key, bytes);
+        let index = self.count;
+        self.indexes.insert(key, index);
+        self.count += 1;
+        index
+    }
+}
+
+fn write_u32(bytes: &mut Vec<u8>, value: usize) {
+    bytes.extend_from_slice(&(value as u32).to_le_bytes());
+}
+
+// Writes the constant, string and jump tables while the code is encoded
+struct Encoder {
+    constants: Table<u64>,
+    constant_bytes: Vec<u8>,
+    strings: Table<String>,
+    string_bytes: Vec<u8>,
+    jumps: Table<usize>,
+    jump_bytes: Vec<u8>,
+    functions: Vec<u8>,
+    function_count: u32,
+    code: Vec<u8>,
+}
+
+impl Encoder {
+    fn constant(&mut self, value: f64) -> u32 {
+        self.constants.index(value.to_bits(), &mut self.constant_bytes, |bits, bytes| bytes.extend_from_slice(&bits.to_le_bytes()))
+    }
+
+    fn string(&mut self, value: &str) -> u32 {
+        self.strings.index(value.to_string(), &mut self.string_bytes, |s, bytes| {
+            write_u32(bytes, s.len());
+            bytes.extend_from_slice(s.as_bytes());
+        })
+    }
+
+    fn jump(&mut self, target: usize) -> u32 {
+        self.jumps.index(target, &mut self.jump_bytes, |target, bytes| write_u32(bytes, *target))
+    }
+
+    // Count followed by the string of each name, for the slots of a frame or the source files
+    fn slots(&mut self, slots: &[String]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        write_u32(&mut bytes, slots.len());
+        for slot in slots {
+            let s = self.string(slot);
+            bytes.extend_from_slice(&s.to_le_bytes());
+        }
+        bytes
+    }
+
+    fn function(&mut self, name: &str, address: usize, param_count: usize, slots: &[String]) -> u32 {
+        let name = self.string(name);
+        let slots = self.slots(slots);
+        self.functions.extend_from_slice(&name.to_le_bytes());
+        write_u32(&mut self.functions, address);
+        write_u32(&mut self.functions, param_count);
+        self.functions.extend_from_slice(&slots);
+        self.function_count += 1;
+        self.function_count - 1
+    }
+
+    fn op(&mut self, opcode: u8, operands: &[u32]) {
+        self.code.push(opcode);
+        for operand in operands {
+            self.code.extend_from_slice(&operand.to_le_bytes());
+        }
+    }
+
+    fn instruction(&mut self, ir: &IR, param_names: &HashMap<String, Vec<String>>, locals: &HashMap<String, Vec<String>>) {
+        match ir {
+            IR::PushNumber(n) => { let c = self.constant(*n); self.op(0x01, &[c]) },
+            IR::PushString(s) => { let s = self.string(s); self.op(0x02, &[s]) },
+            IR::PushBoolean(b) => { self.code.push(0x03); self.code.push(*b as u8) },
+            IR::PushNull => self.op(0x04, &[]),
+            IR::Pop => self.op(0x05, &[]),
+            IR::Dup => self.op(0x06, &[]),
+            IR::Swap => self.op(0x07, &[]),
+            IR::StoreVar(name) => { let s = self.string(name); self.op(0x08, &[s]) },
+            IR::LoadVar(name) => { let s = self.string(name); self.op(0x09, &[s]) },
+            IR::Add => self.op(0x0A, &[]),
+            IR::Subtract => self.op(0x0B, &[]),
+            IR::Multiply => self.op(0x0C, &[]),
+            IR::Divide => self.op(0x0D, &[]),
+            IR::Modulo => self.op(0x0E, &[]),
+            IR::Power => self.op(0x0F, &[]),
+            IR::FloorDiv => self.op(0x10, &[]),
+            IR::Negate => self.op(0x11, &[]),
+            IR::Equal => self.op(0x12, &[]),
+            IR::NotEqual => self.op(0x13, &[]),
+            IR::GreaterThan => self.op(0x14, &[]),
+            IR::GreaterEqual => self.op(0x15, &[]),
+            IR::LessThan => self.op(0x16, &[]),
+            IR::LessEqual => self.op(0x17, &[]),
+            IR::And => self.op(0x18, &[]),
+            IR::Or => self.op(0x19, &[]),
+            IR::Not => self.op(0x1A, &[]),
+            IR::Jump(target) => { let j = self.jump(*target); self.op(0x1B, &[j]) },
+            IR::JumpIfFalse(target) => { let j = self.jump(*target); self.op(0x1C, &[j]) },
+            IR::JumpIfTrue(target) => { let j = self.jump(*target); self.op(0x1D, &[j]) },
+            IR::Call(name, arg_count) => { let s = self.string(name); self.op(0x1E, &[s, *arg_count as u32]) },
+            IR::Return => self.op(0x1F, &[]),
+            IR::Print => self.op(0x20, &[]),
+            IR::CreateArray(count) => self.op(0x21, &[*count as u32]),
+            IR::GetIndex => self.op(0x22, &[]),
+            IR::SetIndex => self.op(0x23, &[]),
+            IR::CreateMap(count) => self.op(0x24, &[*count as u32]),
+            IR::GetKey => self.op(0x25, &[]),
+            IR::SetKey => self.op(0x26, &[]),
+            IR::DefineFunction(name, address) => {
+                let params = param_names.get(name).cloned().unwrap_or_default();
+                let slots = locals.get(name).unwrap_or(&params);
+                let f = self.function(name, *address, params.len(), slots);
+                self.op(0x27, &[f])
+            },
+            IR::Label(label) => { let s = self.string(label); self.op(0x28, &[s]) },
+            IR::ReadInput => self.op(0x29, &[]),
+            IR::Exit => self.op(0x2A, &[]),
+            IR::SetGlobal(name) => { let s = self.string(name); self.op(0x2B, &[s]) },
+            IR::Sleep => self.op(0x2C, &[]),
+            IR::LibraryCall(library, function, arg_count) => {
+                let l = self.string(library);
+                let f = self.string(function);
+                self.op(0x2D, &[l, f, *arg_count as u32])
+            },
+            IR::SetupTryCatch(target) => { let j = self.jump(*target); self.op(0x2E, &[j]) },
+            IR::ClearTryCatch => self.op(0x2F, &[]),
+            IR::ThrowException => self.op(0x30, &[]),
+            IR::StoreLocal(slot) => self.op(0x31, &[*slot as u32]),
+            IR::LoadLocal(slot) => self.op(0x32, &[*slot as u32]),
+            IR::StoreCapture(index) => self.op(0x33, &[*index as u32]),
+            IR::LoadCapture(index) => self.op(0x34, &[*index as u32]),
+            IR::CallValue(arg_count) => self.op(0x35, &[*arg_count as u32]),
+            IR::MakeClosure(name, captures) => {
+                let s = self.string(name);
+                self.op(0x36, &[s, captures.len() as u32]);
+                for capture in captures {
+                    let (kind, index) = match capture {
+                        Capture::Local(slot) => (0, slot),
+                        Capture::Captured(index) => (1, index),
+                    };
+                    self.code.push(kind);
+                    write_u32(&mut self.code, *index);
+                }
+            },
+            IR::CallMethod(method, arg_count) => { let s = self.string(method); self.op(0x37, &[s, *arg_count as u32]) },
+            IR::DefineClass(name, parent) => {
+                let s = self.string(name);
+                self.op(0x38, &[s]);
+                match parent {
+                    Some(parent) => {
+                        let p = self.string(parent);
+                        self.code.push(1);
+                        write_u32(&mut self.code, p as usize);
+                    },
+                    None => self.code.push(0),
+                }
+            },
+        }
+    }
+}
+
+// Serialize a compiled program
+pub fn encode(
+    ir: &[IR],
+    source_map: &[Option<Span>],
+    files: &[String],
+    param_names: &HashMap<String, Vec<String>>,
+    locals: &HashMap<String, Vec<String>>,
+) -> Vec<u8> {
+    let mut encoder = Encoder {
+        constants: Table::new(),
+        constant_bytes: Vec::new(),
+        strings: Table::new(),
+        string_bytes: Vec::new(),
+        jumps: Table::new(),
+        jump_bytes: Vec::new(),
+        functions: Vec::new(),
+        function_count: 0,
+        code: Vec::new(),
+    };
+    for instruction in ir {
+        encoder.instruction(instruction, param_names, locals);
+    }
+    let top_level = encoder.slots(locals.get(TOP_LEVEL_FRAME).map_or(&[], Vec::as_slice));
+    let file_names = encoder.slots(files);
+
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(MAGIC);
+    bytes.extend_from_slice(&VERSION.to_le_bytes());
+    bytes.extend_from_slice(&0u16.to_le_bytes());
+    for (count, section) in [
+        (encoder.constants.count, &encoder.constant_bytes),
+        (encoder.strings.count, &encoder.string_bytes),
+        (encoder.function_count, &encoder.functions),
+    ] {
+        bytes.extend_from_slice(&count.to_le_bytes());
+        bytes.extend_from_slice(section);
+    }
+    bytes.extend_from_slice(&top_level);
+    bytes.extend_from_slice(&file_names);
+    for (count, section) in [(encoder.jumps.count, &encoder.jump_bytes), (ir.len() as u32, &encoder.code)] {
+        bytes.extend_from_slice(&count.to_le_bytes());
+        bytes.extend_from_slice(section);
+    }
+    for i in 0..ir.len() {
+        let span = source_map.get(i).copied().flatten().unwrap_or_default();
+        for value in [span.file, span.start, span.end, span.line, span.column] {
+            write_u32(&mut bytes, value);
+        }
+    }
+    bytes
+}
+
+// Reads values from a bytecode file, failing on truncated input
+struct Decoder<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        let end = self.offset.checked_add(len).filter(|end| *end <= self.bytes.len())
+            .ok_or_else(|| format!("Truncated bytecode at offset {}", self.offset))?;
+        let slice = &self.bytes[self.offset..end];
+        self.offset = end;
+        Ok(slice)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, String> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<usize, String> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize)
+    }
+
+    fn f64(&mut self) -> Result<f64, String> {
+        let mut bits = [0u8; 8];
+        bits.copy_from_slice(self.take(8)?);
+        Ok(f64::from_bits(u64::from_le_bytes(bits)))
+    }
+
+    // Read a count and reject counts that cannot fit in the remaining bytes
+    fn count(&mut self, min_size: usize) -> Result<usize, String> {
+        let count = self.u32()?;
+        if count.saturating_mul(min_size) > self.bytes.len() - self.offset {
+            return Err(format!("Truncated bytecode at offset {}", self.offset));
+        }
+        Ok(count)
+    }
+
+    // Read an index into a table of `len` entries
+    fn index(&mut self, len: usize, table: &str) -> Result<usize, String> {
+        let index = self.u32()?;
+        if index >= len {
+            return Err(format!("Invalid {} index {} at offset {}", table, index, self.offset - 4));
+        }
+        Ok(index)
+    }
+}
+
+// Load a program serialized by `encode`
+pub fn decode(bytes: &[u8]) -> Result<Bytecode, String> {
+    let mut decoder = Decoder { bytes, offset: 0 };
+    if decoder.take(4).ok() != Some(MAGIC.as_slice()) {
+        return Err("Not a Razen bytecode file".to_string());
+    }
+    let version = decoder.u16()?;
+    if version != VERSION {
+        return Err(format!("Unsupported bytecode version {} (expected {})", version, VERSION));
+    }
+    decoder.u16()?; // Reserved
+
+    let constant_count = decoder.count(8)?;
+    let constants = (0..constant_count).map(|_| decoder.f64()).collect::<Result<Vec<_>, _>>()?;
+
+    let string_count = decoder.count(4)?;
+    let mut strings = Vec::with_capacity(string_count);
+    for _ in 0..string_count {
+        let len = decoder.u32()?;
+        let offset = decoder.offset;
+        let string = std::str::from_utf8(decoder.take(len)?)
+            .map_err(|_| format!("Invalid UTF-8 string at offset {}", offset))?;
+        strings.push(string.to_string());
+    }
+
+    let slots = |decoder: &mut Decoder| {
+        let count = decoder.count(4)?;
+        (0..count)
+            .map(|_| decoder.index(strings.len(), "string").map(|i| strings[i].clone()))
+            .collect::<Result<Vec<_>, String>>()
+    };
+
+    let function_count = decoder.count(16)?;
+    let mut functions = Vec::with_capacity(function_count);
+    let mut function_param_names = HashMap::new();
+    let mut function_locals = HashMap::new();
+    for _ in 0..function_count {
+        let name = strings[decoder.index(strings.len(), "string")?].clone();
+        let address = decoder.u32()?;
+        let param_count = decoder.u32()?;
+        let locals = slots(&mut decoder)?;
+        if param_count > locals.len() {
+            return Err(format!("Function {} has {} parameters but {} slots", name, param_count, locals.len()));
+        }
+        function_param_names.insert(name.clone(), locals[..param_count].to_vec());
+        function_locals.insert(name.clone(), locals);
+        functions.push((name, address));
+    }
+    function_locals.insert(TOP_LEVEL_FRAME.to_string(), slots(&mut decoder)?);
+    let files = slots(&mut decoder)?;
+
+    let jump_count = decoder.count(4)?;
+    let jumps = (0..jump_count).map(|_| decoder.u32()).collect::<Result<Vec<_>, _>>()?;
+
+    let instruction_count = decoder.count(1)?;
+    if let Some(target) = jumps.iter().chain(functions.iter().map(|(_, address)| address)).find(|target| **target > instruction_count) {
+        return Err(format!("Jump target {} is outside the {} instructions", target, instruction_count));
+    }
+    let mut ir = Vec::with_capacity(instruction_count);
+    for _ in 0..instruction_count {
+        let offset = decoder.offset;
+        let string = |decoder: &mut Decoder| decoder.index(strings.len(), "string").map(|i| strings[i].clone());
+        let jump = |decoder: &mut Decoder| decoder.index(jumps.len(), "jump").map(|i| jumps[i]);
+        let instruction = match decoder.u8()? {
+            0x01 => IR::PushNumber(constants[decoder.index(constants.len(), "constant")?]),
+            0x02 => IR::PushString(string(&mut decoder)?),
+            0x03 => IR::PushBoolean(decoder.u8()? != 0),
+            0x04 => IR::PushNull,
+            0x05 => IR::Pop,
+            0x06 => IR::Dup,
+            0x07 => IR::Swap,
+            0x08 => IR::StoreVar(string(&mut decoder)?),
+            0x09 => IR::LoadVar(string(&mut decoder)?),
+            0x0A => IR::Add,
+            0x0B => IR::Subtract,
+            0x0C => IR::Multiply,
+            0x0D => IR::Divide,
+            0x0E => IR::Modulo,
+            0x0F => IR::Power,
+            0x10 => IR::FloorDiv,
+            0x11 => IR::Negate,
+            0x12 => IR::Equal,
+            0x13 => IR::NotEqual,
+            0x14 => IR::GreaterThan,
+            0x15 => IR::GreaterEqual,
+            0x16 => IR::LessThan,
+            0x17 => IR::LessEqual,
+            0x18 => IR::And,
+            0x19 => IR::Or,
+            0x1A => IR::Not,
+            0x1B => IR::Jump(jump(&mut decoder)?),
+            0x1C => IR::JumpIfFalse(jump(&mut decoder)?),
+            0x1D => IR::JumpIfTrue(jump(&mut decoder)?),
+            0x1E => IR::Call(string(&mut decoder)?, decoder.u32()?),
+            0x1F => IR::Return,
+            0x20 => IR::Print,
+            0x21 => IR::CreateArray(decoder.u32()?),
+            0x22 => IR::GetIndex,
+            0x23 => IR::SetIndex,
+            0x24 => IR::CreateMap(decoder.u32()?),
+            0x25 => IR::GetKey,
+            0x26 => IR::SetKey,
+            0x27 => {
+                let (name, address) = functions[decoder.index(functions.len(), "function")?].clone();
+                IR::DefineFunction(name, address)
+            },
+            0x28 => IR::Label(string(&mut decoder)?),
+            0x29 => IR::ReadInput,
+            0x2A => IR::Exit,
+            0x2B => IR::SetGlobal(string(&mut decoder)?),
+            0x2C => IR::Sleep,
+            0x2D => IR::LibraryCall(string(&mut decoder)?, string(&mut decoder)?, decoder.u32()?),
+            0x2E => IR::SetupTryCatch(jump(&mut decoder)?),
+            0x2F => IR::ClearTryCatch,
+            0x30 => IR::ThrowException,
+            0x31 => IR::StoreLocal(decoder.u32()?),
+            0x32 => IR::LoadLocal(decoder.u32()?),
+            0x33 => IR::StoreCapture(decoder.u32()?),
+            0x34 => IR::LoadCapture(decoder.u32()?),
+            0x35 => IR::CallValue(decoder.u32()?),
+            0x36 => {
+                let name = string(&mut decoder)?;
+                let count = decoder.count(5)?;
+                let mut captures = Vec::with_capacity(count);
+                for _ in 0..count {
+                    let kind_offset = decoder.offset;
+                    captures.push(match (decoder.u8()?, decoder.u32()?) {
+                        (0, slot) => Capture::Local(slot),
+                        (1, index) => Capture::Captured(index),
+                        (kind, _) => return Err(format!("Unknown capture kind {} at offset {}", kind, kind_offset)),
+                    });
+                }
+                IR::MakeClosure(name, captures)
+            },
+            0x37 => IR::CallMethod(string(&mut decoder)?, decoder.u32()?),
+            0x38 => {
+                let name = string(&mut decoder)?;
+                let flag_offset = decoder.offset;
+                let parent = match decoder.u8()? {
+                    0 => None,
+                    1 => Some(string(&mut decoder)?),
+                    flag => return Err(format!("Invalid parent flag {} at offset {}", flag, flag_offset)),
+                };
+                IR::DefineClass(name, parent)
+            },
+            opcode => return Err(format!("Unknown opcode 0x{:02X} at offset {}", opcode, offset)),
+        };
+        ir.push(instruction);
+    }
+
+    let mut source_map = Vec::with_capacity(instruction_count);
+    for _ in 0..instruction_count {
+        let (file, start, end) = (decoder.u32()?, decoder.u32()?, decoder.u32()?);
+        let (line, column) = (decoder.u32()?, decoder.u32()?);
+        source_map.push(if line == 0 { None } else { Some(Span { file, start, end, line, column }) });
+    }
+    if decoder.offset != bytes.len() {
+        return Err(format!("Unexpected data after the bytecode at offset {}", decoder.offset));
+    }
+
+    Ok(Bytecode { ir, source_map, files, function_param_names, function_locals })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::Compiler;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+
+    fn compile(input: &str) -> Compiler {
+        let mut parser = Parser::new(Lexer::new(input.to_string()));
+        let program = parser.parse_program();
+        assert_eq!(parser.get_errors().len(), 0, "Parser errors: {:?}", parser.get_errors());
+        let mut compiler = Compiler::new();
+        compiler.set_clean_output(true);
+        compiler.compile_program(program);
+        compiler
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let compiler = compile(r#"
+            fun add(a, b) { return a + b; }
+            fun adder(n) { return fun (x) { n = n + 1; return x + n; }; }
+            class Shape { var sides = 0; fun describe() { return sides; } }
+            class Square extends Shape { fun init() { self.sides = 4; } }
+            show Square().describe();
+            list items = [1.5, "two", true];
+            num i = 0;
+            while (i < 3) { show add(i, 2); i = i + 1; }
+        "#);
+        let files = ["main.rzn".to_string()];
+        let bytes = encode(&compiler.ir, compiler.source_map(), &files, compiler.function_param_names(), compiler.function_locals());
+        let bytecode = decode(&bytes).unwrap();
+
+        assert_eq!(format!("{:?}", bytecode.ir), format!("{:?}", compiler.ir));
+        assert_eq!(bytecode.source_map, compiler.source_map());
+        assert_eq!(bytecode.files, files);
+        assert_eq!(&bytecode.function_param_names, compiler.function_param_names());
+        assert_eq!(&bytecode.function_locals, compiler.function_locals());
+    }
+
+    #[test]
+    fn test_rejects_invalid_files() {
+        let compiler = compile("show 1;");
+        let mut bytes = encode(&compiler.ir, compiler.source_map(), &[], compiler.function_param_names(), compiler.function_locals());
+
+        assert_eq!(decode(b"\x7FELF").unwrap_err(), "Not a Razen bytecode file");
+        assert!(decode(&bytes[..bytes.len() - 1]).unwrap_err().starts_with("Truncated bytecode"));
+        bytes[4] = 99;
+        assert_eq!(decode(&bytes).unwrap_err(), format!("Unsupported bytecode version 99 (expected {})", VERSION));
+    }
+}
@@ -0,0 +1,738 @@
+// Static checks on a parsed program, run by `razen check` without compiling or executing it.
+// They find names that are used but never defined, calls to user functions with the wrong
+// number of arguments, assignments to constants, `break`, `continue` and `return` where they
+// have nothing to leave, and statements that can never run.
+
+use std::collections::HashMap;
+
+use crate::ast::{Expression, Pattern, Program, Span, Statement, WhenArm};
+use crate::compiler::BUILTIN_FUNCTIONS;
+use crate::diagnostic::{self, Diagnostic};
+
+// What a name stands for where it is used
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Binding {
+    Variable,
+    Constant(Option<Span>), // Where the constant was declared
+    Function,               // Functions, builtins and classes, which are called to build objects
+}
+
+// Names declared in one block. The root scope of a function is a boundary: the variables of
+// the functions around it are only visible from function literals, which capture them
+struct Scope {
+    names: HashMap<String, Binding>,
+    function: bool,
+    closure: bool,
+}
+
+impl Scope {
+    fn new(function: bool, closure: bool) -> Self {
+        Scope { names: HashMap::new(), function, closure }
+    }
+}
+
+// Parameter count of a user function and where it was declared
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Signature {
+    parameters: usize,
+    span: Option<Span>,
+}
+
+struct ClassInfo {
+    parent: Option<String>,
+    fields: Vec<String>,
+    init: Option<Signature>,
+}
+
+struct Checker {
+    scopes: Vec<Scope>,                             // The global scope first, the innermost last
+    functions: HashMap<String, Option<Signature>>,  // None for a name declared with different signatures
+    classes: HashMap<String, ClassInfo>,
+    loop_depth: usize,                              // Loops around the code being checked, within its function
+    function_depth: usize,
+    diagnostics: Vec<Diagnostic>,
+}
+
+// Check a whole program, returning its errors and warnings in source order
+pub fn check(program: &Program) -> Vec<Diagnostic> {
+    let mut checker = Checker {
+        scopes: vec![Scope::new(false, false)],
+        functions: HashMap::new(),
+        classes: HashMap::new(),
+        loop_depth: 0,
+        function_depth: 0,
+        diagnostics: Vec::new(),
+    };
+    for name in BUILTIN_FUNCTIONS {
+        checker.define(name, Binding::Function);
+    }
+
+    // Functions and classes can be used before their declaration, and so can globals from
+    // inside functions, which only run once the program has declared them
+    checker.declare_globals(&program.statements, None, true);
+    checker.check_block(&program.statements);
+
+    checker.diagnostics.sort_by_key(|d| d.primary.as_ref().map(|label| (label.span.line, label.span.column)));
+    checker.diagnostics
+}
+
+fn span_of(statement: &Statement) -> Option<Span> {
+    match statement {
+        Statement::Located { span, .. } => Some(*span),
+        _ => None,
+    }
+}
+
+fn plural(count: usize, noun: &str) -> String {
+    if count == 1 {
+        format!("{} {}", count, noun)
+    } else {
+        format!("{} {}s", count, noun)
+    }
+}
+
+impl Checker {
+    // Global names declared by `statements`. Every declaration outside functions defines a
+    // global, even inside a block, and named functions can be called by name from anywhere
+    fn declare_globals(&mut self, statements: &[Statement], span: Option<Span>, top_level: bool) {
+        for statement in statements {
+            let span = span_of(statement).or(span);
+            match statement.unlocated() {
+                Statement::FunctionDeclaration { name, parameters, body } |
+                Statement::InlineFunctionDeclaration { name, parameters, body } => {
+                    self.declare_function(name, parameters.len(), span);
+                    self.declare_globals(body, span, false);
+                },
+                Statement::ClassDeclaration { name, parent, body } |
+                Statement::FinalClassDeclaration { name, parent, body } => {
+                    self.declare_class(name, parent, body, span);
+                },
+                _ if !top_level => {
+                    for block in statement_blocks(statement.unlocated()) {
+                        self.declare_globals(block, span, false);
+                    }
+                },
+                Statement::VariableDeclaration { var_type, name, .. } if var_type == "const" => {
+                    self.define_global(name, Binding::Constant(span));
+                },
+                Statement::ConstDeclaration { name, .. } => self.define_global(name, Binding::Constant(span)),
+                Statement::VariableDeclaration { name, .. } |
+                Statement::VolatileDeclaration { name, .. } |
+                Statement::ReadStatement { name } |
+                Statement::EnumDeclaration { name, .. } |
+                Statement::ApiDeclaration { name, .. } |
+                Statement::ConnectStatement { name, .. } |
+                Statement::LibStatement { name } => self.define_global(name, Binding::Variable),
+                Statement::ModuleImport { names, alias, .. } => {
+                    for name in names.iter().chain(alias) {
+                        self.define_global(name, Binding::Variable);
+                    }
+                },
+                Statement::ImportStatement { imports, .. } => {
+                    for name in imports {
+                        self.define_global(name, Binding::Variable);
+                    }
+                },
+                other => {
+                    match other {
+                        Statement::ForStatement { iterator, .. } => self.define_global(iterator, Binding::Variable),
+                        Statement::TryStatement { catch_param: Some(name), .. } => self.define_global(name, Binding::Variable),
+                        Statement::WhenStatement { arms, .. } => {
+                            for arm in arms {
+                                for name in pattern_bindings(&arm.pattern) {
+                                    self.define_global(name, Binding::Variable);
+                                }
+                            }
+                        },
+                        _ => {
+                            if let Some(name) = compiler_construction_name(other) {
+                                self.define_global(name, Binding::Variable);
+                            }
+                        },
+                    }
+                    for block in statement_blocks(other) {
+                        self.declare_globals(block, span, true);
+                    }
+                },
+            }
+        }
+    }
+
+    fn declare_function(&mut self, name: &str, parameters: usize, span: Option<Span>) {
+        let signature = Signature { parameters, span };
+        self.functions.entry(name.to_string())
+            .and_modify(|existing| {
+                if existing.is_some_and(|existing| existing.parameters != parameters) {
+                    *existing = None;
+                }
+            })
+            .or_insert(Some(signature));
+        self.define_global(name, Binding::Function);
+    }
+
+    fn declare_class(&mut self, name: &str, parent: &Option<String>, body: &[Statement], span: Option<Span>) {
+        let mut fields = Vec::new();
+        let mut init = None;
+        for member in body {
+            let span = span_of(member).or(span);
+            match member.unlocated() {
+                Statement::VariableDeclaration { name: field, .. } => fields.push(field.clone()),
+                Statement::FunctionDeclaration { name: method, parameters, body } => {
+                    if method == "init" {
+                        init = Some(Signature { parameters: parameters.len(), span });
+                    }
+                    self.declare_globals(body, span, false);
+                },
+                _ => {},
+            }
+        }
+        self.classes.insert(name.to_string(), ClassInfo { parent: parent.clone(), fields, init });
+        self.define_global(name, Binding::Function);
+    }
+
+    fn define_global(&mut self, name: &str, binding: Binding) {
+        self.scopes[0].names.insert(name.to_string(), binding);
+    }
+
+    fn define(&mut self, name: &str, binding: Binding) {
+        if let Some(scope) = self.scopes.last_mut() {
+            scope.names.insert(name.to_string(), binding);
+        }
+    }
+
+    // What a name refers to from the current scope. Globals are visible everywhere, the
+    // variables of an enclosing function only from the function literals inside it
+    fn resolve(&self, name: &str) -> Option<Binding> {
+        let mut crossed_function = false;
+        for (depth, scope) in self.scopes.iter().enumerate().rev() {
+            match scope.names.get(name) {
+                Some(Binding::Variable | Binding::Constant(_)) if crossed_function && depth > 0 => {},
+                Some(binding) => return Some(*binding),
+                None => {},
+            }
+            crossed_function |= scope.function && !scope.closure;
+        }
+        None
+    }
+
+    fn report(&mut self, diagnostic: Diagnostic, span: Option<Span>, label: impl Into<String>) {
+        let diagnostic = match span {
+            Some(span) => diagnostic.with_primary(span, label),
+            None => diagnostic,
+        };
+        self.diagnostics.push(diagnostic);
+    }
+
+    // Fields a method of `class` sees by name, including those of the classes it extends
+    fn fields_of(&self, class: &str) -> Vec<String> {
+        let mut fields = Vec::new();
+        let mut current = Some(class.to_string());
+        while let Some(info) = current.and_then(|name| self.classes.get(&name)) {
+            fields.extend(info.fields.iter().cloned());
+            current = info.parent.clone();
+        }
+        fields
+    }
+
+    // Parameters taken by a class's constructor: those of its `init`, which may be inherited,
+    // or none. Unknown when it extends a class that is not declared
+    fn constructor_of(&self, class: &str) -> Option<Signature> {
+        let mut current = Some(class.to_string());
+        while let Some(name) = current {
+            let info = self.classes.get(&name)?;
+            if info.init.is_some() {
+                return info.init;
+            }
+            current = info.parent.clone();
+        }
+        Some(Signature { parameters: 0, span: None })
+    }
+
+    // Check a statement list in its own scope
+    fn check_scoped_block(&mut self, statements: &[Statement]) {
+        self.scopes.push(Scope::new(false, false));
+        self.check_block(statements);
+        self.scopes.pop();
+    }
+
+    fn check_block(&mut self, statements: &[Statement]) {
+        // Nothing after a return, break, continue or throw runs. Declarations are exempt, as
+        // functions and classes are defined before the code around them runs
+        let exit = statements.iter().position(|statement| match statement.unlocated() {
+            Statement::ReturnStatement { .. } => self.function_depth > 0,
+            Statement::BreakStatement | Statement::ContinueStatement => self.loop_depth > 0,
+            Statement::ThrowStatement { .. } => true,
+            _ => false,
+        });
+        if let Some(exit) = exit {
+            let unreachable = statements[exit + 1..].iter().find(|statement| !matches!(statement.unlocated(),
+                Statement::FunctionDeclaration { .. } | Statement::InlineFunctionDeclaration { .. } |
+                Statement::ClassDeclaration { .. } | Statement::FinalClassDeclaration { .. }));
+            if let Some(statement) = unreachable {
+                let mut warning = Diagnostic::warning(diagnostic::UNREACHABLE_CODE, "Unreachable statement");
+                if let Some(span) = span_of(&statements[exit]) {
+                    warning = warning.with_label(span, "any code following this is unreachable");
+                }
+                self.report(warning, span_of(statement), "unreachable statement");
+            }
+        }
+
+        for statement in statements {
+            self.check_statement(statement, None);
+        }
+    }
+
+    fn check_statement(&mut self, statement: &Statement, span: Option<Span>) {
+        match statement {
+            Statement::Located { span, statement } => self.check_statement(statement, Some(*span)),
+            Statement::VariableDeclaration { var_type, name, value } => {
+                if let Some(value) = value {
+                    self.check_expression(value, span);
+                }
+                let binding = if var_type == "const" { Binding::Constant(span) } else { Binding::Variable };
+                self.define(name, binding);
+            },
+            Statement::VolatileDeclaration { name, value, .. } => {
+                if let Some(value) = value {
+                    self.check_expression(value, span);
+                }
+                self.define(name, Binding::Variable);
+            },
+            Statement::ConstDeclaration { name, value } => {
+                self.check_expression(value, span);
+                self.define(name, Binding::Constant(span));
+            },
+            Statement::FunctionDeclaration { name, parameters, body } |
+            Statement::InlineFunctionDeclaration { name, parameters, body } => {
+                self.define(name, Binding::Function);
+                self.check_function(parameters, body, false, Vec::new());
+            },
+            Statement::ClassDeclaration { name, body, .. } |
+            Statement::FinalClassDeclaration { name, body, .. } => {
+                self.define(name, Binding::Function);
+                self.check_class(name, body);
+            },
+            Statement::ReturnStatement { value } => {
+                if self.function_depth == 0 {
+                    let error = Diagnostic::error(diagnostic::RETURN_OUTSIDE_FUNCTION, "'return' outside of a function")
+                        .with_note("use 'exit' to end the program early");
+                    self.report(error, span, "cannot return from here");
+                }
+                if let Some(value) = value {
+                    self.check_expression(value, span);
+                }
+            },
+            Statement::BreakStatement | Statement::ContinueStatement if self.loop_depth == 0 => {
+                let keyword = if matches!(statement, Statement::BreakStatement) { "break" } else { "continue" };
+                let error = Diagnostic::error(diagnostic::OUTSIDE_LOOP, format!("'{}' outside of a loop", keyword));
+                self.report(error, span, format!("cannot '{}' outside of a loop", keyword));
+            },
+            Statement::ExpressionStatement { expression } |
+            Statement::ShowStatement { value: expression, .. } |
+            Statement::ThrowStatement { value: expression } |
+            Statement::DebugStatement { value: expression } |
+            Statement::TraceStatement { value: expression } => self.check_expression(expression, span),
+            Statement::AssertStatement { condition, message } => {
+                self.check_expression(condition, span);
+                if let Some(message) = message {
+                    self.check_expression(message, span);
+                }
+            },
+            Statement::BlockStatement { statements } => self.check_scoped_block(statements),
+            Statement::IfStatement { condition, consequence, alternative } => {
+                self.check_expression(condition, span);
+                self.check_scoped_block(consequence);
+                if let Some(alternative) = alternative {
+                    self.check_scoped_block(alternative);
+                }
+            },
+            Statement::WhileStatement { condition, body } => {
+                self.check_expression(condition, span);
+                self.check_loop_body(body, None);
+            },
+            Statement::ForStatement { iterator, iterable, body } => {
+                self.check_expression(iterable, span);
+                self.check_loop_body(body, Some(iterator));
+            },
+            Statement::LoadStatement { cycles, block } => {
+                self.check_expression(cycles, span);
+                self.check_scoped_block(block);
+            },
+            Statement::TryStatement { try_block, catch_param, catch_block, finally_block } => {
+                self.check_scoped_block(try_block);
+                if let Some(catch_block) = catch_block {
+                    self.scopes.push(Scope::new(false, false));
+                    if let Some(name) = catch_param {
+                        self.define(name, Binding::Variable);
+                    }
+                    self.check_block(catch_block);
+                    self.scopes.pop();
+                }
+                if let Some(finally_block) = finally_block {
+                    self.check_scoped_block(finally_block);
+                }
+            },
+            Statement::WhenStatement { value, arms } => {
+                self.check_expression(value, span);
+                for arm in arms {
+                    self.check_when_arm(arm, span);
+                }
+            },
+            Statement::EnumDeclaration { name, variants } => {
+                for value in variants.iter().filter_map(|(_, value)| value.as_ref()) {
+                    self.check_expression(value, span);
+                }
+                self.define(name, Binding::Variable);
+            },
+            Statement::ReadStatement { name } |
+            Statement::ApiDeclaration { name, .. } |
+            Statement::ConnectStatement { name, .. } |
+            Statement::LibStatement { name } => self.define(name, Binding::Variable),
+            Statement::ModuleImport { names, alias, .. } => {
+                for name in names.iter().chain(alias) {
+                    self.define(name, Binding::Variable);
+                }
+            },
+            Statement::ImportStatement { imports, .. } => {
+                for name in imports {
+                    self.define(name, Binding::Variable);
+                }
+            },
+            other => {
+                if let Some(name) = compiler_construction_name(other) {
+                    self.define(name, Binding::Variable);
+                }
+            },
+        }
+    }
+
+    fn check_loop_body(&mut self, body: &[Statement], iterator: Option<&String>) {
+        self.scopes.push(Scope::new(false, false));
+        if let Some(iterator) = iterator {
+            self.define(iterator, Binding::Variable);
+        }
+        self.loop_depth += 1;
+        self.check_block(body);
+        self.loop_depth -= 1;
+        self.scopes.pop();
+    }
+
+    fn check_when_arm(&mut self, arm: &WhenArm, span: Option<Span>) {
+        self.scopes.push(Scope::new(false, false));
+        self.check_pattern(&arm.pattern, span);
+        if let Some(guard) = &arm.guard {
+            self.check_expression(guard, span);
+        }
+        self.check_block(&arm.body);
+        self.scopes.pop();
+    }
+
+    fn check_pattern(&mut self, pattern: &Pattern, span: Option<Span>) {
+        match pattern {
+            Pattern::Binding(name) => self.define(name, Binding::Variable),
+            Pattern::Literal(value) => self.check_expression(value, span),
+            Pattern::Range { start, end, .. } => {
+                self.check_expression(start, span);
+                self.check_expression(end, span);
+            },
+            Pattern::Array { elements, rest } => {
+                for element in elements {
+                    self.check_pattern(element, span);
+                }
+                if let Some(rest) = rest {
+                    self.check_pattern(rest, span);
+                }
+            },
+            Pattern::Wildcard | Pattern::EnumVariant { .. } => {},
+        }
+    }
+
+    // Check a function body in a scope of its own. Loops around the function do not extend
+    // into it, and a method sees the fields of its class by name
+    fn check_function(&mut self, parameters: &[String], body: &[Statement], closure: bool, fields: Vec<String>) {
+        let mut scope = Scope::new(true, closure);
+        for name in fields.iter().chain(parameters) {
+            scope.names.insert(name.clone(), Binding::Variable);
+        }
+        self.scopes.push(scope);
+        let outer_loops = std::mem::replace(&mut self.loop_depth, 0);
+        self.function_depth += 1;
+        self.check_block(body);
+        self.function_depth -= 1;
+        self.loop_depth = outer_loops;
+        self.scopes.pop();
+    }
+
+    // Field initializers run in the constructor, which only has the new object, and methods
+    // take the object as `self`
+    fn check_class(&mut self, class: &str, body: &[Statement]) {
+        let fields = self.fields_of(class);
+        for member in body {
+            let span = span_of(member);
+            match member.unlocated() {
+                Statement::VariableDeclaration { value: Some(value), .. } => {
+                    self.scopes.push(Scope::new(true, false));
+                    self.define("self", Binding::Variable);
+                    self.check_expression(value, span);
+                    self.scopes.pop();
+                },
+                Statement::FunctionDeclaration { parameters, body, .. } => {
+                    let mut method_parameters = vec!["self".to_string()];
+                    method_parameters.extend(parameters.iter().cloned());
+                    self.check_function(&method_parameters, body, false, fields.clone());
+                },
+                _ => {},
+            }
+        }
+    }
+
+    fn check_expression(&mut self, expression: &Expression, span: Option<Span>) {
+        match expression {
+            Expression::Located { span, expression } => self.check_expression(expression, Some(*span)),
+            Expression::Identifier(name) => {
+                if self.resolve(name).is_none() {
+                    let error = Diagnostic::error(diagnostic::UNDEFINED_VARIABLE, format!("Undefined variable '{}'", name));
+                    self.report(error, span, "not found in this scope");
+                }
+            },
+            Expression::PrefixExpression { right, .. } => self.check_expression(right, span),
+            // The right of a dot is a field name
+            Expression::InfixExpression { left, operator, .. } if operator == "." => self.check_expression(left, span),
+            Expression::InfixExpression { left, right, .. } => {
+                self.check_expression(left, span);
+                self.check_expression(right, span);
+            },
+            Expression::AssignmentExpression { left, operator, right } => {
+                self.check_expression(right, span);
+                self.check_assignment_target(left, operator, span);
+            },
+            Expression::CallExpression { function, arguments } => {
+                self.check_call(function, arguments.len(), span);
+                self.check_expressions(arguments, span);
+            },
+            Expression::ArrayLiteral { elements } => self.check_expressions(elements, span),
+            Expression::IndexExpression { left, index } => {
+                self.check_expression(left, span);
+                self.check_index(index, span);
+            },
+            Expression::MapLiteral { pairs } => {
+                for (key, value) in pairs {
+                    self.check_expression(key, span);
+                    self.check_expression(value, span);
+                }
+            },
+            Expression::LibraryCall { library, function, arguments } => {
+                // `handlers[key](x)` calls an element of a variable; anything else names a library
+                if let Expression::Identifier(name) = library.unlocated() {
+                    if matches!(self.resolve(name), Some(Binding::Variable | Binding::Constant(_))) {
+                        self.check_index(function, span);
+                    }
+                }
+                self.check_expressions(arguments, span);
+            },
+            Expression::NamespaceCall { arguments, .. } => self.check_expressions(arguments, span),
+            Expression::FunctionLiteral { parameters, body } => self.check_function(parameters, body, true, Vec::new()),
+            Expression::StringLiteral(_) | Expression::NumberLiteral(_) |
+            Expression::BooleanLiteral(_) | Expression::NullLiteral => {},
+        }
+    }
+
+    fn check_expressions(&mut self, expressions: &[Expression], span: Option<Span>) {
+        for expression in expressions {
+            self.check_expression(expression, span);
+        }
+    }
+
+    // An undefined name used as an index is a key, as in Color[RED]
+    fn check_index(&mut self, index: &Expression, span: Option<Span>) {
+        match index.unlocated() {
+            Expression::Identifier(name) if self.resolve(name).is_none() => {},
+            _ => self.check_expression(index, span),
+        }
+    }
+
+    fn check_assignment_target(&mut self, target: &Expression, operator: &str, span: Option<Span>) {
+        match target {
+            Expression::Located { span, expression } => self.check_assignment_target(expression, operator, Some(*span)),
+            Expression::Identifier(name) => match self.resolve(name) {
+                Some(Binding::Constant(declaration)) => {
+                    let mut error = Diagnostic::error(diagnostic::ASSIGN_TO_CONSTANT, format!("Cannot assign to constant '{}'", name))
+                        .with_note("declare it with 'var' to make it changeable");
+                    if let Some(declaration) = declaration {
+                        error = error.with_label(declaration, "declared as a constant here");
+                    }
+                    self.report(error, span, "cannot assign twice to a constant");
+                },
+                // Assigning to an undeclared name creates a global, but `x += 1` reads it first
+                None if operator == "=" => self.define_global(name, Binding::Variable),
+                _ => self.check_expression(target, span),
+            },
+            _ => self.check_expression(target, span),
+        }
+    }
+
+    // Check what a call calls, and for a user function or class, how many arguments it gets
+    fn check_call(&mut self, function: &Expression, arguments: usize, span: Option<Span>) {
+        match function.unlocated() {
+            Expression::Identifier(name) => {
+                let function_span = match function {
+                    Expression::Located { span, .. } => Some(*span),
+                    _ => span,
+                };
+                match self.resolve(name) {
+                    Some(Binding::Function) => {
+                        let signature = match self.functions.get(name) {
+                            Some(signature) => *signature,
+                            None if self.classes.contains_key(name) => self.constructor_of(name),
+                            None => None,
+                        };
+                        if let Some(signature) = signature.filter(|signature| signature.parameters != arguments) {
+                            self.wrong_argument_count(name, signature, arguments, span);
+                        }
+                    },
+                    Some(_) => {},
+                    None => {
+                        let error = Diagnostic::error(diagnostic::UNDEFINED_FUNCTION, format!("Undefined function '{}'", name));
+                        self.report(error, function_span, "not found in this scope");
+                    },
+                }
+            },
+            // `super.method(...)` calls the parent class's method on `self`
+            Expression::InfixExpression { left, operator, .. } if operator == "." => {
+                if !matches!(left.unlocated(), Expression::Identifier(receiver) if receiver == "super") {
+                    self.check_expression(function, span);
+                }
+            },
+            _ => self.check_expression(function, span),
+        }
+    }
+
+    fn wrong_argument_count(&mut self, name: &str, signature: Signature, arguments: usize, span: Option<Span>) {
+        let given = if arguments == 1 { "was" } else { "were" };
+        let message = format!("'{}' takes {} but {} {} given", name, plural(signature.parameters, "argument"), arguments, given);
+        let mut error = Diagnostic::error(diagnostic::WRONG_ARGUMENT_COUNT, message);
+        if let Some(declaration) = signature.span {
+            error = error.with_label(declaration, format!("'{}' defined here", name));
+        }
+        self.report(error, span, format!("expected {}", plural(signature.parameters, "argument")));
+    }
+}
+
+// Statement lists nested in a statement, other than function and class bodies
+fn statement_blocks(statement: &Statement) -> Vec<&[Statement]> {
+    match statement {
+        Statement::BlockStatement { statements } => vec![statements],
+        Statement::IfStatement { consequence, alternative, .. } => {
+            std::iter::once(consequence.as_slice()).chain(alternative.as_deref()).collect()
+        },
+        Statement::WhileStatement { body, .. } | Statement::ForStatement { body, .. } => vec![body],
+        Statement::LoadStatement { block, .. } => vec![block],
+        Statement::TryStatement { try_block, catch_block, finally_block, .. } => {
+            std::iter::once(try_block.as_slice()).chain(catch_block.as_deref()).chain(finally_block.as_deref()).collect()
+        },
+        Statement::WhenStatement { arms, .. } => arms.iter().map(|arm| arm.body.as_slice()).collect(),
+        _ => Vec::new(),
+    }
+}
+
+// Variable a compiler construction statement, such as `token PLUS = "+"`, stores its definition in
+fn compiler_construction_name(statement: &Statement) -> Option<&String> {
+    match statement {
+        Statement::GrammarStatement { name, .. } | Statement::TokenStatement { name, .. } |
+        Statement::LexerStatement { name, .. } | Statement::ParserStatement { name, .. } |
+        Statement::NodeStatement { name, .. } | Statement::RuleStatement { name, .. } |
+        Statement::VisitorStatement { name, .. } | Statement::SymbolStatement { name, .. } |
+        Statement::ScopeStatement { name, .. } | Statement::TypeStatement { name, .. } |
+        Statement::IRStatement { name, .. } | Statement::CodeGenStatement { name, .. } |
+        Statement::OptimizeStatement { name, .. } | Statement::TargetStatement { name, .. } |
+        Statement::AttributeStatement { name, .. } => Some(name),
+        _ => None,
+    }
+}
+
+// Names a pattern binds
+fn pattern_bindings(pattern: &Pattern) -> Vec<&String> {
+    match pattern {
+        Pattern::Binding(name) => vec![name],
+        Pattern::Array { elements, rest } => elements.iter().chain(rest.as_deref()).flat_map(pattern_bindings).collect(),
+        _ => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diagnostic::Severity;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+
+    // Code and line of each diagnostic for `source`
+    fn check_source(source: &str) -> Vec<(&'static str, usize)> {
+        let mut parser = Parser::new(Lexer::new(source.to_string()));
+        let program = parser.parse_program();
+        assert!(parser.get_errors().is_empty(), "Parser errors: {:?}", parser.get_errors());
+        check(&program).iter()
+            .map(|d| (d.code, d.primary.as_ref().map_or(0, |label| label.span.line)))
+            .collect()
+    }
+
+    #[test]
+    fn test_valid_program() {
+        let source = "\
+fun total(items) {
+    var sum = 0;
+    for (item in items) {
+        sum = sum + item;
+    }
+    return sum + offset;
+}
+num offset = 1;
+class Counter {
+    var count = 0
+    fun init(start) { count = start; }
+    fun add(n) { count = count + n; return self; }
+}
+var c = Counter(5);
+c.add(total([1, 2]));
+var twice = fun(x) { return x * 2; };
+show twice(len(\"ab\"));
+enum Shade { LIGHT, DARK }
+show Shade[DARK];
+when c.count { 0 => { show \"none\"; }, n if n > 3 => { show n; }, _ => { show \"few\"; } }
+";
+        assert_eq!(check_source(source), vec![]);
+    }
+
+    #[test]
+    fn test_errors() {
+        use crate::diagnostic::{UNDEFINED_VARIABLE as VARIABLE, UNDEFINED_FUNCTION as FUNCTION, WRONG_ARGUMENT_COUNT as ARITY};
+
+        let corpus: &[(&str, &[(&str, usize)])] = &[
+            ("show missing;\nshow greet();", &[(VARIABLE, 1), (FUNCTION, 2)]),
+            ("fun add(a, b) { return a + b; }\nshow add(1);\nshow add(1, 2, 3);", &[(ARITY, 2), (ARITY, 3)]),
+            ("class P { fun init(name) { } }\nvar p = P();", &[(ARITY, 2)]),
+            // Locals do not outlive their function, and named functions do not see the caller's
+            ("fun f() { var inner = 1; }\nshow inner;", &[(VARIABLE, 2)]),
+            ("fun outer() {\n    var x = 1;\n    fun nested() { return x; }\n}", &[(VARIABLE, 3)]),
+            ("const LIMIT = 10;\nLIMIT = 11;\nfun f() { LIMIT += 1; }", &[(diagnostic::ASSIGN_TO_CONSTANT, 2), (diagnostic::ASSIGN_TO_CONSTANT, 3)]),
+            ("break;\nwhile (true) { fun f() { continue; } }", &[(diagnostic::OUTSIDE_LOOP, 1), (diagnostic::OUTSIDE_LOOP, 2)]),
+            ("return 1;", &[(diagnostic::RETURN_OUTSIDE_FUNCTION, 1)]),
+            ("fun f() {\n    return 1;\n    show \"never\";\n}", &[(diagnostic::UNREACHABLE_CODE, 3)]),
+        ];
+
+        for (source, expected) in corpus {
+            assert_eq!(&check_source(source), expected, "Diagnostics for {:?}", source);
+        }
+    }
+
+    #[test]
+    fn test_diagnostic_details() {
+        let source = "fun add(a, b) { return a + b; }\nshow add(1);";
+        let mut parser = Parser::new(Lexer::new(source.to_string()));
+        let diagnostics = check(&parser.parse_program());
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].severity, Severity::Error);
+        assert_eq!(diagnostics[0].to_string(), "error[E0012]: 'add' takes 2 arguments but 1 was given at line 2, column 6");
+        assert_eq!(diagnostics[0].labels[0].span.line, 1);
+    }
+}
@@ -1,16 +1,152 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::path::Path;
 use std::process::Command;
 use std::io::Read;
 use std::fs::File;
 use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
 use std::{thread, time::Duration};
 
-use crate::ast::{Program, Statement, Expression};
+use crate::ast::{Node, Program, Statement, Expression, Span, WhenArm, Pattern};
 use crate::parser::Parser;
-use crate::value::Value as RazenValue;
+use crate::diagnostic::{self, Diagnostic, ErrorFormat, Severity, SourceFiles};
+use crate::value::{Closure, Instance, Value as RazenValue};
 use crate::library;
+use crate::bytecode;
+use crate::disasm;
+use crate::optimizer;
+
+// Key of the top-level code's frame in `Compiler::function_locals`
+pub const TOP_LEVEL_FRAME: &str = "<main>";
+
+// Largest function body, in IR instructions, that `inline fun` calls are expanded for
+const INLINE_SIZE_LIMIT: usize = 64;
+
+// Functions every program can call by name: print, math operations and so on
+pub const BUILTIN_FUNCTIONS: &[&str] = &[
+    "print",
+    "read",
+    "len",
+    "append",
+    "remove",
+
+    // Object built-ins
+    "__new_instance",
+
+    // Pattern matching built-ins
+    "__match_array",
+    "__array_rest",
+    "__comparable",
+
+    // Module system built-ins
+    "__import_symbol",
+    "__import_module",
+    "__export_symbol",
+
+    // Developer tools built-ins
+    "__debug",
+    "__assert",
+    "__assert_with_message",
+    "__trace",
+
+    // Type checking built-ins
+    "__check_kind",
+
+    // Standard library functions
+    "floor",            // Math functions
+    "ceil",
+    "round",
+    "sin",
+    "cos",
+    "tan",
+    "sqrt",
+    "random",
+
+    "format",           // String functions
+    "substring",
+    "uppercase",
+    "lowercase",
+    "trim",
+    "replace",
+
+    "map",              // Array functions
+    "filter",
+    "reduce",
+    "join",
+    "split",
+
+    "now",              // Time functions
+    "format_date",
+    "sleep",
+
+    "parse_json",       // JSON functions
+    "stringify_json",
+
+    "read_file",        // File I/O functions
+    "write_file",
+    "append_file",
+
+    "get_args",         // Scripting functions
+    "get_env",
+    "set_env",
+    "run_command",
+    "exit_with",
+
+    "create_dir",       // File system functions
+    "remove_dir",
+    "list_dir",
+    "is_file",
+    "is_dir",
+    "file_exists",
+    "copy_file",
+    "move_file",
+    "delete_file",
+
+    "join_path",        // Path manipulation functions
+    "basename",
+    "dirname",
+    "absolute_path",
+];
+
+// Kind of value a variable declared with `keyword` must hold. Other declarations, like
+// `var`, hold anything
+fn declared_kind(keyword: &str) -> Option<&'static str> {
+    match keyword {
+        "num" => Some("num"),
+        "str" => Some("str"),
+        "bool" => Some("bool"),
+        "list" | "arr" => Some("list"),
+        "map" => Some("map"),
+        _ => None,
+    }
+}
+
+// An `inline fun` whose body is substituted at call sites
+#[derive(Debug, Clone)]
+struct InlineFunction {
+    parameters: Vec<String>,
+    body: Vec<Statement>,
+}
+
+// A try statement around the code being compiled. A break, continue or return that leaves it
+// clears its handler, if one is installed, and runs its finally block on the way out
+#[derive(Debug, Clone)]
+struct TryRegion {
+    loop_depth: usize, // Loops around the try statement
+    handler: bool,
+    finally: Option<Vec<Statement>>,
+}
+
+// A declared class: the class it extends, every field with its initial value, inherited
+// fields first, and the methods the class defines itself
+#[derive(Debug, Clone)]
+struct ClassInfo {
+    parent: Option<String>,
+    fields: Vec<(String, Option<Expression>)>,
+    methods: Vec<String>,
+    is_final: bool,
+}
 
 // Intermediate representation for code generation
 #[derive(Debug, Clone)]
@@ -25,7 +161,7 @@ pub enum IR {
     Swap,
 
     // Exception handling
-    SetupTryCatch,
+    SetupTryCatch(usize),  // address of the catch handler
     ClearTryCatch,
     ThrowException,
 
@@ -33,6 +169,10 @@ pub enum IR {
     StoreVar(String),
     LoadVar(String),
     SetGlobal(String),  // Global variable operations
+    StoreLocal(usize),  // Slot in the current call frame
+    LoadLocal(usize),
+    StoreCapture(usize),  // Variable captured by the running closure
+    LoadCapture(usize),
 
     // Arithmetic operations
     Add,
@@ -62,6 +202,8 @@ pub enum IR {
     JumpIfFalse(usize),
     JumpIfTrue(usize),
     Call(String, usize),  // function name, arg count
+    CallValue(usize),     // arg count; the function value is below the arguments
+    CallMethod(String, usize),  // method name, arg count; the object is below the arguments
     Return,
 
     // I/O operations
@@ -81,6 +223,8 @@ pub enum IR {
 
     // Function definition
     DefineFunction(String, usize),  // function name, address
+    MakeClosure(String, Vec<Capture>),  // function name, variables it captures
+    DefineClass(String, Option<String>),  // class name, class it extends
 
     // Labels for jumps
     Label(String),
@@ -92,48 +236,149 @@ pub enum IR {
     LibraryCall(String, String, usize),  // library name, function name, arg count
 }
 
+// Where a closure gets each captured variable from when it is created
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Capture {
+    Local(usize),     // Slot of the frame creating the closure
+    Captured(usize),  // Variable captured by the closure creating it
+}
+
+// Where a variable lives at run time
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Storage {
+    Global,           // Shared by the whole program, looked up by name
+    Local(usize),     // Slot in the current call frame
+    Captured(usize),  // Variable of an enclosing function, captured by the running closure
+    Function,         // User function or builtin, called by name
+    Field,            // Field of `self`, in a method of its class
+}
+
 // Symbol table for variable and function tracking
 #[derive(Debug, Clone)]
 struct SymbolTable {
-    symbols: HashMap<String, usize>,
+    symbols: HashMap<String, Storage>,
+    kinds: HashMap<String, (&'static str, Option<Span>)>, // Kind of value a variable is declared to hold, and where
     parent: Option<Box<SymbolTable>>,
-    next_index: usize,
+    locals: bool,    // Variables defined here get a slot in the current frame
+    boundary: bool,  // Root scope of a function body
+    closure: bool,   // Root scope of a function literal, which sees enclosing locals
+    depth: usize,    // Index of the frame the variables defined here live in
 }
 
 impl SymbolTable {
     fn new() -> Self {
         SymbolTable {
             symbols: HashMap::new(),
+            kinds: HashMap::new(),
             parent: None,
-            next_index: 0,
+            locals: false,
+            boundary: false,
+            closure: false,
+            depth: 0,
         }
     }
 
     fn new_enclosed(parent: SymbolTable) -> Self {
         SymbolTable {
             symbols: HashMap::new(),
+            kinds: HashMap::new(),
+            locals: parent.locals,
+            depth: parent.depth,
             parent: Some(Box::new(parent)),
-            next_index: 0,
+            boundary: false,
+            closure: false,
         }
     }
 
-    fn define(&mut self, name: &str) -> usize {
-        let index = self.next_index;
-        self.symbols.insert(name.to_string(), index);
-        self.next_index += 1;
-        index
+    // Root scope of a function body, whose variables live in the frame at `depth`
+    fn new_function(parent: SymbolTable, depth: usize) -> Self {
+        SymbolTable {
+            symbols: HashMap::new(),
+            kinds: HashMap::new(),
+            parent: Some(Box::new(parent)),
+            locals: true,
+            boundary: true,
+            closure: false,
+            depth,
+        }
     }
 
-    fn resolve(&self, name: &str) -> Option<usize> {
-        match self.symbols.get(name) {
-            Some(index) => Some(*index),
-            None => {
-                if let Some(parent) = &self.parent {
-                    parent.resolve(name)
-                } else {
-                    None
-                }
+    // Root scope of a function literal, which captures the enclosing locals it uses
+    fn new_closure(parent: SymbolTable, depth: usize) -> Self {
+        SymbolTable {
+            closure: true,
+            ..SymbolTable::new_function(parent, depth)
+        }
+    }
+
+    fn define(&mut self, name: &str) {
+        self.symbols.insert(name.to_string(), Storage::Global);
+        self.kinds.remove(name);
+    }
+
+    fn define_local(&mut self, name: &str, slot: usize) {
+        self.symbols.insert(name.to_string(), Storage::Local(slot));
+        self.kinds.remove(name);
+    }
+
+    fn define_captured(&mut self, name: &str, index: usize) {
+        self.symbols.insert(name.to_string(), Storage::Captured(index));
+    }
+
+    fn define_function(&mut self, name: &str) {
+        self.symbols.insert(name.to_string(), Storage::Function);
+    }
+
+    fn define_field(&mut self, name: &str) {
+        self.symbols.insert(name.to_string(), Storage::Field);
+    }
+
+    // Record the kind of value a variable may hold, or that it may hold any
+    fn set_kind(&mut self, name: &str, kind: Option<(&'static str, Option<Span>)>) {
+        match kind {
+            Some(kind) => self.kinds.insert(name.to_string(), kind),
+            None => self.kinds.remove(name),
+        };
+    }
+
+    // Storage of a name and the depth of the frame it lives in. Locals and fields of an
+    // enclosing function are only visible from function literals, which capture them
+    fn resolve(&self, name: &str) -> Option<(Storage, usize)> {
+        self.lookup(name).map(|(table, storage)| (storage, table.depth))
+    }
+
+    // Kind of value the variable a name resolves to is declared to hold, and where
+    fn kind_of(&self, name: &str) -> Option<(&'static str, Option<Span>)> {
+        self.lookup(name).and_then(|(table, _)| table.kinds.get(name).copied())
+    }
+
+    fn lookup(&self, name: &str) -> Option<(&SymbolTable, Storage)> {
+        let mut table = self;
+        let mut crossed_function = false;
+        loop {
+            match table.symbols.get(name) {
+                Some(Storage::Local(_) | Storage::Captured(_) | Storage::Field) if crossed_function => {},
+                Some(storage) => return Some((table, *storage)),
+                None => {},
             }
+            crossed_function |= table.boundary && !table.closure;
+            table = table.parent.as_deref()?;
+        }
+    }
+
+    // Root scope of the function whose frame is at `depth`
+    fn function_scope_mut(&mut self, depth: usize) -> Option<&mut SymbolTable> {
+        if self.boundary && self.depth == depth {
+            Some(self)
+        } else {
+            self.parent.as_deref_mut()?.function_scope_mut(depth)
+        }
+    }
+
+    fn root(&self) -> &SymbolTable {
+        match &self.parent {
+            Some(parent) => parent.root(),
+            None => self,
         }
     }
 }
@@ -160,37 +405,140 @@ impl FunctionTable {
     }
 }
 
+// Variables of a running function: its slots, the cells of the slots that closures captured,
+// and the variables captured by the closure being run
+struct Frame {
+    locals: Vec<RazenValue>,
+    cells: Vec<Option<Arc<Mutex<RazenValue>>>>,
+    captures: Vec<Arc<Mutex<RazenValue>>>,
+}
+
+impl Frame {
+    fn new(locals: Vec<RazenValue>, captures: Vec<Arc<Mutex<RazenValue>>>) -> Self {
+        Frame { locals, cells: Vec::new(), captures }
+    }
+
+    fn load(&self, slot: usize) -> RazenValue {
+        match self.cells.get(slot) {
+            Some(Some(cell)) => cell.lock().unwrap().clone(),
+            _ => self.locals.get(slot).cloned().unwrap_or(RazenValue::Null),
+        }
+    }
+
+    fn store(&mut self, slot: usize, value: RazenValue) {
+        if let Some(Some(cell)) = self.cells.get(slot) {
+            *cell.lock().unwrap() = value;
+            return;
+        }
+        if slot >= self.locals.len() {
+            self.locals.resize(slot + 1, RazenValue::Null);
+        }
+        self.locals[slot] = value;
+    }
+
+    // Cell shared by the frame and the closures capturing a slot. Once a slot is captured
+    // all of its loads and stores go through the cell
+    fn cell(&mut self, slot: usize) -> Arc<Mutex<RazenValue>> {
+        if slot >= self.cells.len() {
+            self.cells.resize(slot + 1, None);
+        }
+        let locals = &self.locals;
+        self.cells[slot]
+            .get_or_insert_with(|| Arc::new(Mutex::new(locals.get(slot).cloned().unwrap_or(RazenValue::Null))))
+            .clone()
+    }
+
+    fn capture(&self, index: usize) -> Arc<Mutex<RazenValue>> {
+        self.captures.get(index).cloned().unwrap_or_else(|| Arc::new(Mutex::new(RazenValue::Null)))
+    }
+}
+
+// Command-line settings for compiling a source file
+#[derive(Debug, Clone, Copy)]
+pub struct CompileOptions {
+    // How parse and type errors are printed
+    pub error_format: ErrorFormat,
+    // Run the IR optimization passes; off with --no-opt to compare behavior
+    pub optimize: bool,
+    // Only show program output, without the compiler's trace
+    pub clean_output: bool,
+}
+
+impl Default for CompileOptions {
+    fn default() -> Self {
+        CompileOptions {
+            error_format: ErrorFormat::Human,
+            optimize: true,
+            clean_output: false,
+        }
+    }
+}
+
 // Compiler for translating AST to machine code
 pub struct Compiler {
     pub ir: Vec<IR>,
+    source_map: Vec<Option<Span>>, // Source span of each IR instruction
+    current_span: Option<Span>,    // Span of the innermost statement or expression being compiled
+    files: Vec<String>,            // Source file names, indexed by the file ids of spans
     symbol_table: SymbolTable,
     function_table: FunctionTable,
     function_param_names: HashMap<String, Vec<String>>,
+    function_locals: HashMap<String, Vec<String>>, // Slot names of each frame, parameters first
+    frames: Vec<Vec<String>>,       // Slot names of the frames being compiled, innermost last
+    frame_captures: Vec<Vec<Capture>>, // Variables captured by each frame being compiled
     current_function: Option<String>,
     break_stack: Vec<Vec<usize>>,    // Stack of break statement positions for nested loops
     continue_stack: Vec<Vec<usize>>, // Stack of continue statement positions for nested loops
     label_counter: usize,            // Counter for generating unique labels
     clean_output: bool,              // Flag to only show program output
     errors: Vec<String>,            // Compilation errors
-    variable_types: HashMap<String, String>, // Track variable types (name -> type)
+    diagnostics: Vec<Diagnostic>,   // Type errors, which stop the build, and warnings
+    enums: HashMap<String, Vec<String>>, // Variant names of each declared enum
+    return_kinds: Vec<Vec<Option<&'static str>>>, // Kinds returned by each function being compiled, None when unknown
+    function_kinds: HashMap<String, &'static str>, // Kind every call of a compiled function returns
+    global_kinds: HashMap<String, Option<(&'static str, Option<Span>)>>, // Kind each top-level variable is declared with, None when its declarations disagree
     in_show_statement: bool,        // Flag to track if we're inside a show statement
+    inline_functions: HashMap<String, InlineFunction>, // Inline functions small enough to expand
+    inline_stack: Vec<String>,      // Inline functions being expanded, innermost last
+    inline_returns: Vec<(usize, Vec<usize>)>, // Result slot and return jumps of each expansion
+    try_regions: Vec<TryRegion>,    // Try statements around the code being compiled, innermost last
+    classes: HashMap<String, ClassInfo>, // Declared classes by name
+    current_class: Option<String>,  // Class whose methods are being compiled
+    method_fields: Vec<String>,     // Fields visible by name in the next method compiled
 }
 
 impl Compiler {
     pub fn new() -> Self {
         Compiler {
             ir: Vec::new(),
+            source_map: Vec::new(),
+            current_span: None,
+            files: Vec::new(),
             symbol_table: SymbolTable::new(),
             function_table: FunctionTable::new(),
             function_param_names: HashMap::new(),
+            function_locals: HashMap::new(),
+            frames: vec![Vec::new()],
+            frame_captures: vec![Vec::new()],
             current_function: None,
             break_stack: Vec::new(),
             continue_stack: Vec::new(),
             label_counter: 0,
             clean_output: false,
             errors: Vec::new(),
-            variable_types: HashMap::new(),
+            diagnostics: Vec::new(),
+            enums: HashMap::new(),
+            return_kinds: Vec::new(),
+            function_kinds: HashMap::new(),
+            global_kinds: HashMap::new(),
             in_show_statement: false,
+            inline_functions: HashMap::new(),
+            inline_stack: Vec::new(),
+            inline_returns: Vec::new(),
+            try_regions: Vec::new(),
+            classes: HashMap::new(),
+            current_class: None,
+            method_fields: Vec::new(),
         }
     }
 
@@ -199,106 +547,145 @@ impl Compiler {
         self.clean_output = clean;
     }
 
-    // Helper methods for type checking
-    fn is_number_expression(&self, expr: &Expression) -> bool {
-        match expr {
-            Expression::NumberLiteral(_) => true,
-            Expression::Identifier(name) => {
-                // Check if the identifier refers to a variable of type 'let'
-                self.variable_types.get(name).map_or(false, |var_type| var_type == "let")
-            },
-            Expression::PrefixExpression { right, .. } => self.is_number_expression(right),
-            Expression::InfixExpression { left, right, operator, .. } => {
-                // Most infix operations with numbers result in numbers
-                match operator.as_str() {
-                    "+" | "-" | "*" | "/" | "%" | "**" | "//" => {
-                        self.is_number_expression(left) && self.is_number_expression(right)
-                    },
-                    _ => false,
-                }
-            },
-            Expression::CallExpression { function, .. } => {
-                // Check if it's a function known to return numbers
-                if let Expression::Identifier(name) = &**function {
-                    matches!(name.as_str(),
-                        "plus" | "minus" | "times" | "by" | "mod" | "power" |
-                        "round" | "sqrt" | "abs" | "size" | "count")
-                } else {
-                    false
-                }
-            },
-            _ => false,
+    // Parameter names of every user function, keyed by function name
+    pub fn function_param_names(&self) -> &HashMap<String, Vec<String>> {
+        &self.function_param_names
+    }
+
+    // Variable name of each frame slot, keyed by function name, with the top-level frame
+    // under TOP_LEVEL_FRAME
+    pub fn function_locals(&self) -> &HashMap<String, Vec<String>> {
+        &self.function_locals
+    }
+
+    // Names of the `inline fun`s whose calls were expanded in place
+    pub fn inline_function_names(&self) -> impl Iterator<Item = &str> {
+        self.inline_functions.keys().map(String::as_str)
+    }
+
+    // Errors and warnings found while compiling, such as a value of the wrong kind stored in
+    // a `num` variable or a `when` that misses enum variants
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        &self.diagnostics
+    }
+
+    pub fn error_count(&self) -> usize {
+        self.diagnostics.iter().filter(|d| d.severity == Severity::Error).count()
+    }
+
+    // Source span of each IR instruction, parallel to `ir`
+    pub fn source_map(&self) -> &[Option<Span>] {
+        &self.source_map
+    }
+
+    // Where a span starts, as `file.rzn:12:5`, or `12:5` for source that did not come from a file
+    pub fn location(&self, span: Span) -> String {
+        match self.files.get(span.file) {
+            Some(file) => format!("{}:{}:{}", file, span.line, span.column),
+            None => format!("{}:{}", span.line, span.column),
         }
     }
 
-    fn is_string_expression(&self, expr: &Expression) -> bool {
-        match expr {
-            Expression::StringLiteral(_) => true,
-            Expression::Identifier(name) => {
-                // Check if the identifier refers to a variable of type 'take'
-                self.variable_types.get(name).map_or(false, |var_type| var_type == "take")
+    // Kind of value an expression is known to produce: num, str, bool, list, map, function or
+    // null. None when it depends on values only known at runtime
+    fn infer_kind(&self, expr: &Expression) -> Option<&'static str> {
+        match expr.unlocated() {
+            Expression::NumberLiteral(_) => Some("num"),
+            Expression::StringLiteral(_) => Some("str"),
+            Expression::BooleanLiteral(_) => Some("bool"),
+            Expression::NullLiteral => Some("null"),
+            Expression::ArrayLiteral { .. } => Some("list"),
+            Expression::MapLiteral { .. } => Some("map"),
+            Expression::FunctionLiteral { .. } => Some("function"),
+            Expression::Identifier(name) => self.symbol_table.kind_of(name).map(|(kind, _)| kind),
+            // Negation and arithmetic other than `+` give a number or fail
+            Expression::PrefixExpression { operator, .. } if operator == "-" => Some("num"),
+            Expression::PrefixExpression { .. } => Some("bool"),
+            Expression::InfixExpression { left, operator, right } => match operator.as_str() {
+                "+" => match (self.infer_kind(left), self.infer_kind(right)) {
+                    (Some("str"), _) | (_, Some("str")) => Some("str"),
+                    (Some("num"), Some("num")) => Some("num"),
+                    (Some("list"), Some("list")) => Some("list"),
+                    _ => None,
+                },
+                "-" | "*" | "/" | "%" | "**" | "//" => Some("num"),
+                "==" | "!=" | ">" | ">=" | "<" | "<=" | "&&" | "||" => Some("bool"),
+                _ => None,
             },
-            Expression::InfixExpression { left, right, operator, .. } => {
-                // String concatenation
-                operator == "+" && (self.is_string_expression(left) || self.is_string_expression(right))
-            },
-            Expression::CallExpression { function, .. } => {
-                // Check if it's a function known to return strings
-                if let Expression::Identifier(name) = &**function {
-                    matches!(name.as_str(),
-                        "join" | "big" | "small" | "trim" | "replace" |
-                        "date" | "read_file")
-                } else {
-                    false
-                }
+            Expression::AssignmentExpression { operator, right, .. } if operator == "=" => self.infer_kind(right),
+            Expression::CallExpression { function, .. } => match function.unlocated() {
+                Expression::Identifier(name) if matches!(self.symbol_table.resolve(name), Some((Storage::Function, _)) | None) => {
+                    match name.as_str() {
+                        "len" => Some("num"),
+                        _ => self.function_kinds.get(name).copied(),
+                    }
+                },
+                _ => None,
             },
-            _ => false,
+            _ => None,
         }
     }
 
-    fn is_boolean_expression(&self, expr: &Expression) -> bool {
-        match expr {
-            Expression::BooleanLiteral(_) => true,
-            Expression::Identifier(name) => {
-                // Check if the identifier refers to a variable of type 'hold'
-                self.variable_types.get(name).map_or(false, |var_type| var_type == "hold")
-            },
-            Expression::PrefixExpression { operator, right } => {
-                operator == "!" && self.is_boolean_expression(right)
-            },
-            Expression::InfixExpression { operator, .. } => {
-                // Comparison and logical operators return booleans
-                matches!(operator.as_str(),
-                    "==" | "!=" | ">" | ">=" | "<" | "<=" | "&&" | "||")
-            },
-            Expression::CallExpression { function, .. } => {
-                // Check if it's a function known to return booleans
-                if let Expression::Identifier(name) = &**function {
-                    matches!(name.as_str(), "contains")
-                } else {
-                    false
+    // Check a value about to be stored in a variable declared to hold `kind`, or null like a
+    // variable declared without a value. A value of a known kind is checked now, anything
+    // else when it is stored
+    fn check_kind(&mut self, name: &str, kind: &'static str, found: Option<&'static str>, value: Option<Span>, declaration: Option<Span>) {
+        match found {
+            Some(found) if found == kind || found == "null" => {},
+            Some(found) => {
+                let mut error = Diagnostic::error(diagnostic::TYPE_MISMATCH, format!("Type mismatch: cannot store {} in '{}', which is declared {}", found, name, kind));
+                if let Some(span) = value.or(self.current_span) {
+                    error = error.with_primary(span, format!("this is {}", found));
+                }
+                if let Some(declaration) = declaration.filter(|declaration| Some(*declaration) != self.current_span) {
+                    error = error.with_label(declaration, format!("'{}' declared {} here", name, kind));
                 }
+                self.diagnostics.push(error.with_note(format!("'{}' can only hold {} values; declare it with 'var' to hold a value of any type", name, kind)));
+            },
+            None => {
+                self.emit(IR::PushString(kind.to_string()));
+                self.emit(IR::PushString(name.to_string()));
+                self.emit(IR::Call("__check_kind".to_string(), 3));
             },
-            _ => false,
         }
     }
 
-    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+    // Report an error at `span`, or at the statement being compiled
+    fn report_error(&mut self, code: &'static str, message: String, span: Option<Span>, label: &str) {
+        let mut error = Diagnostic::error(code, message);
+        if let Some(span) = span.or(self.current_span) {
+            error = error.with_primary(span, label);
+        }
+        self.diagnostics.push(error);
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P, options: CompileOptions) -> Result<Self, String> {
+        let mut compiler = Compiler::new();
+        let name = path.as_ref().display().to_string();
         match Parser::from_file(path) {
             Ok(mut parser) => {
+                let mut sources = SourceFiles::new();
+                parser.set_file(sources.add(name.clone(), parser.source()));
+                compiler.files.push(name);
                 let program = parser.parse_program();
-                if !parser.get_errors().is_empty() {
-                    return Err(format!("Parser errors: {:?}", parser.get_errors()));
-                }
-
-                let mut compiler = Compiler::new();
 
-                // Check for clean output flag in environment
-                if std::env::args().any(|arg| arg == "--clean-output") {
-                    compiler.set_clean_output(true);
+                // Diagnostics are printed with their source excerpts, or as JSON for tools
+                let errors = parser.get_errors();
+                if !errors.is_empty() {
+                    diagnostic::emit(errors, &sources, options.error_format);
+                    return Err(diagnostic::aborting(errors.len()));
                 }
 
+                compiler.set_clean_output(options.clean_output);
                 compiler.compile_program(program);
+                diagnostic::emit(compiler.diagnostics(), &sources, options.error_format);
+                if compiler.error_count() > 0 {
+                    return Err(diagnostic::aborting(compiler.error_count()));
+                }
+
+                if options.optimize {
+                    compiler.optimize();
+                }
                 Ok(compiler)
             },
             Err(e) => Err(e),
@@ -314,6 +701,7 @@ impl Compiler {
     fn emit(&mut self, code: IR) -> usize {
         let pos = self.ir.len();
         self.ir.push(code);
+        self.source_map.push(self.current_span);
         pos
     }
 
@@ -336,6 +724,87 @@ impl Compiler {
         }
     }
 
+    // Reserve a slot for a variable in the frame being compiled
+    fn new_slot(&mut self, name: &str) -> usize {
+        let frame = self.frames.last_mut().expect("the top-level frame is never popped");
+        frame.push(name.to_string());
+        frame.len() - 1
+    }
+
+    // Define a variable in the current scope, in a slot of the current frame inside functions
+    fn define_variable(&mut self, name: &str) {
+        if self.symbol_table.locals {
+            let slot = self.new_slot(name);
+            self.symbol_table.define_local(name, slot);
+        } else {
+            self.symbol_table.define(name);
+        }
+    }
+
+    // Look up a name. A local of an enclosing function used inside a function literal is
+    // captured by that literal and every literal between them
+    fn resolve(&mut self, name: &str) -> Option<Storage> {
+        let (storage, depth) = self.symbol_table.resolve(name)?;
+        let frame = self.frames.len() - 1;
+        match storage {
+            Storage::Local(slot) if depth < frame => Some(Storage::Captured(self.capture(name, Capture::Local(slot), depth, frame))),
+            Storage::Captured(index) if depth < frame => Some(Storage::Captured(self.capture(name, Capture::Captured(index), depth, frame))),
+            storage => Some(storage),
+        }
+    }
+
+    // Capture a variable found at `source` in the frame at `depth` into the function literal
+    // whose frame is at `frame`, returning its index among that literal's captures
+    fn capture(&mut self, name: &str, source: Capture, depth: usize, frame: usize) -> usize {
+        let source = if depth + 1 == frame {
+            source
+        } else {
+            Capture::Captured(self.capture(name, source, depth, frame - 1))
+        };
+        let captures = &mut self.frame_captures[frame];
+        captures.push(source);
+        let index = captures.len() - 1;
+        if let Some(scope) = self.symbol_table.function_scope_mut(frame) {
+            scope.define_captured(name, index);
+        }
+        index
+    }
+
+    // Locals are accessed by slot, captured variables through the running closure, functions
+    // as closures without captures, and globals and undeclared names by name
+    fn emit_load(&mut self, name: &str) {
+        let code = match self.resolve(name) {
+            Some(Storage::Local(slot)) => IR::LoadLocal(slot),
+            Some(Storage::Captured(index)) => IR::LoadCapture(index),
+            Some(Storage::Function) => IR::MakeClosure(name.to_string(), Vec::new()),
+            Some(Storage::Field) => {
+                self.emit_load("self");
+                self.emit(IR::PushString(name.to_string()));
+                IR::GetIndex
+            },
+            _ => IR::LoadVar(name.to_string()),
+        };
+        self.emit(code);
+    }
+
+    fn emit_store(&mut self, name: &str) {
+        let code = match self.resolve(name) {
+            Some(Storage::Local(slot)) => IR::StoreLocal(slot),
+            Some(Storage::Captured(index)) => IR::StoreCapture(index),
+            Some(Storage::Field) => {
+                // Stack: value -> self, value -> self, name, value -> self
+                self.emit_load("self");
+                self.emit(IR::Swap);
+                self.emit(IR::PushString(name.to_string()));
+                self.emit(IR::Swap);
+                self.emit(IR::SetIndex);
+                IR::Pop
+            },
+            _ => IR::StoreVar(name.to_string()),
+        };
+        self.emit(code);
+    }
+
     fn enter_loop(&mut self) {
         self.break_stack.push(Vec::new());
         self.continue_stack.push(Vec::new());
@@ -358,88 +827,33 @@ impl Compiler {
     }
 
     fn define_builtins(&mut self) {
-        // Define built-in functions like print, math operations, etc.
-        self.symbol_table.define("print");
-        self.symbol_table.define("read");
-        self.symbol_table.define("len");
-        self.symbol_table.define("append");
-        self.symbol_table.define("remove");
-
-        // Module system built-ins
-        self.symbol_table.define("__import_symbol");
-        self.symbol_table.define("__import_module");
-        self.symbol_table.define("__export_symbol");
-
-        // Developer tools built-ins
-        self.symbol_table.define("__debug");
-        self.symbol_table.define("__assert");
-        self.symbol_table.define("__assert_with_message");
-        self.symbol_table.define("__trace");
-
-        // Standard library functions
-        self.symbol_table.define("floor");       // Math functions
-        self.symbol_table.define("ceil");
-        self.symbol_table.define("round");
-        self.symbol_table.define("sin");
-        self.symbol_table.define("cos");
-        self.symbol_table.define("tan");
-        self.symbol_table.define("sqrt");
-        self.symbol_table.define("random");
-
-        self.symbol_table.define("format");      // String functions
-        self.symbol_table.define("substring");
-        self.symbol_table.define("uppercase");
-        self.symbol_table.define("lowercase");
-        self.symbol_table.define("trim");
-        self.symbol_table.define("replace");
-
-        self.symbol_table.define("map");         // Array functions
-        self.symbol_table.define("filter");
-        self.symbol_table.define("reduce");
-        self.symbol_table.define("join");
-        self.symbol_table.define("split");
-
-        self.symbol_table.define("now");         // Time functions
-        self.symbol_table.define("format_date");
-        self.symbol_table.define("sleep");
-
-        self.symbol_table.define("parse_json");  // JSON functions
-        self.symbol_table.define("stringify_json");
-
-        self.symbol_table.define("read_file");   // File I/O functions
-        self.symbol_table.define("write_file");
-        self.symbol_table.define("append_file");
-
-        self.symbol_table.define("get_args");    // Scripting functions
-        self.symbol_table.define("get_env");
-        self.symbol_table.define("set_env");
-        self.symbol_table.define("run_command");
-        self.symbol_table.define("exit_with");
-
-        self.symbol_table.define("create_dir");  // File system functions
-        self.symbol_table.define("remove_dir");
-        self.symbol_table.define("list_dir");
-        self.symbol_table.define("is_file");
-        self.symbol_table.define("is_dir");
-        self.symbol_table.define("file_exists");
-        self.symbol_table.define("copy_file");
-        self.symbol_table.define("move_file");
-        self.symbol_table.define("delete_file");
-
-        self.symbol_table.define("join_path");   // Path manipulation functions
-        self.symbol_table.define("basename");
-        self.symbol_table.define("dirname");
-        self.symbol_table.define("absolute_path");
+        for name in BUILTIN_FUNCTIONS {
+            self.symbol_table.define_function(name);
+        }
     }
 
     pub fn compile_program(&mut self, program: Program) {
         // Define built-in functions
         self.define_builtins();
 
-        // First pass: register all functions
+        // First pass: register all functions, and the kinds of top-level variables so that
+        // functions compiled before a declaration still check what they store
         for stmt in &program.statements {
-            if let Statement::FunctionDeclaration { name, .. } = stmt {
-                self.symbol_table.define(name);
+            match stmt.unlocated() {
+                Statement::FunctionDeclaration { name, .. } |
+                Statement::ClassDeclaration { name, .. } |
+                Statement::FinalClassDeclaration { name, .. } => self.symbol_table.define_function(name),
+                Statement::VariableDeclaration { var_type, name, .. } => {
+                    let span = match stmt {
+                        Statement::Located { span, .. } => Some(*span),
+                        _ => None,
+                    };
+                    let kind = declared_kind(var_type).map(|kind| (kind, span));
+                    self.global_kinds.entry(name.clone())
+                        .and_modify(|known| if known.map(|(kind, _)| kind) != kind.map(|(kind, _)| kind) { *known = None; })
+                        .or_insert(kind);
+                },
+                _ => {},
             }
         }
 
@@ -447,10 +861,16 @@ impl Compiler {
         for stmt in program.statements {
             self.compile_statement(stmt);
         }
+        self.function_locals.insert(TOP_LEVEL_FRAME.to_string(), self.frames[0].clone());
     }
 
     fn compile_statement(&mut self, stmt: Statement) {
         match stmt {
+            Statement::Located { span, statement } => {
+                let outer_span = self.current_span.replace(span);
+                self.compile_statement(*statement);
+                self.current_span = outer_span;
+            },
             Statement::VariableDeclaration { var_type, name, value } => {
                 self.compile_variable_declaration(var_type, name, value);
             },
@@ -477,6 +897,9 @@ impl Compiler {
             Statement::ForStatement { iterator, iterable, body } => {
                 self.compile_for_statement(iterator, iterable, body);
             },
+            Statement::WhenStatement { value, arms } => {
+                self.compile_when_statement(value, arms);
+            },
             Statement::BreakStatement => {
                 self.compile_break_statement();
             },
@@ -522,11 +945,11 @@ impl Compiler {
                 self.compile_trace_statement(value);
             },
             // OOP (Section 12)
-            Statement::ClassDeclaration { name, body } => {
-                self.compile_class_declaration(name, body);
+            Statement::ClassDeclaration { name, parent, body } => {
+                self.compile_class_declaration(name, parent, body, false);
             },
-            Statement::FinalClassDeclaration { name, body } => {
-                self.compile_final_class_declaration(name, body);
+            Statement::FinalClassDeclaration { name, parent, body } => {
+                self.compile_class_declaration(name, parent, body, true);
             },
             // Performance and Type Safety
             Statement::ConstDeclaration { name, value } => {
@@ -610,59 +1033,49 @@ impl Compiler {
     }
 
     fn compile_variable_declaration(&mut self, var_type: String, name: String, value: Option<Expression>) {
-        // Define the variable in the symbol table
-        self.symbol_table.define(&name);
+        let kind = declared_kind(&var_type);
+        let found = value.as_ref().and_then(|expr| self.infer_kind(expr));
 
-        // Store the variable type for future type checking
-        self.variable_types.insert(name.clone(), var_type.clone());
+        // Define the variable in the symbol table
+        self.define_variable(&name);
 
         // Compile the initializer expression if it exists
-        if let Some(expr) = value.clone() {
-            // Type checking based on variable type
-            match var_type.as_str() {
-                // Numeric types
-                "num" => {
-                    // Check that the value is a number
-                    if !self.is_number_expression(&expr) {
-                        self.errors.push(format!("Type error: '{}' variables can only be used with numeric values, but '{}' was assigned a non-numeric value", var_type, name));
-                    }
-                },
-                // String types
-                "str" => {
-                    // Check that the value is a string
-                    if !self.is_string_expression(&expr) {
-                        self.errors.push(format!("Type error: '{}' variables can only be used with string values, but '{}' was assigned a non-string value", var_type, name));
-                    }
-                },
-                // Boolean types
-                "bool" => {
-                    // Check that the value is a boolean
-                    if !self.is_boolean_expression(&expr) {
-                        self.errors.push(format!("Type error: '{}' variables can only be used with boolean values, but '{}' was assigned a non-boolean value", var_type, name));
-                    }
-                },
-                // Generic types - no type checking needed
-                "var" | "list" | "arr" | "map" | "store" | "box" | "ref" |
-                "len" | "key" | "value" | "append" | "remove" => {
-                    // These can hold any type, so no type checking needed
-                },
-                _ => {
-                    // For new variable types not explicitly handled
-                    // For now, we don't do type checking on these
-                }
-            }
-
+        if let Some(expr) = value {
+            let value_span = expr.span();
             self.compile_expression(expr);
+            if let Some(kind) = kind {
+                self.check_kind(&name, kind, found, value_span, None);
+            }
         } else {
             // If no initializer, push null as the default value
             self.emit(IR::PushNull);
         }
 
+        // Later stores into the variable are checked against its kind too
+        self.symbol_table.set_kind(&name, kind.map(|kind| (kind, self.current_span)));
+
         // Store the value in the variable
-        self.emit(IR::StoreVar(name));
+        self.emit_store(&name);
+    }
+
+    fn compile_function_declaration(&mut self, name: String, parameters: Vec<String>, body: Vec<Statement>) -> (usize, usize) {
+        // The name can be used as a function value in the enclosing scope
+        self.symbol_table.define_function(&name);
+        let (function_start, function_end, _) = self.compile_function(name, parameters, body, false);
+        (function_start, function_end)
     }
 
-    fn compile_function_declaration(&mut self, name: String, parameters: Vec<String>, body: Vec<Statement>) {
+    // Compile an anonymous function and push a closure over the variables it captures
+    fn compile_function_literal(&mut self, parameters: Vec<String>, body: Vec<Statement>) {
+        let name = self.generate_label("__lambda_");
+        let (_, _, captures) = self.compile_function(name.clone(), parameters, body, true);
+        self.emit(IR::MakeClosure(name, captures));
+    }
+
+    // Compile a function body in its own frame, skipped over by the surrounding code. Returns
+    // the IR positions of its start and end labels, and for a function literal the variables
+    // it captures
+    fn compile_function(&mut self, name: String, parameters: Vec<String>, body: Vec<Statement>, closure: bool) -> (usize, usize, Vec<Capture>) {
         // Save the current function name
         let old_function = self.current_function.clone();
         self.current_function = Some(name.clone());
@@ -686,15 +1099,36 @@ impl Compiler {
         // This is what allows the function to be called at runtime
         self.emit(IR::DefineFunction(name.clone(), function_start));
 
-        // Create a new scope for the function body
-        self.enter_scope();
+        // Create a new scope and frame for the function body
+        let parent = std::mem::replace(&mut self.symbol_table, SymbolTable::new());
+        let depth = self.frames.len();
+        self.symbol_table = if closure {
+            SymbolTable::new_closure(parent, depth)
+        } else {
+            SymbolTable::new_function(parent, depth)
+        };
+        self.frames.push(Vec::new());
+        self.frame_captures.push(Vec::new());
+
+        // Loops, try statements and show statements around the function do not extend into its body
+        let outer_breaks = std::mem::take(&mut self.break_stack);
+        let outer_continues = std::mem::take(&mut self.continue_stack);
+        let outer_tries = std::mem::take(&mut self.try_regions);
+        let outer_in_show = std::mem::replace(&mut self.in_show_statement, false);
+
+        // A method sees the fields of its class by name, unless a parameter shadows them
+        for field in std::mem::take(&mut self.method_fields) {
+            self.symbol_table.define_field(&field);
+        }
 
-        // Define parameters in the function's scope
-        for param in &parameters { // Iterate over a reference to parameters
-            self.symbol_table.define(&param);
+        // Define parameters in the function's scope, in the first slots of its frame
+        for param in &parameters {
+            self.define_variable(param);
         }
 
-        // Compile the function body
+        // Compile the function body, noting the kind of everything it returns
+        self.return_kinds.push(Vec::new());
+        let falls_through = !matches!(body.last().map(Statement::unlocated), Some(Statement::ReturnStatement { .. } | Statement::ThrowStatement { .. }));
         for stmt in body {
             self.compile_statement(stmt);
         }
@@ -703,6 +1137,20 @@ impl Compiler {
         self.emit(IR::PushNull);
         self.emit(IR::Return);
 
+        // Calls are known to give a kind only when every return gives that kind
+        let mut returned = self.return_kinds.pop().unwrap_or_default();
+        if falls_through {
+            returned.push(Some("null"));
+        }
+        match returned.first() {
+            Some(Some(kind)) if returned.iter().all(|other| other == &Some(*kind)) => {
+                self.function_kinds.insert(name.clone(), kind);
+            },
+            _ => {
+                self.function_kinds.remove(&name);
+            },
+        }
+
         // Mark the end of the function
         let function_end = self.emit_label(&end_label);
 
@@ -710,7 +1158,14 @@ impl Compiler {
         self.replace_instruction(jump_pos, IR::Jump(function_end));
 
         // Leave the function's scope
+        let locals = self.frames.pop().unwrap_or_default();
+        let captures = self.frame_captures.pop().unwrap_or_default();
+        self.function_locals.insert(name.clone(), locals);
         self.leave_scope();
+        self.break_stack = outer_breaks;
+        self.continue_stack = outer_continues;
+        self.try_regions = outer_tries;
+        self.in_show_statement = outer_in_show;
 
         // Restore the previous function name
         self.current_function = old_function;
@@ -718,9 +1173,20 @@ impl Compiler {
         if !self.clean_output {
             println!("[Compiler] Defined function {} at address {}", name, function_start);
         }
+        (function_start, function_end, captures)
     }
 
     fn compile_return_statement(&mut self, value: Option<Expression>) {
+        if self.inline_returns.is_empty() {
+            let kind = match &value {
+                Some(expr) => self.infer_kind(expr),
+                None => Some("null"),
+            };
+            if let Some(returned) = self.return_kinds.last_mut() {
+                returned.push(kind);
+            }
+        }
+
         // Compile the return value if it exists
         if let Some(expr) = value {
             self.compile_expression(expr);
@@ -729,7 +1195,19 @@ impl Compiler {
             self.emit(IR::PushNull);
         }
 
-        // Emit the return instruction
+        // Inside an inline expansion, store the value in its result and jump to its end
+        if let Some((result, _)) = self.inline_returns.last() {
+            self.emit(IR::StoreLocal(*result));
+            self.leave_try_regions(0);
+            let return_pos = self.emit(IR::Jump(0)); // Placeholder
+            if let Some((_, returns)) = self.inline_returns.last_mut() {
+                returns.push(return_pos);
+            }
+            return;
+        }
+
+        // Emit the return instruction, after the finally blocks of the try statements it leaves
+        self.leave_try_regions(0);
         self.emit(IR::Return);
     }
 
@@ -759,9 +1237,7 @@ impl Compiler {
         let jump_to_else_pos = self.ir.len() - 1;
 
         // Compile the consequence (if branch)
-        for stmt in consequence {
-            self.compile_statement(stmt);
-        }
+        self.compile_block_statement(consequence);
 
         // Emit a jump to the end of the if statement
         self.emit(IR::Jump(0)); // Placeholder for end_label
@@ -775,9 +1251,7 @@ impl Compiler {
 
         // Compile the alternative (else branch) if it exists
         if let Some(alt) = alternative {
-            for stmt in alt {
-                self.compile_statement(stmt);
-            }
+            self.compile_block_statement(alt);
         }
 
         // Mark the end of the if statement
@@ -806,9 +1280,7 @@ impl Compiler {
         let jump_to_end_pos = self.ir.len() - 1;
 
         // Compile the loop body
-        for stmt in body {
-            self.compile_statement(stmt);
-        }
+        self.compile_block_statement(body);
 
         // Emit a jump back to the start of the loop
         self.emit(IR::Jump(loop_start));
@@ -826,24 +1298,28 @@ impl Compiler {
     fn compile_for_statement(&mut self, iterator: String, iterable: Expression, body: Vec<Statement>) {
         // Generate labels for the for loop
         let loop_label = self.generate_label("for_loop_");
+        let step_label = self.generate_label("for_step_");
         let end_label = self.generate_label("for_end_");
 
         // Create a new scope for the loop
         self.enter_scope();
 
         // Define the iterator variable
-        self.symbol_table.define(&iterator);
+        self.define_variable(&iterator);
 
-        // Compile the iterable expression
+        // Evaluate the iterable once and keep it in a temporary variable
+        let iterable_var = format!("__iter_{}", self.generate_label(""));
+        self.define_variable(&iterable_var);
         self.compile_expression(iterable);
+        self.emit_store(&iterable_var);
 
         // Create a temporary index variable
         let index_var = format!("__index_{}", self.generate_label(""));
-        self.symbol_table.define(&index_var);
+        self.define_variable(&index_var);
 
         // Initialize the index to 0
         self.emit(IR::PushNumber(0.0));
-        self.emit(IR::StoreVar(index_var.clone()));
+        self.emit_store(&index_var);
 
         // Mark the start of the loop
         let loop_start = self.emit_label(&loop_label);
@@ -852,37 +1328,204 @@ impl Compiler {
         self.enter_loop();
 
         // Check if the index is less than the length of the iterable
-        self.emit(IR::LoadVar(index_var.clone()));
-        self.emit(IR::Dup); // Duplicate the index for the array access
-        self.emit(IR::GetIndex); // Get the element at the current index
+        self.emit_load(&index_var);
+        self.emit_load(&iterable_var);
+        self.emit(IR::Call("len".to_string(), 1));
+        self.emit(IR::LessThan);
+        let jump_to_end_pos = self.emit(IR::JumpIfFalse(0)); // Placeholder for end_label
 
         // Store the current element in the iterator variable
-        self.emit(IR::StoreVar(iterator));
-
-        // Increment the index
-        self.emit(IR::LoadVar(index_var.clone()));
-        self.emit(IR::PushNumber(1.0));
-        self.emit(IR::Add);
-        self.emit(IR::StoreVar(index_var.clone()));
+        self.emit_load(&iterable_var);
+        self.emit_load(&index_var);
+        self.emit(IR::GetIndex);
+        self.emit_store(&iterator);
 
         // Compile the loop body
         for stmt in body {
             self.compile_statement(stmt);
         }
 
+        // Increment the index; continue statements jump here
+        let step_pos = self.emit_label(&step_label);
+        self.emit_load(&index_var);
+        self.emit(IR::PushNumber(1.0));
+        self.emit(IR::Add);
+        self.emit_store(&index_var);
+
         // Emit a jump back to the start of the loop
         self.emit(IR::Jump(loop_start));
 
         // Mark the end of the loop
         let end_pos = self.emit_label(&end_label);
+        self.replace_instruction(jump_to_end_pos, IR::JumpIfFalse(end_pos));
 
         // Leave the loop context and patch break/continue statements
-        self.leave_loop(loop_start, end_pos);
+        self.leave_loop(step_pos, end_pos);
 
         // Leave the loop's scope
         self.leave_scope();
     }
 
+    // Compile a when statement into a chain of tests, one arm after another. The value is kept
+    // in a temporary variable, and every failed test of an arm jumps to the next arm
+    fn compile_when_statement(&mut self, value: Expression, arms: Vec<WhenArm>) {
+        let end_label = self.generate_label("when_end_");
+        self.check_when_exhaustive(&arms);
+
+        // Evaluate the value once
+        self.enter_scope();
+        let subject = self.generate_label("__when_");
+        self.define_variable(&subject);
+        self.compile_expression(value);
+        self.emit_store(&subject);
+
+        let mut end_jumps = Vec::new();
+        for arm in arms {
+            let next_label = self.generate_label("when_next_");
+
+            // Names bound by the pattern are visible to the guard and the body only
+            self.enter_scope();
+            let mut fail_jumps = Vec::new();
+            self.compile_pattern_test(&arm.pattern, &subject, &[], &mut fail_jumps);
+            if let Some(guard) = arm.guard {
+                self.compile_expression(guard);
+                fail_jumps.push(self.emit(IR::JumpIfFalse(0)));
+            }
+            self.compile_block_statement(arm.body);
+            end_jumps.push(self.emit(IR::Jump(0))); // Placeholder for end_label
+            self.leave_scope();
+
+            let next_pos = self.emit_label(&next_label);
+            for pos in fail_jumps {
+                self.replace_instruction(pos, IR::JumpIfFalse(next_pos));
+            }
+        }
+
+        let end_pos = self.emit_label(&end_label);
+        for pos in end_jumps {
+            self.replace_instruction(pos, IR::Jump(end_pos));
+        }
+        self.leave_scope();
+    }
+
+    // Emit the tests of a pattern against the part of the when value at `path`, a list of
+    // indexes into nested lists. Each test leaves a JumpIfFalse in `fail_jumps` to be patched
+    fn compile_pattern_test(&mut self, pattern: &Pattern, subject: &str, path: &[usize], fail_jumps: &mut Vec<usize>) {
+        match pattern {
+            Pattern::Wildcard => {},
+            Pattern::Binding(name) => {
+                self.emit_load_path(subject, path);
+                self.define_variable(name);
+                self.emit_store(name);
+            },
+            Pattern::Literal(value) => {
+                self.emit_load_path(subject, path);
+                self.compile_expression(value.clone());
+                self.emit(IR::Equal);
+                fail_jumps.push(self.emit(IR::JumpIfFalse(0)));
+            },
+            Pattern::Range { start, end, inclusive } => {
+                // A value that cannot be ordered against the bounds does not match, rather than
+                // raising the type error a comparison would
+                self.emit_load_path(subject, path);
+                self.compile_expression(start.clone());
+                self.emit(IR::Call("__comparable".to_string(), 2));
+                fail_jumps.push(self.emit(IR::JumpIfFalse(0)));
+
+                self.emit_load_path(subject, path);
+                self.compile_expression(start.clone());
+                self.emit(IR::GreaterEqual);
+                fail_jumps.push(self.emit(IR::JumpIfFalse(0)));
+
+                self.emit_load_path(subject, path);
+                self.compile_expression(end.clone());
+                self.emit(if *inclusive { IR::LessEqual } else { IR::LessThan });
+                fail_jumps.push(self.emit(IR::JumpIfFalse(0)));
+            },
+            Pattern::EnumVariant { enum_name, variant } => {
+                if self.enums.get(enum_name).is_some_and(|variants| !variants.contains(variant)) {
+                    self.report_error(diagnostic::UNKNOWN_VARIANT, format!("Enum '{}' has no variant '{}'", enum_name, variant), None, "pattern names an unknown variant");
+                }
+                self.emit_load_path(subject, path);
+                self.emit_load(enum_name);
+                self.emit(IR::PushString(variant.clone()));
+                self.emit(IR::GetIndex);
+                self.emit(IR::Equal);
+                fail_jumps.push(self.emit(IR::JumpIfFalse(0)));
+            },
+            Pattern::Array { elements, rest } => {
+                // The value must be a list of exactly the right length, or at least that long
+                // when the pattern ends with a rest
+                self.emit_load_path(subject, path);
+                self.emit(IR::PushNumber(elements.len() as f64));
+                self.emit(IR::PushBoolean(rest.is_some()));
+                self.emit(IR::Call("__match_array".to_string(), 3));
+                fail_jumps.push(self.emit(IR::JumpIfFalse(0)));
+
+                for (index, element) in elements.iter().enumerate() {
+                    let mut element_path = path.to_vec();
+                    element_path.push(index);
+                    self.compile_pattern_test(element, subject, &element_path, fail_jumps);
+                }
+
+                if let Some(Pattern::Binding(name)) = rest.as_deref() {
+                    self.emit_load_path(subject, path);
+                    self.emit(IR::PushNumber(elements.len() as f64));
+                    self.emit(IR::Call("__array_rest".to_string(), 2));
+                    self.define_variable(name);
+                    self.emit_store(name);
+                }
+            },
+        }
+    }
+
+    // Push the part of the when value at `path`
+    fn emit_load_path(&mut self, subject: &str, path: &[usize]) {
+        self.emit_load(subject);
+        for index in path {
+            self.emit(IR::PushNumber(*index as f64));
+            self.emit(IR::GetIndex);
+        }
+    }
+
+    // Warn when a when statement matching the variants of a declared enum can miss some of
+    // them. Guarded arms do not count, since their guard may fail
+    fn check_when_exhaustive(&mut self, arms: &[WhenArm]) {
+        let mut matched_enum = None;
+        let mut covered = HashSet::new();
+        for arm in arms {
+            match &arm.pattern {
+                pattern if pattern.is_irrefutable() && arm.guard.is_none() => return,
+                Pattern::EnumVariant { enum_name, variant } => {
+                    matched_enum.get_or_insert_with(|| enum_name.clone());
+                    if arm.guard.is_none() {
+                        covered.insert(variant.clone());
+                    }
+                },
+                _ => {},
+            }
+        }
+
+        let enum_name = match matched_enum {
+            Some(name) => name,
+            None => return,
+        };
+        let missing: Vec<&str> = match self.enums.get(&enum_name) {
+            Some(variants) => variants.iter().filter(|v| !covered.contains(*v)).map(String::as_str).collect(),
+            None => return,
+        };
+        if !missing.is_empty() {
+            let mut warning = Diagnostic::warning(
+                diagnostic::NON_EXHAUSTIVE_WHEN,
+                format!("'when' over enum '{}' is not exhaustive: missing {}", enum_name, missing.join(", ")),
+            ).with_note("add arms for the missing variants, or a '_' arm");
+            if let Some(span) = self.current_span {
+                warning = warning.with_primary(span, format!("{} not covered", missing.join(", ")));
+            }
+            self.diagnostics.push(warning);
+        }
+    }
+
     fn compile_break_statement(&mut self) {
         // Check if we're inside a loop
         if self.break_stack.is_empty() {
@@ -890,6 +1533,7 @@ impl Compiler {
         }
 
         // Emit a jump to the end of the loop (will be patched later)
+        self.leave_try_regions(self.loop_try_regions());
         let break_pos = self.emit(IR::Jump(0)); // Placeholder
 
         // Add the break position to the current loop's break stack
@@ -905,6 +1549,7 @@ impl Compiler {
         }
 
         // Emit a jump to the start of the loop (will be patched later)
+        self.leave_try_regions(self.loop_try_regions());
         let continue_pos = self.emit(IR::Jump(0)); // Placeholder
 
         // Add the continue position to the current loop's continue stack
@@ -913,6 +1558,31 @@ impl Compiler {
         }
     }
 
+    // Number of try statements outside the innermost loop
+    fn loop_try_regions(&self) -> usize {
+        self.try_regions.iter().take_while(|region| region.loop_depth < self.break_stack.len()).count()
+    }
+
+    // Leave every try statement but the outermost `keep`, innermost first: clear its handler
+    // and run a copy of its finally block outside of it
+    fn leave_try_regions(&mut self, keep: usize) {
+        let regions = self.try_regions.clone();
+        while self.try_regions.len() > keep {
+            let Some(region) = self.try_regions.pop() else { break };
+            if region.handler {
+                self.emit(IR::ClearTryCatch);
+            }
+            if let Some(finally) = region.finally {
+                self.enter_scope();
+                for stmt in finally {
+                    self.compile_statement(stmt);
+                }
+                self.leave_scope();
+            }
+        }
+        self.try_regions = regions;
+    }
+
     fn compile_show_statement(&mut self, value: Expression, color: Option<String>) {
         // Set the flag that we're inside a show statement
         let old_in_show = self.in_show_statement;
@@ -958,20 +1628,29 @@ impl Compiler {
     }
 
     fn compile_try_statement(&mut self, try_block: Vec<Statement>, catch_param: Option<String>, catch_block: Option<Vec<Statement>>, finally_block: Option<Vec<Statement>>) {
-        // Proper implementation of try/catch/finally with exception handling
-
-        // Generate unique labels for the try-catch-finally blocks
-        let try_end_label = self.generate_label("try_end");
+        // SetupTryCatch holds the address of its handler, which starts with the exception on the
+        // stack. A finally block also runs when the catch block throws or there is no catch
+        // block; the exception is kept in a variable and thrown again at the end of finally
         let catch_start_label = self.generate_label("catch_start");
-        let catch_end_label = self.generate_label("catch_end");
+        let rethrow_label = self.generate_label("rethrow");
         let finally_start_label = self.generate_label("finally_start");
+        let finally_body_label = self.generate_label("finally_body");
         let finally_end_label = self.generate_label("finally_end");
+        let pending_var = self.generate_label("__pending_exception_");
+        let rethrow_var = format!("{}_rethrow", pending_var);
+        if finally_block.is_some() {
+            self.define_variable(&pending_var);
+            self.define_variable(&rethrow_var);
+        }
 
-        // Set up exception handling
-        self.emit(IR::PushString(catch_start_label.clone())); // Push catch handler address
-        self.emit(IR::SetupTryCatch); // Setup try-catch block
+        let setup_pos = self.emit(IR::SetupTryCatch(0)); // Placeholder, will be updated
 
         // Compile the try block
+        self.try_regions.push(TryRegion {
+            loop_depth: self.break_stack.len(),
+            handler: true,
+            finally: finally_block.clone(),
+        });
         self.enter_scope();
         for stmt in try_block {
             self.compile_statement(stmt);
@@ -979,54 +1658,92 @@ impl Compiler {
         self.leave_scope();
 
         // End of try block - clear exception handler and jump to finally
-        self.emit(IR::ClearTryCatch); // Clear the try-catch handler
-
-        // Store the position for the jump to finally
-        let jump_to_finally_pos = self.emit(IR::Jump(0)); // Placeholder, will be updated
+        self.emit(IR::ClearTryCatch);
+        let mut jumps_to_finally = vec![self.emit(IR::Jump(0))]; // Placeholder, will be updated
+
+        // Handlers that run finally and then throw the exception again
+        let mut rethrow_setups = Vec::new();
+        match catch_block {
+            Some(catch) => {
+                let catch_start = self.emit_label(&catch_start_label);
+                self.replace_instruction(setup_pos, IR::SetupTryCatch(catch_start));
+                self.enter_scope();
+
+                // If we have a catch parameter, store the exception in it
+                if let Some(param_name) = catch_param {
+                    self.define_variable(&param_name);
+                    self.emit_store(&param_name);
+                } else {
+                    self.emit(IR::Pop);
+                }
 
-        // Catch handler starts here
-        self.emit_label(&catch_start_label);
+                if finally_block.is_some() {
+                    rethrow_setups.push(self.emit(IR::SetupTryCatch(0))); // Placeholder, will be updated
+                }
+                // The handler that caught the exception is gone, and only a finally block
+                // installs another around the catch block
+                if let Some(region) = self.try_regions.last_mut() {
+                    region.handler = finally_block.is_some();
+                }
+                for stmt in catch {
+                    self.compile_statement(stmt);
+                }
+                if finally_block.is_some() {
+                    self.emit(IR::ClearTryCatch);
+                }
+                self.leave_scope();
+                jumps_to_finally.push(self.emit(IR::Jump(0))); // Placeholder, will be updated
+            },
+            None if finally_block.is_some() => rethrow_setups.push(setup_pos),
+            None => {
+                // Without catch or finally the exception is discarded
+                let catch_start = self.emit_label(&catch_start_label);
+                self.replace_instruction(setup_pos, IR::SetupTryCatch(catch_start));
+                self.emit(IR::Pop);
+            },
+        }
 
-        // Compile the catch block if it exists
-        if let Some(catch) = catch_block {
-            self.enter_scope();
+        self.try_regions.pop();
 
-            // If we have a catch parameter, store the exception in it
-            if let Some(param_name) = catch_param {
-                let index = self.symbol_table.define(&param_name);
-                self.emit(IR::StoreVar(param_name)); // Store exception in the variable
-            } else {
-                self.emit(IR::Pop); // Pop the exception if no parameter to store it
+        let mut skip_rethrow = None;
+        if let Some(finally) = finally_block {
+            let rethrow_start = self.emit_label(&rethrow_label);
+            for pos in rethrow_setups {
+                self.replace_instruction(pos, IR::SetupTryCatch(rethrow_start));
             }
-
-            for stmt in catch {
-                self.compile_statement(stmt);
+            self.emit_store(&pending_var);
+            self.emit(IR::PushBoolean(true));
+            self.emit_store(&rethrow_var);
+            let jump_to_body = self.emit(IR::Jump(0)); // Placeholder, will be updated
+
+            let finally_start = self.emit_label(&finally_start_label);
+            for pos in jumps_to_finally.drain(..) {
+                self.replace_instruction(pos, IR::Jump(finally_start));
             }
-            self.leave_scope();
-        } else {
-            // If no catch block, just pop the exception
-            self.emit(IR::Pop);
-        }
+            self.emit(IR::PushBoolean(false));
+            self.emit_store(&rethrow_var);
 
-        // Store the position for the jump to finally after catch
-        let jump_after_catch_pos = self.emit(IR::Jump(0)); // Placeholder, will be updated
-        self.emit_label(&catch_end_label);
-
-        // Finally block - always executed
-        let finally_pos = self.ir.len();
-        self.emit_label(&finally_start_label);
-        if let Some(finally) = finally_block {
+            let finally_body = self.emit_label(&finally_body_label);
+            self.replace_instruction(jump_to_body, IR::Jump(finally_body));
             self.enter_scope();
             for stmt in finally {
                 self.compile_statement(stmt);
             }
             self.leave_scope();
+
+            self.emit_load(&rethrow_var);
+            skip_rethrow = Some(self.emit(IR::JumpIfFalse(0))); // Placeholder, will be updated
+            self.emit_load(&pending_var);
+            self.emit(IR::ThrowException);
         }
-        self.emit_label(&finally_end_label);
 
-        // Update the jump positions now that we know where the finally block is
-        self.replace_instruction(jump_to_finally_pos, IR::Jump(finally_pos));
-        self.replace_instruction(jump_after_catch_pos, IR::Jump(finally_pos));
+        let finally_end = self.emit_label(&finally_end_label);
+        if let Some(pos) = skip_rethrow {
+            self.replace_instruction(pos, IR::JumpIfFalse(finally_end));
+        }
+        for pos in jumps_to_finally {
+            self.replace_instruction(pos, IR::Jump(finally_end));
+        }
     }
 
     fn compile_throw_statement(&mut self, value: Expression) {
@@ -1039,11 +1756,11 @@ impl Compiler {
 
     fn compile_read_statement(&mut self, name: String) {
         // Define the variable in the symbol table
-        self.symbol_table.define(&name);
+        self.define_variable(&name);
 
         // Add a custom IR operation for reading user input
         self.emit(IR::ReadInput);
-        self.emit(IR::StoreVar(name));
+        self.emit_store(&name);
     }
 
     fn compile_exit_statement(&mut self) {
@@ -1061,8 +1778,13 @@ impl Compiler {
 
     fn compile_expression(&mut self, expr: Expression) {
         match expr {
+            Expression::Located { span, expression } => {
+                let outer_span = self.current_span.replace(span);
+                self.compile_expression(*expression);
+                self.current_span = outer_span;
+            },
             Expression::Identifier(name) => {
-                self.emit(IR::LoadVar(name));
+                self.emit_load(&name);
             },
             Expression::StringLiteral(value) => {
                 self.emit(IR::PushString(value));
@@ -1103,6 +1825,9 @@ impl Compiler {
             Expression::NamespaceCall { namespace, function, arguments } => {
                 self.compile_namespace_call(namespace, function, arguments);
             },
+            Expression::FunctionLiteral { parameters, body } => {
+                self.compile_function_literal(parameters, body);
+            },
         }
     }
 
@@ -1119,6 +1844,17 @@ impl Compiler {
     }
 
     fn compile_infix_expression(&mut self, left: Expression, operator: String, right: Expression) {
+        // A dot reads a field of an object or a key of a map
+        if operator == "." {
+            let Expression::Identifier(field) = right.into_unlocated() else {
+                panic!("Expected a field name after '.'");
+            };
+            self.compile_expression(left);
+            self.emit(IR::PushString(field));
+            self.emit(IR::GetIndex);
+            return;
+        }
+
         // Compile the left operand
         self.compile_expression(left);
 
@@ -1147,70 +1883,150 @@ impl Compiler {
     }
 
     fn compile_assignment_expression(&mut self, left: Expression, operator: String, right: Expression) {
-        // For simple assignment (=), just compile the right expression and store it
+        // A variable declared with a kind keeps it, so work out what is stored before compiling
+        let declared = match left.unlocated() {
+            Expression::Identifier(name) => {
+                // A global declared further down is not in scope yet but keeps its kind
+                let kind = match self.symbol_table.resolve(name) {
+                    Some(_) => self.symbol_table.kind_of(name),
+                    None => self.global_kinds.get(name).copied().flatten(),
+                };
+                kind.map(|(kind, declaration)| (name.clone(), kind, declaration))
+            },
+            _ => None,
+        };
+        let checked = declared.map(|(name, kind, declaration)| {
+            let found = if operator == "=" {
+                self.infer_kind(&right)
+            } else {
+                self.infer_kind(&Expression::InfixExpression {
+                    left: Box::new(left.clone()),
+                    operator: operator.trim_end_matches('=').to_string(),
+                    right: Box::new(right.clone()),
+                })
+            };
+            (name, kind, found, right.span(), declaration)
+        });
+
         if operator == "=" {
             // Compile the right expression
             self.compile_expression(right);
+        } else {
+            // For compound assignments (+=, -=, etc.), load the current value and apply the operation
+            self.compile_expression(left.clone());
+            self.compile_expression(right);
+
+            match operator.as_str() {
+                "+=" => { self.emit(IR::Add); },
+                "-=" => { self.emit(IR::Subtract); },
+                "*=" => { self.emit(IR::Multiply); },
+                "/=" => { self.emit(IR::Divide); },
+                "%=" => { self.emit(IR::Modulo); },
+                _ => panic!("Unknown assignment operator: {}", operator),
+            }
+        }
+
+        if let Some((name, kind, found, value, declaration)) = checked {
+            self.check_kind(&name, kind, found, value, declaration);
+        }
+
+        // An assignment is an expression, so leave the assigned value on the stack
+        self.emit(IR::Dup);
+        self.compile_store_target(left);
+    }
 
-            // Store the result in the variable
-            if let Expression::Identifier(name) = left {
-                self.emit(IR::StoreVar(name));
-            } else if let Expression::IndexExpression { left: array, index } = left {
-                // Compile the array and index
-                self.compile_expression(*array);
+    /// Store the value on top of the stack into a variable or a (possibly nested) element
+    fn compile_store_target(&mut self, target: Expression) {
+        match target.into_unlocated() {
+            Expression::Identifier(name) => {
+                self.emit_store(&name);
+            },
+            Expression::IndexExpression { left, index } => {
+                // Stack: value -> container, value -> container, index, value
+                self.compile_expression((*left).clone());
+                self.emit(IR::Swap);
                 self.compile_expression(*index);
+                self.emit(IR::Swap);
+                self.emit(IR::SetIndex);
 
-                // Store the value in the array at the given index
+                // The updated container must be written back to wherever it came from
+                self.compile_store_target(*left);
+            },
+            Expression::InfixExpression { left, operator, right } if operator == "." => {
+                let Expression::Identifier(field) = right.into_unlocated() else {
+                    panic!("Invalid left-hand side in assignment");
+                };
+                self.compile_expression((*left).clone());
+                self.emit(IR::Swap);
+                self.emit(IR::PushString(field));
+                self.emit(IR::Swap);
                 self.emit(IR::SetIndex);
-            } else {
-                panic!("Invalid left-hand side in assignment");
-            }
-        } else {
-            // For compound assignments (+=, -=, etc.), load the variable, apply the operation, and store it back
-            if let Expression::Identifier(name) = left.clone() {
-                // Load the current value
-                self.emit(IR::LoadVar(name.clone()));
-
-                // Compile the right expression
-                self.compile_expression(right);
-
-                // Apply the operation
-                match operator.as_str() {
-                    "+=" => { self.emit(IR::Add); },
-                    "-=" => { self.emit(IR::Subtract); },
-                    "*=" => { self.emit(IR::Multiply); },
-                    "/=" => { self.emit(IR::Divide); },
-                    "%=" => { self.emit(IR::Modulo); },
-                    _ => panic!("Unknown assignment operator: {}", operator),
-                }
 
-                // Store the result back in the variable
-                self.emit(IR::StoreVar(name));
-            } else {
-                panic!("Invalid left-hand side in compound assignment");
-            }
+                // Objects change in place, but a map field is written back like an element
+                match left.unlocated() {
+                    Expression::Identifier(_) | Expression::IndexExpression { .. } | Expression::InfixExpression { .. } => self.compile_store_target(*left),
+                    _ => { self.emit(IR::Pop); },
+                }
+            },
+            _ => panic!("Invalid left-hand side in assignment"),
         }
     }
 
     fn compile_call_expression(&mut self, function: Expression, arguments: Vec<Expression>) {
+        // Functions, undeclared names and names dotted onto a class or other non-variable are
+        // called by name. A method is called on the object it is dotted onto. Anything else,
+        // including a variable, is evaluated to a function value first
+        let func_name = match function.unlocated() {
+            Expression::Identifier(name) => match self.resolve(name) {
+                Some(Storage::Function) | None => Some(name.clone()),
+                Some(_) => None,
+            },
+            Expression::InfixExpression { left, operator, right } if operator == "." => {
+                let Expression::Identifier(method) = right.unlocated() else {
+                    panic!("Expected a method name after '.'");
+                };
+                match left.unlocated() {
+                    Expression::Identifier(receiver) if receiver == "super" => {
+                        self.compile_super_call(method, arguments);
+                        return;
+                    },
+                    Expression::Identifier(receiver) if matches!(self.resolve(receiver), Some(Storage::Function) | None) => {
+                        Some(format!("{}.{}", receiver, method))
+                    },
+                    _ => {
+                        self.compile_expression((**left).clone());
+                        for arg in &arguments {
+                            self.compile_expression(arg.clone());
+                        }
+                        self.emit(IR::CallMethod(method.clone(), arguments.len()));
+                        return;
+                    },
+                }
+            },
+            _ => None,
+        };
+
+        let Some(func_name) = func_name else {
+            self.compile_expression(function);
+            for arg in &arguments {
+                self.compile_expression(arg.clone());
+            }
+            self.emit(IR::CallValue(arguments.len()));
+            return;
+        };
+
         // Compile each argument
         for arg in &arguments {
             self.compile_expression(arg.clone());
         }
 
-        // Support function names like Identifier or InfixExpression (dot notation)
-        let func_name = match function {
-            Expression::Identifier(name) => name,
-            Expression::InfixExpression { left, operator, right } if operator == "." => {
-                // Only support left/right as Identifier for now
-                if let (Expression::Identifier(left_name), Expression::Identifier(right_name)) = (*left, *right) {
-                    format!("{}.{}", left_name, right_name)
-                } else {
-                    panic!("Dot expression must be identifiers on both sides");
-                }
-            },
-            _ => panic!("Function call on non-identifier or unsupported expression"),
-        };
+        // Small inline functions are expanded in place, except inside their own body
+        if let Some(function) = self.inline_functions.get(&func_name).cloned() {
+            if !self.inline_stack.contains(&func_name) {
+                self.compile_inline_call(func_name, function, arguments.len());
+                return;
+            }
+        }
 
         // Call the function with the given number of arguments
         self.emit(IR::Call(func_name, arguments.len()));
@@ -1237,7 +2053,11 @@ impl Compiler {
         self.compile_expression(left);
 
         // Special handling for enum access with a direct identifier
-        match index {
+        match index.unlocated() {
+            Expression::Identifier(name) if self.resolve(name).is_some() => {
+                // A known variable used as an index, like items[i]
+                self.emit_load(name);
+            },
             Expression::Identifier(name) => {
                 // When accessing an enum value like Color[RED], we need to handle it specially
                 // Just push the identifier name as a string
@@ -1265,24 +2085,35 @@ impl Compiler {
     }
 
     fn compile_library_call(&mut self, library: Expression, function: Expression, arguments: Vec<Expression>) {
+        // `handlers[key](x)` parses like a library call, but calls an element of a variable
+        let indexes_variable = match library.unlocated() {
+            Expression::Identifier(name) => !matches!(self.resolve(name), None | Some(Storage::Function)),
+            _ => true,
+        };
+        if indexes_variable {
+            let callee = Expression::IndexExpression { left: Box::new(library), index: Box::new(function) };
+            self.compile_call_expression(callee, arguments);
+            return;
+        }
+
         // Compile each argument
         for arg in &arguments {
             self.compile_expression(arg.clone());
         }
 
         // Get the library name
-        let lib_name = match library {
+        let lib_name = match library.into_unlocated() {
             Expression::Identifier(name) => name,
             _ => panic!("Library call on non-identifier"),
         };
 
         // Get the function name
-        let func_name = match function {
+        let func_name = match function.into_unlocated() {
             Expression::Identifier(name) => name,
             Expression::IndexExpression { left, index } => {
                 // Only support left as Identifier for now
-                if let Expression::Identifier(left_name) = *left {
-                    if let Expression::StringLiteral(index_str) = *index {
+                if let Expression::Identifier(left_name) = left.into_unlocated() {
+                    if let Expression::StringLiteral(index_str) = index.into_unlocated() {
                         format!("{}.{}", left_name, index_str)
                     } else {
                         panic!("Bracket notation must be used with string literal");
@@ -1328,8 +2159,8 @@ impl Compiler {
 
     fn compile_load_statement(&mut self, cycles: Expression, block: Vec<Statement>) {
         // Determine cycles count - default to 3 if not a literal
-        let cycles_value = match cycles {
-            Expression::NumberLiteral(num) => num as usize,
+        let cycles_value = match cycles.unlocated() {
+            Expression::NumberLiteral(num) => *num as usize,
             _ => {
                 // If not a number literal, we need to evaluate the expression at runtime
                 self.compile_expression(cycles.clone());
@@ -1343,7 +2174,7 @@ impl Compiler {
 
         // Verify all statements in the block are 'show' statements
         for stmt in &block {
-            if !matches!(stmt, Statement::ShowStatement { .. }) {
+            if !matches!(stmt.unlocated(), Statement::ShowStatement { .. }) {
                 self.errors.push("Only 'show' statements are allowed inside a 'load' block".to_string());
             }
         }
@@ -1364,7 +2195,7 @@ impl Compiler {
         for _ in 0..cycles_count {
             // Loop through each show statement in the block
             for stmt in &block {
-                if let Statement::ShowStatement { value, color } = stmt {
+                if let Statement::ShowStatement { value, color } = stmt.unlocated() {
                     // If color is specified, add ANSI color code before printing the value
                     if let Some(color_name) = color {
                         // Get the color code using our color utility
@@ -1405,87 +2236,43 @@ impl Compiler {
         self.emit(IR::Print);
     }
 
-    // Generate machine code from the IR
-    pub fn generate_machine_code(&self) -> Result<Vec<u8>, String> {
-        // This is a simplified implementation that would be replaced with actual machine code generation
-        // In a real implementation, this would translate the IR to native machine code
-
-        // For now, we'll just return a placeholder binary
-        let mut code = Vec::new();
-
-        // Add a simple header
-        code.extend_from_slice(b"\x7FELF"); // ELF magic number
-
-        // Add placeholder code for each IR instruction
-        for ir in &self.ir {
-            match ir {
-                IR::PushNumber(_) => code.push(0x01),
-                IR::PushString(_) => code.push(0x02),
-                IR::PushBoolean(_) => code.push(0x03),
-                IR::PushNull => code.push(0x04),
-                IR::Pop => code.push(0x05),
-                IR::Dup => code.push(0x06),
-                IR::Swap => code.push(0x07),
-                IR::StoreVar(_) => code.push(0x08),
-                IR::LoadVar(_) => code.push(0x09),
-                IR::Add => code.push(0x0A),
-                IR::Subtract => code.push(0x0B),
-                IR::Multiply => code.push(0x0C),
-                IR::Divide => code.push(0x0D),
-                IR::Modulo => code.push(0x0E),
-                IR::Power => code.push(0x0F),
-                IR::FloorDiv => code.push(0x10),
-                IR::Negate => code.push(0x11),
-                IR::Equal => code.push(0x12),
-                IR::NotEqual => code.push(0x13),
-                IR::GreaterThan => code.push(0x14),
-                IR::GreaterEqual => code.push(0x15),
-                IR::LessThan => code.push(0x16),
-                IR::LessEqual => code.push(0x17),
-                IR::And => code.push(0x18),
-                IR::Or => code.push(0x19),
-                IR::Not => code.push(0x1A),
-                IR::Jump(_) => code.push(0x1B),
-                IR::JumpIfFalse(_) => code.push(0x1C),
-                IR::JumpIfTrue(_) => code.push(0x1D),
-                IR::Call(_, _) => code.push(0x1E),
-                IR::Return => code.push(0x1F),
-                IR::Print => code.push(0x20),
-                IR::ReadInput => code.push(0x29),
-                IR::Exit => code.push(0x2A),
-                IR::CreateArray(_) => code.push(0x21),
-                IR::GetIndex => code.push(0x22),
-                IR::SetIndex => code.push(0x23),
-                IR::CreateMap(_) => code.push(0x24),
-                IR::GetKey => code.push(0x25),
-                IR::SetKey => code.push(0x26),
-                IR::DefineFunction(_, _) => code.push(0x27),
-                IR::Label(_) => code.push(0x28),
-                IR::SetGlobal(_) => code.push(0x2B), // Global variable operations
-                IR::Sleep => code.push(0x2C),
-                IR::LibraryCall(_, _, _) => code.push(0x2D),
-                IR::SetupTryCatch => code.push(0x2E),
-                IR::ClearTryCatch => code.push(0x2F),
-                IR::ThrowException => code.push(0x30),
-            }
+    // Run the IR optimization passes over the compiled program
+    pub fn optimize(&mut self) {
+        let before = self.ir.len();
+        optimizer::optimize(&mut self.ir, &mut self.source_map);
+        if !self.clean_output {
+            println!("[Compiler] Optimized IR from {} to {} instructions", before, self.ir.len());
         }
+    }
 
-        Ok(code)
+    // Serialize the program into the .rzb bytecode format
+    pub fn generate_bytecode(&self) -> Vec<u8> {
+        bytecode::encode(&self.ir, &self.source_map, &self.files, &self.function_param_names, &self.function_locals)
     }
 
-    // Write the generated machine code to a file
+    // Write the program as a bytecode file that `from_bytecode_file` can run without the source
     pub fn write_to_file<P: AsRef<Path>>(&self, path: P) -> Result<(), String> {
-        match self.generate_machine_code() {
-            Ok(code) => {
-                match fs::write(path, code) {
-                    Ok(_) => Ok(()),
-                    Err(e) => Err(format!("Failed to write to file: {}", e)),
-                }
-            },
-            Err(e) => Err(e),
+        match fs::write(path, self.generate_bytecode()) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(format!("Failed to write to file: {}", e)),
         }
     }
 
+    // Load a program written by `write_to_file`
+    pub fn from_bytecode_file<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let path = path.as_ref();
+        let bytes = fs::read(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        let program = bytecode::decode(&bytes)?;
+
+        let mut compiler = Compiler::new();
+        compiler.ir = program.ir;
+        compiler.source_map = program.source_map;
+        compiler.files = program.files;
+        compiler.function_param_names = program.function_param_names;
+        compiler.function_locals = program.function_locals;
+        Ok(compiler)
+    }
+
     // Execute the compiled code directly
     // In compiler.rs
 
@@ -1495,37 +2282,118 @@ impl Compiler {
     pub fn execute(&self) -> Result<(), String> {
         if !self.clean_output {
             println!("Executing Razen program...");
-            for (i, ir) in self.ir.iter().enumerate() {
-                println!("{}: {:?}", i, ir);
-            }
-        }
-
-        // Helper function for boolean logic
-        fn is_truthy(s: &str) -> bool {
-            !matches!(s, "false" | "0" | "" | "null" | "undefined" | "False")
+            print!("{}", disasm::listing(self, None));
         }
 
-        let mut stack: Vec<String> = Vec::new();
-        let mut variables: HashMap<String, String> = HashMap::new();
-        let mut call_stack: Vec<(usize, HashMap<String, String>)> = Vec::new();
-        let mut exception_handlers: Vec<(String, usize)> = Vec::new();
+        let mut stack: Vec<RazenValue> = Vec::new();
+        let mut globals: HashMap<String, RazenValue> = HashMap::new();
+        let mut functions: HashMap<String, usize> = HashMap::new();
+        let mut classes: HashMap<String, Option<String>> = HashMap::new(); // Class -> parent
+        // The running frame, and the return address and frame of each caller
+        let mut frame = Frame::new(Vec::new(), Vec::new());
+        let mut call_stack: Vec<(usize, Frame)> = Vec::new();
+        // Each handler remembers the call depth and stack height it was installed at so a throw
+        // can unwind frames and drop the values of the expression it interrupted
+        let mut exception_handlers: Vec<(usize, usize, usize)> = Vec::new();
 
         // Simplified pre-pass: Just register function addresses.
         // Parameter binding is handled at call time.
         for ir in self.ir.iter() {
-            if let IR::DefineFunction(name, address) = ir {
-                variables.insert(name.clone(), address.to_string());
+            match ir {
+                IR::DefineFunction(name, address) => { functions.insert(name.clone(), *address); },
+                IR::DefineClass(name, parent) => { classes.insert(name.clone(), parent.clone()); },
+                _ => {},
             }
         }
 
         let mut pc = 0;
+
+        // Route an error to the innermost exception handler, or abort execution if there is none
+        macro_rules! raise {
+            ($prefix:expr, $value:expr) => {{
+                let thrown: RazenValue = $value;
+                if let Some((handler_pc, depth, height)) = exception_handlers.pop() {
+                    // The frame at the handler's depth is the one that installed it
+                    if let Some((_, handler_frame)) = call_stack.drain(depth..).next() {
+                        frame = handler_frame;
+                    }
+                    stack.truncate(height);
+                    stack.push(thrown);
+                    pc = handler_pc;
+                    continue;
+                } else {
+                    // An uncaught error reports where in the source it was raised
+                    let message = format!("{}{}", $prefix, thrown);
+                    return Err(match self.source_map.get(pc).copied().flatten() {
+                        Some(span) => format!("{}: {}", self.location(span), message),
+                        None => message,
+                    });
+                }
+            }};
+        }
+
+        // Unwrap a Result from a value operation, raising its error as a Razen exception
+        macro_rules! check {
+            ($result:expr) => {
+                match $result {
+                    Ok(value) => value,
+                    Err(e) => raise!("Unhandled exception: ", RazenValue::String(e)),
+                }
+            };
+        }
+
+        macro_rules! pop {
+            () => {
+                match stack.pop() {
+                    Some(value) => value,
+                    None => return Err(format!("Stack underflow at instruction {}: {:?}", pc, self.ir[pc])),
+                }
+            };
+        }
+
+        macro_rules! pop_args {
+            ($count:expr) => {{
+                let mut args = Vec::new();
+                for _ in 0..$count {
+                    args.push(pop!());
+                }
+                args.reverse();
+                args
+            }};
+        }
+
+        // Enter a user function with a new frame, or run a builtin
+        macro_rules! call {
+            ($name:expr, $args:expr, $captures:expr) => {{
+                let name: &str = $name;
+                let mut args: Vec<RazenValue> = $args;
+                if let Some(func_addr) = functions.get(name) {
+                    // Arguments fill the parameter slots of the new frame
+                    let param_count = self.function_param_names.get(name).map_or(0, Vec::len);
+                    args.resize(param_count, RazenValue::Null);
+                    call_stack.push((pc + 1, std::mem::replace(&mut frame, Frame::new(args, $captures))));
+                    pc = *func_addr;
+                    continue;
+                }
+
+                match self.call_builtin(name, &args) {
+                    Some(Ok(value)) => stack.push(value),
+                    Some(Err(e)) => raise!("Unhandled exception: ", RazenValue::String(e)),
+                    None => {
+                        if !self.clean_output { println!("Unknown function: {}", name); }
+                        stack.push(RazenValue::Null);
+                    }
+                }
+            }};
+        }
+
         while pc < self.ir.len() {
             let ir = &self.ir[pc];
             match ir {
-                IR::PushNumber(n) => stack.push(n.to_string()),
-                IR::PushString(s) => stack.push(s.clone()),
-                IR::PushBoolean(b) => stack.push(b.to_string()),
-                IR::PushNull => stack.push("null".to_string()),
+                IR::PushNumber(n) => stack.push(RazenValue::number(*n)),
+                IR::PushString(s) => stack.push(RazenValue::String(s.clone())),
+                IR::PushBoolean(b) => stack.push(RazenValue::Bool(*b)),
+                IR::PushNull => stack.push(RazenValue::Null),
                 IR::Pop => { stack.pop(); },
                 IR::Dup => {
                     if let Some(value) = stack.last().cloned() {
@@ -1533,175 +2401,109 @@ impl Compiler {
                     }
                 },
                 IR::Swap => {
-                    if let (Some(b), Some(a)) = (stack.pop(), stack.pop()) {
-                        stack.push(b);
-                        stack.push(a);
-                    }
+                    let b = pop!();
+                    let a = pop!();
+                    stack.push(b);
+                    stack.push(a);
                 },
-                // **IMPROVED**: Handles function-local scope
-                IR::StoreVar(name) => {
-                    if let Some(value) = stack.pop() {
-                        if let Some((_, func_vars)) = call_stack.last_mut() {
-                            func_vars.insert(name.clone(), value);
-                        } else {
-                            variables.insert(name.clone(), value);
-                        }
-                    }
+                IR::StoreVar(name) | IR::SetGlobal(name) => {
+                    let value = pop!();
+                    globals.insert(name.clone(), value);
                 },
-                // **IMPROVED**: Handles function-local scope
                 IR::LoadVar(name) => {
-                    let value = if let Some((_, func_vars)) = call_stack.last() {
-                        func_vars.get(name)
-                    } else { None };
-
-                    if let Some(val) = value {
-                        stack.push(val.clone());
-                    } else if let Some(val) = variables.get(name) {
-                        stack.push(val.clone());
-                    } else {
-                        stack.push("undefined".to_string());
-                    }
+                    let value = globals.get(name).cloned().unwrap_or(RazenValue::Null);
+                    stack.push(value);
                 },
-                IR::SetGlobal(name) => {
-                    if let Some(value) = stack.pop() {
-                        variables.insert(name.clone(), value);
-                    }
-                },
-                // **RESTORED**: Full set of operators
-                IR::Add => {
-                    if let (Some(b), Some(a)) = (stack.pop(), stack.pop()) {
-                        if let (Ok(a_num), Ok(b_num)) = (a.parse::<f64>(), b.parse::<f64>()) {
-                            stack.push((a_num + b_num).to_string());
-                        } else {
-                            stack.push(format!("{}{}", a, b));
-                        }
-                    }
-                },
-                IR::Subtract => {
-                    if let (Some(b), Some(a)) = (stack.pop(), stack.pop()) {
-                        if let (Ok(a_num), Ok(b_num)) = (a.parse::<f64>(), b.parse::<f64>()) {
-                            stack.push((a_num - b_num).to_string());
-                        }
-                    }
-                },
-                IR::Multiply => {
-                    if let (Some(b), Some(a)) = (stack.pop(), stack.pop()) {
-                        if let (Ok(a_num), Ok(b_num)) = (a.parse::<f64>(), b.parse::<f64>()) {
-                            stack.push((a_num * b_num).to_string());
-                        }
-                    }
+                IR::StoreLocal(slot) => {
+                    let value = pop!();
+                    frame.store(*slot, value);
                 },
-                IR::Divide => {
-                    if let (Some(b), Some(a)) = (stack.pop(), stack.pop()) {
-                        if let (Ok(a_num), Ok(b_num)) = (a.parse::<f64>(), b.parse::<f64>()) {
-                            if b_num != 0.0 { stack.push((a_num / b_num).to_string()); }
-                            else { return Err("Division by zero".to_string()); }
-                        }
-                    }
+                IR::LoadLocal(slot) => stack.push(frame.load(*slot)),
+                IR::StoreCapture(index) => {
+                    let value = pop!();
+                    *frame.capture(*index).lock().unwrap() = value;
                 },
-                IR::Modulo => {
-                    if let (Some(b), Some(a)) = (stack.pop(), stack.pop()) {
-                        if let (Ok(a_num), Ok(b_num)) = (a.parse::<f64>(), b.parse::<f64>()) {
-                            if b_num != 0.0 { stack.push((a_num % b_num).to_string()); }
-                            else { return Err("Modulo by zero".to_string()); }
-                        }
-                    }
+                IR::LoadCapture(index) => {
+                    let value = frame.capture(*index).lock().unwrap().clone();
+                    stack.push(value);
                 },
-                IR::Power => {
-                    if let (Some(b), Some(a)) = (stack.pop(), stack.pop()) {
-                        if let (Ok(a_num), Ok(b_num)) = (a.parse::<f64>(), b.parse::<f64>()) {
-                            stack.push(a_num.powf(b_num).to_string());
-                        }
-                    }
+                IR::MakeClosure(name, captures) => {
+                    let captures = captures.iter()
+                        .map(|capture| match capture {
+                            Capture::Local(slot) => frame.cell(*slot),
+                            Capture::Captured(index) => frame.capture(*index),
+                        })
+                        .collect();
+                    stack.push(RazenValue::Function(Arc::new(Closure { name: name.clone(), captures })));
                 },
-                IR::FloorDiv => {
-                    if let (Some(b), Some(a)) = (stack.pop(), stack.pop()) {
-                        if let (Ok(a_num), Ok(b_num)) = (a.parse::<f64>(), b.parse::<f64>()) {
-                            if b_num != 0.0 { stack.push((a_num / b_num).floor().to_string()); }
-                            else { return Err("Division by zero".to_string()); }
-                        }
-                    }
+                IR::Add | IR::Subtract | IR::Multiply | IR::Divide |
+                IR::Modulo | IR::Power | IR::FloorDiv => {
+                    let b = pop!();
+                    let a = pop!();
+                    let result = match ir {
+                        IR::Add => a.add(&b),
+                        IR::Subtract => a.subtract(&b),
+                        IR::Multiply => a.multiply(&b),
+                        IR::Divide => a.divide(&b),
+                        IR::Modulo => a.modulo(&b),
+                        IR::Power => a.power(&b),
+                        _ => a.floor_div(&b),
+                    };
+                    stack.push(check!(result));
                 },
                 IR::Negate => {
-                    if let Some(a) = stack.pop() {
-                        if let Ok(a_num) = a.parse::<f64>() {
-                            stack.push((-a_num).to_string());
-                        }
-                    }
+                    let a = pop!();
+                    stack.push(check!(a.negate()));
                 },
                 IR::Equal => {
-                    if let (Some(b), Some(a)) = (stack.pop(), stack.pop()) {
-                        if let (Ok(a_num), Ok(b_num)) = (a.parse::<f64>(), b.parse::<f64>()) {
-                            stack.push((a_num == b_num).to_string());
-                        } else { stack.push((a == b).to_string()); }
-                    }
+                    let b = pop!();
+                    let a = pop!();
+                    stack.push(RazenValue::Bool(a == b));
                 },
                 IR::NotEqual => {
-                    if let (Some(b), Some(a)) = (stack.pop(), stack.pop()) {
-                        if let (Ok(a_num), Ok(b_num)) = (a.parse::<f64>(), b.parse::<f64>()) {
-                            stack.push((a_num != b_num).to_string());
-                        } else { stack.push((a != b).to_string()); }
-                    }
+                    let b = pop!();
+                    let a = pop!();
+                    stack.push(RazenValue::Bool(a != b));
                 },
-                IR::GreaterThan => {
-                    if let (Some(b), Some(a)) = (stack.pop(), stack.pop()) {
-                        if let (Ok(a_num), Ok(b_num)) = (a.parse::<f64>(), b.parse::<f64>()) {
-                            stack.push((a_num > b_num).to_string());
-                        } else { stack.push((a > b).to_string()); }
-                    }
-                },
-                IR::GreaterEqual => {
-                    if let (Some(b), Some(a)) = (stack.pop(), stack.pop()) {
-                        if let (Ok(a_num), Ok(b_num)) = (a.parse::<f64>(), b.parse::<f64>()) {
-                            stack.push((a_num >= b_num).to_string());
-                        } else { stack.push((a >= b).to_string()); }
-                    }
-                },
-                IR::LessThan => {
-                    if let (Some(b), Some(a)) = (stack.pop(), stack.pop()) {
-                        if let (Ok(a_num), Ok(b_num)) = (a.parse::<f64>(), b.parse::<f64>()) {
-                            stack.push((a_num < b_num).to_string());
-                        } else { stack.push((a < b).to_string()); }
-                    }
-                },
-                IR::LessEqual => {
-                    if let (Some(b), Some(a)) = (stack.pop(), stack.pop()) {
-                        if let (Ok(a_num), Ok(b_num)) = (a.parse::<f64>(), b.parse::<f64>()) {
-                            stack.push((a_num <= b_num).to_string());
-                        } else { stack.push((a <= b).to_string()); }
-                    }
+                IR::GreaterThan | IR::GreaterEqual | IR::LessThan | IR::LessEqual => {
+                    let b = pop!();
+                    let a = pop!();
+                    let ordering = check!(a.compare(&b));
+                    let result = match ir {
+                        IR::GreaterThan => ordering.is_gt(),
+                        IR::GreaterEqual => ordering.is_ge(),
+                        IR::LessThan => ordering.is_lt(),
+                        _ => ordering.is_le(),
+                    };
+                    stack.push(RazenValue::Bool(result));
                 },
                 IR::And => {
-                    if let (Some(b), Some(a)) = (stack.pop(), stack.pop()) {
-                        stack.push((is_truthy(&a) && is_truthy(&b)).to_string());
-                    }
+                    let b = pop!();
+                    let a = pop!();
+                    stack.push(RazenValue::Bool(a.is_truthy() && b.is_truthy()));
                 },
                 IR::Or => {
-                    if let (Some(b), Some(a)) = (stack.pop(), stack.pop()) {
-                        stack.push((is_truthy(&a) || is_truthy(&b)).to_string());
-                    }
+                    let b = pop!();
+                    let a = pop!();
+                    stack.push(RazenValue::Bool(a.is_truthy() || b.is_truthy()));
                 },
                 IR::Not => {
-                    if let Some(a) = stack.pop() {
-                        stack.push((!is_truthy(&a)).to_string());
-                    }
+                    let a = pop!();
+                    stack.push(RazenValue::Bool(!a.is_truthy()));
                 },
                 IR::Jump(target) => { pc = *target; continue; },
-                IR::JumpIfFalse(target) => {
-                    if let Some(value) = stack.pop() {
-                        if !is_truthy(&value) { pc = *target; continue; }
-                    }
+                IR::JumpIfFalse(target) => {
+                    if !pop!().is_truthy() { pc = *target; continue; }
                 },
                 IR::JumpIfTrue(target) => {
-                    if let Some(value) = stack.pop() {
-                        if is_truthy(&value) { pc = *target; continue; }
-                    }
+                    if pop!().is_truthy() { pc = *target; continue; }
                 },
-                // **IMPROVED**: Handles function returns correctly
                 IR::Return => {
-                    let return_value = stack.pop().unwrap_or_else(|| "null".to_string());
-                    if let Some((return_addr, caller_variables)) = call_stack.pop() {
-                        variables = caller_variables;
+                    let return_value = stack.pop().unwrap_or(RazenValue::Null);
+                    if let Some((return_addr, caller_frame)) = call_stack.pop() {
+                        frame = caller_frame;
+                        // Handlers of try blocks the function returned from no longer apply
+                        exception_handlers.retain(|&(_, depth, _)| depth <= call_stack.len());
                         stack.push(return_value);
                         pc = return_addr;
                         continue;
@@ -1709,159 +2511,131 @@ impl Compiler {
                         stack.push(return_value);
                     }
                 },
-                // **THE ONLY MAJOR FIX**: Handles user-defined function calls correctly
                 IR::Call(name, arg_count) => {
                     if !self.clean_output {
                         println!("Calling user function: {} with {} arguments", name, arg_count);
                     }
-                    let mut args = Vec::new();
-                    for _ in 0..*arg_count {
-                        if let Some(arg) = stack.pop() { args.push(arg); }
+                    let args = pop_args!(*arg_count);
+
+                    // Code compiled before a global was declared calls the function it holds by name
+                    match globals.get(name) {
+                        Some(RazenValue::Function(closure)) if !functions.contains_key(name) => {
+                            let closure = closure.clone();
+                            call!(&closure.name, args, closure.captures.clone())
+                        },
+                        _ => call!(name, args, Vec::new()),
                     }
-                    args.reverse();
-
-                    if let Some(func_addr_str) = variables.get(name) {
-                        if let Ok(func_addr) = func_addr_str.parse::<usize>() {
-                            let mut func_variables = variables.clone(); // Inherit globals
-                            if let Some(param_names) = self.function_param_names.get(name) {
-                                for (i, param_name) in param_names.iter().enumerate() {
-                                    if i < args.len() {
-                                        func_variables.insert(param_name.clone(), args[i].clone());
-                                    } else {
-                                        func_variables.insert(param_name.clone(), "undefined".to_string());
-                                    }
-                                }
-                            }
-                            call_stack.push((pc + 1, variables.clone()));
-                            variables = func_variables;
-                            pc = func_addr;
-                            continue;
-                        }
-                    } else {
-                        if !self.clean_output { println!("Unknown function: {}", name); }
-                        stack.push("undefined".to_string());
+                },
+                IR::CallValue(arg_count) => {
+                    if !self.clean_output {
+                        println!("Calling function value with {} arguments", arg_count);
+                    }
+                    let args = pop_args!(*arg_count);
+                    match pop!() {
+                        RazenValue::Function(closure) => call!(&closure.name, args, closure.captures.clone()),
+                        other => raise!("Unhandled exception: ", RazenValue::String(format!("Type error: cannot call {}", other.type_name()))),
                     }
                 },
-                // **RESTORED**: The original, full-featured GetIndex logic
-                IR::GetIndex => {
-                    if let (Some(index), Some(container)) = (stack.pop(), stack.pop()) {
-                        let mut found = false;
-                        // Check for enums/maps which are stored as "KEY:VALUE,KEY2:VALUE2"
-                        let enum_key_str = format!("{}:", index);
-                        if let Some(start_pos) = container.find(&enum_key_str) {
-                            let after_key = &container[start_pos + enum_key_str.len()..];
-                            let end_pos = after_key.find(',').unwrap_or(after_key.len());
-                            let value = &after_key[0..end_pos];
-                            stack.push(value.trim().to_string());
-                            found = true;
-                        }
-                        // Handle array indexing
-                        if !found && container.starts_with('[') && container.ends_with(']') {
-                            if let Ok(idx) = index.parse::<usize>() {
-                                let content = &container[1..container.len() - 1];
-                                 // This is a simplified split, a full parser would be needed for nested arrays.
-                                let elements: Vec<&str> = content.split(',').map(str::trim).collect();
-                                if idx < elements.len() {
-                                    stack.push(elements[idx].to_string());
-                                    found = true;
-                                }
+                IR::CallMethod(method, arg_count) => {
+                    if !self.clean_output {
+                        println!("Calling method: {} with {} arguments", method, arg_count);
+                    }
+                    let mut args = pop_args!(*arg_count);
+                    let receiver = pop!();
+
+                    // Look the method up in the object's class, then in the classes it extends
+                    let mut target = None;
+                    if let RazenValue::Instance(instance) = &receiver {
+                        let mut class = Some(instance.class.clone());
+                        while let Some(name) = class {
+                            let function = format!("{}.{}", name, method);
+                            if functions.contains_key(&function) {
+                                target = Some(function);
+                                break;
                             }
+                            class = classes.get(&name).cloned().flatten();
                         }
-                        if !found {
-                            stack.push("undefined".to_string());
-                        }
+                    }
+
+                    if let Some(function) = target {
+                        args.insert(0, receiver);
+                        call!(&function, args, Vec::new())
                     } else {
-                        stack.push("undefined".to_string());
+                        // A field or map entry holding a function is called with the arguments alone
+                        match receiver.get_index(&RazenValue::String(method.clone())) {
+                            Ok(RazenValue::Function(closure)) => call!(&closure.name, args, closure.captures.clone()),
+                            _ => {
+                                let owner = match &receiver {
+                                    RazenValue::Instance(instance) => format!("{} object", instance.class),
+                                    other => other.type_name().to_string(),
+                                };
+                                raise!("Unhandled exception: ", RazenValue::String(format!("Type error: {} has no method '{}'", owner, method)))
+                            },
+                        }
                     }
                 },
-                // **RESTORED**: The original, full-featured SetIndex logic
+                IR::GetIndex => {
+                    let index = pop!();
+                    let container = pop!();
+                    stack.push(check!(container.get_index(&index)));
+                },
                 IR::SetIndex => {
-                     if let (Some(value), Some(index_str), Some(container_str)) = (stack.pop(), stack.pop(), stack.pop()) {
-                        if container_str.starts_with('[') && container_str.ends_with(']') {
-                            let content = &container_str[1..container_str.len() - 1];
-                            let mut elements: Vec<String> = content.split(',').map(|s| s.trim().to_string()).collect();
-                            if let Ok(idx) = index_str.parse::<usize>() {
-                                while elements.len() <= idx {
-                                    elements.push("null".to_string());
-                                }
-                                elements[idx] = value;
-                                stack.push(format!("[{}]", elements.join(", ")));
-                            }
-                        } else {
-                            // In a real implementation, you'd handle map setting here too.
-                            stack.push(container_str); // No-op for now on non-arrays
-                        }
-                     }
+                    let value = pop!();
+                    let index = pop!();
+                    let container = pop!();
+                    stack.push(check!(container.set_index(&index, value)));
+                },
+                IR::GetKey => {
+                    let key = pop!();
+                    let container = pop!();
+                    match container {
+                        RazenValue::Map(map) => match map.get(&key.to_string()) {
+                            Some(value) => stack.push(value.clone()),
+                            None => raise!("Unhandled exception: ", RazenValue::String(format!("Key '{}' not found in map", key))),
+                        },
+                        other => raise!("Unhandled exception: ", RazenValue::String(format!("Type error: cannot read key '{}' from {}", key, other.type_name()))),
+                    }
+                },
+                IR::SetKey => {
+                    let value = pop!();
+                    let key = pop!();
+                    let container = pop!();
+                    match container {
+                        RazenValue::Map(mut map) => {
+                            map.insert(key.to_string(), value);
+                            stack.push(RazenValue::Map(map));
+                        },
+                        other => raise!("Unhandled exception: ", RazenValue::String(format!("Type error: cannot set key '{}' on {}", key, other.type_name()))),
+                    }
                 },
-                // **RESTORED**: The original, full-featured LibraryCall logic
                 IR::LibraryCall(lib_name, func_name, arg_count) => {
                     if !self.clean_output {
                         println!("Calling library function: {}.{} with {} arguments", lib_name, func_name, arg_count);
                     }
                     let function_name_only = func_name.split('.').last().unwrap_or(func_name);
-                    let mut args = Vec::new();
-                    for _ in 0..*arg_count {
-                        if let Some(arg) = stack.pop() {
-                            if let Ok(i) = arg.parse::<i64>() { args.push(crate::value::Value::Int(i)); }
-                            else if let Ok(f) = arg.parse::<f64>() { args.push(crate::value::Value::Float(f)); }
-                            else if arg == "true" { args.push(crate::value::Value::Bool(true)); }
-                            else if arg == "false" { args.push(crate::value::Value::Bool(false)); }
-                            else if arg == "null" || arg == "undefined" { args.push(crate::value::Value::Null); }
-                            else if arg.starts_with('[') && arg.ends_with(']') {
-                                let inner = &arg[1..arg.len()-1];
-                                let elements: Vec<crate::value::Value> = inner.split(',')
-                                    .map(|s| {
-                                        let s = s.trim();
-                                        if let Ok(i) = s.parse::<i64>() { crate::value::Value::Int(i) }
-                                        else if let Ok(f) = s.parse::<f64>() { crate::value::Value::Float(f) }
-                                        else { crate::value::Value::String(s.to_string()) }
-                                    }).collect();
-                                args.push(crate::value::Value::Array(elements));
-                            } else {
-                                 let mut final_arg_str = arg.clone();
-                                 if final_arg_str.starts_with('"') && final_arg_str.ends_with('"') && final_arg_str.len() >= 2 {
-                                     final_arg_str = final_arg_str[1..final_arg_str.len()-1].to_string();
-                                 }
-                                 args.push(crate::value::Value::String(final_arg_str));
-                            }
-                        }
-                    }
-                    args.reverse();
-
-                    match crate::library::call_library(&lib_name.to_lowercase(), function_name_only, args) {
-                        Ok(value) => stack.push(value.to_string()),
-                        Err(e) => {
-                            // Handle library errors by trying to throw an exception
-                            if let Some((_, handler_pc)) = exception_handlers.pop() {
-                                stack.push(e);
-                                pc = handler_pc;
-                                continue;
-                            } else {
-                                return Err(format!("Unhandled library exception: {}", e));
-                            }
-                        }
+                    let args = pop_args!(*arg_count);
+
+                    match library::call_library(&lib_name.to_lowercase(), function_name_only, args) {
+                        Ok(value) => stack.push(value),
+                        Err(e) => raise!("Unhandled library exception: ", RazenValue::String(e)),
                     };
                 },
-                // **RESTORED**: Other necessary opcodes
                 IR::CreateArray(count) => {
-                    let mut array = Vec::new();
+                    let mut array = Vec::with_capacity(*count);
                     for _ in 0..*count {
-                        if let Some(value) = stack.pop() {
-                            array.push(value);
-                        }
+                        array.push(pop!());
                     }
                     array.reverse();
-                    stack.push(format!("[{}]", array.join(", ")));
+                    stack.push(RazenValue::Array(array));
                 },
                 IR::CreateMap(count) => {
-                    let mut map_entries = Vec::new();
+                    let mut entries = Vec::with_capacity(*count);
                     for _ in 0..*count {
-                        if let (Some(value), Some(key)) = (stack.pop(), stack.pop()) {
-                            map_entries.push(format!("{}:{}", key, value));
-                        }
+                        let value = pop!();
+                        let key = pop!();
+                        entries.push((key.to_string(), value));
                     }
-                    map_entries.reverse(); // Reverse to keep order from source code
-                    stack.push(format!("{{{}}}", map_entries.join(", ")));
+                    stack.push(RazenValue::Map(entries.into_iter().rev().collect()));
                 },
                 IR::Print => {
                     if let Some(value) = stack.pop() {
@@ -1876,43 +2650,21 @@ impl Compiler {
                     let mut line = String::new();
                     stdin.lock().read_line(&mut line).expect("Failed to read line");
                     if line.ends_with('\n') { line.pop(); if line.ends_with('\r') { line.pop(); } }
-                    stack.push(line);
+                    stack.push(RazenValue::String(line));
                 },
                 IR::Exit => { return Ok(()); },
                 IR::Sleep => {
-                    if let Some(duration_str) = stack.pop() {
-                        if let Ok(duration) = duration_str.parse::<f64>() {
-                             thread::sleep(Duration::from_secs_f64(duration));
-                        }
+                    if let Ok(duration) = pop!().as_float() {
+                        thread::sleep(Duration::from_secs_f64(duration.max(0.0)));
                     }
                 },
-                // **RESTORED**: Exception Handling
-                IR::SetupTryCatch => {
-                    if let Some(handler_label) = stack.pop() {
-                       let handler_pc = self.ir.iter().position(|ir| matches!(ir, IR::Label(l) if l == &handler_label));
-                       if let Some(pc) = handler_pc {
-                           exception_handlers.push((handler_label, pc));
-                       }
-                   }
-                },
+                IR::SetupTryCatch(handler_pc) => exception_handlers.push((*handler_pc, call_stack.len(), stack.len())),
                 IR::ClearTryCatch => { exception_handlers.pop(); },
                 IR::ThrowException => {
-                    if let Some(error_message) = stack.pop() {
-                         if let Some((_, handler_pc)) = exception_handlers.pop() {
-                            stack.push(error_message);
-                            pc = handler_pc;
-                            continue;
-                        } else {
-                            return Err(format!("Unhandled exception: {}", error_message));
-                        }
-                    }
+                    let thrown = pop!();
+                    raise!("Unhandled exception: ", thrown);
                 },
-                IR::DefineFunction(_, _) | IR::Label(_) => {}, // Ignored at runtime
-                _ => {
-                    if !self.clean_output {
-                        println!("Unimplemented instruction: {:?}", ir);
-                    }
-                }
+                IR::DefineFunction(_, _) | IR::DefineClass(_, _) | IR::Label(_) => {}, // Ignored at runtime
             }
             pc += 1;
         }
@@ -1923,6 +2675,64 @@ impl Compiler {
         Ok(())
     }
 
+    /// Run one of the interpreter's built-in functions, or None if `name` is not a builtin
+    fn call_builtin(&self, name: &str, args: &[RazenValue]) -> Option<Result<RazenValue, String>> {
+        let arg = |i: usize| args.get(i).cloned().unwrap_or(RazenValue::Null);
+        let result = match name {
+            "len" => arg(0).len().map(|n| RazenValue::Int(n as i64)),
+            "__match_array" => {
+                let length = arg(1).as_int().unwrap_or(0).max(0) as usize;
+                Ok(RazenValue::Bool(match arg(0) {
+                    RazenValue::Array(items) if arg(2).is_truthy() => items.len() >= length,
+                    RazenValue::Array(items) => items.len() == length,
+                    _ => false,
+                }))
+            },
+            "__new_instance" => Ok(RazenValue::Instance(Arc::new(Instance::new(&arg(0).to_string())))),
+            "__comparable" => Ok(RazenValue::Bool(arg(0).compare(&arg(1)).is_ok())),
+            "__array_rest" => match arg(0) {
+                RazenValue::Array(items) => {
+                    let start = arg(1).as_int().unwrap_or(0).max(0) as usize;
+                    Ok(RazenValue::Array(items.into_iter().skip(start).collect()))
+                },
+                other => Err(format!("Type error: cannot take the rest of {}", other.type_name())),
+            },
+            "__assert" => {
+                if arg(0).is_truthy() {
+                    Ok(RazenValue::Null)
+                } else {
+                    Err("Assertion failed".to_string())
+                }
+            },
+            "__assert_with_message" => {
+                if arg(0).is_truthy() {
+                    Ok(RazenValue::Null)
+                } else {
+                    Err(format!("Assertion failed: {}", arg(1)))
+                }
+            },
+            "__debug" => {
+                println!("[DEBUG] {:?}", arg(0));
+                Ok(RazenValue::Null)
+            },
+            "__trace" => {
+                println!("[TRACE] {}", arg(0));
+                Ok(RazenValue::Null)
+            },
+            "__check_kind" => {
+                let value = arg(0);
+                let kind = arg(1).to_string();
+                if value.type_name() == kind || matches!(value, RazenValue::Null) {
+                    Ok(value)
+                } else {
+                    Err(format!("Type error: cannot store {} in '{}', which is declared {}", value.type_name(), arg(2), kind))
+                }
+            },
+            _ => return None,
+        };
+        Some(result)
+    }
+
     // Module System Methods
 
     /// Compile module import statement
@@ -2010,7 +2820,7 @@ impl Compiler {
         }
 
         // Check if the symbol exists in current scope
-        if self.symbol_table.resolve(&name).is_none() && self.function_table.resolve(&name).is_none() {
+        if self.resolve(&name).is_none() && self.function_table.resolve(&name).is_none() {
             self.errors.push(format!("Cannot export undefined symbol: {}", name));
             return;
         }
@@ -2068,31 +2878,153 @@ impl Compiler {
 
     // OOP Methods (Section 12)
 
-    // Compile class declaration
-    fn compile_class_declaration(&mut self, name: String, body: Vec<Statement>) {
+    // Compile a class into a constructor function named after it and a function per method,
+    // named Class.method, that takes the object as its first parameter `self`
+    fn compile_class_declaration(&mut self, name: String, parent: Option<String>, body: Vec<Statement>, is_final: bool) {
         if !self.clean_output {
             println!("[Compiler] Class declaration: {}", name);
         }
 
-        // Create a new scope for the class
-        self.enter_scope();
+        // A subclass starts from the fields of its parent
+        let mut fields = Vec::new();
+        let mut parent = parent;
+        if let Some(parent_name) = parent.clone() {
+            match self.classes.get(&parent_name) {
+                Some(info) if info.is_final => {
+                    self.report_error(diagnostic::INVALID_PARENT_CLASS, format!("Class '{}' cannot extend final class '{}'", name, parent_name), None, "extends a final class");
+                    parent = None;
+                },
+                Some(info) => fields = info.fields.clone(),
+                None => {
+                    self.report_error(diagnostic::INVALID_PARENT_CLASS, format!("Class '{}' extends unknown class '{}'", name, parent_name), None, "no class with this name is declared before it");
+                    parent = None;
+                },
+            }
+        }
 
-        // Store class name in symbol table
-        self.symbol_table.define(&name);
+        let mut methods = Vec::new();
+        for stmt in body {
+            let span = match &stmt {
+                Statement::Located { span, .. } => Some(*span),
+                _ => None,
+            };
+            match stmt.unlocated().clone() {
+                Statement::VariableDeclaration { name: field, value, .. } => {
+                    match fields.iter_mut().find(|(existing, _)| *existing == field) {
+                        Some(existing) => existing.1 = value,
+                        None => fields.push((field, value)),
+                    }
+                },
+                Statement::FunctionDeclaration { name: method, parameters, body } => methods.push((method, parameters, body)),
+                other => self.report_error(
+                    diagnostic::INVALID_CLASS_MEMBER,
+                    format!("Only fields and methods are allowed in class '{}', found: {}", name, Node::Statement(other)),
+                    span,
+                    "not a field or method",
+                ),
+            }
+        }
 
-        // Emit class definition
-        self.emit(IR::PushString(name.clone()));
+        self.classes.insert(name.clone(), ClassInfo {
+            parent: parent.clone(),
+            fields: fields.clone(),
+            methods: methods.iter().map(|(method, _, _)| method.clone()).collect(),
+            is_final,
+        });
+        self.symbol_table.define_function(&name);
+        self.emit(IR::DefineClass(name.clone(), parent));
+
+        let field_names: Vec<String> = fields.iter().map(|(field, _)| field.clone()).collect();
+        let outer_class = self.current_class.replace(name.clone());
+        for (method, parameters, body) in methods {
+            let mut method_parameters = vec!["self".to_string()];
+            method_parameters.extend(parameters);
+            self.method_fields = field_names.clone();
+            self.compile_function(format!("{}.{}", name, method), method_parameters, body, false);
+        }
+        self.current_class = outer_class;
+
+        // The constructor creates the object, gives its fields their initial values and passes
+        // its arguments on to `init`, which may be inherited
+        let init_parameters = self.find_method(&name, "init")
+            .and_then(|owner| self.function_param_names.get(&format!("{}.init", owner)))
+            .map(|parameters| parameters.len() - 1);
+        let arguments: Vec<String> = (0..init_parameters.unwrap_or(0)).map(|i| format!("__arg{}", i)).collect();
+        let object = || Expression::Identifier("self".to_string());
+        let field_of_object = |field: String| Expression::InfixExpression {
+            left: Box::new(object()),
+            operator: ".".to_string(),
+            right: Box::new(Expression::Identifier(field)),
+        };
 
-        // Compile class body
-        for stmt in body {
-            self.compile_statement(stmt);
+        let mut constructor = vec![Statement::VariableDeclaration {
+            var_type: "var".to_string(),
+            name: "self".to_string(),
+            value: Some(Expression::CallExpression {
+                function: Box::new(Expression::Identifier("__new_instance".to_string())),
+                arguments: vec![Expression::StringLiteral(name.clone())],
+            }),
+        }];
+        for (field, value) in fields {
+            constructor.push(Statement::ExpressionStatement {
+                expression: Expression::AssignmentExpression {
+                    left: Box::new(field_of_object(field)),
+                    operator: "=".to_string(),
+                    right: Box::new(value.unwrap_or(Expression::NullLiteral)),
+                },
+            });
         }
+        if init_parameters.is_some() {
+            constructor.push(Statement::ExpressionStatement {
+                expression: Expression::CallExpression {
+                    function: Box::new(field_of_object("init".to_string())),
+                    arguments: arguments.iter().cloned().map(Expression::Identifier).collect(),
+                },
+            });
+        }
+        constructor.push(Statement::ReturnStatement { value: Some(object()) });
+        self.compile_function(name, arguments, constructor, false);
+    }
 
-        // Leave class scope
-        self.leave_scope();
+    // Class that defines `method` for objects of `class`: the class itself or the nearest
+    // parent that does
+    fn find_method(&self, class: &str, method: &str) -> Option<String> {
+        let mut current = Some(class.to_string());
+        while let Some(name) = current {
+            let info = self.classes.get(&name)?;
+            if info.methods.iter().any(|defined| defined == method) {
+                return Some(name);
+            }
+            current = info.parent.clone();
+        }
+        None
+    }
+
+    // Call a parent class's version of a method on `self`: super.method(args)
+    fn compile_super_call(&mut self, method: &str, arguments: Vec<Expression>) {
+        let owner = match &self.current_class {
+            None => Err("'super' used outside of a method".to_string()),
+            Some(class) => match self.classes.get(class).and_then(|info| info.parent.clone()) {
+                None => Err(format!("Class '{}' has no parent class for 'super'", class)),
+                Some(parent) => self.find_method(&parent, method)
+                    .ok_or_else(|| format!("No parent class of '{}' has a method '{}'", class, method)),
+            },
+        };
+        let owner = match owner {
+            Ok(owner) => owner,
+            Err(message) => {
+                // The call still leaves a value so the rest of the program compiles
+                self.report_error(diagnostic::INVALID_SUPER, message, None, "'super' cannot be called here");
+                self.emit(IR::PushNull);
+                return;
+            },
+        };
 
-        // Call class definition function
-        self.emit(IR::Call("__define_class".to_string(), 1));
+        self.emit_load("self");
+        for arg in &arguments {
+            self.compile_expression(arg.clone());
+        }
+        self.emit(IR::Call(format!("{}.{}", owner, method), arguments.len() + 1));
     }
 
     // API Integration Methods (Section 13)
@@ -2157,16 +3089,13 @@ impl Compiler {
         self.emit(IR::PushString(name.clone()));
         self.emit(IR::PushString(url));
 
-        // Create options map
-        self.emit(IR::PushNumber(options.len() as f64));
-        self.emit(IR::CreateMap(options.len()));
-
-        // Compile connection options
+        // Create options map from the connection options
+        let option_count = options.len();
         for (option_name, option_value) in options {
             self.emit(IR::PushString(option_name));
             self.compile_expression(option_value);
-            self.emit(IR::SetKey);
         }
+        self.emit(IR::CreateMap(option_count));
 
         // Call connection function
         self.emit(IR::Call("__connect".to_string(), 3));
@@ -2184,14 +3113,10 @@ impl Compiler {
         self.emit(IR::PushString(path));
 
         // Create array of imports
-        self.emit(IR::PushNumber(imports.len() as f64));
-        self.emit(IR::CreateArray(imports.len()));
-
-        // Add each import to the array
         for import in &imports {
             self.emit(IR::PushString(import.clone()));
-            self.emit(IR::SetIndex);
         }
+        self.emit(IR::CreateArray(imports.len()));
 
         // Call import function
         self.emit(IR::Call("__import".to_string(), 2));
@@ -2345,15 +3270,10 @@ impl Compiler {
         self.emit(IR::PushString("methods".to_string()));
 
         // Create an array for methods
-        self.emit(IR::CreateArray(methods.len()));
-
-        // Add each method to the array
-        for (i, method) in methods.iter().enumerate() {
+        for method in &methods {
             self.emit(IR::PushString(method.clone()));
-            // Use i as the index for the array
-            self.emit(IR::PushNumber(i as f64));
-            self.emit(IR::SetIndex);
         }
+        self.emit(IR::CreateArray(methods.len()));
 
         // Set the methods array in the map
         self.emit(IR::SetKey);
@@ -2370,15 +3290,10 @@ impl Compiler {
         self.emit(IR::PushString("attributes".to_string()));
 
         // Create an array for attributes
-        self.emit(IR::CreateArray(attributes.len()));
-
-        // Add each attribute to the array
-        for (i, attr) in attributes.iter().enumerate() {
+        for attr in &attributes {
             self.emit(IR::PushString(attr.clone()));
-            // Use i as the index for the array
-            self.emit(IR::PushNumber(i as f64));
-            self.emit(IR::SetIndex);
         }
+        self.emit(IR::CreateArray(attributes.len()));
 
         // Set the attributes array in the map
         self.emit(IR::SetKey);
@@ -2412,15 +3327,10 @@ impl Compiler {
         self.emit(IR::PushString("operations".to_string()));
 
         // Create an array for operations
-        self.emit(IR::CreateArray(operations.len()));
-
-        // Add each operation to the array
-        for (i, op) in operations.iter().enumerate() {
+        for op in &operations {
             self.emit(IR::PushString(op.clone()));
-            // Use i as the index for the array
-            self.emit(IR::PushNumber(i as f64));
-            self.emit(IR::SetIndex);
         }
+        self.emit(IR::CreateArray(operations.len()));
 
         // Set the operations array in the map
         self.emit(IR::SetKey);
@@ -2442,15 +3352,10 @@ impl Compiler {
         self.emit(IR::PushString("operands".to_string()));
 
         // Create an array for operands
-        self.emit(IR::CreateArray(operands.len()));
-
-        // Add each operand to the array
-        for (i, operand) in operands.iter().enumerate() {
+        for operand in &operands {
             self.emit(IR::PushString(operand.clone()));
-            // Use i as the index for the array
-            self.emit(IR::PushNumber(i as f64));
-            self.emit(IR::SetIndex);
         }
+        self.emit(IR::CreateArray(operands.len()));
 
         // Set the operands array in the map
         self.emit(IR::SetKey);
@@ -2497,15 +3402,10 @@ impl Compiler {
         self.emit(IR::PushString("passes".to_string()));
 
         // Create an array for passes
-        self.emit(IR::CreateArray(passes.len()));
-
-        // Add each pass to the array
-        for (i, pass) in passes.iter().enumerate() {
+        for pass in &passes {
             self.emit(IR::PushString(pass.clone()));
-            // Use i as the index for the array
-            self.emit(IR::PushNumber(i as f64));
-            self.emit(IR::SetIndex);
         }
+        self.emit(IR::CreateArray(passes.len()));
 
         // Set the passes array in the map
         self.emit(IR::SetKey);
@@ -2612,16 +3512,13 @@ impl Compiler {
         }
 
         // Define the constant in the symbol table
-        self.symbol_table.define(&name);
-
-        // Store the variable type for future type checking
-        self.variable_types.insert(name.clone(), "const".to_string());
+        self.define_variable(&name);
 
         // Compile the initializer expression
         self.compile_expression(value);
 
         // Store the value in the constant
-        self.emit(IR::StoreVar(name.clone()));
+        self.emit_store(&name);
 
         // For now, constants are just regular variables
         // In a full implementation, we would add runtime checks to prevent modification
@@ -2634,17 +3531,15 @@ impl Compiler {
         }
 
         // Define the enum in the symbol table
-        self.symbol_table.define(&name);
+        self.define_variable(&name);
+        self.enums.insert(name.clone(), variants.iter().map(|(variant, _)| variant.clone()).collect());
 
         // Create a map to store the enum variants
-        self.emit(IR::CreateMap(variants.len()));
+        self.emit(IR::CreateMap(0));
 
         // Add each variant to the map
         let mut variant_index = 0;
         for (variant_name, variant_value) in variants {
-            // Push the map
-            self.emit(IR::Dup);
-
             // Push the variant name as key
             self.emit(IR::PushString(variant_name.clone()));
 
@@ -2661,144 +3556,658 @@ impl Compiler {
         }
 
         // Store the enum in a variable
-        self.emit(IR::StoreVar(name));
+        self.emit_store(&name);
     }
 
-    // Compile inline function declaration
+    // Compile inline function declaration. The function is also compiled normally, for calls
+    // that are not expanded: recursive calls, calls before the declaration, and large bodies
     fn compile_inline_function_declaration(&mut self, name: String, parameters: Vec<String>, body: Vec<Statement>) {
         if !self.clean_output {
             println!("[Compiler] Inline function declaration: {}", name);
         }
 
-        // For now, inline functions are compiled the same way as regular functions
-        // In a full implementation, the compiler would perform inlining optimizations
-
-        // Generate a label for the function
-        let function_label = self.generate_label("function");
-        let end_label = self.generate_label("end");
+        let (function_start, function_end) = self.compile_function_declaration(name.clone(), parameters.clone(), body.clone());
+        let function_ir = &self.ir[function_start..function_end];
+        let has_nested_functions = function_ir.iter().any(|ir| matches!(ir, IR::DefineFunction(defined, _) if *defined != name));
+        if function_ir.len() <= INLINE_SIZE_LIMIT && !has_nested_functions {
+            self.inline_functions.insert(name, InlineFunction { parameters, body });
+        } else if !self.clean_output {
+            println!("[Compiler] Inline function {} is too large to expand at call sites", name);
+        }
+    }
 
-        // Skip over the function body during normal execution
-        // We need to add a placeholder jump that we'll fix later
-        let jump_pos = self.emit(IR::Jump(0));
+    // Expand an inline function at a call site whose arguments are already on the stack.
+    // Parameters and the body's variables get fresh slots in the caller's frame, and the body
+    // sees globals but not the caller's locals, as with a real call. Returns go through a
+    // result slot so no value is left on the stack across a jump
+    fn compile_inline_call(&mut self, name: String, function: InlineFunction, arg_count: usize) {
+        let expansion = self.generate_label("__inline_");
 
-        // Define the function in the function table
-        let function_address = self.emit_label(&function_label);
-        self.function_table.define(&name, function_address);
+        // Missing arguments are null and extra ones are dropped, as for a call
+        for _ in function.parameters.len()..arg_count {
+            self.emit(IR::Pop);
+        }
+        for _ in arg_count..function.parameters.len() {
+            self.emit(IR::PushNull);
+        }
 
-        // Create a new scope for the function
-        self.enter_scope();
+        // Compile the body with its own scope, loop context and return jumps
+        let globals = self.symbol_table.root().clone();
+        let depth = self.frames.len() - 1;
+        let outer_scope = std::mem::replace(&mut self.symbol_table, SymbolTable::new_function(globals, depth));
+        let result_slot = self.new_slot(&format!("{}_result", expansion));
+        for param in &function.parameters {
+            self.define_variable(param);
+        }
+        for param in function.parameters.iter().rev() {
+            self.emit_store(param);
+        }
+        let outer_breaks = std::mem::take(&mut self.break_stack);
+        let outer_continues = std::mem::take(&mut self.continue_stack);
+        let outer_tries = std::mem::take(&mut self.try_regions);
+        self.inline_stack.push(name.clone());
+        self.inline_returns.push((result_slot, Vec::new()));
+
+        let body_start = self.ir.len();
+        for stmt in function.body {
+            self.compile_statement(stmt);
+        }
+        self.emit(IR::PushNull);
+        self.emit(IR::StoreLocal(result_slot));
+        let end_label = self.generate_label("inline_end_");
+        let body_end = self.emit_label(&end_label);
+        self.emit(IR::LoadLocal(result_slot));
+
+        if let Some((_, returns)) = self.inline_returns.pop() {
+            for pos in returns {
+                self.replace_instruction(pos, IR::Jump(body_end));
+            }
+        }
+        self.inline_stack.pop();
+        self.break_stack = outer_breaks;
+        self.continue_stack = outer_continues;
+        self.try_regions = outer_tries;
+        self.symbol_table = outer_scope;
 
-        // Define parameters in the symbol table
-        for param in &parameters {
-            self.symbol_table.define(param);
+        if !self.clean_output {
+            println!("[Compiler] Expanded inline function {} ({} instructions)", name, self.ir.len() - body_start);
         }
+    }
 
-        // Compile function body
-        for stmt in body {
-            self.compile_statement(stmt);
+    // Compile volatile variable declaration
+    fn compile_volatile_declaration(&mut self, var_type: String, name: String, value: Option<Expression>) {
+        if !self.clean_output {
+            println!("[Compiler] Volatile variable declaration: {}", name);
         }
 
-        // If no explicit return, return null
-        self.emit(IR::PushNull);
-        self.emit(IR::Return);
+        // For now, volatile variables are compiled the same way as regular variables
+        // In a full implementation, the compiler would add memory barriers or other synchronization
+        self.compile_variable_declaration(var_type, name, value);
+    }
+}
 
-        // Leave function scope
-        self.leave_scope();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+
+    // Runs a program; the programs below throw when a check does not hold
+    fn run(input: &str) -> Result<(), String> {
+        let lexer = Lexer::new(input.to_string());
+        let mut parser = Parser::new(lexer);
+        let program = parser.parse_program();
+        assert_eq!(parser.get_errors().len(), 0, "Parser errors: {:?}", parser.get_errors());
+
+        let mut compiler = Compiler::new();
+        compiler.set_clean_output(true);
+        compiler.compile_program(program);
+        compiler.execute()
+    }
 
-        // Mark the end of the function
-        let end_pos = self.emit_label(&end_label);
+    #[test]
+    fn test_nested_arrays() {
+        let input = r#"
+            list grid = [[1, 2], [3, [4, 5]]];
+            if (grid[1][1][0] != 4) { throw "nested read"; }
+            grid[0][1] = 9;
+            if (grid[0][1] != 9) { throw "nested write"; }
+            if (len(grid[1]) != 2) { throw "inner length"; }
+        "#;
+        assert_eq!(run(input), Ok(()));
+    }
 
-        // Fix the jump instruction to point to the end label position
-        self.replace_instruction(jump_pos, IR::Jump(end_pos));
+    #[test]
+    fn test_strings_with_commas() {
+        let input = r#"
+            list words = ["a, b", "c"];
+            if (len(words) != 2) { throw "length"; }
+            if (words[0] != "a, b") { throw "element"; }
+        "#;
+        assert_eq!(run(input), Ok(()));
     }
 
-    // Compile final class declaration
-    fn compile_final_class_declaration(&mut self, name: String, body: Vec<Statement>) {
-        if !self.clean_output {
-            println!("[Compiler] Final class declaration: {}", name);
-        }
+    #[test]
+    fn test_maps() {
+        let input = r#"
+            map person = {"name": "Ada, Countess", "age": 36};
+            if (person["name"] != "Ada, Countess") { throw "read"; }
+            person["age"] = person["age"] + 1;
+            if (person["age"] != 37) { throw "write"; }
+        "#;
+        assert_eq!(run(input), Ok(()));
+    }
 
-        // For now, final classes are compiled the same way as regular classes
-        // In a full implementation, the compiler would add checks to prevent inheritance
+    #[test]
+    fn test_for_loop_over_list() {
+        let input = r#"
+            num total = 0;
+            for (n in [1, 2, 3, 4]) {
+                total = total + n;
+            }
+            if (total != 10) { throw "sum"; }
+        "#;
+        assert_eq!(run(input), Ok(()));
+    }
 
-        // Create a new scope for the class
-        self.enter_scope();
+    #[test]
+    fn test_runtime_error_can_be_caught() {
+        let input = r#"
+            str caught = "";
+            try {
+                num x = 1 / 0;
+            } catch (e) {
+                caught = e;
+            }
+            if (caught != "Division by zero") { throw "not caught"; }
+        "#;
+        assert_eq!(run(input), Ok(()));
+        assert_eq!(run("throw \"boom\";"), Err("1:1: Unhandled exception: boom".to_string()));
+    }
 
-        // Store class name in symbol table
-        self.symbol_table.define(&name);
+    #[test]
+    fn test_integer_edge_cases() {
+        let input = r#"
+            num smallest = -4611686018427387904 * 2;
+            if (smallest / -1 != 9223372036854775808) { throw "overflowing division"; }
+            if (smallest % -1 != 0) { throw "overflowing modulo"; }
+            if (smallest // -1 != 9223372036854775808) { throw "overflowing floor division"; }
+            if (6 / 3 != 2 || 7 / 2 != 3.5) { throw "division"; }
+            list items = [1];
+            items[1] = 2;
+            if (len(items) != 2) { throw "append by index"; }
+        "#;
+        assert_eq!(run(input), Ok(()));
+        assert_eq!(run("list items = [1];\nitems[1000000000000000] = 1;"), Err("2:1: Unhandled exception: List index 1000000000000000 is out of range".to_string()));
+    }
 
-        // Emit class definition
-        self.emit(IR::PushString(name.clone()));
+    #[test]
+    fn test_runtime_error_location() {
+        let path = std::env::temp_dir().join(format!("razen_location_{}.rzn", std::process::id()));
+        fs::write(&path, "num zero = 0;\nfun half(n) {\n    return n / zero;\n}\nshow half(1);\n").unwrap();
+        let mut compiler = Compiler::from_file(&path, CompileOptions::default()).unwrap();
+        fs::remove_file(&path).unwrap();
+        compiler.set_clean_output(true);
 
-        // Compile class body
-        for stmt in body {
-            self.compile_statement(stmt);
-        }
+        let expected = format!("{}:3:12: Unhandled exception: Division by zero", path.display());
+        assert_eq!(compiler.execute(), Err(expected));
+    }
 
-        // Leave class scope
-        self.leave_scope();
+    #[test]
+    fn test_try_catch_finally() {
+        let input = r#"
+            str log = "";
+            try {
+                try {
+                    throw "inner";
+                } catch (e) {
+                    log = log + "catch " + e + ";";
+                    throw "rethrown " + e;
+                } finally {
+                    log = log + "inner finally;";
+                }
+                log = log + "not reached;";
+            } catch (e) {
+                log = log + "outer " + e + ";";
+            } finally {
+                log = log + "outer finally;";
+            }
+            if (log != "catch inner;inner finally;outer rethrown inner;outer finally;") { throw log; }
 
-        // Call class definition function
-        self.emit(IR::Call("__define_class".to_string(), 1));
+            fun fail(n) { throw "failed " + n; }
+            fun guarded() {
+                try { return 1; } catch (e) { return 2; }
+            }
+            str result = "";
+            try {
+                try {
+                    num x = 1 + fail(3);
+                } finally {
+                    result = "finally ran;";
+                }
+            } catch (e) {
+                result = result + e;
+            }
+            if (result != "finally ran;failed 3") { throw result; }
+            if (guarded() != 1) { throw "return from try"; }
+
+            str after = "";
+            try { guarded(); throw "after return"; } catch (e) { after = e; }
+            if (after != "after return") { throw "stale handler"; }
+        "#;
+        assert_eq!(run(input), Ok(()));
+        assert_eq!(run("try { throw \"a\"; } finally { show 1; }"), Err("1:1: Unhandled exception: a".to_string()));
     }
 
-    // Compile volatile variable declaration
-    fn compile_volatile_declaration(&mut self, var_type: String, name: String, value: Option<Expression>) {
-        if !self.clean_output {
-            println!("[Compiler] Volatile variable declaration: {}", name);
-        }
+    #[test]
+    fn test_jumps_out_of_try() {
+        let input = r#"
+            str log = "";
+            num i = 0;
+            while (i < 5) {
+                i = i + 1;
+                try {
+                    if (i == 1) { continue; }
+                    if (i == 2) { throw "two"; }
+                    break;
+                } catch (e) {
+                    log = log + e + ";";
+                    continue;
+                } finally {
+                    log = log + "finally " + i + ";";
+                }
+            }
+            if (log != "finally 1;two;finally 2;finally 3;") { throw "loop exits: " + log; }
 
-        // For now, volatile variables are compiled the same way as regular variables
-        // In a full implementation, the compiler would add memory barriers or other synchronization
+            fun early() {
+                try {
+                    try { return "early"; } finally { log = log + "inner;"; }
+                } finally {
+                    log = log + "outer;";
+                }
+                return "late";
+            }
+            log = "";
+            if (early() != "early" || log != "inner;outer;") { throw "return: " + log; }
+
+            str caught = "";
+            try { throw "later"; } catch (e) { caught = e; }
+            if (caught != "later") { throw "stale handler"; }
+        "#;
+        assert_eq!(run(input), Ok(()));
+        assert_eq!(
+            run("num i = 0;\nwhile (i < 3) { try { i = i + 1; break; } catch (e) { show \"STALE \" + e; } }\nthrow \"boom\";"),
+            Err("3:1: Unhandled exception: boom".to_string())
+        );
+    }
 
-        // Define the variable in the symbol table
-        self.symbol_table.define(&name);
+    #[test]
+    fn test_inline_function_expansion() {
+        let input = r#"
+            num x = 100;
+            inline fun clamp(x, lo, hi) {
+                if (x < lo) { return lo; }
+                if (x > hi) { return hi; }
+                return x;
+            }
+            if (clamp(15, 0, 10) != 10) { throw "upper"; }
+            if (clamp(-3, 0, 10) != 0) { throw "lower"; }
+            if (clamp(clamp(4, 0, 3), 0, 10) != 3) { throw "nested"; }
+            if (x != 100) { throw "caller variable"; }
+        "#;
+        assert_eq!(run(input), Ok(()));
+
+        let mut parser = Parser::new(Lexer::new(input.to_string()));
+        let mut compiler = Compiler::new();
+        compiler.compile_program(parser.parse_program());
+        assert!(!compiler.ir.iter().any(|ir| matches!(ir, IR::Call(name, _) if name == "clamp")));
+    }
 
-        // Store the variable type for future type checking
-        self.variable_types.insert(name.clone(), format!("volatile_{}", var_type));
+    #[test]
+    fn test_inline_return_leaves_try() {
+        let input = r#"
+            str log = "";
+            inline fun safe() {
+                try { return 1; } catch (e) { log = log + "stale " + e; } finally { log = log + "finally;"; }
+                return 0;
+            }
+            if (safe() != 1 || log != "finally;") { throw "inline return: " + log; }
+            str caught = "";
+            try { throw "later"; } catch (e) { caught = e; }
+            if (caught != "later" || log != "finally;") { throw "stale handler: " + log; }
+        "#;
+        assert_eq!(run(input), Ok(()));
+        assert_eq!(
+            run("inline fun safe() { try { return 1; } catch (e) { show \"STALE \" + e; } return 0; }\nshow safe();\nthrow \"later\";"),
+            Err("3:1: Unhandled exception: later".to_string())
+        );
+
+        let mut parser = Parser::new(Lexer::new(input.to_string()));
+        let mut compiler = Compiler::new();
+        compiler.set_clean_output(true);
+        compiler.compile_program(parser.parse_program());
+        assert!(!compiler.ir.iter().any(|ir| matches!(ir, IR::Call(name, _) if name == "safe")));
+    }
 
-        // Compile the initializer expression if it exists
-        if let Some(expr) = value {
-            // Type checking based on variable type
-            match var_type.as_str() {
-                // Numeric types
-                "let" | "sum" | "diff" | "prod" | "div" | "mod" => {
-                    // Check that the value is a number
-                    if !self.is_number_expression(&expr) {
-                        self.errors.push(format!("Type error: 'volatile {}' variables can only be used with numeric values, but '{}' was assigned a non-numeric value", var_type, name));
-                    }
-                },
-                // String types
-                "take" | "text" | "concat" | "slice" => {
-                    // Check that the value is a string
-                    if !self.is_string_expression(&expr) {
-                        self.errors.push(format!("Type error: 'volatile {}' variables can only be used with string values, but '{}' was assigned a non-string value", var_type, name));
-                    }
-                },
-                // Boolean types
-                "hold" => {
-                    // Check that the value is a boolean
-                    if !self.is_boolean_expression(&expr) {
-                        self.errors.push(format!("Type error: 'volatile {}' variables can only be used with boolean values, but '{}' was assigned a non-boolean value", var_type, name));
-                    }
-                },
-                // Generic types - no type checking needed
-                "put" | "list" | "arr" | "map" | "store" | "box" | "ref" => {
-                    // These can hold any type, so no type checking needed
-                },
-                _ => {
-                    // For new variable types not explicitly handled
-                    // For now, we don't do type checking on these
+    #[test]
+    fn test_call_frames() {
+        let input = r#"
+            num calls = 0;
+            num n = 5;
+            fun fib(n) {
+                calls = calls + 1;
+                if (n < 2) { return n; }
+                num a = fib(n - 1);
+                num b = fib(n - 2);
+                return a + b;
+            }
+            fun depth(n) {
+                if (n == 0) { return 0; }
+                return depth(n - 1) + 1;
+            }
+            fun shadow() {
+                num calls = -1;
+                if (true) { num calls = -2; }
+                return calls;
+            }
+            if (fib(10) != 55) { throw "recursion"; }
+            if (calls != 177) { throw "global mutated in function"; }
+            if (n != 5) { throw "parameter leaked"; }
+            if (depth(20000) != 20000) { throw "deep recursion"; }
+            if (shadow() != -1) { throw "block shadowing"; }
+            if (calls != 177) { throw "local shadowed global"; }
+        "#;
+        assert_eq!(run(input), Ok(()));
+    }
+
+    #[test]
+    fn test_closures() {
+        let input = r#"
+            fun counter() {
+                num count = 0;
+                return fun () {
+                    count = count + 1;
+                    return count;
+                };
+            }
+            fun twice(f, x) { return f(f(x)); }
+            fun adder(n) {
+                var add = fun (x) { return x + n; };
+                n = n * 10;
+                return add;
+            }
+            fun nested(a) {
+                return fun (b) { return fun (c) { return a + b + c; }; };
+            }
+            fun square(x) { return x * x; }
+
+            var next = counter();
+            var other = counter();
+            next();
+            if (next() != 2) { throw "captured variable not shared between calls"; }
+            if (other() != 1) { throw "counters share state"; }
+            if (twice(fun (x) { return x + 3; }, 1) != 7) { throw "function argument"; }
+            if (adder(2)(1) != 21) { throw "closure sees later assignments"; }
+            if (nested(1)(2)(3) != 6) { throw "nested capture"; }
+            if (twice(square, 3) != 81) { throw "named function as value"; }
+            list ops = [square, fun (x) { return -x; }];
+            if (ops[1](4) != -4) { throw "call through index"; }
+            if ((fun (x) { return x * 2; })(5) != 10) { throw "immediate call"; }
+            var fact = fun (n) {
+                if (n < 2) { return 1; }
+                return n * fact(n - 1);
+            };
+            if (fact(5) != 120) { throw "recursive literal"; }
+        "#;
+        assert_eq!(run(input), Ok(()));
+        assert_eq!(run("num x = 1; x(2);"), Err("1:12: Unhandled exception: Type error: cannot call num".to_string()));
+    }
+
+    #[test]
+    fn test_classes() {
+        let input = r#"
+            class Hero {
+                var health: num = 100
+                var inventory: list<str> = []
+                var name: str = "Hero"
+
+                fun init(name) {
+                    self.name = name;
+                }
+
+                fun addItem(item) {
+                    inventory = inventory + [item];
+                    return len(inventory);
+                }
+
+                fun takeDamage(amount) {
+                    health = health - amount;
+                    return self.isAlive();
+                }
+
+                fun isAlive() {
+                    return health > 0;
+                }
+
+                fun describe() {
+                    return name + " (" + health + ")";
                 }
             }
 
-            self.compile_expression(expr);
-        } else {
-            // If no initializer, push null as the default value
-            self.emit(IR::PushNull);
+            class Mage extends Hero {
+                var mana = 50;
+
+                fun init(name, mana) {
+                    super.init(name);
+                    self.mana = mana;
+                }
+
+                fun describe() {
+                    return "Mage " + super.describe();
+                }
+            }
+
+            fun hurt(target, amount) {
+                return target.takeDamage(amount);
+            }
+
+            var hero = Hero("Aria");
+            var other = Hero("Bo");
+            hero.addItem("sword");
+            if (hero.addItem("shield") != 2) { throw "method updates field"; }
+            if (len(other.inventory) != 0) { throw "fields shared between objects"; }
+            if (!hurt(hero, 30) || hero.health != 70) { throw "object passed by reference"; }
+            if (hurt(hero, 70)) { throw "method calling method"; }
+            hero.health = 5;
+            hero.health += 1;
+            if (hero.describe() != "Aria (6)") { throw "field assignment"; }
+
+            var mage = Mage("Zed", 80);
+            if (mage.mana != 80 || mage.health != 100) { throw "inherited fields"; }
+            if (mage.describe() != "Mage Zed (100)") { throw "override and super"; }
+            if (mage.takeDamage(10) != true || mage.health != 90) { throw "inherited method"; }
+            if (mage == Mage("Zed", 80) || mage != mage) { throw "object identity"; }
+
+            var counter = fun () { return 1; };
+            map handlers = {"get": counter};
+            if (handlers.get() != 1) { throw "function in a map"; }
+        "#;
+        assert_eq!(run(input), Ok(()));
+        assert_eq!(
+            run("class Point { var x = 0; } var p = Point(); p.move(1);"),
+            Err("1:45: Unhandled exception: Type error: Point object has no method 'move'".to_string())
+        );
+        assert_eq!(
+            run("class Point { var x = 0; } show Point().y;"),
+            Err("1:33: Unhandled exception: Point object has no field 'y'".to_string())
+        );
+    }
+
+    #[test]
+    fn test_final_class_rejects_subclass() {
+        let cases = [
+            ("final class Config { var level = 1; }\nclass Admin extends Config { }", "Class 'Admin' cannot extend final class 'Config'"),
+            ("class Admin extends Config { }", "Class 'Admin' extends unknown class 'Config'"),
+            ("class Base { fun greet() { super.greet(); } }", "Class 'Base' has no parent class for 'super'"),
+            ("class Base { }\nclass Child extends Base { fun greet() { super.greet(); } }", "No parent class of 'Child' has a method 'greet'"),
+        ];
+        for (source, message) in cases {
+            let mut parser = Parser::new(Lexer::new(source.to_string()));
+            let mut compiler = Compiler::new();
+            compiler.set_clean_output(true);
+            compiler.compile_program(parser.parse_program());
+            assert_eq!(compiler.error_count(), 1, "{}", source);
+            let error = &compiler.diagnostics()[0];
+            assert_eq!(error.message, message, "{}", source);
+            assert!(matches!(error.code, diagnostic::INVALID_PARENT_CLASS | diagnostic::INVALID_SUPER));
+            assert!(error.primary.is_some(), "{}", source);
+        }
+    }
+
+    #[test]
+    fn test_unknown_enum_variant() {
+        let input = "enum Color { RED, GREEN }\nwhen Color.RED {\n    Color.PURPLE => { show 1; },\n    _ => { show 2; },\n}";
+        let mut parser = Parser::new(Lexer::new(input.to_string()));
+        let mut compiler = Compiler::new();
+        compiler.set_clean_output(true);
+        compiler.compile_program(parser.parse_program());
+        assert_eq!(compiler.error_count(), 1);
+        assert_eq!(compiler.diagnostics()[0].code, diagnostic::UNKNOWN_VARIANT);
+        assert_eq!(compiler.diagnostics()[0].message, "Enum 'Color' has no variant 'PURPLE'");
+    }
+
+    #[test]
+    fn test_when_statement() {
+        let input = r#"
+            enum Shape { CIRCLE, SQUARE, TRIANGLE }
+            fun classify(x) {
+                str kind = "";
+                when x {
+                    0 => { kind = "zero"; },
+                    "zero" => { kind = "text"; },
+                    -5..0 => { kind = "negative"; },
+                    1..=9 => { kind = "digit"; },
+                    [] => { kind = "empty"; },
+                    [first, 0] => { kind = "pair ending in zero"; },
+                    [[a, b], ..rest] => { kind = "nested"; if (a + b != len(rest)) { kind = "bad nested"; } },
+                    [head, ..] => { kind = "list"; },
+                    n if n == 500 => { kind = "big"; },
+                    _ => { kind = "other"; }
+                }
+                return kind;
+            }
+            fun sides(shape) {
+                when shape {
+                    Shape.CIRCLE => { return 0; },
+                    Shape[SQUARE] => { return 4; },
+                    Shape.TRIANGLE => { return 3; }
+                }
+                return -1;
+            }
+            if (classify(0) != "zero") { throw "literal"; }
+            if (classify("zero") != "text") { throw "string literal"; }
+            if (classify(-3) != "negative") { throw "range"; }
+            if (classify(9) != "digit") { throw "inclusive range"; }
+            if (classify(10) != "other") { throw "range end"; }
+            if (classify(500) != "big") { throw "guard"; }
+            if (classify([]) != "empty") { throw "empty list"; }
+            if (classify([7, 0]) != "pair ending in zero") { throw "list elements"; }
+            if (classify([[1, 2], 5, 6, 7]) != "nested") { throw "nested list and rest"; }
+            if (classify([1, 2, 3]) != "list") { throw "list with rest"; }
+            if (classify("text") != "other") { throw "wildcard"; }
+            if (sides(Shape[SQUARE]) != 4 || sides(Shape[CIRCLE]) != 0) { throw "enum variant"; }
+
+            num matched = 0;
+            when 3 {
+                n if n > 5 => { matched = 1; },
+                n => { matched = n * 2; }
+            }
+            if (matched != 6) { throw "binding at top level"; }
+        "#;
+        assert_eq!(run(input), Ok(()));
+
+        let source = "enum Shape { CIRCLE, SQUARE, TRIANGLE }\nvar s = Shape[SQUARE];\nwhen s {\n    Shape.CIRCLE => { show 0; },\n    Shape.SQUARE if true => { show 4; }\n}";
+        let mut parser = Parser::new(Lexer::new(source.to_string()));
+        let mut compiler = Compiler::new();
+        compiler.compile_program(parser.parse_program());
+        let warnings: Vec<String> = compiler.diagnostics().iter().map(|w| w.to_string()).collect();
+        assert_eq!(warnings, ["warning[W0001]: 'when' over enum 'Shape' is not exhaustive: missing SQUARE, TRIANGLE at line 3, column 1"]);
+
+        let source = source.replace("}\n}", "},\n    _ => { show -1; }\n}");
+        let mut parser = Parser::new(Lexer::new(source));
+        let mut compiler = Compiler::new();
+        compiler.compile_program(parser.parse_program());
+        assert!(compiler.diagnostics().is_empty());
+    }
+
+    #[test]
+    fn test_string_interpolation() {
+        let input = r##"
+            str name = "Ada";
+            num count = 2;
+            var scores = {"ada": [1, 2]};
+            if ("Hello {name}, you have {count + 1} items" != "Hello Ada, you have 3 items") { throw "expressions"; }
+            if ("{count}{count}" != "22") { throw "adjacent numbers"; }
+            if ("{scores["ada"][1]} and {"inner {name}"}" != "2 and inner Ada") { throw "nested strings"; }
+            if ("\{name\}" != r"{name}") { throw "escaped braces"; }
+            if (len("a\tb\n\u{1F600}") != 5) { throw "escapes"; }
+            if (r#"say "{name}""# != "say \"\{name}\"") { throw "raw string"; }
+            str letter = """
+Dear {name},
+bye""";
+            if (letter != "Dear Ada,\nbye") { throw "triple-quoted string"; }
+        "##;
+        assert_eq!(run(input), Ok(()));
+    }
+
+    #[test]
+    fn test_declared_kinds() {
+        let input = r#"
+            fun label(n) { return "item " + n; }
+            fun anything(x) { return x; }
+            num count = 1;
+            count += 2;
+            str name = label(count);
+            list items = [name] + [count];
+            bool empty = len(items) == 0;
+            map seen = {};
+            num missing = null;
+            num copied = anything(count);
+            try { str text = anything(5); } catch (e) { copied = -1; }
+            if (copied != -1) { throw "unchecked store"; }
+        "#;
+        assert_eq!(run(input), Ok(()));
+        assert_eq!(
+            run("fun f(x) { return x; }\nbool done = f(\"yes\");"),
+            Err("2:1: Unhandled exception: Type error: cannot store str in 'done', which is declared bool".to_string())
+        );
+        assert_eq!(
+            run("fun f(x) { return x; }\nfun g() { n = f(\"yes\"); }\nnum n = 0;\ng();"),
+            Err("2:11: Unhandled exception: Type error: cannot store str in 'n', which is declared num".to_string())
+        );
+
+        // Values whose kind is known are rejected before the program runs
+        let cases = [
+            ("num x = \"hello\";", "Type mismatch: cannot store str in 'x', which is declared num"),
+            ("str s = \"a\";\nnum n = s + 1;", "Type mismatch: cannot store str in 'n', which is declared num"),
+            ("fun f() { return [1]; }\nmap m = f();", "Type mismatch: cannot store list in 'm', which is declared map"),
+            ("num n = 0;\nfun g() { n = \"text\"; }", "Type mismatch: cannot store str in 'n', which is declared num"),
+            ("fun g() { n = \"text\"; }\nnum n = 0;\ng();", "Type mismatch: cannot store str in 'n', which is declared num"),
+            ("num total = 1;\ntotal += \" items\";", "Type mismatch: cannot store str in 'total', which is declared num"),
+        ];
+        for (source, message) in cases {
+            let mut parser = Parser::new(Lexer::new(source.to_string()));
+            let mut compiler = Compiler::new();
+            compiler.set_clean_output(true);
+            compiler.compile_program(parser.parse_program());
+            assert_eq!(compiler.error_count(), 1, "{}", source);
+            assert_eq!(compiler.diagnostics()[0].message, message, "{}", source);
         }
+    }
 
-        // Store the value in the variable
-        self.emit(IR::StoreVar(name));
+    #[test]
+    fn test_incorrect_variables_file() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("razen-tests").join("incorrect_variables.rzn");
+        let options = CompileOptions { clean_output: true, ..CompileOptions::default() };
+        assert!(Compiler::from_file(&path, options).is_err());
     }
 }
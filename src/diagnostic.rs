@@ -0,0 +1,405 @@
+// Structured diagnostics for Razen programs: errors and warnings with a stable code, the
+// source they point at, notes and suggested fixes. They render either as annotated source
+// excerpts for people or as one JSON object per line for CI and editor integrations.
+
+use std::fmt;
+
+use serde_json::{json, Value as JsonValue};
+
+use crate::ast::Span;
+
+// Stable diagnostic codes. A published code keeps its meaning, and retired codes are not reused
+pub const UNEXPECTED_TOKEN: &str = "E0001";
+pub const EXPECTED_EXPRESSION: &str = "E0002";
+pub const UNEXPECTED_CHARACTER: &str = "E0003";
+pub const INVALID_STRING: &str = "E0004";
+pub const INVALID_INTERPOLATION: &str = "E0005";
+pub const INVALID_NUMBER: &str = "E0006";
+pub const TYPE_MISMATCH: &str = "E0007";
+pub const ELSE_WITHOUT_IF: &str = "E0008";
+pub const INVALID_LOAD_BODY: &str = "E0009";
+pub const UNDEFINED_VARIABLE: &str = "E0010";
+pub const UNDEFINED_FUNCTION: &str = "E0011";
+pub const WRONG_ARGUMENT_COUNT: &str = "E0012";
+pub const ASSIGN_TO_CONSTANT: &str = "E0013";
+pub const OUTSIDE_LOOP: &str = "E0014";
+pub const RETURN_OUTSIDE_FUNCTION: &str = "E0015";
+pub const INVALID_PARENT_CLASS: &str = "E0016";
+pub const INVALID_CLASS_MEMBER: &str = "E0017";
+pub const INVALID_SUPER: &str = "E0018";
+pub const UNKNOWN_VARIANT: &str = "E0019";
+pub const NON_EXHAUSTIVE_WHEN: &str = "W0001";
+pub const UNREACHABLE_CODE: &str = "W0002";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+// A stretch of source with a message shown under it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Label {
+    pub span: Span,
+    pub message: String,
+}
+
+// A proposed fix: replacing the source at `span` with `replacement`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Suggestion {
+    pub message: String,
+    pub span: Span,
+    pub replacement: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub code: &'static str,
+    pub message: String,
+    pub primary: Option<Label>, // Where the problem is, when it is known
+    pub labels: Vec<Label>,     // Related source, such as the declaration a use conflicts with
+    pub notes: Vec<String>,
+    pub suggestions: Vec<Suggestion>,
+}
+
+impl Diagnostic {
+    pub fn error(code: &'static str, message: impl Into<String>) -> Self {
+        Diagnostic::new(Severity::Error, code, message.into())
+    }
+
+    pub fn warning(code: &'static str, message: impl Into<String>) -> Self {
+        Diagnostic::new(Severity::Warning, code, message.into())
+    }
+
+    fn new(severity: Severity, code: &'static str, message: String) -> Self {
+        Diagnostic {
+            severity,
+            code,
+            message,
+            primary: None,
+            labels: Vec::new(),
+            notes: Vec::new(),
+            suggestions: Vec::new(),
+        }
+    }
+
+    pub fn with_primary(mut self, span: Span, message: impl Into<String>) -> Self {
+        self.primary = Some(Label { span, message: message.into() });
+        self
+    }
+
+    pub fn with_label(mut self, span: Span, message: impl Into<String>) -> Self {
+        self.labels.push(Label { span, message: message.into() });
+        self
+    }
+
+    pub fn with_note(mut self, note: impl Into<String>) -> Self {
+        self.notes.push(note.into());
+        self
+    }
+
+    pub fn with_suggestion(mut self, message: impl Into<String>, span: Span, replacement: impl Into<String>) -> Self {
+        self.suggestions.push(Suggestion { message: message.into(), span, replacement: replacement.into() });
+        self
+    }
+
+    // Render for a terminal: the message, where it is, and the source lines it points at with
+    // the primary span underlined by carets and related spans by dashes
+    pub fn render(&self, files: &SourceFiles) -> String {
+        let mut out = format!("{}[{}]: {}\n", self.severity, self.code, self.message);
+
+        // Source lines to show, each with the labels on it; labels in other files are left out
+        let mut lines: Vec<(SourceLine, Vec<(char, &Label)>)> = Vec::new();
+        if let Some(primary) = &self.primary {
+            let labels = std::iter::once(('^', primary))
+                .chain(self.labels.iter().filter(|l| l.span.file == primary.span.file).map(|l| ('-', l)));
+            for (marker, label) in labels {
+                let Some(line) = files.line_of(label.span) else { continue };
+                match lines.iter_mut().find(|(shown, _)| shown.number == line.number) {
+                    Some((_, on_line)) => on_line.push((marker, label)),
+                    None => lines.push((line, vec![(marker, label)])),
+                }
+            }
+        }
+        lines.sort_by_key(|(line, _)| line.number);
+        let width = lines.last().map_or(1, |(line, _)| line.number.to_string().len());
+        let gutter = " ".repeat(width);
+
+        if let Some(primary) = &self.primary {
+            out.push_str(&format!("{}--> {}\n", gutter, files.location(primary.span)));
+        }
+        if !lines.is_empty() {
+            out.push_str(&format!("{} |\n", gutter));
+            let mut previous = None;
+            for (line, labels) in &lines {
+                if previous.is_some_and(|number| line.number > number + 1) {
+                    out.push_str("...\n");
+                }
+                previous = Some(line.number);
+                out.push_str(&format!("{:>width$} | {}\n", line.number, line.text, width = width));
+                for (marker, label) in labels {
+                    let (indent, length) = line.underline(label.span);
+                    let underline = format!("{}{} {}", indent, marker.to_string().repeat(length), label.message);
+                    out.push_str(&format!("{} | {}\n", gutter, underline.trim_end()));
+                }
+            }
+        }
+
+        if !self.notes.is_empty() || !self.suggestions.is_empty() {
+            if !lines.is_empty() {
+                out.push_str(&format!("{} |\n", gutter));
+            }
+            for note in &self.notes {
+                out.push_str(&format!("{} = note: {}\n", gutter, note));
+            }
+            for suggestion in &self.suggestions {
+                out.push_str(&format!("{} = help: {}\n", gutter, suggestion.message));
+            }
+        }
+        out
+    }
+
+    // Machine-readable form: positions are given both as byte offsets and as 1-based lines and
+    // columns, columns counted in characters with the end column exclusive
+    pub fn to_json(&self, files: &SourceFiles) -> JsonValue {
+        let span_json = |span: Span, label: Option<&str>, is_primary: bool| {
+            let (line_start, column_start) = files.position(span, span.start);
+            let (line_end, column_end) = files.position(span, span.end);
+            json!({
+                "file": files.name(span.file),
+                "byte_start": span.start,
+                "byte_end": span.end,
+                "line_start": line_start,
+                "column_start": column_start,
+                "line_end": line_end,
+                "column_end": column_end,
+                "is_primary": is_primary,
+                "label": label,
+            })
+        };
+
+        let spans: Vec<JsonValue> = self.primary.iter().map(|l| span_json(l.span, Some(&l.message), true))
+            .chain(self.labels.iter().map(|l| span_json(l.span, Some(&l.message), false)))
+            .collect();
+        let suggestions: Vec<JsonValue> = self.suggestions.iter()
+            .map(|s| json!({
+                "message": s.message,
+                "replacement": s.replacement,
+                "span": span_json(s.span, None, false),
+            }))
+            .collect();
+        json!({
+            "severity": self.severity.to_string(),
+            "code": self.code,
+            "message": self.message,
+            "spans": spans,
+            "notes": self.notes,
+            "suggestions": suggestions,
+            "rendered": self.render(files),
+        })
+    }
+}
+
+// One-line form, for contexts without the source at hand
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}[{}]: {}", self.severity, self.code, self.message)?;
+        if let Some(primary) = &self.primary {
+            write!(f, " at line {}, column {}", primary.span.line, primary.span.column)?;
+        }
+        Ok(())
+    }
+}
+
+// A line of source shown in a rendered diagnostic
+struct SourceLine {
+    number: usize,
+    start: usize, // Byte offset of the line in its file
+    text: String,
+}
+
+impl SourceLine {
+    // Text to put before the underline of `span`, and the underline's length. Tabs are kept so
+    // the underline lines up with the source however the terminal shows them
+    fn underline(&self, span: Span) -> (String, usize) {
+        let from = span.start.saturating_sub(self.start).min(self.text.len());
+        let to = span.end.saturating_sub(self.start).clamp(from, self.text.len());
+        let indent = self.text[..from].chars().map(|c| if c == '\t' { '\t' } else { ' ' }).collect();
+        (indent, self.text[from..to].chars().count().max(1))
+    }
+}
+
+// Names and contents of the files that spans point into, indexed by file id
+#[derive(Debug, Default)]
+pub struct SourceFiles {
+    files: Vec<(String, String)>,
+}
+
+impl SourceFiles {
+    pub fn new() -> Self {
+        SourceFiles::default()
+    }
+
+    // Add a file and return its id
+    pub fn add(&mut self, name: impl Into<String>, source: impl Into<String>) -> usize {
+        self.files.push((name.into(), source.into()));
+        self.files.len() - 1
+    }
+
+    fn name(&self, file: usize) -> Option<&str> {
+        self.files.get(file).map(|(name, _)| name.as_str())
+    }
+
+    fn source(&self, file: usize) -> Option<&str> {
+        self.files.get(file).map(|(_, source)| source.as_str())
+    }
+
+    // Where a span starts, as "file:line:col", or "line:col" for an unknown file
+    fn location(&self, span: Span) -> String {
+        let (line, column) = self.position(span, span.start);
+        match self.name(span.file) {
+            Some(name) => format!("{}:{}:{}", name, line, column),
+            None => format!("{}:{}", line, column),
+        }
+    }
+
+    // Line and column of a byte offset in the span's file, falling back to where the span
+    // says it starts when the source is not available
+    fn position(&self, span: Span, offset: usize) -> (usize, usize) {
+        let Some(source) = self.source(span.file).filter(|source| offset <= source.len() && source.is_char_boundary(offset)) else {
+            return (span.line, span.column);
+        };
+        let before = &source[..offset];
+        let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+        (before.matches('\n').count() + 1, before[line_start..].chars().count() + 1)
+    }
+
+    fn line_of(&self, span: Span) -> Option<SourceLine> {
+        let source = self.source(span.file)?;
+        if span.start > source.len() || !source.is_char_boundary(span.start) {
+            return None;
+        }
+        let start = source[..span.start].rfind('\n').map_or(0, |i| i + 1);
+        let end = source[start..].find('\n').map_or(source.len(), |i| start + i);
+        Some(SourceLine {
+            number: source[..start].matches('\n').count() + 1,
+            start,
+            text: source[start..end].trim_end_matches('\r').to_string(),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorFormat {
+    Human,
+    Json,
+}
+
+impl ErrorFormat {
+    pub fn parse(format: &str) -> Result<Self, String> {
+        match format {
+            "human" => Ok(ErrorFormat::Human),
+            "json" => Ok(ErrorFormat::Json),
+            _ => Err(format!("Invalid error format '{}', expected human or json", format)),
+        }
+    }
+}
+
+// Print diagnostics to stderr, rendered or as one JSON object per line
+pub fn emit(diagnostics: &[Diagnostic], files: &SourceFiles, format: ErrorFormat) {
+    for diagnostic in diagnostics {
+        match format {
+            ErrorFormat::Human => eprintln!("{}", diagnostic.render(files)),
+            ErrorFormat::Json => eprintln!("{}", diagnostic.to_json(files)),
+        }
+    }
+}
+
+// Why a build stopped, after its errors were emitted
+pub fn aborting(error_count: usize) -> String {
+    format!("aborting due to {} previous error{}", error_count, if error_count == 1 { "" } else { "s" })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn span(source: &str, text: &str) -> Span {
+        let start = source.find(text).unwrap();
+        let line = source[..start].matches('\n').count() + 1;
+        let column = start - source[..start].rfind('\n').map_or(0, |i| i + 1) + 1;
+        Span { file: 0, start, end: start + text.len(), line, column }
+    }
+
+    #[test]
+    fn test_render() {
+        let source = "num total = 1;\n\tnum count = \"many\";\nshow count;";
+        let mut files = SourceFiles::new();
+        files.add("main.rzn", source);
+
+        let diagnostic = Diagnostic::error(TYPE_MISMATCH, "Type mismatch: 'num' should be used for numeric values")
+            .with_primary(span(source, "\"many\""), "expected a number, found a string")
+            .with_label(span(source, "num count"), "declared as 'num' here")
+            .with_note("'var' accepts a value of any type")
+            .with_suggestion("declare it with 'var'", span(source, "num count"), "var");
+
+        assert_eq!(diagnostic.render(&files), [
+            "error[E0007]: Type mismatch: 'num' should be used for numeric values",
+            " --> main.rzn:2:14",
+            "  |",
+            "2 | \tnum count = \"many\";",
+            "  | \t            ^^^^^^ expected a number, found a string",
+            "  | \t--------- declared as 'num' here",
+            "  |",
+            "  = note: 'var' accepts a value of any type",
+            "  = help: declare it with 'var'",
+            "",
+        ].join("\n"));
+
+        // Without the source only the location is known
+        let unknown = Diagnostic::warning(NON_EXHAUSTIVE_WHEN, "not exhaustive")
+            .with_primary(Span { file: 4, line: 7, column: 2, ..Span::default() }, "");
+        assert_eq!(unknown.render(&files), "warning[W0001]: not exhaustive\n --> 7:2\n");
+        assert_eq!(unknown.to_string(), "warning[W0001]: not exhaustive at line 7, column 2");
+    }
+
+    #[test]
+    fn test_json() {
+        let source = "show \"é\" & x;";
+        let mut files = SourceFiles::new();
+        files.add("main.rzn", source);
+
+        let diagnostic = Diagnostic::error(UNEXPECTED_CHARACTER, "Unexpected character '&'")
+            .with_primary(span(source, "&"), "")
+            .with_suggestion("use '&&' for a logical and", span(source, "&"), "&&");
+        let json = diagnostic.to_json(&files);
+
+        assert_eq!(json["severity"], "error");
+        assert_eq!(json["code"], "E0003");
+        assert_eq!(json["spans"][0], json!({
+            "file": "main.rzn",
+            "byte_start": 10,
+            "byte_end": 11,
+            "line_start": 1,
+            "column_start": 10,
+            "line_end": 1,
+            "column_end": 11,
+            "is_primary": true,
+            "label": "",
+        }));
+        assert_eq!(json["suggestions"][0]["replacement"], "&&");
+        assert_eq!(json["rendered"], diagnostic.render(&files));
+        // One object per line
+        assert!(!json.to_string().contains('\n'));
+    }
+}
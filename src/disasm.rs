@@ -0,0 +1,265 @@
+// Readable dumps of compiled IR: an annotated listing and a control-flow graph in DOT format
+
+use std::collections::{BTreeSet, HashMap};
+use std::fmt::Write;
+
+use crate::compiler::{Capture, Compiler, IR, TOP_LEVEL_FRAME};
+
+// A user function's extent in the IR, from the jump over its body to its end label
+struct FunctionRegion {
+    name: String,
+    start: usize,
+    end: usize,
+}
+
+fn function_regions(ir: &[IR]) -> Vec<FunctionRegion> {
+    ir.iter()
+        .filter_map(|instruction| match instruction {
+            IR::DefineFunction(name, start) => match start.checked_sub(1).and_then(|jump| ir.get(jump)) {
+                Some(IR::Jump(end)) => Some(FunctionRegion { name: name.clone(), start: start - 1, end: *end }),
+                _ => None,
+            },
+            _ => None,
+        })
+        .collect()
+}
+
+// Label names by the position they mark
+fn label_positions(ir: &[IR]) -> HashMap<usize, &str> {
+    ir.iter()
+        .enumerate()
+        .filter_map(|(pos, instruction)| match instruction {
+            IR::Label(name) => Some((pos, name.as_str())),
+            _ => None,
+        })
+        .collect()
+}
+
+// Index of the innermost function whose body contains `pos`
+fn enclosing_region(regions: &[FunctionRegion], pos: usize) -> Option<usize> {
+    regions.iter().enumerate()
+        .filter(|(_, region)| region.start < pos && pos < region.end)
+        .min_by_key(|(_, region)| region.end - region.start)
+        .map(|(index, _)| index)
+}
+
+// Variable names of the frame slots used at `pos`
+fn frame_slots<'a>(compiler: &'a Compiler, regions: &[FunctionRegion], pos: usize) -> &'a [String] {
+    let frame = enclosing_region(regions, pos).map_or(TOP_LEVEL_FRAME, |index| regions[index].name.as_str());
+    compiler.function_locals().get(frame).map_or(&[], Vec::as_slice)
+}
+
+fn signature(compiler: &Compiler, name: &str) -> String {
+    let params = compiler.function_param_names().get(name).cloned().unwrap_or_default();
+    format!("{}({})", name, params.join(", "))
+}
+
+// One instruction, with jump targets shown by the label they land on and frame slots by
+// their variable name
+fn format_instruction(instruction: &IR, labels: &HashMap<usize, &str>, slots: &[String]) -> String {
+    let target = |pos: &usize| match labels.get(pos) {
+        Some(label) => format!("{} ({})", pos, label),
+        None => pos.to_string(),
+    };
+    let slot = |slot: &usize| match slots.get(*slot) {
+        Some(name) => format!("{} ({})", slot, name),
+        None => slot.to_string(),
+    };
+    match instruction {
+        IR::PushNumber(n) => format!("PushNumber {}", n),
+        IR::PushString(s) => format!("PushString {:?}", s),
+        IR::PushBoolean(b) => format!("PushBoolean {}", b),
+        IR::StoreVar(name) => format!("StoreVar {}", name),
+        IR::LoadVar(name) => format!("LoadVar {}", name),
+        IR::SetGlobal(name) => format!("SetGlobal {}", name),
+        IR::StoreLocal(index) => format!("StoreLocal {}", slot(index)),
+        IR::LoadLocal(index) => format!("LoadLocal {}", slot(index)),
+        IR::Jump(pos) => format!("Jump {}", target(pos)),
+        IR::JumpIfFalse(pos) => format!("JumpIfFalse {}", target(pos)),
+        IR::JumpIfTrue(pos) => format!("JumpIfTrue {}", target(pos)),
+        IR::SetupTryCatch(pos) => format!("SetupTryCatch {}", target(pos)),
+        IR::Call(name, arg_count) => format!("Call {}, {}", name, arg_count),
+        IR::CallValue(arg_count) => format!("CallValue {}", arg_count),
+        IR::CallMethod(method, arg_count) => format!("CallMethod {}, {}", method, arg_count),
+        IR::DefineClass(name, Some(parent)) => format!("DefineClass {} extends {}", name, parent),
+        IR::DefineClass(name, None) => format!("DefineClass {}", name),
+        IR::StoreCapture(index) => format!("StoreCapture {}", index),
+        IR::LoadCapture(index) => format!("LoadCapture {}", index),
+        IR::MakeClosure(name, captures) => {
+            let captures: Vec<String> = captures.iter()
+                .map(|capture| match capture {
+                    Capture::Local(index) => format!("slot {}", slot(index)),
+                    Capture::Captured(index) => format!("capture {}", index),
+                })
+                .collect();
+            format!("MakeClosure {} [{}]", name, captures.join(", "))
+        },
+        IR::CreateArray(count) => format!("CreateArray {}", count),
+        IR::CreateMap(count) => format!("CreateMap {}", count),
+        IR::DefineFunction(name, pos) => format!("DefineFunction {} -> {}", name, target(pos)),
+        IR::Label(name) => format!("{}:", name),
+        IR::LibraryCall(library, function, arg_count) => format!("LibraryCall {} {}, {}", library, function, arg_count),
+        other => format!("{:?}", other),
+    }
+}
+
+// Listing of every instruction with its position, source line and column and the enclosing
+// functions. With the program's source, each new source line is quoted above its code
+pub fn listing(compiler: &Compiler, source: Option<&str>) -> String {
+    let ir = &compiler.ir;
+    let labels = label_positions(ir);
+    let regions = function_regions(ir);
+    let source_lines: Vec<&str> = source.map(|source| source.lines().collect()).unwrap_or_default();
+
+    let mut out = String::new();
+    let mut last_line = None;
+    for (pos, instruction) in ir.iter().enumerate() {
+        for region in regions.iter().filter(|region| region.start == pos) {
+            let _ = writeln!(out, "; function {}", signature(compiler, &region.name));
+        }
+        let depth = regions.iter().filter(|region| region.start < pos && pos < region.end).count();
+
+        let position = compiler.source_map().get(pos).copied().flatten();
+        if let Some(position) = position {
+            if last_line != Some(position.line) {
+                if let Some(text) = source_lines.get(position.line.wrapping_sub(1)) {
+                    let _ = writeln!(out, "{:>5}  ; {} | {}", "", position.line, text.trim());
+                }
+                last_line = Some(position.line);
+            }
+        }
+        let location = position.map(|p| format!("{}:{}", p.line, p.column)).unwrap_or_default();
+        let indent = if matches!(instruction, IR::Label(_)) { depth * 2 } else { depth * 2 + 4 };
+        let text = format_instruction(instruction, &labels, frame_slots(compiler, &regions, pos));
+        let _ = writeln!(out, "{:>5}  {:<8}{:indent$}{}", pos, location, "", text, indent = indent);
+
+        let last = pos + 1 == ir.len();
+        for region in regions.iter().filter(|region| region.end == pos || (last && region.end > pos)) {
+            let _ = writeln!(out, "; end function {}", region.name);
+        }
+    }
+    out
+}
+
+// Escape text for a double-quoted DOT string
+fn escape_dot(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+// Control-flow graph of the program as a DOT digraph. Nodes are basic blocks, user functions
+// are drawn as clusters, and dashed edges lead from a try block to its catch handler
+pub fn control_flow_graph(compiler: &Compiler) -> String {
+    let ir = &compiler.ir;
+    let labels = label_positions(ir);
+    let regions = function_regions(ir);
+
+    // A block starts at the program start, at every jump target and after every transfer
+    let mut leaders = BTreeSet::new();
+    leaders.insert(0);
+    for (pos, instruction) in ir.iter().enumerate() {
+        match instruction {
+            IR::Jump(target) | IR::JumpIfFalse(target) | IR::JumpIfTrue(target) => {
+                leaders.insert(*target);
+                leaders.insert(pos + 1);
+            },
+            IR::Return | IR::Exit | IR::ThrowException => { leaders.insert(pos + 1); },
+            IR::SetupTryCatch(handler) => { leaders.insert(*handler); },
+            _ => {},
+        }
+    }
+    let leaders: Vec<usize> = leaders.into_iter().filter(|&pos| pos < ir.len()).collect();
+    let block_name = |pos: usize| {
+        if pos >= ir.len() {
+            return "exit".to_string();
+        }
+        let leader = leaders[leaders.partition_point(|&leader| leader <= pos) - 1];
+        format!("b{}", leader)
+    };
+
+    let mut out = String::from("digraph cfg {\n    node [shape=box, fontname=\"monospace\"];\n");
+    let mut clusters: Vec<Vec<String>> = vec![Vec::new(); regions.len()];
+    let mut edges = Vec::new();
+    for (i, &start) in leaders.iter().enumerate() {
+        let end = leaders.get(i + 1).copied().unwrap_or(ir.len());
+        let slots = frame_slots(compiler, &regions, start);
+        let mut text = String::new();
+        for (pos, instruction) in ir.iter().enumerate().take(end).skip(start) {
+            text.push_str(&escape_dot(&format!("{}: {}", pos, format_instruction(instruction, &labels, slots))));
+            text.push_str("\\l");
+        }
+        let node = format!("    b{} [label=\"{}\"];\n", start, text);
+        // Blocks inside a function body go in the innermost function's cluster
+        match enclosing_region(&regions, start) {
+            Some(index) => clusters[index].push(node),
+            None => out.push_str(&node),
+        }
+
+        let last = end - 1;
+        let from = format!("b{}", start);
+        match &ir[last] {
+            IR::Jump(target) => edges.push(format!("    {} -> {};\n", from, block_name(*target))),
+            IR::JumpIfFalse(target) | IR::JumpIfTrue(target) => {
+                let taken = matches!(ir[last], IR::JumpIfTrue(_));
+                edges.push(format!("    {} -> {} [label=\"{}\"];\n", from, block_name(*target), taken));
+                edges.push(format!("    {} -> {} [label=\"{}\"];\n", from, block_name(end), !taken));
+            },
+            IR::Return | IR::Exit | IR::ThrowException => {},
+            _ => edges.push(format!("    {} -> {};\n", from, block_name(end))),
+        }
+        for instruction in &ir[start..end] {
+            if let IR::SetupTryCatch(handler) = instruction {
+                edges.push(format!("    {} -> {} [style=dashed, label=\"catch\"];\n", from, block_name(*handler)));
+            }
+        }
+    }
+
+    for (region, nodes) in regions.iter().zip(&clusters) {
+        let _ = writeln!(out, "    subgraph cluster_{} {{", region.start);
+        let _ = writeln!(out, "        label=\"{}\";", escape_dot(&signature(compiler, &region.name)));
+        for node in nodes {
+            out.push_str("    ");
+            out.push_str(node);
+        }
+        out.push_str("    }\n");
+    }
+    if edges.iter().any(|edge| edge.contains("-> exit")) {
+        out.push_str("    exit [shape=doublecircle];\n");
+    }
+    for edge in edges {
+        out.push_str(&edge);
+    }
+    out.push_str("}\n");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+
+    fn compile(input: &str) -> Compiler {
+        let mut parser = Parser::new(Lexer::new(input.to_string()));
+        let mut compiler = Compiler::new();
+        compiler.set_clean_output(true);
+        compiler.compile_program(parser.parse_program());
+        compiler
+    }
+
+    #[test]
+    fn test_listing_and_cfg() {
+        let input = "fun twice(n) {\n    return n * 2;\n}\nif (twice(2) > 3) { show \"big\"; }\n";
+        let compiler = compile(input);
+
+        let listing = listing(&compiler, Some(input));
+        assert!(listing.contains("; function twice(n)"));
+        assert!(listing.contains("; end function twice"));
+        assert!(listing.contains("; 2 | return n * 2;"));
+        assert!(listing.contains("DefineFunction twice -> 1 (function_0)"));
+
+        let cfg = control_flow_graph(&compiler);
+        assert!(cfg.starts_with("digraph cfg {"));
+        assert!(cfg.contains("label=\"twice(n)\";"));
+        assert!(cfg.contains("[label=\"false\"]"));
+    }
+}
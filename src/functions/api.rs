@@ -555,6 +555,8 @@ fn value_to_json(value: Value) -> Result<JsonValue, String> {
             }
             Ok(JsonValue::Object(json_obj))
         },
+        Value::Function(_) => Err("Cannot convert a function to JSON".to_string()),
+        Value::Instance(_) => Err("Cannot convert an object to JSON".to_string()),
     }
 }
 
@@ -91,5 +91,7 @@ pub fn razen_value_to_json(value: &Value) -> Result<JsonValue, String> {
             }
             Ok(JsonValue::Object(json_object))
         },
+        Value::Function(_) => Err("Cannot convert a function to JSON".to_string()),
+        Value::Instance(_) => Err("Cannot convert an object to JSON".to_string()),
     }
 }
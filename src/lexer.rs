@@ -4,11 +4,19 @@ use crate::token::{Token, TokenType, lookup_identifier};
 
 pub struct Lexer {
     input: String,
-    position: usize,      // current position in input (points to current char)
-    read_position: usize, // current reading position in input (after current char)
+    position: usize,      // byte offset of the current char in input
+    read_position: usize, // byte offset of the char after the current one
     ch: char,             // current char under examination
     line: usize,          // current line number
     column: usize,        // current column number
+    interpolations: Vec<Interpolation>, // `{...}` string interpolations currently open
+}
+
+// An open `{...}` inside a string literal: how many plain braces are nested within it and
+// which kind of string to resume once its closing brace is reached
+struct Interpolation {
+    depth: usize,
+    triple: bool,
 }
 
 impl Lexer {
@@ -20,6 +28,7 @@ impl Lexer {
             ch: '\0',
             line: 1,
             column: 0,
+            interpolations: Vec::new(),
         };
         lexer.read_char();
         lexer
@@ -32,28 +41,49 @@ impl Lexer {
         }
     }
     
+    // The text being lexed, for showing source excerpts
+    pub fn input(&self) -> &str {
+        &self.input
+    }
+    
+    // The cursor moves by byte offsets, so every step is O(1) and slices of the input always
+    // fall on char boundaries. Past the end both offsets stay at input.len() and ch is '\0'
     fn read_char(&mut self) {
-        if self.read_position >= self.input.len() {
-            self.ch = '\0';
-        } else {
-            self.ch = self.input.chars().nth(self.read_position).unwrap();
-        }
         self.position = self.read_position;
-        self.read_position += 1;
+        match self.input[self.position..].chars().next() {
+            Some(ch) => {
+                self.ch = ch;
+                self.read_position = self.position + ch.len_utf8();
+            },
+            None => self.ch = '\0',
+        }
         self.column += 1;
     }
     
     fn peek_char(&self) -> char {
-        if self.read_position >= self.input.len() {
-            '\0'
-        } else {
-            self.input.chars().nth(self.read_position).unwrap()
-        }
+        self.peek_char_at(0)
+    }
+    
+    // Look `offset` chars past the next one without consuming anything
+    fn peek_char_at(&self, offset: usize) -> char {
+        self.input[self.read_position..].chars().nth(offset).unwrap_or('\0')
     }
     
     pub fn next_token(&mut self) -> Token {
         self.skip_whitespace();
         
+        let (start, line, column) = (self.position, self.line, self.column);
+        let mut token = self.read_token();
+        token.start = start;
+        token.end = self.position;
+        // Tokens always report where they begin, even ones spanning lines such as multi-line strings
+        token.line = line;
+        token.column = column;
+        token
+    }
+    
+    // Scan the token starting at the current char and leave the cursor just past it
+    fn read_token(&mut self) -> Token {
         let token = match self.ch {
             '=' => {
                 if self.peek_char() == '=' {
@@ -61,6 +91,11 @@ impl Lexer {
                     self.read_char();
                     let literal = format!("{}{}", ch, self.ch);
                     Token::new(TokenType::Equal, literal, self.line, self.column - 1)
+                } else if self.peek_char() == '>' {
+                    let ch = self.ch;
+                    self.read_char();
+                    let literal = format!("{}{}", ch, self.ch);
+                    Token::new(TokenType::FatArrow, literal, self.line, self.column - 1)
                 } else {
                     Token::new(TokenType::Assign, self.ch.to_string(), self.line, self.column)
                 }
@@ -191,30 +226,75 @@ impl Lexer {
             ',' => Token::new(TokenType::Comma, self.ch.to_string(), self.line, self.column),
             // This case is already handled above
             // '+' => Token::new(TokenType::Plus, self.ch.to_string(), self.line, self.column),
-            '{' => Token::new(TokenType::LeftBrace, self.ch.to_string(), self.line, self.column),
-            '}' => Token::new(TokenType::RightBrace, self.ch.to_string(), self.line, self.column),
+            '{' => {
+                if let Some(interpolation) = self.interpolations.last_mut() {
+                    interpolation.depth += 1;
+                }
+                Token::new(TokenType::LeftBrace, self.ch.to_string(), self.line, self.column)
+            },
+            '}' => match self.interpolations.last_mut() {
+                Some(interpolation) if interpolation.depth == 0 => {
+                    // This brace closes an interpolation, so the string it was part of carries on
+                    let triple = interpolation.triple;
+                    self.interpolations.pop();
+                    let (line, column) = (self.line, self.column);
+                    self.read_char();
+                    self.read_string(line, column, triple, true)
+                },
+                interpolation => {
+                    if let Some(interpolation) = interpolation {
+                        interpolation.depth -= 1;
+                    }
+                    Token::new(TokenType::RightBrace, self.ch.to_string(), self.line, self.column)
+                },
+            },
             '[' => Token::new(TokenType::LeftBracket, self.ch.to_string(), self.line, self.column),
             ']' => Token::new(TokenType::RightBracket, self.ch.to_string(), self.line, self.column),
-            '.' => Token::new(TokenType::Dot, self.ch.to_string(), self.line, self.column),
+            '.' => {
+                if self.peek_char() == '.' {
+                    self.read_char();
+                    if self.peek_char() == '=' {
+                        self.read_char();
+                        Token::new(TokenType::DotDotEqual, "..=".to_string(), self.line, self.column - 2)
+                    } else {
+                        Token::new(TokenType::DotDot, "..".to_string(), self.line, self.column - 1)
+                    }
+                } else {
+                    Token::new(TokenType::Dot, self.ch.to_string(), self.line, self.column)
+                }
+            },
             '#' => {
-                let comment = self.read_comment();
-                let len = comment.len();
-                Token::new(TokenType::Comment, comment, self.line, self.column - len)
+                // read_comment stops on the line break, which skip_whitespace then has to count
+                let (line, column) = (self.line, self.column);
+                return Token::new(TokenType::Comment, self.read_comment(), line, column);
             },
             '"' => {
-                let string = self.read_string();
-                let len = string.len();
-                Token::new(TokenType::StringLiteral, string, self.line, self.column - len)
+                let (line, column) = (self.line, self.column);
+                let triple = self.peek_char() == '"' && self.peek_char_at(1) == '"';
+                if triple {
+                    self.read_char();
+                    self.read_char();
+                }
+                self.read_char();
+                // A line break right after the opening """ is not part of the string
+                if triple && self.ch == '\n' {
+                    self.line += 1;
+                    self.column = 0;
+                    self.read_char();
+                }
+                self.read_string(line, column, triple, false)
             },
+            'r' if self.raw_string_follows() => self.read_raw_string(),
             '\0' => Token::new(TokenType::EOF, "".to_string(), self.line, self.column),
             _ => {
+                let (line, column) = (self.line, self.column);
                 if is_letter(self.ch) {
                     let literal = self.read_identifier();
                     let token_type = lookup_identifier(&literal);
-                    return Token::new(token_type, literal.clone(), self.line, self.column - literal.len());
+                    return Token::new(token_type, literal, line, column);
                 } else if is_digit(self.ch) {
                     let literal = self.read_number();
-                    return Token::new(TokenType::NumberLiteral, literal.clone(), self.line, self.column - literal.len());
+                    return Token::new(TokenType::NumberLiteral, literal, line, column);
                 } else {
                     Token::new(TokenType::Illegal, self.ch.to_string(), self.line, self.column)
                 }
@@ -237,7 +317,7 @@ impl Lexer {
     
     fn read_identifier(&mut self) -> String {
         let position = self.position;
-        while is_letter(self.ch) || is_digit(self.ch) || self.ch == '_' {
+        while is_identifier_char(self.ch) {
             self.read_char();
         }
         self.input[position..self.position].to_string()
@@ -247,7 +327,8 @@ impl Lexer {
         let position = self.position;
         let mut has_dot = false;
         
-        while is_digit(self.ch) || (self.ch == '.' && !has_dot) {
+        // A '.' only belongs to the number when a digit follows, so `1..5` lexes as a range
+        while is_digit(self.ch) || (self.ch == '.' && !has_dot && is_digit(self.peek_char())) {
             if self.ch == '.' {
                 has_dot = true;
             }
@@ -257,21 +338,147 @@ impl Lexer {
         self.input[position..self.position].to_string()
     }
     
-    fn read_string(&mut self) -> String {
-        // Skip the opening quote
+    // Read string text from the current char up to the closing quote or the `{` of an
+    // interpolation, leaving the cursor on that final char. Plain strings become StringLiteral,
+    // strings with interpolations are split into StringStart/StringMiddle/StringEnd segments
+    // with the tokens of each embedded expression between them
+    fn read_string(&mut self, line: usize, column: usize, triple: bool, continued: bool) -> Token {
+        let mut string = String::new();
+        let mut error = None;
+        
+        let interpolates = loop {
+            match self.ch {
+                '\0' => {
+                    return Token::new(TokenType::Illegal, "Unterminated string".to_string(), line, column);
+                },
+                '"' if !triple => break false,
+                '"' if self.peek_char() == '"' && self.peek_char_at(1) == '"' => {
+                    self.read_char();
+                    self.read_char();
+                    break false;
+                },
+                '{' => break true,
+                '\\' => {
+                    self.read_char();
+                    match self.read_escape() {
+                        Ok(ch) => string.push(ch),
+                        Err(message) => {
+                            error.get_or_insert(message);
+                        }
+                    }
+                },
+                '\n' => {
+                    string.push('\n');
+                    self.line += 1;
+                    self.column = 0;
+                },
+                ch => string.push(ch),
+            }
+            self.read_char();
+        };
+        
+        if let Some(message) = error {
+            return Token::new(TokenType::Illegal, message, line, column);
+        }
+        
+        if interpolates {
+            self.interpolations.push(Interpolation { depth: 0, triple });
+        }
+        
+        let token_type = match (continued, interpolates) {
+            (false, false) => TokenType::StringLiteral,
+            (false, true) => TokenType::StringStart,
+            (true, true) => TokenType::StringMiddle,
+            (true, false) => TokenType::StringEnd,
+        };
+        Token::new(token_type, string, line, column)
+    }
+    
+    // Decode the escape sequence whose first char (after the backslash) is under the cursor,
+    // leaving the cursor on its last char
+    fn read_escape(&mut self) -> Result<char, String> {
+        match self.ch {
+            'n' => Ok('\n'),
+            't' => Ok('\t'),
+            'r' => Ok('\r'),
+            '0' => Ok('\0'),
+            '\\' => Ok('\\'),
+            '"' => Ok('"'),
+            '\'' => Ok('\''),
+            '{' => Ok('{'),
+            '}' => Ok('}'),
+            'u' => {
+                if self.peek_char() != '{' {
+                    return Err("Expected '{' after \\u in escape sequence".to_string());
+                }
+                self.read_char();
+                
+                let mut digits = String::new();
+                while self.peek_char().is_ascii_hexdigit() {
+                    self.read_char();
+                    digits.push(self.ch);
+                }
+                if self.peek_char() != '}' {
+                    return Err("Unterminated unicode escape, expected '}'".to_string());
+                }
+                self.read_char();
+                
+                u32::from_str_radix(&digits, 16)
+                    .ok()
+                    .filter(|_| digits.len() <= 6)
+                    .and_then(char::from_u32)
+                    .ok_or_else(|| format!("Invalid unicode escape '\\u{{{}}}'", digits))
+            },
+            '\0' => Err("Unterminated escape sequence".to_string()),
+            ch => Err(format!("Unknown escape sequence '\\{}'", ch)),
+        }
+    }
+    
+    // r"..." and r#"..."# (any number of #s) start a raw string
+    fn raw_string_follows(&self) -> bool {
+        let mut offset = 0;
+        while self.peek_char_at(offset) == '#' {
+            offset += 1;
+        }
+        self.peek_char_at(offset) == '"'
+    }
+    
+    // Raw strings take their text exactly as written: no escapes and no interpolation. The
+    // closing quote must be followed by as many #s as the opening one was preceded by
+    fn read_raw_string(&mut self) -> Token {
+        let (line, column) = (self.line, self.column);
+        
+        let mut hashes = 0;
+        self.read_char();
+        while self.ch == '#' {
+            hashes += 1;
+            self.read_char();
+        }
         self.read_char();
         
-        let position = self.position;
-        while self.ch != '"' && self.ch != '\0' {
-            if self.ch == '\n' {
-                self.line += 1;
-                self.column = 0;
+        let mut string = String::new();
+        loop {
+            match self.ch {
+                '\0' => {
+                    return Token::new(TokenType::Illegal, "Unterminated raw string".to_string(), line, column);
+                },
+                '"' if (0..hashes).all(|offset| self.peek_char_at(offset) == '#') => {
+                    for _ in 0..hashes {
+                        self.read_char();
+                    }
+                    break;
+                },
+                '\n' => {
+                    string.push('\n');
+                    self.line += 1;
+                    self.column = 0;
+                },
+                ch => string.push(ch),
             }
             self.read_char();
         }
         
-        let string = self.input[position..self.position].to_string();
-        string
+        Token::new(TokenType::StringLiteral, string, line, column)
     }
     
     fn read_comment(&mut self) -> String {
@@ -304,12 +511,19 @@ impl Lexer {
     }
 }
 
+// Identifiers start with any Unicode letter or `_` and may continue with Unicode letters and
+// digits, so `naïve`, `größe` and `变量1` are all valid names
 fn is_letter(ch: char) -> bool {
     ch.is_alphabetic() || ch == '_'
 }
 
+fn is_identifier_char(ch: char) -> bool {
+    ch.is_alphanumeric() || ch == '_'
+}
+
+// Number literals stay ASCII-only
 fn is_digit(ch: char) -> bool {
-    ch.is_digit(10)
+    ch.is_ascii_digit()
 }
 
 #[cfg(test)]
@@ -368,4 +582,97 @@ mod tests {
             assert_eq!(token.literal, expected_literal, "Expected token literal '{}', got '{}'", expected_literal, token.literal);
         }
     }
+    
+    #[test]
+    fn test_string_literals() {
+        let input = r#####""a\tb \"q\" \u{e9}\{" r"c:\{x}" r#"say "hi""# """
+two
+lines""" "x{n + 1}y{ {"k": "{v}"} }z""#####;
+        
+        let mut lexer = Lexer::new(input.to_string());
+        
+        let expected_tokens = vec![
+            (TokenType::StringLiteral, "a\tb \"q\" \u{e9}{"),
+            (TokenType::StringLiteral, "c:\\{x}"),
+            (TokenType::StringLiteral, "say \"hi\""),
+            (TokenType::StringLiteral, "two\nlines"),
+            (TokenType::StringStart, "x"),
+            (TokenType::Identifier, "n"),
+            (TokenType::Plus, "+"),
+            (TokenType::NumberLiteral, "1"),
+            (TokenType::StringMiddle, "y"),
+            (TokenType::LeftBrace, "{"),
+            (TokenType::StringLiteral, "k"),
+            (TokenType::Colon, ":"),
+            (TokenType::StringStart, ""),
+            (TokenType::Identifier, "v"),
+            (TokenType::StringEnd, ""),
+            (TokenType::RightBrace, "}"),
+            (TokenType::StringEnd, "z"),
+            (TokenType::EOF, ""),
+        ];
+        
+        for (expected_type, expected_literal) in expected_tokens {
+            let token = lexer.next_token();
+            assert_eq!(token.token_type, expected_type, "Expected token type {:?}, got {:?}", expected_type, token.token_type);
+            assert_eq!(token.literal, expected_literal, "Expected token literal '{}', got '{}'", expected_literal, token.literal);
+        }
+        
+        for input in [r#""\q""#, r#""\u{110000}""#, r#""open"#, r#"r"open"#] {
+            let token = Lexer::new(input.to_string()).next_token();
+            assert_eq!(token.token_type, TokenType::Illegal, "Expected {} to be rejected", input);
+        }
+    }
+    
+    #[test]
+    fn test_unicode_source() {
+        let input = "# naïve 🌿 comment\nvar größe = \"日本 {größe}\"; 变量1 + π";
+        
+        let tokens = Lexer::new(input.to_string()).tokenize_all();
+        let summary: Vec<(TokenType, &str, usize, usize)> = tokens.iter()
+            .map(|token| (token.token_type.clone(), &input[token.start..token.end], token.line, token.column))
+            .collect();
+        
+        assert_eq!(summary, vec![
+            (TokenType::Comment, "# naïve 🌿 comment", 1, 1),
+            (TokenType::Var, "var", 2, 1),
+            (TokenType::Identifier, "größe", 2, 5),
+            (TokenType::Assign, "=", 2, 11),
+            (TokenType::StringStart, "\"日本 {", 2, 13),
+            (TokenType::Identifier, "größe", 2, 18),
+            (TokenType::StringEnd, "}\"", 2, 23),
+            (TokenType::Semicolon, ";", 2, 25),
+            (TokenType::Identifier, "变量1", 2, 27),
+            (TokenType::Plus, "+", 2, 31),
+            (TokenType::Identifier, "π", 2, 33),
+            (TokenType::EOF, "", 2, 34),
+        ]);
+        assert_eq!(tokens[4].literal, "日本 ");
+    }
+    
+    // Lexing time has to grow linearly with the source size. Run with
+    // `cargo test --release lexer_scales_linearly -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn bench_lexer_scales_linearly() {
+        use std::time::Instant;
+        
+        let chunk = "fun größe(a, b) {\n    str s = \"héllo {a + b} wörld\\n\";\n    return a * 2.5 + b; # 🌿 note\n}\n";
+        let time_lexing = |megabytes: usize| {
+            let source = chunk.repeat(megabytes * 1024 * 1024 / chunk.len());
+            let started = Instant::now();
+            let tokens = Lexer::new(source).tokenize_all();
+            (started.elapsed(), tokens.len())
+        };
+        
+        time_lexing(1);
+        let (small, small_tokens) = time_lexing(2);
+        let (large, large_tokens) = time_lexing(8);
+        println!("2 MB: {} tokens in {:?}", small_tokens, small);
+        println!("8 MB: {} tokens in {:?}", large_tokens, large);
+        
+        // 4x the input takes about 4x as long when linear, and 16x when quadratic
+        let ratio = large.as_secs_f64() / small.as_secs_f64();
+        assert!(ratio < 8.0, "Lexing 4x the input took {:.1}x as long", ratio);
+    }
 }
\ No newline at end of file
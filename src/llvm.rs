@@ -1,21 +1,44 @@
+use inkwell::basic_block::BasicBlock;
 use inkwell::builder::Builder;
 use inkwell::context::Context;
-use inkwell::module::Module as LlvmModule;
-use inkwell::passes::PassManager;
-use inkwell::values::{FunctionValue, PointerValue, BasicMetadataValueEnum, BasicValueEnum, BasicValue};
-use inkwell::types::{BasicTypeEnum, BasicMetadataTypeEnum, BasicType};
-use inkwell::AddressSpace;
-use std::collections::HashMap;
+use inkwell::debug_info::{
+    debug_metadata_version, AsDIScope, DICompileUnit, DIFlags, DIFlagsConstants, DISubprogram, DIType,
+    DWARFEmissionKind, DWARFSourceLanguage, DebugInfoBuilder,
+};
+use inkwell::module::{FlagBehavior, Module as LlvmModule};
+use inkwell::passes::PassBuilderOptions;
+use inkwell::targets::{CodeModel, FileType, InitializationConfig, RelocMode, Target, TargetMachine, TargetTriple};
+use inkwell::OptimizationLevel;
+use inkwell::attributes::{Attribute, AttributeLoc};
+use inkwell::intrinsics::Intrinsic;
+use inkwell::values::{FunctionValue, PointerValue, BasicMetadataValueEnum, BasicValueEnum, BasicValue, FloatValue, IntValue};
+use inkwell::types::{BasicTypeEnum, BasicMetadataTypeEnum, BasicType, PointerType};
+use inkwell::{AddressSpace, FloatPredicate, IntPredicate};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+
+// Print a trace line for the compiled code unless clean output was requested
+macro_rules! trace {
+    ($compiler:expr, $($arg:tt)*) => {
+        if !$compiler.clean_output {
+            println!("[LLVM] {}", format_args!($($arg)*));
+        }
+    };
+}
 
 // Assuming your IR enum and Value enum are accessible via crate:: path
-use crate::compiler::IR as RazenIR;
+use crate::ast::Span;
+use crate::compiler::{IR as RazenIR, TOP_LEVEL_FRAME};
 use crate::value::Value as RazenValue;
+use crate::runtime;
 
 pub struct LlvmCompiler<'ctx> {
     pub context: &'ctx Context,
     pub module: LlvmModule<'ctx>,
     pub builder: Builder<'ctx>,
-    fpm: Option<PassManager<FunctionValue<'ctx>>>,
+    // Level of the LLVM pass pipeline and of code generation
+    opt_level: OptLevel,
 
     // Manages named values (variables, function parameters) in the current scope
     // Maps variable names to their LLVM PointerValue and the expected type
@@ -25,6 +48,456 @@ pub struct LlvmCompiler<'ctx> {
     
     // Stack for managing values during compilation
     value_stack: Vec<BasicValueEnum<'ctx>>,
+    // Basic blocks keyed by the IR position they start at (every jump target)
+    blocks: HashMap<usize, BasicBlock<'ctx>>,
+    // Stack depth on entry to each block, fixed by the first jump or fall-through into it
+    block_depths: HashMap<usize, usize>,
+    // Entry-block slots that carry stack values from one block into another
+    stack_slots: Vec<PointerValue<'ctx>>,
+    // Storage kind of every variable in the function being compiled
+    variable_kinds: HashMap<String, ValueKind>,
+    // Top-level variables shared with user functions, stored as boxed LLVM globals
+    globals: HashMap<String, PointerValue<'ctx>>,
+    // DWARF metadata, present when debug info was requested
+    debug_info: Option<DebugInfo<'ctx>>,
+    // Machine that object files are generated for
+    target: TargetOptions,
+    // Functions declared `inline fun`, marked alwaysinline
+    inline_functions: HashSet<String>,
+    clean_output: bool,
+}
+
+// Optimization level, as given by -O0 to -O3 or -Os
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OptLevel {
+    O0,
+    O1,
+    #[default]
+    O2,
+    O3,
+    Os,
+}
+
+impl OptLevel {
+    // Parse the part after `-O`: 0, 1, 2, 3 or s
+    pub fn parse(level: &str) -> Result<Self, String> {
+        match level {
+            "0" => Ok(OptLevel::O0),
+            "1" => Ok(OptLevel::O1),
+            "2" => Ok(OptLevel::O2),
+            "3" => Ok(OptLevel::O3),
+            "s" => Ok(OptLevel::Os),
+            _ => Err(format!("Invalid optimization level '{}', expected 0, 1, 2, 3 or s", level)),
+        }
+    }
+
+    // The standard LLVM module pipeline for this level: inlining, global DCE, loop passes
+    // and the usual function simplifications from -O1 up
+    fn pipeline(self) -> &'static str {
+        match self {
+            OptLevel::O0 => "default<O0>",
+            OptLevel::O1 => "default<O1>",
+            OptLevel::O2 => "default<O2>",
+            OptLevel::O3 => "default<O3>",
+            OptLevel::Os => "default<Os>",
+        }
+    }
+
+    fn codegen_level(self) -> OptimizationLevel {
+        match self {
+            OptLevel::O0 => OptimizationLevel::None,
+            OptLevel::O1 => OptimizationLevel::Less,
+            OptLevel::O2 | OptLevel::Os => OptimizationLevel::Default,
+            OptLevel::O3 => OptimizationLevel::Aggressive,
+        }
+    }
+}
+
+// Output produced by `razen compile --emit=<kind>`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EmitKind {
+    LlvmIr,
+    Bitcode,
+    Asm,
+    Obj,
+    Exe,
+}
+
+impl EmitKind {
+    pub fn parse(kind: &str) -> Result<Self, String> {
+        match kind {
+            "llvm-ir" => Ok(EmitKind::LlvmIr),
+            "bitcode" => Ok(EmitKind::Bitcode),
+            "asm" => Ok(EmitKind::Asm),
+            "obj" => Ok(EmitKind::Obj),
+            "exe" => Ok(EmitKind::Exe),
+            _ => Err(format!("Invalid emit kind '{}', expected llvm-ir, bitcode, asm, obj or exe", kind)),
+        }
+    }
+
+    // File extension of the output, without the dot (empty for executables on Unix)
+    pub fn extension(self) -> &'static str {
+        match self {
+            EmitKind::LlvmIr => "ll",
+            EmitKind::Bitcode => "bc",
+            EmitKind::Asm => "s",
+            EmitKind::Obj => "o",
+            EmitKind::Exe => std::env::consts::EXE_EXTENSION,
+        }
+    }
+}
+
+// Target machine settings for native code. Anything left unset uses the host's value
+#[derive(Debug, Clone, Default)]
+pub struct TargetOptions {
+    pub triple: Option<String>,
+    pub cpu: Option<String>,
+    // LLVM feature string such as "+neon,-fp-armv8"
+    pub features: Option<String>,
+}
+
+impl TargetOptions {
+    // The normalized target triple
+    pub fn triple(&self) -> String {
+        let triple = match &self.triple {
+            Some(triple) => TargetMachine::normalize_triple(&TargetTriple::create(triple)),
+            None => TargetMachine::get_default_triple(),
+        };
+        triple.as_str().to_string_lossy().into_owned()
+    }
+
+    // Whether code is generated for the machine the compiler runs on
+    pub fn is_host(&self) -> bool {
+        self.triple() == TargetOptions::default().triple()
+    }
+}
+
+// Debug info builder for the module, and the subprogram of the function being compiled
+struct DebugInfo<'ctx> {
+    builder: DebugInfoBuilder<'ctx>,
+    compile_unit: DICompileUnit<'ctx>,
+    subprogram: Option<DISubprogram<'ctx>>,
+}
+
+// DWARF base type encodings (DW_ATE_*)
+const DW_ATE_ADDRESS: u32 = 0x01;
+const DW_ATE_BOOLEAN: u32 = 0x02;
+const DW_ATE_FLOAT: u32 = 0x04;
+const DW_ATE_SIGNED: u32 = 0x05;
+
+// Two operands after int/float promotion, or both boxed when either is not a number
+enum NumericOperands<'ctx> {
+    Int(IntValue<'ctx>, IntValue<'ctx>),
+    Float(FloatValue<'ctx>, FloatValue<'ctx>),
+    Dynamic(PointerValue<'ctx>, PointerValue<'ctx>),
+}
+
+// C source of the native runtime, compiled into every linked executable
+const RUNTIME_SOURCE: &str = include_str!("runtime.c");
+
+// How a value is represented in generated code. Numbers and booleans stay unboxed;
+// everything else is a pointer to a boxed runtime value (null is a null pointer)
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ValueKind {
+    Bool,
+    Int,
+    Float,
+    Dynamic,
+}
+
+impl ValueKind {
+    fn of_value(value: &RazenValue) -> Self {
+        match value {
+            RazenValue::Int(_) => ValueKind::Int,
+            RazenValue::Float(_) => ValueKind::Float,
+            RazenValue::Bool(_) => ValueKind::Bool,
+            _ => ValueKind::Dynamic,
+        }
+    }
+
+    // The narrowest kind that can hold values of both kinds
+    fn join(self, other: ValueKind) -> Self {
+        match (self, other) {
+            (a, b) if a == b => a,
+            (ValueKind::Int, ValueKind::Float) | (ValueKind::Float, ValueKind::Int) => ValueKind::Float,
+            _ => ValueKind::Dynamic,
+        }
+    }
+
+    // Result kind of an arithmetic operation, mirroring numeric_operands
+    fn arithmetic(self, other: ValueKind) -> Self {
+        if self == ValueKind::Dynamic || other == ValueKind::Dynamic {
+            ValueKind::Dynamic
+        } else if self == ValueKind::Float || other == ValueKind::Float {
+            ValueKind::Float
+        } else {
+            ValueKind::Int
+        }
+    }
+}
+
+// Work out one storage kind per variable by simulating the stack over the IR until the
+// kinds stop widening, so every store to a variable can share a single alloca
+fn infer_variable_kinds(params: &[(&str, RazenValue)], body_ir: &[RazenIR]) -> HashMap<String, ValueKind> {
+    let mut kinds: HashMap<String, ValueKind> = params.iter()
+        .map(|(name, value)| (name.to_string(), ValueKind::of_value(value)))
+        .collect();
+    let targets: HashSet<usize> = body_ir.iter()
+        .filter_map(|instruction| match instruction {
+            RazenIR::Jump(target) | RazenIR::JumpIfFalse(target) | RazenIR::JumpIfTrue(target) => Some(*target),
+            _ => None,
+        })
+        .collect();
+
+    loop {
+        let mut changed = false;
+        let mut stack: Vec<ValueKind> = Vec::new();
+        let pop = |stack: &mut Vec<ValueKind>, count: usize| {
+            let mut popped = Vec::with_capacity(count);
+            for _ in 0..count {
+                popped.push(stack.pop().unwrap_or(ValueKind::Dynamic));
+            }
+            popped.reverse();
+            popped
+        };
+
+        for (pos, instruction) in body_ir.iter().enumerate() {
+            // Values carried into a jump target come out of their slots boxed
+            if targets.contains(&pos) {
+                stack.iter_mut().for_each(|kind| *kind = ValueKind::Dynamic);
+            }
+            match instruction {
+                RazenIR::PushNumber(n) => stack.push(ValueKind::of_value(&RazenValue::number(*n))),
+                RazenIR::PushBoolean(_) => stack.push(ValueKind::Bool),
+                RazenIR::PushString(_) | RazenIR::PushNull | RazenIR::ReadInput => stack.push(ValueKind::Dynamic),
+                RazenIR::Pop | RazenIR::Print | RazenIR::Sleep | RazenIR::ThrowException | RazenIR::Return |
+                RazenIR::JumpIfFalse(_) | RazenIR::JumpIfTrue(_) => { pop(&mut stack, 1); }
+                RazenIR::Dup => {
+                    let top = stack.last().copied().unwrap_or(ValueKind::Dynamic);
+                    stack.push(top);
+                }
+                RazenIR::Swap => {
+                    let operands = pop(&mut stack, 2);
+                    stack.push(operands[1]);
+                    stack.push(operands[0]);
+                }
+                RazenIR::StoreVar(name) | RazenIR::SetGlobal(name) => {
+                    let value = pop(&mut stack, 1)[0];
+                    let kind = kinds.get(name).map_or(value, |existing| existing.join(value));
+                    if kinds.insert(name.clone(), kind) != Some(kind) {
+                        changed = true;
+                    }
+                }
+                RazenIR::LoadVar(name) => stack.push(kinds.get(name).copied().unwrap_or(ValueKind::Dynamic)),
+                RazenIR::Modulo | RazenIR::Power => {
+                    let operands = pop(&mut stack, 2);
+                    stack.push(operands[0].arithmetic(operands[1]));
+                }
+                RazenIR::Add | RazenIR::Subtract | RazenIR::Multiply | RazenIR::Divide | RazenIR::FloorDiv => {
+                    // Floats give a float. Integers give an integer unless the result overflows
+                    // or, for division, is not exact, so their results are boxed
+                    let operands = pop(&mut stack, 2);
+                    stack.push(match operands[0].arithmetic(operands[1]) {
+                        ValueKind::Float => ValueKind::Float,
+                        _ => ValueKind::Dynamic,
+                    });
+                }
+                RazenIR::Negate => {
+                    // Negating the smallest integer overflows into a float
+                    let operand = pop(&mut stack, 1)[0];
+                    stack.push(match operand.arithmetic(ValueKind::Int) {
+                        ValueKind::Float => ValueKind::Float,
+                        _ => ValueKind::Dynamic,
+                    });
+                }
+                RazenIR::Equal | RazenIR::NotEqual | RazenIR::GreaterThan | RazenIR::GreaterEqual |
+                RazenIR::LessThan | RazenIR::LessEqual | RazenIR::And | RazenIR::Or => {
+                    pop(&mut stack, 2);
+                    stack.push(ValueKind::Bool);
+                }
+                RazenIR::Not => {
+                    pop(&mut stack, 1);
+                    stack.push(ValueKind::Bool);
+                }
+                RazenIR::Call(name, arg_count) => {
+                    pop(&mut stack, *arg_count);
+                    stack.push(if name == "len" { ValueKind::Int } else { ValueKind::Dynamic });
+                }
+                RazenIR::LibraryCall(_, _, arg_count) | RazenIR::CreateArray(arg_count) => {
+                    pop(&mut stack, *arg_count);
+                    stack.push(ValueKind::Dynamic);
+                }
+                RazenIR::CreateMap(pair_count) => {
+                    pop(&mut stack, pair_count * 2);
+                    stack.push(ValueKind::Dynamic);
+                }
+                RazenIR::GetIndex | RazenIR::GetKey => {
+                    pop(&mut stack, 2);
+                    stack.push(ValueKind::Dynamic);
+                }
+                RazenIR::SetIndex | RazenIR::SetKey => {
+                    pop(&mut stack, 3);
+                    stack.push(ValueKind::Dynamic);
+                }
+                RazenIR::StoreLocal(_) | RazenIR::StoreCapture(_) => { pop(&mut stack, 1); }
+                RazenIR::LoadLocal(_) | RazenIR::LoadCapture(_) | RazenIR::MakeClosure(_, _) => stack.push(ValueKind::Dynamic),
+                RazenIR::CallValue(arg_count) | RazenIR::CallMethod(_, arg_count) => {
+                    pop(&mut stack, arg_count + 1);
+                    stack.push(ValueKind::Dynamic);
+                }
+                RazenIR::SetupTryCatch(_) | RazenIR::ClearTryCatch | RazenIR::Jump(_) | RazenIR::Exit |
+                RazenIR::Label(_) | RazenIR::DefineFunction(_, _) | RazenIR::DefineClass(_, _) => {}
+            }
+        }
+
+        if !changed {
+            return kinds;
+        }
+    }
+}
+
+// A function cut out of the program IR, with jump targets renumbered from zero
+struct FunctionIr {
+    name: String,
+    params: Vec<String>,
+    body: Vec<RazenIR>,
+    // Source position of each body instruction, and of the declaration itself
+    positions: Vec<Option<Span>>,
+    position: Option<Span>,
+}
+
+// LLVM variable holding a frame slot. Razen names cannot contain '#', so slot variables never
+// clash with globals
+fn slot_variable(slots: &[String], slot: usize) -> String {
+    format!("{}#{}", slots.get(slot).map_or("local", String::as_str), slot)
+}
+
+// Razen name of an LLVM variable, for debug info
+fn source_name(variable: &str) -> &str {
+    variable.split('#').next().unwrap_or(variable)
+}
+
+// Turn the frame slot accesses of a body into named variable accesses
+fn name_slots(body: &mut [RazenIR], slots: &[String]) {
+    for instruction in body {
+        match instruction {
+            RazenIR::StoreLocal(slot) => *instruction = RazenIR::StoreVar(slot_variable(slots, *slot)),
+            RazenIR::LoadLocal(slot) => *instruction = RazenIR::LoadVar(slot_variable(slots, *slot)),
+            _ => {}
+        }
+    }
+}
+
+// Split program IR into top-level code and one body per `fun`. The compiler lays a function
+// out as `Jump(end) Label DefineFunction <body> Label(end)`; the whole region is removed from
+// the surrounding code and the body becomes its own function. `source_map` holds the source
+// position of each instruction and may be empty. `locals` names the frame slots of each
+// function, and of the top-level code under TOP_LEVEL_FRAME
+fn split_functions(
+    ir: &[RazenIR],
+    source_map: &[Option<Span>],
+    param_names: &HashMap<String, Vec<String>>,
+    locals: &HashMap<String, Vec<String>>,
+) -> Result<(FunctionIr, Vec<FunctionIr>), String> {
+    let frame_slots = |name: &str| locals.get(name).map_or(&[][..], Vec::as_slice);
+    let position_at = |pos: usize| source_map.get(pos).copied().flatten();
+
+    // (name, region start, body start, region end) of every function
+    let mut regions = Vec::new();
+    for (pos, instruction) in ir.iter().enumerate() {
+        if let RazenIR::DefineFunction(name, start) = instruction {
+            let end = match start.checked_sub(1).and_then(|jump| ir.get(jump)) {
+                Some(RazenIR::Jump(end)) if *end > pos => *end,
+                _ => return Err(format!("Function '{}' is not preceded by a jump over its body", name)),
+            };
+            regions.push((name.clone(), start - 1, pos + 1, end));
+        }
+    }
+
+    // Each instruction belongs to the innermost function region around it, if any
+    let mut owner: Vec<Option<usize>> = vec![None; ir.len()];
+    let mut outermost_first: Vec<usize> = (0..regions.len()).collect();
+    outermost_first.sort_by_key(|&i| std::cmp::Reverse(regions[i].3 - regions[i].1));
+    for i in outermost_first {
+        // The end label belongs to the region, but once labels are optimized away the end
+        // target is the first instruction of the code after the function
+        let (_, region_start, _, region_end) = regions[i];
+        let region_end = match ir.get(region_end) {
+            Some(RazenIR::Label(_)) => region_end + 1,
+            _ => region_end.min(ir.len()),
+        };
+        for slot in &mut owner[region_start..region_end] {
+            *slot = Some(i);
+        }
+    }
+
+    // Outside a function the interpreter treats Return as a no-op that leaves its value on the stack
+    let (mut main_body, main_positions) = extract_instructions(ir, source_map, |pos| owner[pos].is_none() && !matches!(ir[pos], RazenIR::Return));
+    name_slots(&mut main_body, frame_slots(TOP_LEVEL_FRAME));
+    let main = FunctionIr {
+        name: "main".to_string(),
+        params: Vec::new(),
+        position: main_positions.iter().copied().flatten().next(),
+        body: main_body,
+        positions: main_positions,
+    };
+    let functions = regions.iter().enumerate()
+        .map(|(i, (name, region_start, body_start, end))| {
+            let (mut body, positions) = extract_instructions(ir, source_map, |pos| owner[pos] == Some(i) && pos >= *body_start && pos < *end);
+            name_slots(&mut body, frame_slots(name));
+            // Parameters are the first slots of the frame
+            let param_count = param_names.get(name).map_or(0, Vec::len);
+            FunctionIr {
+                name: name.clone(),
+                params: (0..param_count).map(|slot| slot_variable(frame_slots(name), slot)).collect(),
+                body,
+                positions,
+                position: position_at(*region_start),
+            }
+        })
+        .collect();
+    Ok((main, functions))
+}
+
+// Copy the instructions selected by `keep` along with their source positions, renumbering
+// jump targets to their new positions. A jump to a removed instruction lands on the next kept one
+fn extract_instructions(ir: &[RazenIR], source_map: &[Option<Span>], keep: impl Fn(usize) -> bool) -> (Vec<RazenIR>, Vec<Option<Span>>) {
+    let kept: Vec<usize> = (0..ir.len()).filter(|&pos| keep(pos)).collect();
+    let renumber = |target: usize| kept.partition_point(|&pos| pos < target);
+    let instructions = kept.iter()
+        .map(|&pos| match &ir[pos] {
+            RazenIR::Jump(target) => RazenIR::Jump(renumber(*target)),
+            RazenIR::JumpIfFalse(target) => RazenIR::JumpIfFalse(renumber(*target)),
+            RazenIR::JumpIfTrue(target) => RazenIR::JumpIfTrue(renumber(*target)),
+            RazenIR::SetupTryCatch(target) => RazenIR::SetupTryCatch(renumber(*target)),
+            other => other.clone(),
+        })
+        .collect();
+    let positions = kept.iter().map(|&pos| source_map.get(pos).copied().flatten()).collect();
+    (instructions, positions)
+}
+
+// Runtime function behind a builtin the compiler emits, which takes `arg_count` boxed values
+fn runtime_builtin(name: &str, arg_count: usize) -> Option<&'static str> {
+    match (name, arg_count) {
+        ("__check_kind", 3) => Some("rz_check_kind"),
+        ("__match_array", 3) => Some("rz_match_array"),
+        ("__array_rest", 2) => Some("rz_array_rest"),
+        ("__comparable", 2) => Some("rz_comparable"),
+        _ => None,
+    }
+}
+
+// Globals that user functions read or assign, which must live in LLVM globals. Every variable
+// a function accesses by name is a global, its locals are slot variables
+fn shared_globals(functions: &[FunctionIr]) -> BTreeSet<String> {
+    functions.iter()
+        .flat_map(|function| &function.body)
+        .filter_map(|instruction| match instruction {
+            RazenIR::LoadVar(name) | RazenIR::StoreVar(name) | RazenIR::SetGlobal(name) if !name.contains('#') => Some(name.clone()),
+            _ => None,
+        })
+        .collect()
 }
 
 impl<'ctx> LlvmCompiler<'ctx> {
@@ -40,48 +513,77 @@ impl<'ctx> LlvmCompiler<'ctx> {
         }
     }
     
-    // Helper to convert BasicValueEnum to BasicMetadataValueEnum
-    fn basic_value_to_metadata(&self, value: BasicValueEnum<'ctx>) -> BasicMetadataValueEnum<'ctx> {
-        match value {
-            BasicValueEnum::IntValue(v) => v.into(),
-            BasicValueEnum::FloatValue(v) => v.into(),
-            BasicValueEnum::PointerValue(v) => v.into(),
-            BasicValueEnum::ArrayValue(v) => v.into(),
-            BasicValueEnum::StructValue(v) => v.into(),
-            BasicValueEnum::VectorValue(v) => v.into(),
-        }
-    }
-
-    pub fn new(context: &'ctx Context, module_name: &str, enable_optimizations: bool) -> Self {
+    pub fn new(context: &'ctx Context, module_name: &str, opt_level: OptLevel) -> Self {
         let module = context.create_module(module_name);
         let builder = context.create_builder();
-        
-        let fpm = if enable_optimizations {
-            let fpm_instance = PassManager::create(&module);
-            // Common optimization passes
-            fpm_instance.add_instruction_combining_pass();
-            fpm_instance.add_reassociate_pass();
-            fpm_instance.add_gvn_pass(); // Global Value Numbering
-            fpm_instance.add_cfg_simplification_pass(); // Control Flow Graph Simplification
-            fpm_instance.add_promote_memory_to_register_pass(); // Mem2Reg
-            // Add more passes like dead code elimination, loop optimizations etc. as needed
-            fpm_instance.initialize();
-            Some(fpm_instance)
-        } else {
-            None
-        };
 
         LlvmCompiler {
             context,
             module,
             builder,
-            fpm,
+            opt_level,
             variables: HashMap::new(),
             functions: HashMap::new(),
             value_stack: Vec::new(),
+            blocks: HashMap::new(),
+            block_depths: HashMap::new(),
+            stack_slots: Vec::new(),
+            variable_kinds: HashMap::new(),
+            globals: HashMap::new(),
+            debug_info: None,
+            target: TargetOptions::default(),
+            inline_functions: HashSet::new(),
+            clean_output: false,
         }
     }
 
+    pub fn set_clean_output(&mut self, clean: bool) {
+        self.clean_output = clean;
+    }
+
+    // Generate object files for another machine than the host
+    pub fn set_target(&mut self, target: TargetOptions) {
+        self.target = target;
+    }
+
+    // Mark these functions alwaysinline so the pass pipeline inlines every remaining call
+    pub fn set_inline_functions<'a>(&mut self, names: impl IntoIterator<Item = &'a str>) {
+        self.inline_functions = names.into_iter().map(str::to_string).collect();
+    }
+
+    // Emit DWARF debug info for `source_path` in everything compiled after this call, so the
+    // program can be stepped through in gdb or lldb
+    pub fn enable_debug_info(&mut self, source_path: &Path) {
+        let source_path = fs::canonicalize(source_path).unwrap_or_else(|_| source_path.to_path_buf());
+        let file_name = source_path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+        let directory = source_path.parent().map(|dir| dir.to_string_lossy().into_owned()).unwrap_or_default();
+
+        let i32_type = self.context.i32_type();
+        self.module.add_basic_value_flag("Debug Info Version", FlagBehavior::Warning, i32_type.const_int(debug_metadata_version() as u64, false));
+        self.module.add_basic_value_flag("Dwarf Version", FlagBehavior::Warning, i32_type.const_int(4, false));
+
+        // DWARF has no language code for Razen; C is the closest match for debuggers
+        let (builder, compile_unit) = self.module.create_debug_info_builder(
+            true,
+            DWARFSourceLanguage::C,
+            &file_name,
+            &directory,
+            "razen_compiler",
+            self.opt_level != OptLevel::O0,
+            "",
+            0,
+            "",
+            DWARFEmissionKind::Full,
+            0,
+            false,
+            false,
+            "",
+            "",
+        );
+        self.debug_info = Some(DebugInfo { builder, compile_unit, subprogram: None });
+        trace!(self, "Emitting debug info for {}", source_path.display());
+    }
+
     // --- Type Conversion --- 
     fn to_llvm_type(&self, razen_type: &RazenValue) -> BasicTypeEnum<'ctx> {
         // This is a simplified mapping. Real type mapping can be more complex,
@@ -102,8 +604,89 @@ impl<'ctx> LlvmCompiler<'ctx> {
     }
 
     // --- Main Compilation Logic --- 
+
+    // Compile a whole program: one LLVM function per user `fun` plus `main` for the top-level code
+    // `source_map` gives the source position of each IR instruction and is used for debug info
+    // `param_names` and `locals` give the parameters and frame slot names of each function
+    pub fn compile_program(
+        &mut self,
+        ir: &[RazenIR],
+        source_map: &[Option<Span>],
+        param_names: &HashMap<String, Vec<String>>,
+        locals: &HashMap<String, Vec<String>>,
+    ) -> Result<FunctionValue<'ctx>, String> {
+        let (main, functions) = split_functions(ir, source_map, param_names, locals)?;
+
+        for name in shared_globals(&functions) {
+            let global = self.module.add_global(self.dynamic_type(), None, &format!("global.{}", name));
+            global.set_initializer(&self.dynamic_type().const_null());
+            if let Some(debug_info) = &self.debug_info {
+                // Globals are described at their first top-level assignment
+                let line = main.body.iter().zip(&main.positions)
+                    .find(|(instruction, _)| matches!(instruction, RazenIR::StoreVar(n) | RazenIR::SetGlobal(n) if *n == name))
+                    .and_then(|(_, position)| *position)
+                    .map_or(0, |position| position.line as u32);
+                let expression = debug_info.builder.create_global_variable_expression(
+                    debug_info.compile_unit.as_debug_info_scope(),
+                    &name,
+                    &format!("global.{}", name),
+                    debug_info.compile_unit.get_file(),
+                    line,
+                    Self::debug_type(debug_info, self.dynamic_type().as_basic_type_enum())?,
+                    false,
+                    None,
+                    None,
+                    0,
+                );
+                global.set_metadata(expression.as_metadata_value(self.context), self.context.get_kind_id("dbg"));
+            }
+            self.globals.insert(name, global.as_pointer_value());
+        }
+
+        // User functions take and return boxed values. All of them are declared before any
+        // body is compiled so calls can refer to functions defined later
+        let mut declared: Vec<(FunctionValue<'ctx>, Vec<(&str, RazenValue)>)> = Vec::with_capacity(functions.len());
+        for function in &functions {
+            let params: Vec<(&str, RazenValue)> = function.params.iter()
+                .map(|param| (param.as_str(), RazenValue::Null))
+                .collect();
+            let llvm_function = self.declare_function(&function.name, &params, &RazenValue::Null);
+            declared.push((llvm_function, params));
+        }
+        for (function, (llvm_function, params)) in functions.iter().zip(&declared) {
+            self.build_function_body(*llvm_function, params, function)?;
+            trace!(self, "Compiled function '{}' with {} parameters", function.name, params.len());
+        }
+
+        let main_function = self.declare_function("main", &[], &RazenValue::Int(0));
+        self.build_function_body(main_function, &[], &main)?;
+        if let Some(debug_info) = &self.debug_info {
+            debug_info.builder.finalize();
+            for (llvm_function, _) in &declared {
+                self.verify_function(*llvm_function)?;
+            }
+            self.verify_function(main_function)?;
+        }
+        self.optimize()?;
+        Ok(main_function)
+    }
+
+    // Compile bare IR with no source positions into a single function
+    #[cfg(test)]
     pub fn compile_function(&mut self, name: &str, params: Vec<(&str, RazenValue)>, return_type: RazenValue, body_ir: &[RazenIR]) -> Result<FunctionValue<'ctx>, String> {
-        let llvm_return_type = self.to_llvm_type(&return_type);
+        let function = self.declare_function(name, &params, &return_type);
+        let body = FunctionIr {
+            name: name.to_string(),
+            params: params.iter().map(|(param_name, _)| param_name.to_string()).collect(),
+            body: body_ir.to_vec(),
+            positions: Vec::new(),
+            position: None,
+        };
+        self.build_function_body(function, &params, &body)
+    }
+
+    fn declare_function(&mut self, name: &str, params: &[(&str, RazenValue)], return_type: &RazenValue) -> FunctionValue<'ctx> {
+        let llvm_return_type = self.to_llvm_type(return_type);
         let llvm_param_types: Vec<BasicMetadataTypeEnum<'ctx>> = params
             .iter()
             .map(|(_, razen_val)| self.to_llvm_type(razen_val).into())
@@ -111,33 +694,106 @@ impl<'ctx> LlvmCompiler<'ctx> {
 
         let fn_type = llvm_return_type.fn_type(&llvm_param_types, false);
         let function = self.module.add_function(name, fn_type, None);
+        if self.inline_functions.contains(name) {
+            let kind = Attribute::get_named_enum_kind_id("alwaysinline");
+            function.add_attribute(AttributeLoc::Function, self.context.create_enum_attribute(kind, 0));
+        }
         self.functions.insert(name.to_string(), function);
+        function
+    }
+
+    // Compile IR into a declared function. Shared globals are read and written in place
+    fn build_function_body(&mut self, function: FunctionValue<'ctx>, params: &[(&str, RazenValue)], body: &FunctionIr) -> Result<FunctionValue<'ctx>, String> {
+        let body_ir = &body.body;
+        let name = function.get_name().to_string_lossy().into_owned();
+        let llvm_return_type = function.get_type().get_return_type()
+            .ok_or_else(|| format!("Function '{}' has no return type", name))?;
+        self.variables.clear();
+        self.value_stack.clear();
 
         let entry_block = self.context.append_basic_block(function, "entry");
         self.builder.position_at_end(entry_block);
+        self.begin_debug_function(function, body)?;
 
         // Create allocas for parameters and store initial values
         for (i, (param_name, param_type)) in params.iter().enumerate() {
             if let Some(param_value) = function.get_nth_param(i as u32) {
                 let param_llvm_type = self.to_llvm_type(param_type);
                 let alloca = self.create_entry_block_alloca(&format!("param_{}", param_name), param_llvm_type, function)?;
+                self.declare_debug_variable(param_name, alloca, param_llvm_type, Some(i as u32 + 1))?;
                 self.builder.build_store(alloca, param_value);
                 self.variables.insert(param_name.to_string(), (alloca, param_llvm_type));
             }
         }
 
-        // Compile IR instructions for the function body
+        let mut seeds = params.to_vec();
+        let globals: Vec<(String, PointerValue<'ctx>)> = self.globals.iter()
+            .map(|(global_name, global)| (global_name.clone(), *global))
+            .collect();
+        for (global_name, global) in &globals {
+            let dynamic_type = self.dynamic_type().as_basic_type_enum();
+            self.variables.insert(global_name.clone(), (*global, dynamic_type));
+        }
+        seeds.extend(globals.iter().map(|(global_name, _)| (global_name.as_str(), RazenValue::Null)));
+
+        self.variable_kinds = infer_variable_kinds(&seeds, body_ir);
+
+        // Every jump target starts a new basic block
+        self.blocks.clear();
+        self.block_depths.clear();
+        self.stack_slots.clear();
         for instruction in body_ir {
+            if let RazenIR::Jump(target) | RazenIR::JumpIfFalse(target) | RazenIR::JumpIfTrue(target) = instruction {
+                if !self.blocks.contains_key(target) {
+                    let block = self.context.append_basic_block(function, &format!("L{}", target));
+                    self.blocks.insert(*target, block);
+                }
+            }
+        }
+
+        // Compile IR instructions for the function body
+        for (pos, instruction) in body_ir.iter().enumerate() {
+            if let Some(block) = self.blocks.get(&pos).copied() {
+                // Fall through into the labelled block
+                if !self.current_block_terminated() {
+                    self.spill_stack(pos, function)?;
+                    self.builder.build_unconditional_branch(block);
+                }
+                self.builder.position_at_end(block);
+                self.reload_stack(pos);
+            } else if self.current_block_terminated() {
+                // Code after a jump or return with no label in between can never run,
+                // but it still needs a block to be emitted into
+                let dead_block = self.context.append_basic_block(function, "unreachable");
+                self.builder.position_at_end(dead_block);
+            }
+            if let Some(Some(position)) = body.positions.get(pos) {
+                self.set_debug_location(*position);
+            }
             self.compile_ir_instruction(instruction, function)?;
         }
 
-        // Verify function
-        if function.verify(true) {
-            if let Some(fpm) = &self.fpm {
-                fpm.run_on(&function);
+        // Any block that falls off the end of the body returns zero
+        for block in function.get_basic_blocks() {
+            if block.get_terminator().is_none() {
+                self.builder.position_at_end(block);
+                self.builder.build_return(Some(&llvm_return_type.const_zero()));
             }
-            Ok(function)
+        }
+
+        // Debug info can only be verified once it is finalized, after the whole program
+        if self.debug_info.is_none() {
+            self.verify_function(function)?;
+        }
+        Ok(function)
+    }
+
+    // Verify a finished function
+    fn verify_function(&self, function: FunctionValue<'ctx>) -> Result<(), String> {
+        if function.verify(true) {
+            Ok(())
         } else {
+            let name = function.get_name().to_string_lossy().into_owned();
             unsafe { function.delete(); }
             Err(format!("LLVM function '{}' verification failed.", name))
         }
@@ -146,30 +802,37 @@ impl<'ctx> LlvmCompiler<'ctx> {
     fn compile_ir_instruction(&mut self, instruction: &RazenIR, current_function: FunctionValue<'ctx>) -> Result<(), String> {
         match instruction {
             RazenIR::PushNumber(val) => {
-                let i64_val = self.context.i64_type().const_int(*val as u64, true);
-                self.value_stack.push(i64_val.as_basic_value_enum());
-                println!("[LLVM] Pushed number to stack: {}", val);
+                // Integral literals stay integers, like Value::number in the interpreter
+                let number = match RazenValue::number(*val) {
+                    RazenValue::Int(i) => self.context.i64_type().const_int(i as u64, true).as_basic_value_enum(),
+                    _ => self.context.f64_type().const_float(*val).as_basic_value_enum(),
+                };
+                self.value_stack.push(number);
+                trace!(self, "Pushed number to stack: {}", val);
             }
             RazenIR::PushString(s) => {
                 let str_val = self.builder.build_global_string_ptr(s, ".str");
-                let str_ptr = str_val.as_pointer_value();
-                self.value_stack.push(str_ptr.as_basic_value_enum());
-                println!("[LLVM] Pushed string to stack: {}", s);
+                let boxed = self.call_runtime_value("rz_string", &[str_val.as_pointer_value().into()])?;
+                self.value_stack.push(boxed);
+                trace!(self, "Pushed string to stack: {}", s);
             }
             RazenIR::PushBoolean(val) => {
                 let bool_val = self.context.bool_type().const_int(if *val { 1 } else { 0 }, false);
                 self.value_stack.push(bool_val.as_basic_value_enum());
-                println!("[LLVM] Pushed bool to stack: {}", val);
+                trace!(self, "Pushed bool to stack: {}", val);
             }
             RazenIR::PushNull => {
-                let null_ptr = self.context.i8_type().ptr_type(AddressSpace::default()).const_null();
-                self.value_stack.push(null_ptr.as_basic_value_enum());
-                println!("[LLVM] Pushed null to stack");
+                self.value_stack.push(self.dynamic_type().const_null().as_basic_value_enum());
+                trace!(self, "Pushed null to stack");
             }
             
             RazenIR::LoadVar(name) => {
-                let (ptr_val, element_type) = self.variables.get(name)
-                    .ok_or_else(|| format!("Undefined variable: {}", name))?;
+                // Reading a variable that was never assigned gives null, as in the interpreter
+                let Some((ptr_val, element_type)) = self.variables.get(name) else {
+                    self.value_stack.push(self.dynamic_type().const_null().as_basic_value_enum());
+                    trace!(self, "Loaded unassigned var '{}' as null", name);
+                    return Ok(());
+                };
                 
                 let loaded_val: BasicValueEnum = match element_type {
                     BasicTypeEnum::IntType(int_type) => {
@@ -193,148 +856,279 @@ impl<'ctx> LlvmCompiler<'ctx> {
                 };
 
                 self.value_stack.push(loaded_val);
-                println!("[LLVM] Loaded var '{}' to stack", name);
+                trace!(self, "Loaded var '{}' to stack", name);
             }
             
-            RazenIR::StoreVar(name) => {
+            RazenIR::StoreVar(name) | RazenIR::SetGlobal(name) => {
                 let val_to_store = self.value_stack.pop()
-                    .ok_or_else(|| "Stack underflow during StoreVar".to_string())?;
-
-                if let Some((ptr_val, expected_type)) = self.variables.get(name) {
-                    // Check if the type of val_to_store matches the expected type
-                    let val_type = self.get_basic_value_type(val_to_store)?;
-                    if val_type == *expected_type {
-                        self.builder.build_store(*ptr_val, val_to_store);
-                    } else {
-                        return Err(format!("StoreVar type mismatch for '{}': expected {:?}, got {:?}", 
-                                         name, expected_type, val_type));
+                    .ok_or_else(|| format!("Stack underflow during {:?}", instruction))?;
+                self.store_variable(name, val_to_store, current_function)?;
+                // A function working on a local copy of a global still writes the global itself
+                if let (RazenIR::SetGlobal(_), Some(global)) = (instruction, self.globals.get(name).copied()) {
+                    if self.variables.get(name).map(|(slot, _)| *slot) != Some(global) {
+                        let boxed = self.build_box(val_to_store)?;
+                        self.builder.build_store(global, boxed);
                     }
-                } else {
-                    // If variable doesn't exist, create an alloca for it
-                    let llvm_type = self.get_basic_value_type(val_to_store)?;
-                    let alloca = self.create_entry_block_alloca(name, llvm_type, current_function)?;
-                    self.builder.build_store(alloca, val_to_store);
-                    self.variables.insert(name.clone(), (alloca, llvm_type));
                 }
-                println!("[LLVM] Stored to var '{}'", name);
+                trace!(self, "Stored to var '{}'", name);
             }
             
             RazenIR::Add => {
-                let rhs = self.value_stack.pop().ok_or_else(|| "Stack underflow during Add (rhs)".to_string())?;
-                let lhs = self.value_stack.pop().ok_or_else(|| "Stack underflow during Add (lhs)".to_string())?;
-                
-                let result = match (lhs, rhs) {
-                    (BasicValueEnum::IntValue(l), BasicValueEnum::IntValue(r)) => {
-                        self.builder.build_int_add(l, r, "addtmp").as_basic_value_enum()
-                    }
-                    (BasicValueEnum::FloatValue(l), BasicValueEnum::FloatValue(r)) => {
-                        self.builder.build_float_add(l, r, "addtmp").as_basic_value_enum()
-                    }
-                    _ => return Err("Type mismatch in Add operation".to_string()),
+                let (lhs, rhs) = self.pop_operands("Add")?;
+                let result = match self.numeric_operands(lhs, rhs, "Add")? {
+                    NumericOperands::Int(l, r) => self.build_checked_int_op("llvm.sadd.with.overflow", l, r, current_function)?,
+                    NumericOperands::Float(l, r) => self.builder.build_float_add(l, r, "addtmp").as_basic_value_enum(),
+                    NumericOperands::Dynamic(l, r) => self.call_runtime_value("rz_add", &[l.into(), r.into()])?,
                 };
-                
                 self.value_stack.push(result);
-                println!("[LLVM] Performed Add operation");
+                trace!(self, "Performed Add operation");
             }
-            
+
             RazenIR::Subtract => {
-                let rhs = self.value_stack.pop().ok_or_else(|| "Stack underflow during Subtract (rhs)".to_string())?;
-                let lhs = self.value_stack.pop().ok_or_else(|| "Stack underflow during Subtract (lhs)".to_string())?;
-                
-                let result = match (lhs, rhs) {
-                    (BasicValueEnum::IntValue(l), BasicValueEnum::IntValue(r)) => {
-                        self.builder.build_int_sub(l, r, "subtmp").as_basic_value_enum()
+                let (lhs, rhs) = self.pop_operands("Subtract")?;
+                let result = match self.numeric_operands(lhs, rhs, "Subtract")? {
+                    NumericOperands::Int(l, r) => self.build_checked_int_op("llvm.ssub.with.overflow", l, r, current_function)?,
+                    NumericOperands::Float(l, r) => self.builder.build_float_sub(l, r, "subtmp").as_basic_value_enum(),
+                    NumericOperands::Dynamic(l, r) => self.call_runtime_value("rz_sub", &[l.into(), r.into()])?,
+                };
+                self.value_stack.push(result);
+                trace!(self, "Performed Subtract operation");
+            }
+
+            RazenIR::Multiply => {
+                let (lhs, rhs) = self.pop_operands("Multiply")?;
+                let result = match self.numeric_operands(lhs, rhs, "Multiply")? {
+                    NumericOperands::Int(l, r) => self.build_checked_int_op("llvm.smul.with.overflow", l, r, current_function)?,
+                    NumericOperands::Float(l, r) => self.builder.build_float_mul(l, r, "multmp").as_basic_value_enum(),
+                    NumericOperands::Dynamic(l, r) => self.call_runtime_value("rz_mul", &[l.into(), r.into()])?,
+                };
+                self.value_stack.push(result);
+                trace!(self, "Performed Multiply operation");
+            }
+
+            RazenIR::Divide => {
+                let (lhs, rhs) = self.pop_operands("Divide")?;
+                let result = match self.numeric_operands(lhs, rhs, "Divide")? {
+                    NumericOperands::Int(l, r) => {
+                        // An exact quotient stays an integer and anything else is a float, so
+                        // only the runtime knows which it will be
+                        let l = self.build_box(l.as_basic_value_enum())?;
+                        let r = self.build_box(r.as_basic_value_enum())?;
+                        self.call_runtime_value("rz_div", &[l.into(), r.into()])?
                     }
-                    (BasicValueEnum::FloatValue(l), BasicValueEnum::FloatValue(r)) => {
-                        self.builder.build_float_sub(l, r, "subtmp").as_basic_value_enum()
+                    NumericOperands::Float(l, r) => {
+                        self.build_zero_guard(r.as_basic_value_enum(), "Division by zero", current_function)?;
+                        self.builder.build_float_div(l, r, "divtmp").as_basic_value_enum()
                     }
-                    _ => return Err("Type mismatch in Subtract operation".to_string()),
+                    NumericOperands::Dynamic(l, r) => self.call_runtime_value("rz_div", &[l.into(), r.into()])?,
                 };
-                
                 self.value_stack.push(result);
-                println!("[LLVM] Performed Subtract operation");
+                trace!(self, "Performed Divide operation");
             }
-            
-            RazenIR::Multiply => {
-                let rhs = self.value_stack.pop().ok_or_else(|| "Stack underflow during Multiply (rhs)".to_string())?;
-                let lhs = self.value_stack.pop().ok_or_else(|| "Stack underflow during Multiply (lhs)".to_string())?;
-                
-                let result = match (lhs, rhs) {
-                    (BasicValueEnum::IntValue(l), BasicValueEnum::IntValue(r)) => {
-                        self.builder.build_int_mul(l, r, "multmp").as_basic_value_enum()
+
+            RazenIR::Modulo => {
+                let (lhs, rhs) = self.pop_operands("Modulo")?;
+                let result = match self.numeric_operands(lhs, rhs, "Modulo")? {
+                    NumericOperands::Int(l, r) => {
+                        self.build_zero_guard(r.as_basic_value_enum(), "Modulo by zero", current_function)?;
+                        let r = self.build_overflow_safe_divisor(l, r);
+                        self.builder.build_int_signed_rem(l, r, "modtmp").as_basic_value_enum()
                     }
-                    (BasicValueEnum::FloatValue(l), BasicValueEnum::FloatValue(r)) => {
-                        self.builder.build_float_mul(l, r, "multmp").as_basic_value_enum()
+                    NumericOperands::Float(l, r) => {
+                        self.build_zero_guard(r.as_basic_value_enum(), "Modulo by zero", current_function)?;
+                        self.builder.build_float_rem(l, r, "modtmp").as_basic_value_enum()
                     }
-                    _ => return Err("Type mismatch in Multiply operation".to_string()),
+                    NumericOperands::Dynamic(l, r) => self.call_runtime_value("rz_mod", &[l.into(), r.into()])?,
                 };
-                
                 self.value_stack.push(result);
-                println!("[LLVM] Performed Multiply operation");
+                trace!(self, "Performed Modulo operation");
             }
-            
-            RazenIR::Divide => {
-                let rhs = self.value_stack.pop().ok_or_else(|| "Stack underflow during Divide (rhs)".to_string())?;
-                let lhs = self.value_stack.pop().ok_or_else(|| "Stack underflow during Divide (lhs)".to_string())?;
-                
-                let result = match (lhs, rhs) {
-                    (BasicValueEnum::IntValue(l), BasicValueEnum::IntValue(r)) => {
-                        self.builder.build_int_signed_div(l, r, "divtmp").as_basic_value_enum()
+
+            RazenIR::Power => {
+                let (lhs, rhs) = self.pop_operands("Power")?;
+                let f64_type = self.context.f64_type();
+                let result = match self.numeric_operands(lhs, rhs, "Power")? {
+                    NumericOperands::Int(l, r) => {
+                        // Integer powers go through llvm.pow and are converted back
+                        let l = self.builder.build_signed_int_to_float(l, f64_type, "powbase");
+                        let r = self.builder.build_signed_int_to_float(r, f64_type, "powexp");
+                        let pow = self.build_float_intrinsic("llvm.pow", &[l, r])?;
+                        self.builder.build_float_to_signed_int(pow, self.context.i64_type(), "powtmp").as_basic_value_enum()
                     }
-                    (BasicValueEnum::FloatValue(l), BasicValueEnum::FloatValue(r)) => {
-                        self.builder.build_float_div(l, r, "divtmp").as_basic_value_enum()
+                    NumericOperands::Float(l, r) => self.build_float_intrinsic("llvm.pow", &[l, r])?.as_basic_value_enum(),
+                    NumericOperands::Dynamic(l, r) => self.call_runtime_value("rz_pow", &[l.into(), r.into()])?,
+                };
+                self.value_stack.push(result);
+                trace!(self, "Performed Power operation");
+            }
+
+            RazenIR::FloorDiv => {
+                let (lhs, rhs) = self.pop_operands("FloorDiv")?;
+                let result = match self.numeric_operands(lhs, rhs, "FloorDiv")? {
+                    NumericOperands::Int(l, r) => {
+                        // The smallest integer divided by -1 overflows into a float, which only
+                        // the runtime can give
+                        let l = self.build_box(l.as_basic_value_enum())?;
+                        let r = self.build_box(r.as_basic_value_enum())?;
+                        self.call_runtime_value("rz_floor_div", &[l.into(), r.into()])?
+                    }
+                    NumericOperands::Float(l, r) => {
+                        self.build_zero_guard(r.as_basic_value_enum(), "Division by zero", current_function)?;
+                        let quotient = self.builder.build_float_div(l, r, "fdivq");
+                        self.build_float_intrinsic("llvm.floor", &[quotient])?.as_basic_value_enum()
                     }
-                    _ => return Err("Type mismatch in Divide operation".to_string()),
+                    NumericOperands::Dynamic(l, r) => self.call_runtime_value("rz_floor_div", &[l.into(), r.into()])?,
                 };
-                
                 self.value_stack.push(result);
-                println!("[LLVM] Performed Divide operation");
+                trace!(self, "Performed FloorDiv operation");
             }
-            
+
+            RazenIR::Negate => {
+                let value = self.value_stack.pop().ok_or_else(|| "Stack underflow during Negate".to_string())?;
+                let result = match value {
+                    BasicValueEnum::IntValue(v) => {
+                        let v = self.builder.build_int_z_extend_or_bit_cast(v, self.context.i64_type(), "negext");
+                        self.build_checked_int_op("llvm.ssub.with.overflow", v.get_type().const_zero(), v, current_function)?
+                    }
+                    BasicValueEnum::FloatValue(v) => self.builder.build_float_neg(v, "negtmp").as_basic_value_enum(),
+                    BasicValueEnum::PointerValue(v) => self.call_runtime_value("rz_neg", &[v.into()])?,
+                    _ => return Err("Type mismatch in Negate operation".to_string()),
+                };
+                self.value_stack.push(result);
+                trace!(self, "Performed Negate operation");
+            }
+
+            RazenIR::Equal | RazenIR::NotEqual | RazenIR::GreaterThan |
+            RazenIR::GreaterEqual | RazenIR::LessThan | RazenIR::LessEqual => {
+                let (lhs, rhs) = self.pop_operands("comparison")?;
+                let result = self.build_comparison(instruction, lhs, rhs)?;
+                self.value_stack.push(result.as_basic_value_enum());
+                trace!(self, "Performed comparison {:?}", instruction);
+            }
+
+            RazenIR::And | RazenIR::Or => {
+                let (lhs, rhs) = self.pop_operands("logic operation")?;
+                let lhs = self.build_truthy(lhs)?;
+                let rhs = self.build_truthy(rhs)?;
+                let result = if matches!(instruction, RazenIR::And) {
+                    self.builder.build_and(lhs, rhs, "andtmp")
+                } else {
+                    self.builder.build_or(lhs, rhs, "ortmp")
+                };
+                self.value_stack.push(result.as_basic_value_enum());
+                trace!(self, "Performed {:?} operation", instruction);
+            }
+
+            RazenIR::Not => {
+                let value = self.value_stack.pop().ok_or_else(|| "Stack underflow during Not".to_string())?;
+                let truthy = self.build_truthy(value)?;
+                let result = self.builder.build_not(truthy, "nottmp");
+                self.value_stack.push(result.as_basic_value_enum());
+                trace!(self, "Performed Not operation");
+            }
+
+            RazenIR::Jump(target) => {
+                let target_block = self.block_at(*target)?;
+                self.spill_stack(*target, current_function)?;
+                self.builder.build_unconditional_branch(target_block);
+                trace!(self, "Jump to {}", target);
+            }
+
+            RazenIR::JumpIfFalse(target) | RazenIR::JumpIfTrue(target) => {
+                let condition = self.value_stack.pop()
+                    .ok_or_else(|| format!("Stack underflow during {:?}", instruction))?;
+                let condition = self.build_truthy(condition)?;
+                let target_block = self.block_at(*target)?;
+                self.spill_stack(*target, current_function)?;
+                let next_block = self.context.append_basic_block(current_function, "next");
+                if matches!(instruction, RazenIR::JumpIfTrue(_)) {
+                    self.builder.build_conditional_branch(condition, target_block, next_block);
+                } else {
+                    self.builder.build_conditional_branch(condition, next_block, target_block);
+                }
+                self.builder.position_at_end(next_block);
+                trace!(self, "Conditional jump to {}", target);
+            }
+
+            RazenIR::Label(label) => {
+                // Blocks are created from jump targets before the body is compiled
+                trace!(self, "Label {}", label);
+            }
+
             RazenIR::Call(fn_name, arg_count) => {
-                if let Some(function_to_call) = self.functions.get(fn_name) {
-                    // Pop arguments from stack
-                    let mut call_args: Vec<BasicMetadataValueEnum<'ctx>> = Vec::with_capacity(*arg_count);
-                    for _ in 0..*arg_count {
-                        let arg = self.value_stack.pop()
-                            .ok_or_else(|| format!("Stack underflow during Call to '{}'", fn_name))?;
-                        call_args.push(self.basic_value_to_metadata(arg));
+                let mut args = Vec::with_capacity(*arg_count);
+                for _ in 0..*arg_count {
+                    args.push(self.value_stack.pop()
+                        .ok_or_else(|| format!("Stack underflow during Call to '{}'", fn_name))?);
+                }
+                // Arguments are in reverse order, so reverse them
+                args.reverse();
+
+                if let Some(function_to_call) = self.functions.get(fn_name).copied() {
+                    // User functions take boxed values; missing arguments are null and extra ones are dropped
+                    let mut call_args: Vec<BasicMetadataValueEnum<'ctx>> = Vec::with_capacity(args.len());
+                    for i in 0..function_to_call.count_params() as usize {
+                        let arg = match args.get(i) {
+                            Some(arg) => self.build_box(*arg)?,
+                            None => self.dynamic_type().const_null(),
+                        };
+                        call_args.push(arg.into());
                     }
                     
-                    // Arguments are in reverse order, so reverse them
-                    call_args.reverse();
-                    
-                    let call_site_val = self.builder.build_call(*function_to_call, call_args.as_slice(), "calltmp");
+                    let call_site_val = self.builder.build_call(function_to_call, call_args.as_slice(), "calltmp");
                     
                     if let Some(ret_val_basic) = call_site_val.try_as_basic_value().left() {
                         self.value_stack.push(ret_val_basic);
-                        println!("[LLVM] Call to '{}' returned value", fn_name);
+                        trace!(self, "Call to '{}' returned value", fn_name);
                     } else {
-                        println!("[LLVM] Call to '{}' is void", fn_name);
+                        trace!(self, "Call to '{}' is void", fn_name);
+                    }
+                } else if fn_name == "len" && args.len() == 1 {
+                    let boxed = self.build_box(args[0])?;
+                    let length = self.call_runtime_value("rz_len", &[boxed.into()])?;
+                    self.value_stack.push(length);
+                    trace!(self, "Call to builtin 'len'");
+                } else if let Some(runtime_fn) = runtime_builtin(fn_name, args.len()) {
+                    let mut boxed: Vec<BasicMetadataValueEnum<'ctx>> = Vec::with_capacity(args.len());
+                    for arg in &args {
+                        boxed.push(self.build_box(*arg)?.into());
                     }
+                    let result = self.call_runtime_value(runtime_fn, &boxed)?;
+                    self.value_stack.push(result);
+                    trace!(self, "Call to builtin '{}'", fn_name);
                 } else {
-                    return Err(format!("Call to undefined function: {}", fn_name));
+                    // Like the interpreter, calling an unknown function evaluates to null
+                    self.value_stack.push(self.dynamic_type().const_null().as_basic_value_enum());
+                    trace!(self, "Call to unknown function '{}' gives null", fn_name);
                 }
             }
             
             RazenIR::Return => {
-                if let Some(ret_val) = self.value_stack.pop() {
-                    self.builder.build_return(Some(&ret_val));
-                } else {
-                    self.builder.build_return(None);
+                match current_function.get_type().get_return_type() {
+                    Some(return_type) => {
+                        let ret_val = match self.value_stack.pop() {
+                            Some(value) => self.coerce_value(value, return_type)
+                                .map_err(|e| format!("Return type mismatch: {}", e))?,
+                            None => return_type.const_zero(),
+                        };
+                        self.builder.build_return(Some(&ret_val));
+                    }
+                    None => {
+                        self.builder.build_return(None);
+                    }
                 }
-                println!("[LLVM] Built return instruction");
+                trace!(self, "Built return instruction");
             }
             
             RazenIR::Pop => {
                 self.value_stack.pop().ok_or_else(|| "Stack underflow during Pop".to_string())?;
-                println!("[LLVM] Popped value from stack");
+                trace!(self, "Popped value from stack");
             }
             
             RazenIR::Dup => {
                 let val = self.value_stack.last().ok_or_else(|| "Stack underflow during Dup".to_string())?;
                 self.value_stack.push(*val);
-                println!("[LLVM] Duplicated top stack value");
+                trace!(self, "Duplicated top stack value");
             }
             
             RazenIR::Swap => {
@@ -343,58 +1137,120 @@ impl<'ctx> LlvmCompiler<'ctx> {
                 }
                 let len = self.value_stack.len();
                 self.value_stack.swap(len - 1, len - 2);
-                println!("[LLVM] Swapped top two stack values");
+                trace!(self, "Swapped top two stack values");
             }
             
             RazenIR::Print => {
-                // For now, just pop and discard the value
-                self.value_stack.pop().ok_or_else(|| "Stack underflow during Print".to_string())?;
-                println!("[LLVM] Print instruction (value discarded)");
+                let value = self.value_stack.pop().ok_or_else(|| "Stack underflow during Print".to_string())?;
+                let boxed = self.build_box(value)?;
+                self.call_runtime("rz_print", &[boxed.into()])?;
+                trace!(self, "Print instruction");
+            }
+
+            RazenIR::ReadInput => {
+                let line = self.call_runtime_value("rz_read_line", &[])?;
+                self.value_stack.push(line);
+                trace!(self, "Read line from stdin");
+            }
+
+            RazenIR::Sleep => {
+                let seconds = self.value_stack.pop().ok_or_else(|| "Stack underflow during Sleep".to_string())?;
+                let boxed = self.build_box(seconds)?;
+                self.call_runtime("rz_sleep", &[boxed.into()])?;
+                trace!(self, "Sleep instruction");
             }
             
             RazenIR::Exit => {
                 // Create a call to exit(0)
-                let exit_fn = self.module.add_function("exit", 
-                    self.context.i32_type().fn_type(&[self.context.i32_type().into()], false), 
-                    None
-                );
+                let exit_fn = self.module.get_function("exit").unwrap_or_else(|| {
+                    self.module.add_function("exit",
+                        self.context.i32_type().fn_type(&[self.context.i32_type().into()], false),
+                        None
+                    )
+                });
                 let exit_code = self.context.i32_type().const_int(0, false);
                 self.builder.build_call(exit_fn, &[exit_code.into()], "exit");
                 self.builder.build_unreachable();
-                println!("[LLVM] Added exit call");
+                trace!(self, "Added exit call");
+            }
+
+            RazenIR::ThrowException => {
+                // There are no handlers in native code yet, so a throw ends the program
+                let value = self.value_stack.pop().ok_or_else(|| "Stack underflow during ThrowException".to_string())?;
+                let boxed = self.build_box(value)?;
+                self.call_runtime("rz_throw", &[boxed.into()])?;
+                self.builder.build_unreachable();
+                trace!(self, "Throw instruction");
+            }
+
+            RazenIR::CreateArray(count) => {
+                let mut items = Vec::with_capacity(*count);
+                for _ in 0..*count {
+                    items.push(self.value_stack.pop().ok_or_else(|| "Stack underflow during CreateArray".to_string())?);
+                }
+                items.reverse();
+                let capacity = self.context.i64_type().const_int(*count as u64, false);
+                let array = self.call_runtime_value("rz_array_new", &[capacity.into()])?;
+                for item in items {
+                    let boxed = self.build_box(item)?;
+                    self.call_runtime("rz_array_push", &[array.into(), boxed.into()])?;
+                }
+                self.value_stack.push(array);
+                trace!(self, "Created array with {} elements", count);
+            }
+
+            RazenIR::CreateMap(count) => {
+                let mut entries = Vec::with_capacity(*count);
+                for _ in 0..*count {
+                    let value = self.value_stack.pop().ok_or_else(|| "Stack underflow during CreateMap".to_string())?;
+                    let key = self.value_stack.pop().ok_or_else(|| "Stack underflow during CreateMap".to_string())?;
+                    entries.push((key, value));
+                }
+                entries.reverse();
+                let map = self.call_runtime_value("rz_map_new", &[])?;
+                for (key, value) in entries {
+                    let key = self.build_box(key)?;
+                    let value = self.build_box(value)?;
+                    self.call_runtime("rz_map_insert", &[map.into(), key.into(), value.into()])?;
+                }
+                self.value_stack.push(map);
+                trace!(self, "Created map with {} entries", count);
+            }
+
+            RazenIR::GetIndex | RazenIR::GetKey => {
+                let (container, index) = self.pop_operands(&format!("{:?}", instruction))?;
+                let container = self.build_box(container)?;
+                let index = self.build_box(index)?;
+                let runtime_fn = if matches!(instruction, RazenIR::GetIndex) { "rz_get_index" } else { "rz_get_key" };
+                let result = self.call_runtime_value(runtime_fn, &[container.into(), index.into()])?;
+                self.value_stack.push(result);
+                trace!(self, "Performed {:?}", instruction);
+            }
+
+            RazenIR::SetIndex | RazenIR::SetKey => {
+                let value = self.value_stack.pop().ok_or_else(|| format!("Stack underflow during {:?}", instruction))?;
+                let (container, index) = self.pop_operands(&format!("{:?}", instruction))?;
+                let container = self.build_box(container)?;
+                let index = self.build_box(index)?;
+                let value = self.build_box(value)?;
+                let runtime_fn = if matches!(instruction, RazenIR::SetIndex) { "rz_set_index" } else { "rz_set_key" };
+                let result = self.call_runtime_value(runtime_fn, &[container.into(), index.into(), value.into()])?;
+                self.value_stack.push(result);
+                trace!(self, "Performed {:?}", instruction);
             }
             
             // Handle other IR instructions that exist in your codebase
-            RazenIR::SetupTryCatch |
+            RazenIR::SetupTryCatch(_) |
             RazenIR::ClearTryCatch |
-            RazenIR::ThrowException |
-            RazenIR::SetGlobal(_) |
-            RazenIR::Modulo |
-            RazenIR::Power |
-            RazenIR::FloorDiv |
-            RazenIR::Negate |
-            RazenIR::Equal |
-            RazenIR::NotEqual |
-            RazenIR::GreaterThan |
-            RazenIR::GreaterEqual |
-            RazenIR::LessThan |
-            RazenIR::LessEqual |
-            RazenIR::And |
-            RazenIR::Or |
-            RazenIR::Not |
-            RazenIR::Jump(_) |
-            RazenIR::JumpIfFalse(_) |
-            RazenIR::JumpIfTrue(_) |
-            RazenIR::ReadInput |
-            RazenIR::CreateArray(_) |
-            RazenIR::GetIndex |
-            RazenIR::SetIndex |
-            RazenIR::CreateMap(_) |
-            RazenIR::GetKey |
-            RazenIR::SetKey |
             RazenIR::DefineFunction(_, _) |
-            RazenIR::Label(_) |
-            RazenIR::Sleep |
+            RazenIR::StoreLocal(_) |
+            RazenIR::LoadLocal(_) |
+            RazenIR::StoreCapture(_) |
+            RazenIR::LoadCapture(_) |
+            RazenIR::MakeClosure(_, _) |
+            RazenIR::CallValue(_) |
+            RazenIR::CallMethod(_, _) |
+            RazenIR::DefineClass(_, _) |
             RazenIR::LibraryCall(_, _, _) => {
                 return Err(format!("Unsupported Razen IR instruction for LLVM: {:?}", instruction));
             }
@@ -402,6 +1258,448 @@ impl<'ctx> LlvmCompiler<'ctx> {
         Ok(())
     }
 
+    fn current_block_terminated(&self) -> bool {
+        self.builder.get_insert_block()
+            .and_then(|block| block.get_terminator())
+            .is_some()
+    }
+
+    fn block_at(&self, target: usize) -> Result<BasicBlock<'ctx>, String> {
+        self.blocks.get(&target).copied()
+            .ok_or_else(|| format!("No basic block for jump target {}", target))
+    }
+
+    // Pass the values on the stack to the block at `target`. Without phi nodes a value may only
+    // be used in blocks its own block dominates, so each one is boxed into a slot instead
+    fn spill_stack(&mut self, target: usize, function: FunctionValue<'ctx>) -> Result<(), String> {
+        let depth = self.value_stack.len();
+        match self.block_depths.get(&target) {
+            Some(expected) if *expected != depth => {
+                return Err(format!("Stack holds {} values on one path into {} and {} on another", depth, target, expected));
+            }
+            Some(_) => {}
+            None => { self.block_depths.insert(target, depth); }
+        }
+
+        for (index, value) in self.value_stack.clone().into_iter().enumerate() {
+            let boxed = self.build_box(value)?;
+            let slot = match self.stack_slots.get(index) {
+                Some(slot) => *slot,
+                None => {
+                    let slot = self.create_entry_block_alloca(&format!("stack_{}", index), self.dynamic_type().into(), function)?;
+                    self.stack_slots.push(slot);
+                    slot
+                }
+            };
+            self.builder.build_store(slot, boxed);
+        }
+        Ok(())
+    }
+
+    // Start a block with the stack values spilled by the jumps into it, boxed. A block no jump
+    // has reached yet starts empty
+    fn reload_stack(&mut self, target: usize) {
+        let depth = *self.block_depths.entry(target).or_insert(0);
+        let dynamic = self.dynamic_type();
+        self.value_stack = self.stack_slots[..depth].iter()
+            .map(|slot| self.builder.build_load(dynamic, *slot, "stackval"))
+            .collect();
+    }
+
+    // Pop the two operands of a binary operation, returning them as (lhs, rhs)
+    fn pop_operands(&mut self, op: &str) -> Result<(BasicValueEnum<'ctx>, BasicValueEnum<'ctx>), String> {
+        let rhs = self.value_stack.pop().ok_or_else(|| format!("Stack underflow during {} (rhs)", op))?;
+        let lhs = self.value_stack.pop().ok_or_else(|| format!("Stack underflow during {} (lhs)", op))?;
+        Ok((lhs, rhs))
+    }
+
+    // Bring two numeric operands to a common type, promoting to float when either side is a float.
+    // Booleans are widened to i64 and anything boxed sends both operands through the runtime
+    fn numeric_operands(&self, lhs: BasicValueEnum<'ctx>, rhs: BasicValueEnum<'ctx>, op: &str) -> Result<NumericOperands<'ctx>, String> {
+        let f64_type = self.context.f64_type();
+        let i64_type = self.context.i64_type();
+        match (lhs, rhs) {
+            (BasicValueEnum::PointerValue(_), _) | (_, BasicValueEnum::PointerValue(_)) => {
+                Ok(NumericOperands::Dynamic(self.build_box(lhs)?, self.build_box(rhs)?))
+            }
+            (BasicValueEnum::IntValue(l), BasicValueEnum::IntValue(r)) => {
+                let l = self.builder.build_int_z_extend_or_bit_cast(l, i64_type, "zext");
+                let r = self.builder.build_int_z_extend_or_bit_cast(r, i64_type, "zext");
+                Ok(NumericOperands::Int(l, r))
+            }
+            (BasicValueEnum::FloatValue(l), BasicValueEnum::FloatValue(r)) => Ok(NumericOperands::Float(l, r)),
+            (BasicValueEnum::IntValue(l), BasicValueEnum::FloatValue(r)) => {
+                Ok(NumericOperands::Float(self.builder.build_signed_int_to_float(l, f64_type, "itof"), r))
+            }
+            (BasicValueEnum::FloatValue(l), BasicValueEnum::IntValue(r)) => {
+                Ok(NumericOperands::Float(l, self.builder.build_signed_int_to_float(r, f64_type, "itof")))
+            }
+            _ => Err(format!("Type mismatch in {} operation", op)),
+        }
+    }
+
+    fn build_comparison(&self, instruction: &RazenIR, lhs: BasicValueEnum<'ctx>, rhs: BasicValueEnum<'ctx>) -> Result<IntValue<'ctx>, String> {
+        let int_predicate = match instruction {
+            RazenIR::Equal => IntPredicate::EQ,
+            RazenIR::NotEqual => IntPredicate::NE,
+            RazenIR::GreaterThan => IntPredicate::SGT,
+            RazenIR::GreaterEqual => IntPredicate::SGE,
+            RazenIR::LessThan => IntPredicate::SLT,
+            _ => IntPredicate::SLE,
+        };
+
+        match self.numeric_operands(lhs, rhs, "comparison")? {
+            NumericOperands::Int(l, r) => Ok(self.builder.build_int_compare(int_predicate, l, r, "cmptmp")),
+            NumericOperands::Float(l, r) => {
+                let predicate = match instruction {
+                    RazenIR::Equal => FloatPredicate::OEQ,
+                    RazenIR::NotEqual => FloatPredicate::UNE,
+                    RazenIR::GreaterThan => FloatPredicate::OGT,
+                    RazenIR::GreaterEqual => FloatPredicate::OGE,
+                    RazenIR::LessThan => FloatPredicate::OLT,
+                    _ => FloatPredicate::OLE,
+                };
+                Ok(self.builder.build_float_compare(predicate, l, r, "cmptmp"))
+            }
+            NumericOperands::Dynamic(l, r) => {
+                // Equality works across all types; ordering asks the runtime for -1, 0 or 1
+                let zero = self.context.i32_type().const_zero();
+                if matches!(instruction, RazenIR::Equal | RazenIR::NotEqual) {
+                    let equal = self.call_runtime_value("rz_equal", &[l.into(), r.into()])?.into_int_value();
+                    let predicate = if matches!(instruction, RazenIR::Equal) { IntPredicate::NE } else { IntPredicate::EQ };
+                    Ok(self.builder.build_int_compare(predicate, equal, zero, "cmptmp"))
+                } else {
+                    let ordering = self.call_runtime_value("rz_compare", &[l.into(), r.into()])?.into_int_value();
+                    Ok(self.builder.build_int_compare(int_predicate, ordering, zero, "cmptmp"))
+                }
+            }
+        }
+    }
+
+    // Convert any value to an i1 using the interpreter's truthiness rules
+    fn build_truthy(&self, value: BasicValueEnum<'ctx>) -> Result<IntValue<'ctx>, String> {
+        match value {
+            BasicValueEnum::IntValue(v) if v.get_type().get_bit_width() == 1 => Ok(v),
+            BasicValueEnum::IntValue(v) => {
+                Ok(self.builder.build_int_compare(IntPredicate::NE, v, v.get_type().const_zero(), "truthy"))
+            }
+            BasicValueEnum::FloatValue(v) => {
+                Ok(self.builder.build_float_compare(FloatPredicate::UNE, v, v.get_type().const_zero(), "truthy"))
+            }
+            BasicValueEnum::PointerValue(v) => {
+                let truthy = self.call_runtime_value("rz_truthy", &[v.into()])?.into_int_value();
+                Ok(self.builder.build_int_compare(IntPredicate::NE, truthy, self.context.i32_type().const_zero(), "truthy"))
+            }
+            _ => Err(format!("Cannot use {:?} as a condition", value)),
+        }
+    }
+
+    // Abort with `message` when a numeric divisor is zero, matching the interpreter's error
+    fn build_zero_guard(&self, divisor: BasicValueEnum<'ctx>, message: &str, function: FunctionValue<'ctx>) -> Result<(), String> {
+        let is_zero = match divisor {
+            BasicValueEnum::IntValue(v) => self.builder.build_int_compare(IntPredicate::EQ, v, v.get_type().const_zero(), "iszero"),
+            BasicValueEnum::FloatValue(v) => self.builder.build_float_compare(FloatPredicate::OEQ, v, v.get_type().const_zero(), "iszero"),
+            _ => return Err(format!("Cannot check {:?} for zero", divisor)),
+        };
+        let fail_block = self.context.append_basic_block(function, "divzero");
+        let ok_block = self.context.append_basic_block(function, "divok");
+        self.builder.build_conditional_branch(is_zero, fail_block, ok_block);
+
+        self.builder.position_at_end(fail_block);
+        let message = self.builder.build_global_string_ptr(message, ".err");
+        self.call_runtime("rz_panic", &[message.as_pointer_value().into()])?;
+        self.builder.build_unreachable();
+
+        self.builder.position_at_end(ok_block);
+        Ok(())
+    }
+
+    // Add, subtract or multiply integers with one of the llvm.s*.with.overflow intrinsics. Like
+    // the interpreter, a result that overflows is worked out again in floating point, so the
+    // result is boxed: an int, or a float on overflow
+    fn build_checked_int_op(&self, intrinsic_name: &str, lhs: IntValue<'ctx>, rhs: IntValue<'ctx>, function: FunctionValue<'ctx>) -> Result<BasicValueEnum<'ctx>, String> {
+        let i64_type = self.context.i64_type();
+        let f64_type = self.context.f64_type();
+        let intrinsic = Intrinsic::find(intrinsic_name).ok_or_else(|| format!("Unknown intrinsic {}", intrinsic_name))?;
+        let declaration = intrinsic.get_declaration(&self.module, &[i64_type.into()])
+            .ok_or_else(|| format!("Failed to declare intrinsic {}", intrinsic_name))?;
+        let checked = self.builder.build_call(declaration, &[lhs.into(), rhs.into()], "checked")
+            .try_as_basic_value().left()
+            .ok_or_else(|| format!("Intrinsic {} returned no value", intrinsic_name))?
+            .into_struct_value();
+        let result = self.builder.build_extract_value(checked, 0, "checkedval")
+            .ok_or_else(|| format!("Intrinsic {} gave no result", intrinsic_name))?;
+        let overflowed = self.builder.build_extract_value(checked, 1, "overflowed")
+            .ok_or_else(|| format!("Intrinsic {} gave no overflow flag", intrinsic_name))?
+            .into_int_value();
+
+        let overflow_block = self.context.append_basic_block(function, "intoverflow");
+        let ok_block = self.context.append_basic_block(function, "intok");
+        let done_block = self.context.append_basic_block(function, "intdone");
+        self.builder.build_conditional_branch(overflowed, overflow_block, ok_block);
+
+        self.builder.position_at_end(overflow_block);
+        let l = self.builder.build_signed_int_to_float(lhs, f64_type, "itof");
+        let r = self.builder.build_signed_int_to_float(rhs, f64_type, "itof");
+        let widened = match intrinsic_name {
+            "llvm.sadd.with.overflow" => self.builder.build_float_add(l, r, "addtmp"),
+            "llvm.ssub.with.overflow" => self.builder.build_float_sub(l, r, "subtmp"),
+            _ => self.builder.build_float_mul(l, r, "multmp"),
+        };
+        let widened = self.build_box(widened.as_basic_value_enum())?;
+        self.builder.build_unconditional_branch(done_block);
+
+        self.builder.position_at_end(ok_block);
+        let exact = self.build_box(result)?;
+        self.builder.build_unconditional_branch(done_block);
+
+        self.builder.position_at_end(done_block);
+        let phi = self.builder.build_phi(self.dynamic_type(), "checkedbox");
+        phi.add_incoming(&[(&widened, overflow_block), (&exact, ok_block)]);
+        Ok(phi.as_basic_value())
+    }
+
+    // srem is undefined for i64::MIN by -1. Dividing by 1 instead gives the same remainder, 0
+    fn build_overflow_safe_divisor(&self, dividend: IntValue<'ctx>, divisor: IntValue<'ctx>) -> IntValue<'ctx> {
+        let int_type = dividend.get_type();
+        let is_min = self.builder.build_int_compare(IntPredicate::EQ, dividend, int_type.const_int(i64::MIN as u64, true), "ismin");
+        let is_minus_one = self.builder.build_int_compare(IntPredicate::EQ, divisor, int_type.const_all_ones(), "isminusone");
+        let overflows = self.builder.build_and(is_min, is_minus_one, "divoverflow");
+        self.builder.build_select(overflows, int_type.const_int(1, false), divisor, "safedivisor").into_int_value()
+    }
+
+    // Store a value into a variable's alloca, creating it with the variable's inferred kind
+    fn store_variable(&mut self, name: &str, value: BasicValueEnum<'ctx>, function: FunctionValue<'ctx>) -> Result<(), String> {
+        let (ptr_val, expected_type) = match self.variables.get(name) {
+            Some(slot) => *slot,
+            None => {
+                let kind = self.variable_kinds.get(name).copied()
+                    .unwrap_or(self.kind_of(value)?);
+                let llvm_type = self.kind_type(kind);
+                let alloca = self.create_entry_block_alloca(name, llvm_type, function)?;
+                self.declare_debug_variable(name, alloca, llvm_type, None)?;
+                self.variables.insert(name.to_string(), (alloca, llvm_type));
+                (alloca, llvm_type)
+            }
+        };
+
+        let coerced = self.coerce_value(value, expected_type)
+            .map_err(|e| format!("StoreVar type mismatch for '{}': {}", name, e))?;
+        self.builder.build_store(ptr_val, coerced);
+        Ok(())
+    }
+
+    // Convert a value to a storage type: integers widen, and anything can be boxed
+    fn coerce_value(&self, value: BasicValueEnum<'ctx>, target: BasicTypeEnum<'ctx>) -> Result<BasicValueEnum<'ctx>, String> {
+        let val_type = self.get_basic_value_type(value)?;
+        match (value, target) {
+            _ if val_type == target => Ok(value),
+            (BasicValueEnum::IntValue(v), BasicTypeEnum::IntType(int_type))
+                if v.get_type().get_bit_width() < int_type.get_bit_width() => {
+                Ok(self.builder.build_int_z_extend(v, int_type, "zext").as_basic_value_enum())
+            }
+            (BasicValueEnum::IntValue(v), BasicTypeEnum::FloatType(float_type)) if v.get_type().get_bit_width() > 1 => {
+                Ok(self.builder.build_signed_int_to_float(v, float_type, "itof").as_basic_value_enum())
+            }
+            (_, BasicTypeEnum::PointerType(_)) => Ok(self.build_box(value)?.as_basic_value_enum()),
+            (BasicValueEnum::PointerValue(v), BasicTypeEnum::IntType(int_type)) if int_type.get_bit_width() == 64 => {
+                self.call_runtime_value("rz_as_int", &[v.into()])
+            }
+            _ => Err(format!("expected {:?}, got {:?}", target, val_type)),
+        }
+    }
+
+    fn kind_of(&self, value: BasicValueEnum<'ctx>) -> Result<ValueKind, String> {
+        match value {
+            BasicValueEnum::IntValue(v) if v.get_type().get_bit_width() == 1 => Ok(ValueKind::Bool),
+            BasicValueEnum::IntValue(_) => Ok(ValueKind::Int),
+            BasicValueEnum::FloatValue(_) => Ok(ValueKind::Float),
+            BasicValueEnum::PointerValue(_) => Ok(ValueKind::Dynamic),
+            _ => Err(format!("Unsupported value {:?}", value)),
+        }
+    }
+
+    fn kind_type(&self, kind: ValueKind) -> BasicTypeEnum<'ctx> {
+        match kind {
+            ValueKind::Bool => self.context.bool_type().into(),
+            ValueKind::Int => self.context.i64_type().into(),
+            ValueKind::Float => self.context.f64_type().into(),
+            ValueKind::Dynamic => self.dynamic_type().into(),
+        }
+    }
+
+    // --- Runtime Calls ---
+
+    // Boxed runtime values are opaque pointers
+    fn dynamic_type(&self) -> PointerType<'ctx> {
+        self.context.i8_type().ptr_type(AddressSpace::default())
+    }
+
+    // Box a native value so it can be handed to the runtime; pointers are already boxed
+    fn build_box(&self, value: BasicValueEnum<'ctx>) -> Result<PointerValue<'ctx>, String> {
+        let boxed = match value {
+            BasicValueEnum::PointerValue(v) => return Ok(v),
+            BasicValueEnum::IntValue(v) if v.get_type().get_bit_width() == 1 => {
+                let flag = self.builder.build_int_z_extend(v, self.context.i32_type(), "boolext");
+                self.call_runtime_value("rz_bool", &[flag.into()])?
+            }
+            BasicValueEnum::IntValue(v) => {
+                let v = self.builder.build_int_s_extend_or_bit_cast(v, self.context.i64_type(), "intext");
+                self.call_runtime_value("rz_int", &[v.into()])?
+            }
+            BasicValueEnum::FloatValue(v) => self.call_runtime_value("rz_float", &[v.into()])?,
+            _ => return Err(format!("Cannot box {:?}", value)),
+        };
+        Ok(boxed.into_pointer_value())
+    }
+
+    // Declare (once) and return a function from the C runtime
+    fn runtime_function(&self, name: &str) -> Result<FunctionValue<'ctx>, String> {
+        if let Some(function) = self.module.get_function(name) {
+            return Ok(function);
+        }
+
+        let ptr: BasicMetadataTypeEnum = self.dynamic_type().into();
+        let i32_type = self.context.i32_type();
+        let i64_type = self.context.i64_type();
+        let void_type = self.context.void_type();
+        let dynamic = self.dynamic_type();
+
+        let fn_type = match name {
+            "rz_int" | "rz_array_new" => dynamic.fn_type(&[i64_type.into()], false),
+            "rz_float" => dynamic.fn_type(&[self.context.f64_type().into()], false),
+            "rz_bool" => dynamic.fn_type(&[i32_type.into()], false),
+            "rz_string" | "rz_neg" => dynamic.fn_type(&[ptr], false),
+            "rz_map_new" | "rz_read_line" => dynamic.fn_type(&[], false),
+            "rz_add" | "rz_sub" | "rz_mul" | "rz_div" | "rz_mod" | "rz_pow" | "rz_floor_div" |
+            "rz_get_index" | "rz_get_key" | "rz_array_rest" | "rz_comparable" => dynamic.fn_type(&[ptr, ptr], false),
+            "rz_set_index" | "rz_set_key" | "rz_check_kind" | "rz_match_array" => dynamic.fn_type(&[ptr, ptr, ptr], false),
+            "rz_array_push" => void_type.fn_type(&[ptr, ptr], false),
+            "rz_map_insert" => void_type.fn_type(&[ptr, ptr, ptr], false),
+            "rz_equal" | "rz_compare" => i32_type.fn_type(&[ptr, ptr], false),
+            "rz_truthy" => i32_type.fn_type(&[ptr], false),
+            "rz_len" | "rz_as_int" => i64_type.fn_type(&[ptr], false),
+            "rz_print" | "rz_sleep" | "rz_throw" | "rz_panic" => void_type.fn_type(&[ptr], false),
+            _ => return Err(format!("Unknown runtime function {}", name)),
+        };
+        Ok(self.module.add_function(name, fn_type, None))
+    }
+
+    fn call_runtime(&self, name: &str, args: &[BasicMetadataValueEnum<'ctx>]) -> Result<Option<BasicValueEnum<'ctx>>, String> {
+        let function = self.runtime_function(name)?;
+        // Void calls must not be given a name
+        let call_name = if function.get_type().get_return_type().is_some() { "rttmp" } else { "" };
+        Ok(self.builder.build_call(function, args, call_name).try_as_basic_value().left())
+    }
+
+    fn call_runtime_value(&self, name: &str, args: &[BasicMetadataValueEnum<'ctx>]) -> Result<BasicValueEnum<'ctx>, String> {
+        self.call_runtime(name, args)?
+            .ok_or_else(|| format!("Runtime function {} returned no value", name))
+    }
+
+    fn build_float_intrinsic(&self, name: &str, args: &[FloatValue<'ctx>]) -> Result<FloatValue<'ctx>, String> {
+        let f64_type = self.context.f64_type();
+        let intrinsic = Intrinsic::find(name).ok_or_else(|| format!("Unknown intrinsic {}", name))?;
+        let function = intrinsic.get_declaration(&self.module, &[f64_type.into()])
+            .ok_or_else(|| format!("Failed to declare intrinsic {}", name))?;
+        let call_args: Vec<BasicMetadataValueEnum<'ctx>> = args.iter().map(|arg| (*arg).into()).collect();
+        self.builder.build_call(function, &call_args, "intrinsictmp")
+            .try_as_basic_value().left()
+            .map(|value| value.into_float_value())
+            .ok_or_else(|| format!("Intrinsic {} returned no value", name))
+    }
+
+    // --- Debug Info ---
+
+    // Give a function its DWARF subprogram and point the builder at its declaration
+    fn begin_debug_function(&mut self, function: FunctionValue<'ctx>, body: &FunctionIr) -> Result<(), String> {
+        let Some(debug_info) = &self.debug_info else {
+            self.builder.unset_current_debug_location();
+            return Ok(());
+        };
+        let file = debug_info.compile_unit.get_file();
+        let return_type = function.get_type().get_return_type()
+            .ok_or_else(|| format!("Function '{}' has no return type", body.name))?;
+        let param_types = function.get_type().get_param_types().into_iter()
+            .map(|param_type| Self::debug_type(debug_info, param_type))
+            .collect::<Result<Vec<DIType<'ctx>>, String>>()?;
+        let subroutine_type = debug_info.builder.create_subroutine_type(
+            file,
+            Some(Self::debug_type(debug_info, return_type)?),
+            &param_types,
+            DIFlags::PUBLIC,
+        );
+        let position = body.position.unwrap_or(Span { line: 1, column: 1, ..Span::default() });
+        let subprogram = debug_info.builder.create_function(
+            debug_info.compile_unit.as_debug_info_scope(),
+            &body.name,
+            None,
+            file,
+            position.line as u32,
+            subroutine_type,
+            false,
+            true,
+            position.line as u32,
+            DIFlags::PUBLIC,
+            self.opt_level != OptLevel::O0,
+        );
+        function.set_subprogram(subprogram);
+        if let Some(debug_info) = &mut self.debug_info {
+            debug_info.subprogram = Some(subprogram);
+        }
+        self.set_debug_location(position);
+        Ok(())
+    }
+
+    // Attribute the instructions built from now on to a source position
+    fn set_debug_location(&self, position: Span) {
+        if let Some(DebugInfo { builder, subprogram: Some(subprogram), .. }) = &self.debug_info {
+            let location = builder.create_debug_location(
+                self.context,
+                position.line as u32,
+                position.column as u32,
+                subprogram.as_debug_info_scope(),
+                None,
+            );
+            self.builder.set_current_debug_location(location);
+        }
+    }
+
+    // Describe a variable's stack slot to the debugger under its Razen name. Parameters
+    // are numbered from 1
+    fn declare_debug_variable(&self, name: &str, slot: PointerValue<'ctx>, ty: BasicTypeEnum<'ctx>, arg_no: Option<u32>) -> Result<(), String> {
+        let Some(debug_info @ DebugInfo { builder, subprogram: Some(subprogram), .. }) = &self.debug_info else {
+            return Ok(());
+        };
+        let location = self.builder.get_current_debug_location()
+            .ok_or_else(|| format!("No source location for variable '{}'", name))?;
+        let scope = subprogram.as_debug_info_scope();
+        let file = debug_info.compile_unit.get_file();
+        let di_type = Self::debug_type(debug_info, ty)?;
+        let variable = match arg_no {
+            Some(arg_no) => builder.create_parameter_variable(scope, source_name(name), arg_no, file, location.get_line(), di_type, true, DIFlags::ZERO),
+            None => builder.create_auto_variable(scope, source_name(name), file, location.get_line(), di_type, true, DIFlags::ZERO, 0),
+        };
+        // The slot is followed by its zero initialisation in the entry block
+        let initialiser = slot.as_instruction().and_then(|alloca| alloca.get_next_instruction())
+            .ok_or_else(|| format!("Variable '{}' has no stack slot to describe", name))?;
+        builder.insert_declare_before_instruction(slot, Some(variable), None, location, initialiser);
+        Ok(())
+    }
+
+    // DWARF type of a storage type. Boxed values are shown as opaque addresses
+    fn debug_type(debug_info: &DebugInfo<'ctx>, ty: BasicTypeEnum<'ctx>) -> Result<DIType<'ctx>, String> {
+        let (name, size_in_bits, encoding) = match ty {
+            BasicTypeEnum::IntType(int_type) if int_type.get_bit_width() == 1 => ("bool", 8, DW_ATE_BOOLEAN),
+            BasicTypeEnum::IntType(_) => ("int", 64, DW_ATE_SIGNED),
+            BasicTypeEnum::FloatType(_) => ("float", 64, DW_ATE_FLOAT),
+            _ => ("value", 64, DW_ATE_ADDRESS),
+        };
+        debug_info.builder.create_basic_type(name, size_in_bits, encoding, DIFlags::ZERO)
+            .map(|basic_type| basic_type.as_type())
+            .map_err(|e| format!("Failed to create debug type {}: {}", name, e))
+    }
+
     // Helper to create an alloca in the entry block of a function
     fn create_entry_block_alloca(&self, name: &str, ty: BasicTypeEnum<'ctx>, function: FunctionValue<'ctx>) -> Result<PointerValue<'ctx>, String> {
         let temp_builder = self.context.create_builder();
@@ -411,17 +1709,543 @@ impl<'ctx> LlvmCompiler<'ctx> {
             None => temp_builder.position_at_end(entry),
         }
         
-        match ty {
-            BasicTypeEnum::IntType(int_type) => Ok(temp_builder.build_alloca(int_type, name)),
-            BasicTypeEnum::FloatType(float_type) => Ok(temp_builder.build_alloca(float_type, name)),
-            BasicTypeEnum::PointerType(ptr_type) => Ok(temp_builder.build_alloca(ptr_type, name)),
-            BasicTypeEnum::ArrayType(array_type) => Ok(temp_builder.build_alloca(array_type, name)),
-            BasicTypeEnum::StructType(struct_type) => Ok(temp_builder.build_alloca(struct_type, name)),
-            BasicTypeEnum::VectorType(vector_type) => Ok(temp_builder.build_alloca(vector_type, name)),
-        }
+        let alloca = match ty {
+            BasicTypeEnum::IntType(int_type) => temp_builder.build_alloca(int_type, name),
+            BasicTypeEnum::FloatType(float_type) => temp_builder.build_alloca(float_type, name),
+            BasicTypeEnum::PointerType(ptr_type) => temp_builder.build_alloca(ptr_type, name),
+            BasicTypeEnum::ArrayType(array_type) => temp_builder.build_alloca(array_type, name),
+            BasicTypeEnum::StructType(struct_type) => temp_builder.build_alloca(struct_type, name),
+            BasicTypeEnum::VectorType(vector_type) => temp_builder.build_alloca(vector_type, name),
+        };
+        // Variables read before their first assignment see zero (null for boxed values)
+        temp_builder.build_store(alloca, ty.const_zero());
+        Ok(alloca)
     }
 
     pub fn dump_module(&self) {
         self.module.print_to_stderr();
     }
-}
\ No newline at end of file
+
+    // --- Native Code Emission ---
+
+    // Create the target machine described by the target options
+    fn create_target_machine(&self) -> Result<TargetMachine, String> {
+        let config = InitializationConfig::default();
+        if self.target.is_host() {
+            Target::initialize_native(&config)
+                .map_err(|e| format!("Failed to initialize native target: {}", e))?;
+        } else {
+            Target::initialize_all(&config);
+        }
+
+        let triple = TargetTriple::create(&self.target.triple());
+        let target = Target::from_triple(&triple)
+            .map_err(|e| format!("Unsupported target triple {}: {}", triple.as_str().to_string_lossy(), e))?;
+        // Host CPU details only apply when compiling for the host
+        let (cpu, features) = if self.target.is_host() {
+            (TargetMachine::get_host_cpu_name().to_string(), TargetMachine::get_host_cpu_features().to_string())
+        } else {
+            ("generic".to_string(), String::new())
+        };
+        let cpu = self.target.cpu.clone().unwrap_or(cpu);
+        let features = self.target.features.clone().unwrap_or(features);
+
+        target.create_target_machine(
+            &triple,
+            &cpu,
+            &features,
+            self.opt_level.codegen_level(),
+            RelocMode::PIC,
+            CodeModel::Default,
+        ).ok_or_else(|| format!("Failed to create target machine for {} (cpu {})", triple.as_str().to_string_lossy(), cpu))
+    }
+
+    // Create the target machine and record its triple and data layout in the module
+    fn prepare_module(&self) -> Result<TargetMachine, String> {
+        let target_machine = self.create_target_machine()?;
+        self.module.set_triple(&target_machine.get_triple());
+        self.module.set_data_layout(&target_machine.get_target_data().get_data_layout());
+        Ok(target_machine)
+    }
+
+    // Run the module pass pipeline for the optimization level
+    fn optimize(&self) -> Result<(), String> {
+        let target_machine = self.prepare_module()?;
+        let pipeline = self.opt_level.pipeline();
+        self.module.run_passes(pipeline, &target_machine, PassBuilderOptions::create())
+            .map_err(|e| format!("Failed to run pass pipeline {}: {}", pipeline, e))?;
+        trace!(self, "Ran pass pipeline {}", pipeline);
+        Ok(())
+    }
+
+    // Emit the module as a native object file for the target
+    pub fn write_object_file(&self, path: &Path) -> Result<(), String> {
+        self.write_output(EmitKind::Obj, path)
+    }
+
+    // Write the module as textual IR, bitcode, assembly or an object file. Executables are
+    // produced by linking an object file with `link_executable`
+    pub fn write_output(&self, kind: EmitKind, path: &Path) -> Result<(), String> {
+        let target_machine = self.prepare_module()?;
+        match kind {
+            EmitKind::LlvmIr => self.module.print_to_file(path).map_err(|e| e.to_string()),
+            EmitKind::Bitcode => if self.module.write_bitcode_to_path(path) {
+                Ok(())
+            } else {
+                Err("LLVM could not write the bitcode".to_string())
+            },
+            EmitKind::Asm => target_machine.write_to_file(&self.module, FileType::Assembly, path).map_err(|e| e.to_string()),
+            EmitKind::Obj => target_machine.write_to_file(&self.module, FileType::Object, path).map_err(|e| e.to_string()),
+            EmitKind::Exe => Err("executables are linked from an object file".to_string()),
+        }.map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+        trace!(self, "Wrote {} output {:?} to {}", self.target.triple(), kind, path.display());
+        Ok(())
+    }
+
+    // Run `main` in-process with LLVM's JIT. Runtime calls resolve to the copy of the
+    // runtime linked into the compiler; the program's exit code is returned
+    pub fn run_jit(&self) -> Result<i64, String> {
+        Target::initialize_native(&InitializationConfig::default())
+            .map_err(|e| format!("Failed to initialize native target: {}", e))?;
+        let engine = self.module.create_jit_execution_engine(self.opt_level.codegen_level())
+            .map_err(|e| format!("Failed to create JIT execution engine: {}", e))?;
+
+        for (name, address) in runtime::symbols() {
+            if let Some(function) = self.module.get_function(name) {
+                engine.add_global_mapping(&function, address);
+            }
+        }
+
+        let main = unsafe { engine.get_function::<unsafe extern "C" fn() -> i64>("main") }
+            .map_err(|e| format!("Failed to find main in JIT module: {}", e))?;
+        trace!(self, "Running main through the JIT");
+        let exit_code = unsafe { main.call() };
+        runtime::flush_output();
+        Ok(exit_code)
+    }
+
+    // Link an object file and the Razen runtime into an executable with the C compiler for
+    // `triple`. Targets other than the host need a cross toolchain such as aarch64-linux-gnu-gcc
+    pub fn link_executable(object_path: &Path, output_path: &Path, triple: &str) -> Result<(), String> {
+        let host = TargetOptions::default().triple();
+        let compiler = cc::Build::new()
+            .target(triple)
+            .host(&host)
+            .opt_level(2)
+            .cargo_metadata(false)
+            .try_get_compiler()
+            .map_err(|e| format!("Failed to find a C compiler for linking: {}", e))?;
+
+        // The runtime ships inside the compiler and is built alongside every program
+        let runtime_path = std::env::temp_dir().join(format!("razen_runtime_{}.c", std::process::id()));
+        std::fs::write(&runtime_path, RUNTIME_SOURCE)
+            .map_err(|e| format!("Failed to write runtime source {}: {}", runtime_path.display(), e))?;
+
+        let mut command = compiler.to_command();
+        command.arg(object_path).arg(&runtime_path).arg("-o").arg(output_path);
+        if !compiler.is_like_msvc() {
+            // Math intrinsics such as llvm.pow lower to libm calls
+            command.arg("-lm");
+        }
+        let status = command.status();
+        let _ = std::fs::remove_file(&runtime_path);
+        let status = status.map_err(|e| format!("Failed to run linker {}: {}", compiler.path().display(), e))?;
+
+        if status.success() {
+            println!("[LLVM] Linked executable {}", output_path.display());
+            Ok(())
+        } else {
+            Err(format!("Linker exited with {}", status))
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_variable_kinds_widen_across_stores() {
+        let ir = vec![
+            RazenIR::PushNumber(1.0),
+            RazenIR::StoreVar("n".to_string()),
+            RazenIR::LoadVar("n".to_string()),
+            RazenIR::PushNumber(0.5),
+            RazenIR::Add,
+            RazenIR::StoreVar("n".to_string()),
+            RazenIR::PushNumber(1.0),
+            RazenIR::StoreVar("s".to_string()),
+            RazenIR::PushString("text".to_string()),
+            RazenIR::StoreVar("s".to_string()),
+            RazenIR::PushBoolean(true),
+            RazenIR::StoreVar("b".to_string()),
+        ];
+        let kinds = infer_variable_kinds(&[], &ir);
+        assert_eq!(kinds["n"], ValueKind::Float);
+        assert_eq!(kinds["s"], ValueKind::Dynamic);
+        assert_eq!(kinds["b"], ValueKind::Bool);
+    }
+
+    #[test]
+    fn test_jit_runs_main() {
+        let context = Context::create();
+        let mut compiler = LlvmCompiler::new(&context, "jit_test", OptLevel::O2);
+        compiler.set_clean_output(true);
+        let ir = vec![
+            RazenIR::PushNumber(6.0),
+            RazenIR::StoreVar("x".to_string()),
+            RazenIR::LoadVar("x".to_string()),
+            RazenIR::PushNumber(7.0),
+            RazenIR::Multiply,
+            RazenIR::Return,
+        ];
+        compiler.compile_function("main", vec![], RazenValue::Int(0), &ir).unwrap();
+        assert_eq!(compiler.run_jit(), Ok(42));
+    }
+
+    // Compile top-level code into a verified `main` and run it through the JIT. The exit code
+    // is the final value of the program's `result` variable
+    fn run_program(source: &str) -> Result<i64, String> {
+        let mut parser = crate::parser::Parser::new(crate::lexer::Lexer::new(source.to_string()));
+        let mut razen_compiler = crate::compiler::Compiler::new();
+        razen_compiler.set_clean_output(true);
+        razen_compiler.compile_program(parser.parse_program());
+        let mut ir = razen_compiler.ir.clone();
+        ir.extend([RazenIR::LoadVar("result".to_string()), RazenIR::Return]);
+
+        let context = Context::create();
+        let mut compiler = LlvmCompiler::new(&context, "control_flow_test", OptLevel::O0);
+        compiler.set_clean_output(true);
+        compiler.compile_function("main", vec![], RazenValue::Int(0), &ir)?;
+        compiler.run_jit()
+    }
+
+    // Compile a whole program, with its functions, the way `razen run --jit` and `razen compile` do
+    fn compile_source<'ctx>(context: &'ctx Context, source: &str, debug_source: Option<&Path>) -> Result<LlvmCompiler<'ctx>, String> {
+        let mut parser = crate::parser::Parser::new(crate::lexer::Lexer::new(source.to_string()));
+        let mut razen_compiler = crate::compiler::Compiler::new();
+        razen_compiler.set_clean_output(true);
+        razen_compiler.compile_program(parser.parse_program());
+
+        let mut compiler = LlvmCompiler::new(context, "program_test", OptLevel::O0);
+        compiler.set_clean_output(true);
+        if let Some(path) = debug_source {
+            compiler.enable_debug_info(path);
+        }
+        compiler.compile_program(&razen_compiler.ir, razen_compiler.source_map(), razen_compiler.function_param_names(), razen_compiler.function_locals())?;
+        Ok(compiler)
+    }
+
+    // Link a whole program into an executable and run it, giving its output. None when there
+    // is no C compiler to link with
+    fn native_output(name: &str, source: &str) -> Option<String> {
+        let context = Context::create();
+        let compiler = compile_source(&context, source, None).unwrap();
+        let base = std::env::temp_dir().join(format!("razen_{}_{}", name, std::process::id()));
+        let object_path = base.with_extension("o");
+        let executable = base.with_extension(std::env::consts::EXE_EXTENSION);
+        compiler.write_object_file(&object_path).unwrap();
+        let linked = LlvmCompiler::link_executable(&object_path, &executable, &TargetOptions::default().triple());
+        let _ = fs::remove_file(&object_path);
+        linked.ok()?;
+        let output = std::process::Command::new(&executable).output().unwrap();
+        let _ = fs::remove_file(&executable);
+        // `show` ends each line with a carriage return after the newline
+        Some(String::from_utf8_lossy(&output.stdout).replace('\r', ""))
+    }
+
+    #[test]
+    fn test_control_flow_runs_natively() {
+        let branches = r#"
+            num result = 0;
+            num x = 5;
+            if (x > 3 && x < 10) { result = 1; } else { result = 2; }
+            if (x < 3 || x == 5) { result = result * 10; }
+            if (!(x != 5) && true) { result = result + 3; }
+        "#;
+        assert_eq!(run_program(branches), Ok(13));
+
+        let loops = r#"
+            num result = 0;
+            num i = 0;
+            while (i < 10 || false) {
+                i = i + 1;
+                if (i % 2 == 0) { continue; }
+                if (i > 7 && !false) { break; }
+                result = result + i;
+            }
+        "#;
+        assert_eq!(run_program(loops), Ok(1 + 3 + 5 + 7));
+    }
+
+    #[test]
+    fn test_stack_values_cross_blocks() {
+        // 40 stays on the stack while a branch picks what to add to it
+        let ir = |condition: bool| vec![
+            RazenIR::PushNumber(40.0),
+            RazenIR::PushBoolean(condition),
+            RazenIR::JumpIfFalse(5),
+            RazenIR::PushNumber(2.0),
+            RazenIR::Jump(6),
+            RazenIR::PushNumber(3.0),
+            RazenIR::Add,
+            RazenIR::PushNumber(42.0),
+            RazenIR::Equal,
+            RazenIR::Return,
+        ];
+        for (condition, expected) in [(true, 1), (false, 0)] {
+            let context = Context::create();
+            let mut compiler = LlvmCompiler::new(&context, "stack_test", OptLevel::O0);
+            compiler.set_clean_output(true);
+            compiler.compile_function("main", vec![], RazenValue::Int(0), &ir(condition)).unwrap();
+            assert_eq!(compiler.run_jit(), Ok(expected));
+        }
+
+        // Paths that meet with different stack depths cannot be compiled
+        let context = Context::create();
+        let mut compiler = LlvmCompiler::new(&context, "stack_test", OptLevel::O0);
+        compiler.set_clean_output(true);
+        let unbalanced = [
+            RazenIR::PushBoolean(true),
+            RazenIR::JumpIfFalse(4),
+            RazenIR::PushNumber(1.0),
+            RazenIR::Jump(4),
+            RazenIR::PushNumber(0.0),
+            RazenIR::Return,
+        ];
+        assert!(compiler.compile_function("main", vec![], RazenValue::Int(0), &unbalanced).is_err());
+    }
+
+    #[test]
+    fn test_integer_division_edge_cases() {
+        let source = r#"
+            num smallest = -4611686018427387904 * 2;
+            num minus_one = -1;
+            num result = 0;
+            if (smallest % minus_one == 0) { result = result + 1; }
+            if (smallest // minus_one == 9223372036854775808) { result = result + 1; }
+            if (-7 // 2 == -4 && -7 % 2 == -1) { result = result + 1; }
+        "#;
+        assert_eq!(run_program(source), Ok(3));
+    }
+
+    #[test]
+    fn test_division_matches_interpreter() {
+        // An exact quotient keeps every digit of an integer, which a float would round away
+        let source = r#"
+            num big = 4611686018427387904 + 1;
+            num smallest = -4611686018427387904 * 2;
+            num result = 0;
+            if (big / 1 - (big - 1) == 1) { result = result + 1; }
+            if (6 / 3 == 2 && 7 / 2 == 3.5 && 7.5 / 2.5 == 3) { result = result + 1; }
+            if (smallest / -1 == 9223372036854775808) { result = result + 1; }
+        "#;
+        assert_eq!(run_program(source), Ok(3));
+    }
+
+    #[test]
+    fn test_jit_checks_declared_kinds() {
+        // The value returned by `any` is only known at runtime, so the store calls rz_check_kind
+        let context = Context::create();
+        let compiler = compile_source(&context, "fun any(x) { return x; }\nnum a = 1;\na = any(5);", None).unwrap();
+        assert_eq!(compiler.run_jit(), Ok(0));
+    }
+
+    #[test]
+    fn test_patterns_match_natively() {
+        let source = r#"
+            fun describe(x) {
+                when x {
+                    [] => { return "empty"; },
+                    [a, b] => { return "pair " + a + " " + b; },
+                    [first, ..rest] => { return "first " + first + " then " + len(rest); },
+                    1..=9 => { return "digit"; },
+                    "text" => { return "string"; },
+                    _ => { return "other"; },
+                }
+            }
+            show describe([]);
+            show describe([1, 2]);
+            show describe([1, 2, 3, 4]);
+            show describe(5);
+            show describe("text");
+            show describe(true);
+        "#;
+        if let Some(output) = native_output("patterns", source) {
+            assert_eq!(output, "empty\npair 1 2\nfirst 1 then 3\ndigit\nstring\nother\n");
+        }
+    }
+
+    #[test]
+    fn test_integer_overflow_matches_interpreter() {
+        // Integers that overflow become floats, whether worked out inline or by the runtime
+        // for the boxed parameters of a function
+        let source = r#"
+            fun twice(n) { return n + n; }
+            fun square(n) { return n * n; }
+            fun less(a, b) { return a - b; }
+            fun flip(n) { return -n; }
+            num x = 3037000500;
+            num big = 4611686018427387904;
+            num smallest = -4611686018427387904 * 2;
+            show x * x;
+            show big + big;
+            show smallest - 1;
+            show -smallest;
+            show square(x);
+            show twice(big);
+            show less(smallest, 1);
+            show flip(smallest);
+            show 6 * 7 - 2 + -3;
+        "#;
+        let expected = "9223372037000250000\n9223372036854776000\n-9223372036854776000\n9223372036854776000\n";
+        if let Some(output) = native_output("overflow", source) {
+            assert_eq!(output, format!("{}{}37\n", expected, expected));
+        }
+
+        let source = r#"
+            num big = 4611686018427387904;
+            num result = 0;
+            if (big + big == 9223372036854775808) { result = result + 1; }
+            if (-big - big - big < -9223372036854775807) { result = result + 1; }
+            if (big * 4 / 4 == big) { result = result + 1; }
+            if (2 + 3 * 4 - -1 == 15) { result = result + 1; }
+        "#;
+        assert_eq!(run_program(source), Ok(4));
+    }
+
+    #[test]
+    fn test_split_functions_renumbers_jumps() {
+        // fun double(x) { return x * 2; }  show double(4);
+        let ir = vec![
+            RazenIR::Jump(8),
+            RazenIR::Label("function_0".to_string()),
+            RazenIR::DefineFunction("double".to_string(), 1),
+            RazenIR::LoadLocal(0),
+            RazenIR::PushNumber(2.0),
+            RazenIR::Multiply,
+            RazenIR::Return,
+            RazenIR::Return,
+            RazenIR::Label("end_1".to_string()),
+            RazenIR::PushNumber(4.0),
+            RazenIR::Call("double".to_string(), 1),
+            RazenIR::JumpIfFalse(13),
+            RazenIR::Print,
+            RazenIR::Label("end_2".to_string()),
+        ];
+        let params = HashMap::from([("double".to_string(), vec!["x".to_string()])]);
+        let locals = params.clone();
+        let (main, functions) = split_functions(&ir, &[], &params, &locals).unwrap();
+
+        assert_eq!(main.body.len(), 5);
+        assert!(matches!(main.body[2], RazenIR::JumpIfFalse(4)));
+        assert_eq!(functions.len(), 1);
+        assert_eq!(functions[0].name, "double");
+        assert_eq!(functions[0].params, vec!["x#0".to_string()]);
+        assert!(matches!(&functions[0].body[0], RazenIR::LoadVar(name) if name == "x#0"));
+        assert_eq!(functions[0].body.len(), 5);
+    }
+
+    #[test]
+    fn test_object_file_links_and_runs() {
+        let context = Context::create();
+        let mut compiler = LlvmCompiler::new(&context, "object_test", OptLevel::O2);
+        compiler.set_clean_output(true);
+        let ir = vec![
+            RazenIR::PushString("hello from razen".to_string()),
+            RazenIR::Print,
+            RazenIR::PushNumber(0.0),
+            RazenIR::Return,
+        ];
+        compiler.compile_function("main", vec![], RazenValue::Int(0), &ir).unwrap();
+
+        let base = std::env::temp_dir().join(format!("razen_object_test_{}", std::process::id()));
+        let object_path = base.with_extension("o");
+        compiler.write_object_file(&object_path).unwrap();
+        let object = fs::read(&object_path).unwrap();
+
+        // ELF, Mach-O (64-bit, little-endian) or COFF for x86-64 or ARM64
+        let valid_header = object.starts_with(b"\x7fELF")
+            || object.starts_with(&[0xcf, 0xfa, 0xed, 0xfe])
+            || object.starts_with(&[0x64, 0x86])
+            || object.starts_with(&[0x64, 0xaa]);
+        assert!(valid_header, "Unexpected object header {:02x?}", &object[..object.len().min(4)]);
+
+        // Linking needs a C compiler, which not every machine running the tests has
+        let executable = base.with_extension(std::env::consts::EXE_EXTENSION);
+        let triple = TargetOptions::default().triple();
+        if LlvmCompiler::link_executable(&object_path, &executable, &triple).is_ok() {
+            let output = std::process::Command::new(&executable).output().unwrap();
+            let _ = fs::remove_file(&executable);
+            assert!(output.status.success());
+            assert_eq!(String::from_utf8_lossy(&output.stdout).trim_end(), "hello from razen");
+        }
+        let _ = fs::remove_file(&object_path);
+    }
+
+    #[test]
+    fn test_debug_info() {
+        let context = Context::create();
+        let source = "fun double(x) {\n    return x * 2;\n}\nshow double(4);\n";
+        let compiler = compile_source(&context, source, Some(Path::new("debug_test.rzn"))).unwrap();
+        let ir = compiler.module.print_to_string().to_string();
+
+        assert!(ir.contains("!DICompileUnit("), "no compile unit in:\n{}", ir);
+        assert!(ir.contains("!DIFile(filename: \"debug_test.rzn\""), "no source file in:\n{}", ir);
+        assert!(ir.contains("!DISubprogram(name: \"double\""), "no subprogram for double in:\n{}", ir);
+        assert!(ir.contains("!DISubprogram(name: \"main\""), "no subprogram for main in:\n{}", ir);
+        // The return statement and the call to double carry their source lines
+        assert!(ir.contains("!DILocation(line: 2,"), "no location for line 2 in:\n{}", ir);
+        assert!(ir.contains("!DILocation(line: 4,"), "no location for line 4 in:\n{}", ir);
+    }
+
+    #[test]
+    fn test_cross_target_object_header() {
+        let context = Context::create();
+        let mut compiler = LlvmCompiler::new(&context, "cross_test", OptLevel::O0);
+        compiler.set_clean_output(true);
+        compiler.set_target(TargetOptions {
+            triple: Some("aarch64-unknown-linux-gnu".to_string()),
+            ..TargetOptions::default()
+        });
+        compiler.compile_function("main", vec![], RazenValue::Int(0), &[RazenIR::PushNumber(0.0), RazenIR::Return]).unwrap();
+
+        let path = std::env::temp_dir().join(format!("razen_cross_test_{}.o", std::process::id()));
+        compiler.write_object_file(&path).unwrap();
+        let object = fs::read(&path).unwrap();
+        let _ = fs::remove_file(&path);
+
+        // 64-bit ELF with e_machine EM_AARCH64
+        assert_eq!(&object[..5], b"\x7fELF\x02");
+        assert_eq!(u16::from_le_bytes([object[18], object[19]]), 183);
+    }
+
+    #[test]
+    fn test_module_pipeline_inlines_functions() {
+        // fun double(x) { return x * 2; }  show double(4);
+        let ir = vec![
+            RazenIR::Jump(7),
+            RazenIR::Label("function_0".to_string()),
+            RazenIR::DefineFunction("double".to_string(), 1),
+            RazenIR::LoadLocal(0),
+            RazenIR::PushNumber(2.0),
+            RazenIR::Multiply,
+            RazenIR::Return,
+            RazenIR::Label("end_1".to_string()),
+            RazenIR::PushNumber(4.0),
+            RazenIR::Call("double".to_string(), 1),
+            RazenIR::Print,
+        ];
+        let params = HashMap::from([("double".to_string(), vec!["x".to_string()])]);
+        let locals = params.clone();
+        let compiled_ir = |opt_level: OptLevel| {
+            let context = Context::create();
+            let mut compiler = LlvmCompiler::new(&context, "pipeline_test", opt_level);
+            compiler.set_clean_output(true);
+            compiler.compile_program(&ir, &[], &params, &locals).unwrap();
+            compiler.module.print_to_string().to_string()
+        };
+
+        assert!(compiled_ir(OptLevel::O0).contains("call ptr @double"));
+        assert!(!compiled_ir(OptLevel::O3).contains("call ptr @double"));
+    }
+}
@@ -3,11 +3,17 @@ mod ast;
 mod lexer;
 mod parser;
 mod compiler;
+mod checker;
+mod diagnostic;
+mod bytecode;
+mod disasm;
+mod optimizer;
 mod syntax;
 mod value;
 mod functions;
 mod library;
 mod llvm;
+mod runtime;
 
 use std::env;
 use std::path::Path;
@@ -16,24 +22,64 @@ use std::fs;
 use std::io;
 use std::time::Instant;
 
-use crate::llvm::LlvmCompiler;
-use crate::value::Value as RazenValue; // Assuming RazenValue is needed for return type
+use crate::compiler::CompileOptions;
+use crate::diagnostic::ErrorFormat;
+use crate::llvm::{EmitKind, LlvmCompiler, OptLevel, TargetOptions};
 use inkwell::context::Context;
 
 fn print_usage() {
     println!("Usage: razen <command> [args]\n");
     println!("Commands:");
-    println!("  compile <file>     Compile a Razen source file to machine code");
+    println!("  compile <file> [out]  Compile a Razen source file to an executable (or the --emit output)");
     println!("  run <file>         Compile and execute a Razen source file");
+    println!("  run --jit <file>   Compile a Razen source file to native code and run it in-process");
+    println!("  build <file> [out] Compile a Razen source file to a .rzb bytecode file");
+    println!("  exec <file.rzb>    Run a bytecode file built with 'build'");
+    println!("  disasm <file|.rzb> Print the IR listing of a source or bytecode file");
+    println!("  check <file>       Report every error in a source file without running it");
     println!("  test [dir|file]    Run tests in the specified directory or file");
     println!("  help               Display this help message");
     println!("\nOptions:");
     println!("  --debug            Enable debug mode with additional output");
     println!("  --clean-output     Only show program output (no IR or debug info)");
+    println!("  -g                 Emit DWARF debug info for gdb/lldb when compiling");
+    println!("  --target <triple>  Compile for another target, e.g. aarch64-unknown-linux-gnu");
+    println!("  --cpu <name>       Target CPU to generate code for (default: host CPU, or generic)");
+    println!("  --features <list>  Target CPU features, e.g. +neon,+crc");
+    println!("  -O0 -O1 -O2 -O3 -Os  LLVM optimization level (default: -O2, or -O0 with -g or --debug)");
+    println!("  --opt-level <n>    Same as -O<n>, with n one of 0, 1, 2, 3 or s");
+    println!("  --emit=<kind>      Output kind: llvm-ir, bitcode, asm, obj or exe (default: from the output extension)");
+    println!("  --no-opt           Skip the IR optimization passes, to compare behavior");
+    println!("  --cfg              With disasm, print the control-flow graph in DOT format instead");
+    println!("  --error-format=<f> How to print errors and warnings: human or json (default: human)");
+}
+
+// Exit with an error message when a command-line value is invalid
+fn parse_or_exit<T>(result: Result<T, String>) -> T {
+    result.unwrap_or_else(|e| {
+        println!("Error: {}", e);
+        process::exit(1);
+    })
+}
+
+// Remove `name <value>` or `name=<value>` from the arguments and return the value
+fn take_option(args: &mut Vec<String>, name: &str) -> Option<String> {
+    let prefix = format!("{}=", name);
+    let index = args.iter().position(|arg| arg == name || arg.starts_with(&prefix))?;
+    let arg = args.remove(index);
+    if let Some(value) = arg.strip_prefix(&prefix) {
+        return Some(value.to_string());
+    }
+    if index < args.len() {
+        Some(args.remove(index))
+    } else {
+        println!("Error: Missing value for {}", name);
+        process::exit(1);
+    }
 }
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
+    let mut args: Vec<String> = env::args().collect();
     
     // Initialize the library system
     library::initialize();
@@ -49,9 +95,54 @@ fn main() {
     // Check for clean output flag (used by razen-run to only show program output)
     let clean_output = args.iter().any(|arg| arg == "--clean-output");
     
+    // Run through the LLVM JIT instead of the interpreter
+    let jit_mode = args.iter().any(|arg| arg == "--jit");
+    
+    // Emit DWARF debug info in compiled programs
+    let debug_info = args.iter().any(|arg| arg == "-g");
+    
+    // Disassemble to a control-flow graph instead of a listing
+    let cfg_mode = args.iter().any(|arg| arg == "--cfg");
+    
+    // Keep the IR as compiled, without optimization passes
+    let no_opt = args.iter().any(|arg| arg == "--no-opt");
+    
+    // Target machine for compiled programs
+    let target = TargetOptions {
+        triple: take_option(&mut args, "--target"),
+        cpu: take_option(&mut args, "--cpu"),
+        features: take_option(&mut args, "--features"),
+    };
+    
+    // Optimization level from -O<level> (the last one wins) or --opt-level <level>
+    let mut opt_level = take_option(&mut args, "--opt-level").map(|level| parse_or_exit(OptLevel::parse(&level)));
+    args.retain(|arg| match arg.strip_prefix("-O") {
+        Some(level) => {
+            opt_level = Some(parse_or_exit(OptLevel::parse(level)));
+            false
+        },
+        None => true,
+    });
+    // Debugging keeps the code unoptimized unless a level was asked for
+    let opt_level = opt_level.unwrap_or(if debug_mode || debug_info { OptLevel::O0 } else { OptLevel::O2 });
+    
+    // Kind of output written by the compile command
+    let emit = take_option(&mut args, "--emit").map(|kind| parse_or_exit(EmitKind::parse(&kind)));
+    
+    // How errors and warnings are printed: with source excerpts, or as JSON for tools
+    let error_format = take_option(&mut args, "--error-format")
+        .map_or(ErrorFormat::Human, |format| parse_or_exit(ErrorFormat::parse(&format)));
+    
+    // Settings for every command that compiles a source file
+    let compile_options = CompileOptions {
+        error_format,
+        optimize: !no_opt,
+        clean_output,
+    };
+    
     // Filter out the special flags from arguments
     let filtered_args: Vec<String> = args.iter()
-        .filter(|&arg| arg != "--debug" && arg != "--clean-output")
+        .filter(|&arg| arg != "--debug" && arg != "--clean-output" && arg != "--jit" && arg != "-g" && arg != "--cfg" && arg != "--no-opt")
         .cloned()
         .collect();
     
@@ -63,20 +154,33 @@ fn main() {
             }
             
             let source_path_str = &filtered_args[2];
-            let output_path_str = if filtered_args.len() > 3 {
-                filtered_args[3].clone() // Clone to own the String for output_path_str
-            } else {
-                let source_path_obj = Path::new(source_path_str);
-                let stem = source_path_obj.file_stem().unwrap_or_default().to_str().unwrap_or("output");
-                format!("{}.o", stem) // Default to .o for object file
+            let output_arg = filtered_args.get(3).map(Path::new);
+            // Without --emit the output extension decides; other targets default to an object
+            // file because linking them needs a cross toolchain
+            let emit = emit.unwrap_or_else(|| {
+                match output_arg.and_then(|path| path.extension()).and_then(|ext| ext.to_str()) {
+                    Some("ll") => EmitKind::LlvmIr,
+                    Some("bc") => EmitKind::Bitcode,
+                    Some("s") => EmitKind::Asm,
+                    Some("o") => EmitKind::Obj,
+                    _ if target.is_host() || output_arg.is_some() => EmitKind::Exe,
+                    _ => EmitKind::Obj,
+                }
+            });
+            let output_path_str = match output_arg {
+                Some(path) => path.display().to_string(),
+                None => {
+                    let stem = Path::new(source_path_str).file_stem().unwrap_or_default().to_str().unwrap_or("output");
+                    Path::new(stem).with_extension(emit.extension()).display().to_string()
+                }
             };
             
-            println!("Compiling {} to LLVM IR and then to {}", source_path_str, output_path_str);
+            println!("Compiling {} to native code and then to {}", source_path_str, output_path_str);
             
             // 1. Compile Razen source to Razen IR
-            match compiler::Compiler::from_file(source_path_str) {
+            match compiler::Compiler::from_file(source_path_str, compile_options) {
                 Ok(razen_compiler) => {
-                    let razen_ir_sequence = razen_compiler.ir;
+                    let razen_ir_sequence = &razen_compiler.ir;
 
                     if debug_mode {
                         println!("Successfully parsed Razen source. Number of Razen IR instructions: {}", razen_ir_sequence.len());
@@ -85,29 +189,46 @@ fn main() {
                     // 2. Initialize LLVM Context and our LlvmCompiler
                     let context = Context::create();
                     let module_name = Path::new(source_path_str).file_stem().unwrap_or_default().to_str().unwrap_or("razen_module");
-                    let mut llvm_compiler = LlvmCompiler::new(&context, module_name, !debug_mode); // Enable optimizations if not in debug mode
+                    let mut llvm_compiler = LlvmCompiler::new(&context, module_name, opt_level);
+                    llvm_compiler.set_target(target.clone());
+                    llvm_compiler.set_inline_functions(razen_compiler.inline_function_names());
 
                     // 3. Compile Razen IR to LLVM IR (into a 'main' function)
-                    match llvm_compiler.compile_function("main", vec![], RazenValue::Int(0), &razen_ir_sequence) {
+                    if debug_info {
+                        llvm_compiler.enable_debug_info(Path::new(source_path_str));
+                    }
+                    match llvm_compiler.compile_program(razen_ir_sequence, razen_compiler.source_map(), razen_compiler.function_param_names(), razen_compiler.function_locals()) {
                         Ok(_main_function) => {
                             if debug_mode {
                                 println!("Successfully generated LLVM IR for 'main' function.");
                                 llvm_compiler.dump_module(); // Print LLVM IR to stderr
                             }
 
-                            // 4. Emit LLVM IR to a .ll file (placeholder for object file emission)
-                            let ll_path = format!("{}.ll", Path::new(&output_path_str).file_stem().unwrap_or_default().to_str().unwrap_or("output"));
-                            match llvm_compiler.module.print_to_file(&Path::new(&ll_path)) {
-                                Ok(_) => {
-                                    println!("LLVM IR written to {}", ll_path);
-                                    println!("Compilation (to LLVM IR) successful!");
-                                    println!("Next steps: Implement object file emission in llvm.rs and linking.");
-                                }
-                                Err(e) => {
-                                    println!("Error writing LLVM IR to file: {:?}", e.to_string());
+                            // 4. Write the requested output; executables link an intermediate object file
+                            let output_path = Path::new(&output_path_str);
+                            let wants_exe = emit == EmitKind::Exe;
+                            let object_path = output_path.with_extension("o");
+
+                            let written = if wants_exe {
+                                llvm_compiler.write_object_file(&object_path)
+                            } else {
+                                llvm_compiler.write_output(emit, output_path)
+                            };
+                            if let Err(e) = written {
+                                println!("Error emitting output: {}", e);
+                                process::exit(1);
+                            }
+
+                            if wants_exe {
+                                if let Err(e) = LlvmCompiler::link_executable(&object_path, output_path, &target.triple()) {
+                                    println!("Error linking executable: {}", e);
                                     process::exit(1);
                                 }
+                                // The object file is only an intermediate step here
+                                let _ = fs::remove_file(&object_path);
                             }
+
+                            println!("Compilation successful! Output written to {}", output_path.display());
                         }
                         Err(e) => {
                             println!("LLVM Compilation Error: {}", e);
@@ -137,7 +258,12 @@ fn main() {
                 }
             }
             
-            match compiler::Compiler::from_file(source_path) {
+            if jit_mode {
+                run_jit(source_path, opt_level, debug_mode, compile_options);
+                return;
+            }
+            
+            match compiler::Compiler::from_file(source_path, compile_options) {
                 Ok(compiler) => {
                     match compiler.execute() {
                         Ok(_) => {
@@ -157,6 +283,101 @@ fn main() {
                 }
             }
         },
+        "build" => {
+            if filtered_args.len() < 3 {
+                println!("Error: Missing source file path");
+                process::exit(1);
+            }
+            
+            let source_path = &filtered_args[2];
+            let output_path = match filtered_args.get(3) {
+                Some(path) => path.clone(),
+                None => {
+                    let stem = Path::new(source_path).file_stem().unwrap_or_default().to_str().unwrap_or("output");
+                    Path::new(stem).with_extension("rzb").display().to_string()
+                }
+            };
+            
+            match compiler::Compiler::from_file(source_path, compile_options) {
+                Ok(compiler) => {
+                    if let Err(e) = compiler.write_to_file(&output_path) {
+                        println!("Error: {}", e);
+                        process::exit(1);
+                    }
+                    if !clean_output {
+                        println!("Built {} ({} instructions)", output_path, compiler.ir.len());
+                    }
+                },
+                Err(e) => {
+                    println!("Compilation error: {}", e);
+                    process::exit(1);
+                }
+            }
+        },
+        "exec" => {
+            if filtered_args.len() < 3 {
+                println!("Error: Missing bytecode file path");
+                process::exit(1);
+            }
+            
+            let bytecode_path = &filtered_args[2];
+            match compiler::Compiler::from_bytecode_file(bytecode_path) {
+                Ok(mut compiler) => {
+                    compiler.set_clean_output(clean_output);
+                    if let Err(e) = compiler.execute() {
+                        println!("Execution error: {}", e);
+                        process::exit(1);
+                    }
+                },
+                Err(e) => {
+                    println!("Error loading {}: {}", bytecode_path, e);
+                    process::exit(1);
+                }
+            }
+        },
+        "check" => {
+            if filtered_args.len() < 3 {
+                println!("Error: Missing source file path");
+                process::exit(1);
+            }
+            
+            let path = &filtered_args[2];
+            let source = parse_or_exit(fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path, e)));
+            let error_count = check_source(path, &source, error_format);
+            if error_count > 0 {
+                println!("{}: {} error{} found", path, error_count, if error_count == 1 { "" } else { "s" });
+                process::exit(1);
+            }
+            println!("{}: no errors found", path);
+        },
+        "disasm" => {
+            if filtered_args.len() < 3 {
+                println!("Error: Missing file path");
+                process::exit(1);
+            }
+            
+            let path = &filtered_args[2];
+            let loaded = if Path::new(path).extension().is_some_and(|ext| ext == "rzb") {
+                compiler::Compiler::from_bytecode_file(path).map(|compiler| (compiler, None))
+            } else {
+                fs::read_to_string(path)
+                    .map_err(|e| format!("Failed to read {}: {}", path, e))
+                    .and_then(|source| compile_quietly(path, &source, compile_options).map(|compiler| (compiler, Some(source))))
+            };
+            match loaded {
+                Ok((compiler, source)) => {
+                    if cfg_mode {
+                        print!("{}", disasm::control_flow_graph(&compiler));
+                    } else {
+                        print!("{}", disasm::listing(&compiler, source.as_deref()));
+                    }
+                },
+                Err(e) => {
+                    println!("Error: {}", e);
+                    process::exit(1);
+                }
+            }
+        },
         "test" => {
             println!("Running tests");
             
@@ -178,11 +399,11 @@ fn main() {
             if path.is_dir() {
                 // Run all tests in directory
                 println!("Running all tests in directory: {}", test_path);
-                run_tests_in_directory(path, debug_mode);
+                run_tests_in_directory(path, debug_mode, compile_options);
             } else if path.is_file() {
                 // Run single test file
                 println!("Running test file: {}", test_path);
-                run_test_file(path, debug_mode);
+                run_test_file(path, debug_mode, compile_options);
             } else {
                 println!("Error: Test path '{}' does not exist", test_path);
                 process::exit(1);
@@ -199,8 +420,96 @@ fn main() {
     }
 }
 
+// Parse and statically check a source file without running anything, reporting every error
+// and warning in it. Returns the number of errors
+fn check_source(path: &str, source: &str, error_format: ErrorFormat) -> usize {
+    let mut sources = diagnostic::SourceFiles::new();
+    let mut parser = parser::Parser::new(lexer::Lexer::new(source.to_string()));
+    parser.set_file(sources.add(path, source));
+    let program = parser.parse_program();
+
+    // Each stage only runs on a program the one before accepted: names and calls are checked
+    // in a program that parsed, as the statements lost to a syntax error would show up again
+    // as undefined names, and kinds of values once every name is known
+    let is_error = |d: &diagnostic::Diagnostic| d.severity == diagnostic::Severity::Error;
+    let mut diagnostics = parser.get_errors().to_vec();
+    if diagnostics.is_empty() {
+        diagnostics = checker::check(&program);
+    }
+    if !diagnostics.iter().any(is_error) {
+        let mut compiler = compiler::Compiler::new();
+        compiler.set_clean_output(true);
+        compiler.compile_program(program);
+        diagnostics.extend(compiler.diagnostics().iter().cloned());
+    }
+    diagnostic::emit(&diagnostics, &sources, error_format);
+    diagnostics.iter().filter(|d| is_error(d)).count()
+}
+
+// Compile source code without the compiler's trace output, for commands that print their own
+fn compile_quietly(path: &str, source: &str, options: CompileOptions) -> Result<compiler::Compiler, String> {
+    let mut sources = diagnostic::SourceFiles::new();
+    sources.add(path, source);
+    let mut parser = parser::Parser::new(lexer::Lexer::new(source.to_string()));
+    let program = parser.parse_program();
+    let errors = parser.get_errors();
+    if !errors.is_empty() {
+        diagnostic::emit(errors, &sources, options.error_format);
+        return Err(diagnostic::aborting(errors.len()));
+    }
+    let mut compiler = compiler::Compiler::new();
+    compiler.set_clean_output(true);
+    compiler.compile_program(program);
+    diagnostic::emit(compiler.diagnostics(), &sources, options.error_format);
+    if compiler.error_count() > 0 {
+        return Err(diagnostic::aborting(compiler.error_count()));
+    }
+    if options.optimize {
+        compiler.optimize();
+    }
+    Ok(compiler)
+}
+
+// Compile a source file with LLVM and run its main function through the JIT
+fn run_jit(source_path: &str, opt_level: OptLevel, debug_mode: bool, options: CompileOptions) {
+    let clean_output = options.clean_output;
+    let razen_compiler = match compiler::Compiler::from_file(source_path, options) {
+        Ok(razen_compiler) => razen_compiler,
+        Err(e) => {
+            println!("Compilation error: {}", e);
+            process::exit(1);
+        }
+    };
+
+    let context = Context::create();
+    let module_name = Path::new(source_path).file_stem().unwrap_or_default().to_str().unwrap_or("razen_module");
+    let mut llvm_compiler = LlvmCompiler::new(&context, module_name, opt_level);
+    llvm_compiler.set_clean_output(clean_output);
+    llvm_compiler.set_inline_functions(razen_compiler.inline_function_names());
+
+    if let Err(e) = llvm_compiler.compile_program(&razen_compiler.ir, razen_compiler.source_map(), razen_compiler.function_param_names(), razen_compiler.function_locals()) {
+        println!("LLVM Compilation Error: {}", e);
+        process::exit(1);
+    }
+    if debug_mode {
+        llvm_compiler.dump_module();
+    }
+
+    match llvm_compiler.run_jit() {
+        Ok(_) => {
+            if !clean_output {
+                println!("Execution completed successfully!");
+            }
+        },
+        Err(e) => {
+            println!("Execution error: {}", e);
+            process::exit(1);
+        }
+    }
+}
+
 // Run all tests in a directory
-fn run_tests_in_directory(dir_path: &Path, debug_mode: bool) -> io::Result<()> {
+fn run_tests_in_directory(dir_path: &Path, debug_mode: bool, options: CompileOptions) -> io::Result<()> {
     let mut passed = 0;
     let mut failed = 0;
     let mut total_time = 0.0;
@@ -220,7 +529,7 @@ fn run_tests_in_directory(dir_path: &Path, debug_mode: bool) -> io::Result<()> {
             if debug_mode {
                 println!("Entering directory: {}", path.display());
             }
-            run_tests_in_directory(&path, debug_mode)?;
+            run_tests_in_directory(&path, debug_mode, options)?;
         }
     }
     
@@ -230,7 +539,7 @@ fn run_tests_in_directory(dir_path: &Path, debug_mode: bool) -> io::Result<()> {
     println!("Found {} test files in {}", test_files.len(), dir_path.display());
     
     for test_file in test_files {
-        let (success, duration) = run_test_file(&test_file, debug_mode);
+        let (success, duration) = run_test_file(&test_file, debug_mode, options);
         
         if success {
             passed += 1;
@@ -251,7 +560,7 @@ fn run_tests_in_directory(dir_path: &Path, debug_mode: bool) -> io::Result<()> {
 }
 
 // Run a single test file
-fn run_test_file(file_path: &Path, debug_mode: bool) -> (bool, f64) {
+fn run_test_file(file_path: &Path, debug_mode: bool, options: CompileOptions) -> (bool, f64) {
     let start = Instant::now();
     let file_name = file_path.file_name().unwrap_or_default().to_string_lossy();
     
@@ -261,7 +570,7 @@ fn run_test_file(file_path: &Path, debug_mode: bool) -> (bool, f64) {
         println!();
     }
     
-    match compiler::Compiler::from_file(file_path.to_str().unwrap()) {
+    match compiler::Compiler::from_file(file_path.to_str().unwrap(), options) {
         Ok(compiler) => {
             match compiler.execute() {
                 Ok(_) => {
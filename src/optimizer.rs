@@ -0,0 +1,320 @@
+// Optimization passes over the IR, run before it is interpreted or lowered to LLVM.
+//
+// Removed instructions leave a hole until the final compaction, so jump targets keep their
+// original positions while the passes run. Compaction then drops the holes and renumbers each
+// target to the next remaining instruction.
+//
+// The jump over each function body and its DefineFunction must survive, as the interpreter
+// and the LLVM backend use them to find functions.
+
+use crate::ast::Span;
+use crate::compiler::IR;
+use crate::value::Value;
+
+type Code = Vec<Option<IR>>;
+
+pub fn optimize(ir: &mut Vec<IR>, source_map: &mut Vec<Option<Span>>) {
+    let mut code: Code = ir.drain(..).map(Some).collect();
+    let len = code.len();
+
+    let mut pinned = vec![false; len];
+    let mut in_function = vec![false; len];
+    for pos in 0..len {
+        if let Some(IR::DefineFunction(_, start)) = code[pos] {
+            pinned[pos] = true;
+            if let Some(Some(IR::Jump(end))) = start.checked_sub(1).map(|jump| &code[jump]) {
+                pinned[start - 1] = true;
+                for slot in &mut in_function[start..(*end).min(len)] {
+                    *slot = true;
+                }
+            }
+        }
+    }
+
+    for slot in &mut code {
+        if matches!(slot, Some(IR::Label(_))) {
+            *slot = None;
+        }
+    }
+
+    loop {
+        let mut changed = fold_constants(&mut code);
+        changed |= thread_jumps(&mut code, &pinned);
+        changed |= remove_unreachable(&mut code, &pinned, &in_function);
+        if !changed {
+            break;
+        }
+    }
+
+    let kept: Vec<usize> = (0..len).filter(|&pos| code[pos].is_some()).collect();
+    let renumber = |target: usize| kept.partition_point(|&pos| pos < target);
+    *source_map = kept.iter().map(|&pos| source_map.get(pos).copied().flatten()).collect();
+    *ir = code.into_iter()
+        .flatten()
+        .map(|instruction| match instruction {
+            IR::Jump(target) => IR::Jump(renumber(target)),
+            IR::JumpIfFalse(target) => IR::JumpIfFalse(renumber(target)),
+            IR::JumpIfTrue(target) => IR::JumpIfTrue(renumber(target)),
+            IR::SetupTryCatch(target) => IR::SetupTryCatch(renumber(target)),
+            IR::DefineFunction(name, address) => IR::DefineFunction(name, renumber(address)),
+            other => other,
+        })
+        .collect();
+}
+
+// Positions control can arrive at other than by falling through, including one past the end
+fn jump_targets(code: &Code) -> Vec<bool> {
+    let mut targets = vec![false; code.len() + 1];
+    for instruction in code {
+        match instruction {
+            Some(IR::Jump(target)) | Some(IR::JumpIfFalse(target)) | Some(IR::JumpIfTrue(target)) |
+            Some(IR::SetupTryCatch(target)) | Some(IR::DefineFunction(_, target)) => targets[(*target).min(code.len())] = true,
+            _ => {},
+        }
+    }
+    targets
+}
+
+fn next_live(code: &Code, from: usize) -> Option<usize> {
+    (from..code.len()).find(|&pos| code[pos].is_some())
+}
+
+fn constant(instruction: &IR) -> Option<Value> {
+    match instruction {
+        IR::PushNumber(n) => Some(Value::number(*n)),
+        IR::PushString(s) => Some(Value::String(s.clone())),
+        IR::PushBoolean(b) => Some(Value::Bool(*b)),
+        IR::PushNull => Some(Value::Null),
+        _ => None,
+    }
+}
+
+// The push instruction for a folded value, if pushing it gives back exactly that value
+fn push_constant(value: Value) -> Option<IR> {
+    let instruction = match &value {
+        Value::Int(i) => IR::PushNumber(*i as f64),
+        Value::Float(f) => IR::PushNumber(*f),
+        Value::String(s) => IR::PushString(s.clone()),
+        Value::Bool(b) => IR::PushBoolean(*b),
+        Value::Null => IR::PushNull,
+        Value::Array(_) | Value::Map(_) | Value::Function(_) | Value::Instance(_) => return None,
+    };
+    let round_trip = constant(&instruction)?;
+    (format!("{:?}", round_trip) == format!("{:?}", value)).then_some(instruction)
+}
+
+// Evaluate an operation on constants the way the interpreter would. Operations that would
+// raise an error are left for run time
+fn evaluate_binary(op: &IR, a: &Value, b: &Value) -> Option<Value> {
+    let result = match op {
+        IR::Add => a.add(b),
+        IR::Subtract => a.subtract(b),
+        IR::Multiply => a.multiply(b),
+        IR::Divide => a.divide(b),
+        IR::Modulo => a.modulo(b),
+        IR::Power => a.power(b),
+        IR::FloorDiv => a.floor_div(b),
+        IR::Equal => Ok(Value::Bool(a == b)),
+        IR::NotEqual => Ok(Value::Bool(a != b)),
+        IR::GreaterThan => a.compare(b).map(|ordering| Value::Bool(ordering.is_gt())),
+        IR::GreaterEqual => a.compare(b).map(|ordering| Value::Bool(ordering.is_ge())),
+        IR::LessThan => a.compare(b).map(|ordering| Value::Bool(ordering.is_lt())),
+        IR::LessEqual => a.compare(b).map(|ordering| Value::Bool(ordering.is_le())),
+        IR::And => Ok(Value::Bool(a.is_truthy() && b.is_truthy())),
+        IR::Or => Ok(Value::Bool(a.is_truthy() || b.is_truthy())),
+        _ => return None,
+    };
+    result.ok()
+}
+
+// Replace operations on pushed constants with their result, and branches on a constant
+// with a jump or nothing
+fn fold_constants(code: &mut Code) -> bool {
+    let targets = jump_targets(code);
+    let live: Vec<usize> = (0..code.len()).filter(|&pos| code[pos].is_some()).collect();
+    // No jump may land inside a folded sequence
+    let entered = |from: usize, to: usize| targets[from + 1..=to].iter().any(|&target| target);
+    let mut changed = false;
+    let mut i = 0;
+    while i < live.len() {
+        let first = live[i];
+        let Some(a) = code[first].as_ref().and_then(constant) else {
+            i += 1;
+            continue;
+        };
+
+        if let (Some(&second), Some(&third)) = (live.get(i + 1), live.get(i + 2)) {
+            let b = code[second].as_ref().and_then(constant);
+            let folded = match (&b, &code[third]) {
+                (Some(b), Some(op)) if !entered(first, third) => evaluate_binary(op, &a, b).and_then(push_constant),
+                _ => None,
+            };
+            if let Some(folded) = folded {
+                code[first] = Some(folded);
+                code[second] = None;
+                code[third] = None;
+                changed = true;
+                i += 3;
+                continue;
+            }
+        }
+
+        if let Some(&second) = live.get(i + 1) {
+            if !entered(first, second) {
+                let folded = match &code[second] {
+                    Some(IR::Negate) => a.negate().ok().and_then(push_constant).map(Some),
+                    Some(IR::Not) => Some(Some(IR::PushBoolean(!a.is_truthy()))),
+                    Some(IR::JumpIfFalse(target)) => Some((!a.is_truthy()).then_some(IR::Jump(*target))),
+                    Some(IR::JumpIfTrue(target)) => Some(a.is_truthy().then_some(IR::Jump(*target))),
+                    _ => None,
+                };
+                if let Some(folded) = folded {
+                    code[first] = folded;
+                    code[second] = None;
+                    changed = true;
+                    i += 2;
+                    continue;
+                }
+            }
+        }
+        i += 1;
+    }
+    changed
+}
+
+// Send jumps that land on another jump straight to its target. A jump to the next instruction
+// is dropped, or only pops its condition
+fn thread_jumps(code: &mut Code, pinned: &[bool]) -> bool {
+    let mut changed = false;
+    for pos in 0..code.len() {
+        if pinned[pos] {
+            continue;
+        }
+        let Some(Some(IR::Jump(original) | IR::JumpIfFalse(original) | IR::JumpIfTrue(original))) = code.get(pos).cloned() else {
+            continue;
+        };
+
+        // The hop limit stops on jump cycles such as an empty infinite loop
+        let mut target = original;
+        for _ in 0..code.len() {
+            match next_live(code, target) {
+                Some(landing) if landing != pos => match code[landing] {
+                    Some(IR::Jump(next)) if next != target => target = next,
+                    _ => break,
+                },
+                _ => break,
+            }
+        }
+
+        if next_live(code, target) == next_live(code, pos + 1) {
+            code[pos] = match code[pos] {
+                Some(IR::Jump(_)) => None,
+                _ => Some(IR::Pop),
+            };
+            changed = true;
+        } else if target != original {
+            code[pos] = match code[pos] {
+                Some(IR::JumpIfFalse(_)) => Some(IR::JumpIfFalse(target)),
+                Some(IR::JumpIfTrue(_)) => Some(IR::JumpIfTrue(target)),
+                _ => Some(IR::Jump(target)),
+            };
+            changed = true;
+        }
+    }
+    changed
+}
+
+// Remove code after a jump, exit, throw or function return that nothing jumps to
+fn remove_unreachable(code: &mut Code, pinned: &[bool], in_function: &[bool]) -> bool {
+    let targets = jump_targets(code);
+    let mut changed = false;
+    let mut reachable = true;
+    for pos in 0..code.len() {
+        reachable |= targets[pos];
+        let Some(instruction) = &code[pos] else { continue };
+        if !reachable && !pinned[pos] {
+            code[pos] = None;
+            changed = true;
+            continue;
+        }
+        // Outside a function the interpreter runs on past a Return
+        reachable = match instruction {
+            IR::Jump(_) | IR::Exit | IR::ThrowException => false,
+            IR::Return => !in_function[pos],
+            _ => true,
+        };
+    }
+    changed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::Compiler;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+
+    fn optimized(ir: Vec<IR>) -> Vec<IR> {
+        let mut ir = ir;
+        let mut source_map = vec![None; ir.len()];
+        optimize(&mut ir, &mut source_map);
+        assert_eq!(source_map.len(), ir.len());
+        ir
+    }
+
+    #[test]
+    fn test_folds_constants() {
+        let ir = optimized(vec![
+            IR::PushNumber(2.0), IR::PushNumber(3.0), IR::Multiply, IR::PushNumber(1.0), IR::Add, IR::Negate,
+            IR::StoreVar("x".to_string()),
+            IR::PushNumber(1.0), IR::PushNumber(0.0), IR::Divide, IR::Print,
+        ]);
+        assert_eq!(format!("{:?}", ir), r#"[PushNumber(-7.0), StoreVar("x"), PushNumber(1.0), PushNumber(0.0), Divide, Print]"#);
+    }
+
+    #[test]
+    fn test_threads_jumps_and_removes_dead_code() {
+        let ir = optimized(vec![
+            IR::LoadVar("c".to_string()),
+            IR::JumpIfFalse(4),
+            IR::Jump(6),
+            IR::Print,
+            IR::Label("a".to_string()),
+            IR::Jump(7),
+            IR::Label("b".to_string()),
+            IR::PushBoolean(true),
+            IR::JumpIfFalse(0),
+            IR::Exit,
+            IR::Print,
+        ]);
+        assert_eq!(format!("{:?}", ir), r#"[LoadVar("c"), Pop, Exit]"#);
+    }
+
+    #[test]
+    fn test_optimized_program_behaves_the_same() {
+        let input = r#"
+            fun square(n) { return n * n; }
+            num total = 0;
+            num i = 0;
+            while (true) {
+                if (i >= 2 + 3) { break; }
+                total = total + square(i);
+                i = i + 1;
+            }
+            str caught = "";
+            try { throw "x" + "y"; } catch (e) { caught = e; }
+            if (total != 30) { throw "total"; }
+            if (caught != "xy") { throw "caught"; }
+        "#;
+        let mut parser = Parser::new(Lexer::new(input.to_string()));
+        let mut compiler = Compiler::new();
+        compiler.set_clean_output(true);
+        compiler.compile_program(parser.parse_program());
+        let before = compiler.ir.len();
+        compiler.optimize();
+
+        assert!(compiler.ir.len() < before);
+        assert!(!compiler.ir.iter().any(|ir| matches!(ir, IR::Label(_))));
+        assert_eq!(compiler.execute(), Ok(()));
+    }
+}
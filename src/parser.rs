@@ -1,9 +1,10 @@
 use std::collections::HashMap;
 use std::path::Path;
 
-use crate::ast::{Program, Statement, Expression};
+use crate::ast::{Program, Statement, Expression, Span, WhenArm, Pattern};
 use crate::token::{Token, TokenType};
 use crate::lexer::Lexer;
+use crate::diagnostic::{self, Diagnostic};
 
 // Define operator precedence levels
 #[derive(PartialEq, PartialOrd, Debug)]
@@ -27,7 +28,10 @@ pub struct Parser {
     lexer: Lexer,
     current_token: Token,
     peek_token: Token,
-    errors: Vec<String>,
+    errors: Vec<Diagnostic>,
+    panicking: bool, // Set by an error until the parser resynchronizes, so each mistake is reported once
+    brace_depth: usize, // Braces open at the current token, for resynchronizing at the right block
+    file: usize, // File id recorded in the spans of parsed nodes
     // Maps for prefix and infix parsing functions
     prefix_parse_fns: HashMap<TokenType, fn(&mut Parser) -> Option<Expression>>,
     infix_parse_fns: HashMap<TokenType, fn(&mut Parser, Expression) -> Option<Expression>>,
@@ -38,11 +42,15 @@ impl Parser {
         let current_token = lexer.next_token();
         let peek_token = lexer.next_token();
         
+        let brace_depth = usize::from(current_token.token_type == TokenType::LeftBrace);
         let mut parser = Parser {
             lexer,
             current_token,
             peek_token,
             errors: Vec::new(),
+            panicking: false,
+            brace_depth,
+            file: 0,
             prefix_parse_fns: HashMap::new(),
             infix_parse_fns: HashMap::new(),
         };
@@ -50,6 +58,8 @@ impl Parser {
         // Register prefix parse functions
         parser.register_prefix(TokenType::Identifier, Parser::parse_identifier);
         parser.register_prefix(TokenType::StringLiteral, Parser::parse_string_literal);
+        parser.register_prefix(TokenType::StringStart, Parser::parse_interpolated_string);
+        parser.register_prefix(TokenType::Illegal, Parser::parse_illegal);
         parser.register_prefix(TokenType::NumberLiteral, Parser::parse_number_literal);
         parser.register_prefix(TokenType::True, Parser::parse_boolean_literal);
         parser.register_prefix(TokenType::False, Parser::parse_boolean_literal);
@@ -59,6 +69,7 @@ impl Parser {
         parser.register_prefix(TokenType::LeftBrace, Parser::parse_map_literal);
         parser.register_prefix(TokenType::Minus, Parser::parse_prefix_expression);
         parser.register_prefix(TokenType::Not, Parser::parse_prefix_expression);
+        parser.register_prefix(TokenType::Fun, Parser::parse_function_literal);
         
         // Register mathematical keywords as identifier parsers
         // Mathematical variable tokens removed
@@ -213,6 +224,16 @@ impl Parser {
         }
     }
     
+    // Source text being parsed, which spans index into
+    pub fn source(&self) -> &str {
+        self.lexer.input()
+    }
+    
+    // Set the file id that spans of the nodes parsed from here on refer to
+    pub fn set_file(&mut self, file: usize) {
+        self.file = file;
+    }
+    
     fn register_prefix(&mut self, token_type: TokenType, func: fn(&mut Parser) -> Option<Expression>) {
         self.prefix_parse_fns.insert(token_type, func);
     }
@@ -224,6 +245,11 @@ impl Parser {
     fn next_token(&mut self) {
         self.current_token = self.peek_token.clone();
         self.peek_token = self.lexer.next_token();
+        match self.current_token.token_type {
+            TokenType::LeftBrace => self.brace_depth += 1,
+            TokenType::RightBrace => self.brace_depth = self.brace_depth.saturating_sub(1),
+            _ => {},
+        }
     }
     
     fn current_token_is(&self, token_type: TokenType) -> bool {
@@ -245,17 +271,43 @@ impl Parser {
     }
     
     fn peek_error(&mut self, token_type: TokenType) {
-        let msg = format!(
-            "Expected next token to be {:?}, got {:?} instead at line {}, column {}",
-            token_type,
-            self.peek_token.token_type,
-            self.peek_token.line,
-            self.peek_token.column
-        );
-        self.errors.push(msg);
+        let message = format!("Expected next token to be {:?}, got {:?} instead", token_type, self.peek_token.token_type);
+        let label = format!("expected {:?}", token_type);
+        self.report(Diagnostic::error(diagnostic::UNEXPECTED_TOKEN, message).with_primary(self.peek_span(), label));
     }
     
-    pub fn get_errors(&self) -> &[String] {
+    // Record an error unless one was already reported since the parser last resynchronized;
+    // anything wrong after the first error is usually fallout from it
+    fn report(&mut self, error: Diagnostic) {
+        if !self.panicking {
+            self.errors.push(error);
+            self.panicking = true;
+        }
+    }
+    
+    // Record an error pointing at `span`
+    fn error(&mut self, code: &'static str, span: Span, message: impl Into<String>) {
+        self.report(Diagnostic::error(code, message).with_primary(span, ""));
+    }
+    
+    // Record a declaration whose literal value does not fit its type keyword
+    fn type_mismatch(&mut self, keyword: Span, value: &Expression, message: impl Into<String>) {
+        let found = match value.unlocated() {
+            Expression::NumberLiteral(_) => "a number",
+            Expression::StringLiteral(_) => "a string",
+            _ => "a boolean",
+        };
+        let span = match value {
+            Expression::Located { span, .. } => *span,
+            _ => keyword,
+        };
+        self.report(Diagnostic::error(diagnostic::TYPE_MISMATCH, message)
+            .with_primary(span, format!("found {}", found))
+            .with_label(keyword, "declared type")
+            .with_suggestion("declare it with 'var' to hold a value of any type", keyword, "var"));
+    }
+    
+    pub fn get_errors(&self) -> &[Diagnostic] {
         &self.errors
     }
     
@@ -263,16 +315,105 @@ impl Parser {
         let mut program = Program::new();
         
         while !self.current_token_is(TokenType::EOF) {
-            if let Some(stmt) = self.parse_statement() {
-                program.statements.push(stmt);
+            let depth = self.brace_depth;
+            match self.parse_statement() {
+                Some(stmt) => program.statements.push(stmt),
+                None if self.panicking => {
+                    self.synchronize(depth);
+                },
+                None => {},
             }
+            self.panicking = false;
             self.next_token();
         }
         
         program
     }
     
+    // After a statement fails to parse, skip ahead to where the next one can start: past a `;`
+    // or the `}` that brings the braces back to `depth`, where the statement started, or up to
+    // a statement keyword opening a line or the `}` of the enclosing block. Keywords in the
+    // middle of a line are skipped, since names such as `arr` are keywords too. The current
+    // token is left on the last token of the broken statement. Returns true when that is a `}`
+    // closing the enclosing block
+    fn synchronize(&mut self, depth: usize) -> bool {
+        loop {
+            match self.current_token.token_type {
+                TokenType::EOF => break,
+                TokenType::RightBrace if self.brace_depth < depth => {
+                    self.panicking = false;
+                    return true;
+                },
+                TokenType::RightBrace if self.brace_depth == depth => {
+                    if self.peek_token_is(TokenType::Semicolon) {
+                        self.next_token();
+                    }
+                    break;
+                },
+                TokenType::Semicolon if self.brace_depth == depth => break,
+                _ => {},
+            }
+            let keyword_on_new_line = Self::starts_statement(&self.peek_token.token_type)
+                && self.peek_token.line > self.current_token.line;
+            if self.brace_depth == depth
+                && (keyword_on_new_line || matches!(self.peek_token.token_type, TokenType::RightBrace | TokenType::EOF)) {
+                break;
+            }
+            self.next_token();
+        }
+        self.panicking = false;
+        false
+    }
+    
+    // Keywords that can only begin a statement, where parsing resumes after an error
+    fn starts_statement(token_type: &TokenType) -> bool {
+        matches!(token_type,
+            TokenType::Num | TokenType::Str | TokenType::Bool | TokenType::Var | TokenType::Const |
+            TokenType::List | TokenType::Arr | TokenType::Map | TokenType::Store | TokenType::Box |
+            TokenType::Ref | TokenType::Fun | TokenType::Return | TokenType::If | TokenType::While |
+            TokenType::For | TokenType::Break | TokenType::Continue | TokenType::Show |
+            TokenType::Read | TokenType::Exit | TokenType::Load | TokenType::Try | TokenType::Throw |
+            TokenType::When | TokenType::Use | TokenType::Export | TokenType::Import |
+            TokenType::Class | TokenType::Final | TokenType::Enum | TokenType::Inline |
+            TokenType::Volatile | TokenType::Api | TokenType::Connect | TokenType::Lib)
+    }
+    
+    // After an element of a list fails to parse, skip to the `,` or closing token after it so
+    // the rest of the list is still checked. Gives up, leaving recovery to the statement, at
+    // a `;` or an unbalanced `}` where the list was evidently never closed
+    fn recover_in_list(&mut self, end: &TokenType) -> bool {
+        if !self.panicking {
+            return false;
+        }
+        let mut depth = 0;
+        while !matches!(self.current_token.token_type, TokenType::Semicolon | TokenType::EOF) {
+            let peek = &self.peek_token.token_type;
+            if depth == 0 && (*peek == TokenType::Comma || peek == end) {
+                self.panicking = false;
+                return true;
+            }
+            match peek {
+                TokenType::LeftParen | TokenType::LeftBracket | TokenType::LeftBrace => depth += 1,
+                TokenType::RightParen | TokenType::RightBracket | TokenType::RightBrace if depth > 0 => depth -= 1,
+                TokenType::RightBrace | TokenType::Semicolon | TokenType::EOF => return false,
+                _ => {},
+            }
+            self.next_token();
+        }
+        false
+    }
+    
+    // Parse one statement and tag it with the source it spans, from its first token to its last
     fn parse_statement(&mut self) -> Option<Statement> {
+        let start = self.token_span();
+        let statement = self.parse_untagged_statement()?;
+        Some(Statement::Located {
+            span: Span { end: self.current_token.end, ..start },
+            statement: Box::new(statement),
+        })
+    }
+
+    fn parse_untagged_statement(&mut self) -> Option<Statement> {
         match self.current_token.token_type {
             // Variable declaration keywords
             TokenType::Num | TokenType::Str | TokenType::Bool | TokenType::Var | TokenType::Const |
@@ -298,6 +439,8 @@ impl Parser {
             TokenType::Rule => self.parse_rule_statement(),
             TokenType::Attribute => self.parse_attribute_statement(),
             
+            // A statement starting with `fun (` is an anonymous function expression
+            TokenType::Fun if self.peek_token_is(TokenType::LeftParen) => self.parse_expression_statement(),
             TokenType::Fun => self.parse_function_declaration(),
             TokenType::Return => self.parse_return_statement(),
             TokenType::If => self.parse_if_statement(),
@@ -350,6 +493,8 @@ impl Parser {
                 // Skip comments and return None to continue parsing
                 None
             },
+            // An empty statement, such as a stray `;` after a block
+            TokenType::Semicolon => None,
             _ => self.parse_expression_statement(),
         }
     }
@@ -357,8 +502,7 @@ impl Parser {
     fn parse_variable_declaration(&mut self) -> Option<Statement> {
         let var_type = self.current_token.literal.clone();
         let token_type = self.current_token.token_type.clone();
-        let token_line = self.current_token.line;
-        let token_column = self.current_token.column;
+        let keyword = self.token_span();
         
         if !self.expect_peek(TokenType::Identifier) {
             return None;
@@ -380,7 +524,7 @@ impl Parser {
             TokenType::Num => {
                 // num => for numeric variables (integers, floats)
                 // Also allow function calls and expressions that might return numbers
-                match value {
+                match value.unlocated() {
                     Expression::NumberLiteral(_) => {},
                     Expression::InfixExpression { .. } => {}, // Allow expressions that might result in numbers
                     Expression::PrefixExpression { .. } => {}, // Allow expressions that might result in numbers
@@ -389,16 +533,10 @@ impl Parser {
                     Expression::LibraryCall { .. } => {}, // Allow library function calls (like TimeLib[now]())
                     _ => {
                         // Only show warning for obvious mismatches like strings and booleans
-                        if let Expression::StringLiteral(_) = value {
-                            self.errors.push(format!(
-                                "Type mismatch: 'num' should be used for numeric values at line {}, column {}",
-                                token_line, token_column
-                            ));
-                        } else if let Expression::BooleanLiteral(_) = value {
-                            self.errors.push(format!(
-                                "Type mismatch: 'num' should be used for numeric values at line {}, column {}",
-                                token_line, token_column
-                            ));
+                        if let Expression::StringLiteral(_) = value.unlocated() {
+                            self.type_mismatch(keyword, &value, "Type mismatch: 'num' should be used for numeric values");
+                        } else if let Expression::BooleanLiteral(_) = value.unlocated() {
+                            self.type_mismatch(keyword, &value, "Type mismatch: 'num' should be used for numeric values");
                         }
                         // Allow other types to pass through for flexibility
                     }
@@ -406,7 +544,7 @@ impl Parser {
             },
             TokenType::Str => {
                 // str => for string variables and text manipulation
-                match value {
+                match value.unlocated() {
                     Expression::StringLiteral(_) => {},
                     Expression::InfixExpression { .. } => {}, // Allow expressions that might result in strings
                     Expression::Identifier(_) => {}, // Allow identifiers (runtime check needed)
@@ -414,16 +552,10 @@ impl Parser {
                     Expression::LibraryCall { .. } => {}, // Allow library function calls
                     _ => {
                         // Only show warning for obvious mismatches
-                        if let Expression::NumberLiteral(_) = value {
-                            self.errors.push(format!(
-                                "Type mismatch: 'str' should be used for string values at line {}, column {}",
-                                token_line, token_column
-                            ));
-                        } else if let Expression::BooleanLiteral(_) = value {
-                            self.errors.push(format!(
-                                "Type mismatch: 'str' should be used for string values at line {}, column {}",
-                                token_line, token_column
-                            ));
+                        if let Expression::NumberLiteral(_) = value.unlocated() {
+                            self.type_mismatch(keyword, &value, "Type mismatch: 'str' should be used for string values");
+                        } else if let Expression::BooleanLiteral(_) = value.unlocated() {
+                            self.type_mismatch(keyword, &value, "Type mismatch: 'str' should be used for string values");
                         }
                         // Allow other types to pass through for flexibility
                     }
@@ -431,7 +563,7 @@ impl Parser {
             },
             TokenType::Bool => {
                 // bool => for boolean variables and logical conditions
-                match value {
+                match value.unlocated() {
                     Expression::BooleanLiteral(_) => {},
                     Expression::InfixExpression { .. } => {}, // Allow expressions that might result in booleans
                     Expression::PrefixExpression { .. } => {}, // Allow expressions that might result in booleans
@@ -440,16 +572,10 @@ impl Parser {
                     Expression::LibraryCall { .. } => {}, // Allow library function calls
                     _ => {
                         // Only show warning for obvious mismatches
-                        if let Expression::NumberLiteral(_) = value {
-                            self.errors.push(format!(
-                                "Type mismatch: 'bool' should be used for boolean values at line {}, column {}",
-                                token_line, token_column
-                            ));
-                        } else if let Expression::StringLiteral(_) = value {
-                            self.errors.push(format!(
-                                "Type mismatch: 'bool' should be used for boolean values at line {}, column {}",
-                                token_line, token_column
-                            ));
+                        if let Expression::NumberLiteral(_) = value.unlocated() {
+                            self.type_mismatch(keyword, &value, "Type mismatch: 'bool' should be used for boolean values");
+                        } else if let Expression::StringLiteral(_) = value.unlocated() {
+                            self.type_mismatch(keyword, &value, "Type mismatch: 'bool' should be used for boolean values");
                         }
                         // Allow other types to pass through for flexibility
                     }
@@ -467,7 +593,7 @@ impl Parser {
             TokenType::List | TokenType::Arr | TokenType::Append | TokenType::Remove => {
                 // Collection variables should be used with array literals or identifiers
                 // These tokens are aliases for 'put' when used with collections
-                match value {
+                match value.unlocated() {
                     Expression::ArrayLiteral { .. } => {},
                     Expression::Identifier(_) => {}, // Allow identifiers (runtime check needed)
                     Expression::CallExpression { .. } => {}, // Allow function calls (runtime check needed)
@@ -475,21 +601,12 @@ impl Parser {
                     Expression::LibraryCall { .. } => {}, // Allow library function calls
                     _ => {
                         // Only show warning for obvious mismatches like simple literals
-                        if let Expression::NumberLiteral(_) = value {
-                            self.errors.push(format!(
-                                "Type mismatch: '{}' should be used for collection values at line {}, column {}",
-                                var_type, token_line, token_column
-                            ));
-                        } else if let Expression::StringLiteral(_) = value {
-                            self.errors.push(format!(
-                                "Type mismatch: '{}' should be used for collection values at line {}, column {}",
-                                var_type, token_line, token_column
-                            ));
-                        } else if let Expression::BooleanLiteral(_) = value {
-                            self.errors.push(format!(
-                                "Type mismatch: '{}' should be used for collection values at line {}, column {}",
-                                var_type, token_line, token_column
-                            ));
+                        if let Expression::NumberLiteral(_) = value.unlocated() {
+                            self.type_mismatch(keyword, &value, format!("Type mismatch: '{}' should be used for collection values", var_type));
+                        } else if let Expression::StringLiteral(_) = value.unlocated() {
+                            self.type_mismatch(keyword, &value, format!("Type mismatch: '{}' should be used for collection values", var_type));
+                        } else if let Expression::BooleanLiteral(_) = value.unlocated() {
+                            self.type_mismatch(keyword, &value, format!("Type mismatch: '{}' should be used for collection values", var_type));
                         }
                         // Allow other types to pass through for flexibility
                     }
@@ -500,7 +617,7 @@ impl Parser {
             TokenType::Map | TokenType::Key | TokenType::Value => {
                 // Map variables should be used with map literals or identifiers
                 // These tokens are aliases for 'put' when used with dictionaries/maps
-                match value {
+                match value.unlocated() {
                     Expression::MapLiteral { .. } => {},
                     Expression::ArrayLiteral { .. } => {}, // Allow arrays for key-value pairs
                     Expression::Identifier(_) => {}, // Allow identifiers (runtime check needed)
@@ -508,21 +625,12 @@ impl Parser {
                     Expression::LibraryCall { .. } => {}, // Allow library function calls
                     _ => {
                         // Only show warning for obvious mismatches like simple literals
-                        if let Expression::NumberLiteral(_) = value {
-                            self.errors.push(format!(
-                                "Type mismatch: '{}' should be used for map/dictionary values at line {}, column {}",
-                                var_type, token_line, token_column
-                            ));
-                        } else if let Expression::StringLiteral(_) = value {
-                            self.errors.push(format!(
-                                "Type mismatch: '{}' should be used for map/dictionary values at line {}, column {}",
-                                var_type, token_line, token_column
-                            ));
-                        } else if let Expression::BooleanLiteral(_) = value {
-                            self.errors.push(format!(
-                                "Type mismatch: '{}' should be used for map/dictionary values at line {}, column {}",
-                                var_type, token_line, token_column
-                            ));
+                        if let Expression::NumberLiteral(_) = value.unlocated() {
+                            self.type_mismatch(keyword, &value, format!("Type mismatch: '{}' should be used for map/dictionary values", var_type));
+                        } else if let Expression::StringLiteral(_) = value.unlocated() {
+                            self.type_mismatch(keyword, &value, format!("Type mismatch: '{}' should be used for map/dictionary values", var_type));
+                        } else if let Expression::BooleanLiteral(_) = value.unlocated() {
+                            self.type_mismatch(keyword, &value, format!("Type mismatch: '{}' should be used for map/dictionary values", var_type));
                         }
                         // Allow other types to pass through for flexibility
                     }
@@ -536,27 +644,18 @@ impl Parser {
             TokenType::Store | TokenType::Box | TokenType::Ref => {
                 // These can be used with any type, but we'll add specific validation for Ref
                 if token_type == TokenType::Ref {
-                    match value {
+                    match value.unlocated() {
                         Expression::Identifier(_) => {}, // Ref should point to an existing variable
                         Expression::CallExpression { .. } => {}, // Allow function calls (runtime check needed)
                         Expression::LibraryCall { .. } => {}, // Allow library function calls
                         _ => {
                             // Only show warning for obvious mismatches like literals
-                            if let Expression::NumberLiteral(_) = value {
-                                self.errors.push(format!(
-                                    "Type mismatch: 'ref' should be used with an identifier at line {}, column {}",
-                                    token_line, token_column
-                                ));
-                            } else if let Expression::StringLiteral(_) = value {
-                                self.errors.push(format!(
-                                    "Type mismatch: 'ref' should be used with an identifier at line {}, column {}",
-                                    token_line, token_column
-                                ));
-                            } else if let Expression::BooleanLiteral(_) = value {
-                                self.errors.push(format!(
-                                    "Type mismatch: 'ref' should be used with an identifier at line {}, column {}",
-                                    token_line, token_column
-                                ));
+                            if let Expression::NumberLiteral(_) = value.unlocated() {
+                                self.type_mismatch(keyword, &value, "Type mismatch: 'ref' should be used with an identifier");
+                            } else if let Expression::StringLiteral(_) = value.unlocated() {
+                                self.type_mismatch(keyword, &value, "Type mismatch: 'ref' should be used with an identifier");
+                            } else if let Expression::BooleanLiteral(_) = value.unlocated() {
+                                self.type_mismatch(keyword, &value, "Type mismatch: 'ref' should be used with an identifier");
                             }
                             // Allow other types to pass through for flexibility
                         }
@@ -606,6 +705,23 @@ impl Parser {
         })
     }
     
+    // Anonymous function: fun (params) { body }
+    fn parse_function_literal(&mut self) -> Option<Expression> {
+        if !self.expect_peek(TokenType::LeftParen) {
+            return None;
+        }
+        
+        let parameters = self.parse_function_parameters();
+        
+        if !self.expect_peek(TokenType::LeftBrace) {
+            return None;
+        }
+        
+        let body = self.parse_block_statement();
+        
+        Some(Expression::FunctionLiteral { parameters, body })
+    }
+    
     fn parse_function_parameters(&mut self) -> Vec<String> {
         let mut parameters = Vec::new();
         
@@ -669,9 +785,14 @@ impl Parser {
         
         // Parse statements until we reach the closing brace or EOF
         while !self.current_token_is(TokenType::RightBrace) && !self.current_token_is(TokenType::EOF) {
-            if let Some(stmt) = self.parse_statement() {
-                statements.push(stmt);
+            let depth = self.brace_depth;
+            match self.parse_statement() {
+                Some(stmt) => statements.push(stmt),
+                // A `}` closing this block ends it
+                None if self.panicking && self.synchronize(depth) => break,
+                None => {},
             }
+            self.panicking = false;
             
             // Only advance if we're not at the end of the block
             // This helps prevent skipping over the closing brace
@@ -684,6 +805,10 @@ impl Parser {
             }
         }
         
+        if self.current_token_is(TokenType::EOF) {
+            self.error(diagnostic::UNEXPECTED_TOKEN, self.token_span(), "Expected '}' to close block, reached the end of the file");
+        }
+        
         statements
     }
     
@@ -807,7 +932,7 @@ impl Parser {
             
             // Expect right paren
             if !self.current_token_is(TokenType::RightParen) {
-                self.errors.push(format!("Expected right parenthesis after color name, got {:?}", self.current_token.token_type));
+                self.error(diagnostic::UNEXPECTED_TOKEN, self.token_span(), format!("Expected right parenthesis after color name, got {:?}", self.current_token.token_type));
                 return None;
             }
             
@@ -920,12 +1045,12 @@ impl Parser {
         let expr = self.parse_expression(Precedence::Lowest)?;
         
         // Extract properties from the map literal
-        let properties = match expr {
+        let properties = match expr.into_unlocated() {
             Expression::MapLiteral { pairs } => {
                 // Convert the map pairs to (String, Expression) format
                 pairs.into_iter()
                     .filter_map(|(key, value)| {
-                        if let Expression::StringLiteral(key_str) = key {
+                        if let Expression::StringLiteral(key_str) = key.into_unlocated() {
                             Some((key_str, value))
                         } else {
                             None
@@ -963,7 +1088,7 @@ impl Parser {
         self.next_token();
         
         // Parse the string literal that contains the token pattern
-        let pattern = match self.parse_expression(Precedence::Lowest)? {
+        let pattern = match self.parse_expression(Precedence::Lowest)?.into_unlocated() {
             Expression::StringLiteral(pattern) => pattern,
             _ => String::new(),
         };
@@ -998,12 +1123,12 @@ impl Parser {
         let expr = self.parse_expression(Precedence::Lowest)?;
         
         // Extract configuration from the map literal
-        let config = match expr {
+        let config = match expr.into_unlocated() {
             Expression::MapLiteral { pairs } => {
                 // Convert the map pairs to (String, Expression) format
                 pairs.into_iter()
                     .filter_map(|(key, value)| {
-                        if let Expression::StringLiteral(key_str) = key {
+                        if let Expression::StringLiteral(key_str) = key.into_unlocated() {
                             Some((key_str, value))
                         } else {
                             None
@@ -1044,12 +1169,12 @@ impl Parser {
         let expr = self.parse_expression(Precedence::Lowest)?;
         
         // Extract configuration from the map literal
-        let config = match expr {
+        let config = match expr.into_unlocated() {
             Expression::MapLiteral { pairs } => {
                 // Convert the map pairs to (String, Expression) format
                 pairs.into_iter()
                     .filter_map(|(key, value)| {
-                        if let Expression::StringLiteral(key_str) = key {
+                        if let Expression::StringLiteral(key_str) = key.into_unlocated() {
                             Some((key_str, value))
                         } else {
                             None
@@ -1095,12 +1220,12 @@ impl Parser {
         let expr = self.parse_expression(Precedence::Lowest)?;
         
         // Extract properties from the map literal
-        let properties = match expr {
+        let properties = match expr.into_unlocated() {
             Expression::MapLiteral { pairs } => {
                 // Convert the map pairs to (String, Expression) format
                 pairs.into_iter()
                     .filter_map(|(key, value)| {
-                        if let Expression::StringLiteral(key_str) = key {
+                        if let Expression::StringLiteral(key_str) = key.into_unlocated() {
                             Some((key_str, value))
                         } else {
                             None
@@ -1140,22 +1265,22 @@ impl Parser {
         // Parse the map literal or string literal for the rule
         let expr = self.parse_expression(Precedence::Lowest)?;
         
-        let (production, node_type) = match expr {
+        let (production, node_type) = match expr.into_unlocated() {
             Expression::MapLiteral { pairs } => {
                 // Extract production and node_type from the map
                 let mut production = String::new();
                 let mut node_type = None;
                 
                 for (key, value) in pairs {
-                    if let Expression::StringLiteral(key_str) = key {
+                    if let Expression::StringLiteral(key_str) = key.into_unlocated() {
                         match key_str.as_str() {
                             "production" => {
-                                if let Expression::StringLiteral(prod) = value {
+                                if let Expression::StringLiteral(prod) = value.into_unlocated() {
                                     production = prod;
                                 }
                             },
                             "astNode" => {
-                                if let Expression::Identifier(node) = value {
+                                if let Expression::Identifier(node) = value.into_unlocated() {
                                     node_type = Some(node);
                                 }
                             },
@@ -1204,16 +1329,16 @@ impl Parser {
         let expr = self.parse_expression(Precedence::Lowest)?;
         
         // Extract methods from the map literal
-        let methods = match expr {
+        let methods = match expr.into_unlocated() {
             Expression::MapLiteral { pairs } => {
                 let mut methods_vec = Vec::new();
                 
                 for (key, value) in pairs {
-                    if let Expression::StringLiteral(key_str) = key {
+                    if let Expression::StringLiteral(key_str) = key.into_unlocated() {
                         if key_str == "methods" {
-                            if let Expression::ArrayLiteral { elements } = value {
+                            if let Expression::ArrayLiteral { elements } = value.into_unlocated() {
                                 for elem in elements {
-                                    if let Expression::StringLiteral(method) = elem {
+                                    if let Expression::StringLiteral(method) = elem.into_unlocated() {
                                         methods_vec.push(method);
                                     }
                                 }
@@ -1257,16 +1382,16 @@ impl Parser {
         let expr = self.parse_expression(Precedence::Lowest)?;
         
         // Extract attributes from the map literal
-        let attributes = match expr {
+        let attributes = match expr.into_unlocated() {
             Expression::MapLiteral { pairs } => {
                 let mut attrs_vec = Vec::new();
                 
                 for (key, value) in pairs {
-                    if let Expression::StringLiteral(key_str) = key {
+                    if let Expression::StringLiteral(key_str) = key.into_unlocated() {
                         if key_str == "attributes" {
-                            if let Expression::ArrayLiteral { elements } = value {
+                            if let Expression::ArrayLiteral { elements } = value.into_unlocated() {
                                 for elem in elements {
-                                    if let Expression::StringLiteral(attr) = elem {
+                                    if let Expression::StringLiteral(attr) = elem.into_unlocated() {
                                         attrs_vec.push(attr);
                                     }
                                 }
@@ -1310,14 +1435,14 @@ impl Parser {
         let expr = self.parse_expression(Precedence::Lowest)?;
         
         // Extract parent from the map literal
-        let parent = match expr {
+        let parent = match expr.into_unlocated() {
             Expression::MapLiteral { pairs } => {
                 let mut parent_opt = None;
                 
                 for (key, value) in pairs {
-                    if let Expression::StringLiteral(key_str) = key {
+                    if let Expression::StringLiteral(key_str) = key.into_unlocated() {
                         if key_str == "parent" {
-                            match value {
+                            match value.into_unlocated() {
                                 Expression::Identifier(parent_name) => {
                                     parent_opt = Some(parent_name);
                                 },
@@ -1366,16 +1491,16 @@ impl Parser {
         let expr = self.parse_expression(Precedence::Lowest)?;
         
         // Extract operations from the map literal
-        let operations = match expr {
+        let operations = match expr.into_unlocated() {
             Expression::MapLiteral { pairs } => {
                 let mut ops_vec = Vec::new();
                 
                 for (key, value) in pairs {
-                    if let Expression::StringLiteral(key_str) = key {
+                    if let Expression::StringLiteral(key_str) = key.into_unlocated() {
                         if key_str == "operations" {
-                            if let Expression::ArrayLiteral { elements } = value {
+                            if let Expression::ArrayLiteral { elements } = value.into_unlocated() {
                                 for elem in elements {
-                                    if let Expression::StringLiteral(op) = elem {
+                                    if let Expression::StringLiteral(op) = elem.into_unlocated() {
                                         ops_vec.push(op);
                                     }
                                 }
@@ -1419,23 +1544,23 @@ impl Parser {
         let expr = self.parse_expression(Precedence::Lowest)?;
         
         // Extract opcode and operands from the map literal
-        let (opcode, operands) = match expr {
+        let (opcode, operands) = match expr.into_unlocated() {
             Expression::MapLiteral { pairs } => {
                 let mut opcode_str = String::new();
                 let mut operands_vec = Vec::new();
                 
                 for (key, value) in pairs {
-                    if let Expression::StringLiteral(key_str) = key {
+                    if let Expression::StringLiteral(key_str) = key.into_unlocated() {
                         match key_str.as_str() {
                             "opcode" => {
-                                if let Expression::StringLiteral(op) = value {
+                                if let Expression::StringLiteral(op) = value.into_unlocated() {
                                     opcode_str = op;
                                 }
                             },
                             "operands" => {
-                                if let Expression::ArrayLiteral { elements } = value {
+                                if let Expression::ArrayLiteral { elements } = value.into_unlocated() {
                                     for elem in elements {
-                                        if let Expression::StringLiteral(operand) = elem {
+                                        if let Expression::StringLiteral(operand) = elem.into_unlocated() {
                                             operands_vec.push(operand);
                                         }
                                     }
@@ -1482,16 +1607,16 @@ impl Parser {
         let expr = self.parse_expression(Precedence::Lowest)?;
         
         // Extract target and instructions from the map literal
-        let (target, instructions) = match expr {
+        let (target, instructions) = match expr.into_unlocated() {
             Expression::MapLiteral { pairs } => {
                 let mut target_str = String::new();
                 let mut instructions_pairs = Vec::new();
                 
                 for (key, value) in pairs {
-                    if let Expression::StringLiteral(key_str) = key {
+                    if let Expression::StringLiteral(key_str) = key.into_unlocated() {
                         match key_str.as_str() {
                             "architecture" => {
-                                if let Expression::StringLiteral(arch) = value {
+                                if let Expression::StringLiteral(arch) = value.into_unlocated() {
                                     target_str = arch;
                                 }
                             },
@@ -1539,23 +1664,23 @@ impl Parser {
         let expr = self.parse_expression(Precedence::Lowest)?;
         
         // Extract description and passes from the map literal
-        let (description, passes) = match expr {
+        let (description, passes) = match expr.into_unlocated() {
             Expression::MapLiteral { pairs } => {
                 let mut desc_str = String::new();
                 let mut passes_vec = Vec::new();
                 
                 for (key, value) in pairs {
-                    if let Expression::StringLiteral(key_str) = key {
+                    if let Expression::StringLiteral(key_str) = key.into_unlocated() {
                         match key_str.as_str() {
                             "description" => {
-                                if let Expression::StringLiteral(desc) = value {
+                                if let Expression::StringLiteral(desc) = value.into_unlocated() {
                                     desc_str = desc;
                                 }
                             },
                             "passes" => {
-                                if let Expression::ArrayLiteral { elements } = value {
+                                if let Expression::ArrayLiteral { elements } = value.into_unlocated() {
                                     for elem in elements {
-                                        if let Expression::StringLiteral(pass) = elem {
+                                        if let Expression::StringLiteral(pass) = elem.into_unlocated() {
                                             passes_vec.push(pass);
                                         }
                                     }
@@ -1602,12 +1727,12 @@ impl Parser {
         let expr = self.parse_expression(Precedence::Lowest)?;
         
         // Extract properties from the map literal
-        let properties = match expr {
+        let properties = match expr.into_unlocated() {
             Expression::MapLiteral { pairs } => {
                 // Convert the map pairs to (String, Expression) format
                 pairs.into_iter()
                     .filter_map(|(key, value)| {
-                        if let Expression::StringLiteral(key_str) = key {
+                        if let Expression::StringLiteral(key_str) = key.into_unlocated() {
                             Some((key_str, value))
                         } else {
                             None
@@ -1648,12 +1773,12 @@ impl Parser {
         let expr = self.parse_expression(Precedence::Lowest)?;
         
         // Extract values from the map literal
-        let values = match expr {
+        let values = match expr.into_unlocated() {
             Expression::MapLiteral { pairs } => {
                 // Convert the map pairs to (String, Expression) format
                 pairs.into_iter()
                     .filter_map(|(key, value)| {
-                        if let Expression::StringLiteral(key_str) = key {
+                        if let Expression::StringLiteral(key_str) = key.into_unlocated() {
                             Some((key_str, value))
                         } else {
                             None
@@ -1719,9 +1844,7 @@ impl Parser {
             
             Some(Statement::DocumentTypeDeclaration { doc_type })
         } else {
-            self.errors.push(format!("Expected document type after 'type{}', got {:?}", 
-                                    if has_equals { " =" } else { "" }, 
-                                    self.current_token.token_type));
+            self.error(diagnostic::UNEXPECTED_TOKEN, self.token_span(), format!("Expected document type after 'type{}', got {:?}", if has_equals { " =" } else { "" }, self.current_token.token_type));
             None
         }
     }
@@ -1737,7 +1860,7 @@ impl Parser {
         
         // First name must be an identifier
         if !self.current_token_is(TokenType::Identifier) {
-            self.errors.push(format!("Expected identifier after 'use', got {:?}", self.current_token.token_type));
+            self.error(diagnostic::UNEXPECTED_TOKEN, self.token_span(), format!("Expected identifier after 'use', got {:?}", self.current_token.token_type));
             return None;
         }
         
@@ -1749,7 +1872,7 @@ impl Parser {
             self.next_token(); // Skip 'as'
             
             if !self.current_token_is(TokenType::Identifier) {
-                self.errors.push(format!("Expected identifier after 'as', got {:?}", self.current_token.token_type));
+                self.error(diagnostic::UNEXPECTED_TOKEN, self.token_span(), format!("Expected identifier after 'as', got {:?}", self.current_token.token_type));
                 return None;
             }
             
@@ -1761,7 +1884,7 @@ impl Parser {
                 self.next_token(); // Skip comma
                 
                 if !self.current_token_is(TokenType::Identifier) {
-                    self.errors.push(format!("Expected identifier after comma, got {:?}", self.current_token.token_type));
+                    self.error(diagnostic::UNEXPECTED_TOKEN, self.token_span(), format!("Expected identifier after comma, got {:?}", self.current_token.token_type));
                     return None;
                 }
                 
@@ -1772,7 +1895,7 @@ impl Parser {
         
         // Expect 'from' keyword
         if !self.current_token_is(TokenType::From) {
-            self.errors.push(format!("Expected 'from' after import names, got {:?}", self.current_token.token_type));
+            self.error(diagnostic::UNEXPECTED_TOKEN, self.token_span(), format!("Expected 'from' after import names, got {:?}", self.current_token.token_type));
             return None;
         }
         
@@ -1781,7 +1904,7 @@ impl Parser {
         
         // Expect string literal for module path
         if !self.current_token_is(TokenType::StringLiteral) {
-            self.errors.push(format!("Expected string literal for module path, got {:?}", self.current_token.token_type));
+            self.error(diagnostic::UNEXPECTED_TOKEN, self.token_span(), format!("Expected string literal for module path, got {:?}", self.current_token.token_type));
             return None;
         }
         
@@ -1804,7 +1927,7 @@ impl Parser {
         self.next_token();
         
         if !self.current_token_is(TokenType::Identifier) {
-            self.errors.push(format!("Expected identifier after 'export', got {:?}", self.current_token.token_type));
+            self.error(diagnostic::UNEXPECTED_TOKEN, self.token_span(), format!("Expected identifier after 'export', got {:?}", self.current_token.token_type));
             return None;
         }
         
@@ -1911,16 +2034,15 @@ impl Parser {
         let prefix = self.prefix_parse_fns.get(&self.current_token.token_type).cloned();
         
         if prefix.is_none() {
-            self.errors.push(format!(
-                "No prefix parse function for {:?} found at line {}, column {}",
-                self.current_token.token_type,
-                self.current_token.line,
-                self.current_token.column
-            ));
+            let message = format!("Expected an expression, got {:?} instead", self.current_token.token_type);
+            self.report(Diagnostic::error(diagnostic::EXPECTED_EXPRESSION, message)
+                .with_primary(self.token_span(), "expected an expression"));
             return None;
         }
         
-        let mut left_exp = prefix.unwrap()(self)?;
+        let start = self.token_span();
+        let left_exp = prefix.unwrap()(self)?;
+        let mut left_exp = self.locate(start, left_exp);
         
         while !self.peek_token_is(TokenType::Semicolon) && precedence < self.peek_precedence() {
             let infix = self.infix_parse_fns.get(&self.peek_token.token_type).cloned();
@@ -1931,12 +2053,42 @@ impl Parser {
             
             self.next_token();
             
-            left_exp = infix.unwrap()(self, left_exp)?;
+            let infix_exp = infix.unwrap()(self, left_exp)?;
+            left_exp = self.locate(start, infix_exp);
         }
         
         Some(left_exp)
     }
     
+    // Span of the current token
+    fn token_span(&self) -> Span {
+        self.span_of(&self.current_token)
+    }
+    
+    fn peek_span(&self) -> Span {
+        self.span_of(&self.peek_token)
+    }
+    
+    fn span_of(&self, token: &Token) -> Span {
+        Span {
+            file: self.file,
+            start: token.start,
+            end: token.end,
+            line: token.line,
+            column: token.column,
+        }
+    }
+    
+    // Tag an expression with the source from `start` through the current token. A grouped
+    // expression is already tagged and just takes the wider span that includes its parentheses
+    fn locate(&self, start: Span, expression: Expression) -> Expression {
+        let span = Span { end: self.current_token.end, ..start };
+        match expression {
+            Expression::Located { expression, .. } => Expression::Located { span, expression },
+            expression => Expression::Located { span, expression: Box::new(expression) },
+        }
+    }
+    
     fn parse_identifier(&mut self) -> Option<Expression> {
         // Convert library tokens to identifiers when used in expressions
         let identifier = match self.current_token.token_type {
@@ -1962,16 +2114,73 @@ impl Parser {
         Some(Expression::StringLiteral(self.current_token.literal.clone()))
     }
     
+    // "a{x}b{y}c" arrives as StringStart("a"), x, StringMiddle("b"), y, StringEnd("c") and is
+    // desugared into "a" + x + "b" + y + "c". The leading string literal makes every `+` a
+    // concatenation, so interpolated values of any type are converted to text
+    fn parse_interpolated_string(&mut self) -> Option<Expression> {
+        let mut result = Expression::StringLiteral(self.current_token.literal.clone());
+        
+        loop {
+            if matches!(self.peek_token.token_type, TokenType::StringMiddle | TokenType::StringEnd) {
+                // The opening brace is the last character of the current string segment
+                let brace = Span { start: self.current_token.end - 1, ..self.token_span() };
+                self.report(Diagnostic::error(diagnostic::INVALID_INTERPOLATION, "Empty interpolation in string")
+                    .with_primary(self.peek_span(), "expected an expression before '}'")
+                    .with_suggestion("write \\{ for a literal brace", brace, "\\{"));
+                return None;
+            }
+            
+            self.next_token();
+            let part = self.parse_expression(Precedence::Lowest)?;
+            result = Expression::InfixExpression {
+                left: Box::new(result),
+                operator: "+".to_string(),
+                right: Box::new(part),
+            };
+            
+            if !matches!(self.peek_token.token_type, TokenType::StringMiddle | TokenType::StringEnd) {
+                self.error(diagnostic::INVALID_INTERPOLATION, self.peek_span(), format!("Expected '}}' to close string interpolation, got {:?} instead", self.peek_token.token_type));
+                return None;
+            }
+            self.next_token();
+            
+            if !self.current_token.literal.is_empty() {
+                result = Expression::InfixExpression {
+                    left: Box::new(result),
+                    operator: "+".to_string(),
+                    right: Box::new(Expression::StringLiteral(self.current_token.literal.clone())),
+                };
+            }
+            
+            if self.current_token_is(TokenType::StringEnd) {
+                return Some(result);
+            }
+        }
+    }
+    
+    // Stray characters come through as single-char Illegal tokens, while malformed strings and
+    // escapes carry the lexer's message and span the whole string
+    fn parse_illegal(&mut self) -> Option<Expression> {
+        let literal = &self.current_token.literal;
+        if literal.chars().count() == 1 {
+            let mut error = Diagnostic::error(diagnostic::UNEXPECTED_CHARACTER, format!("Unexpected character '{}'", literal))
+                .with_primary(self.token_span(), "");
+            if literal == "&" || literal == "|" {
+                let operator = literal.repeat(2);
+                error = error.with_suggestion(format!("use '{}' for a logical operator", operator), self.token_span(), operator);
+            }
+            self.report(error);
+        } else {
+            self.error(diagnostic::INVALID_STRING, self.token_span(), literal.clone());
+        }
+        None
+    }
+    
     fn parse_number_literal(&mut self) -> Option<Expression> {
         match self.current_token.literal.parse::<f64>() {
             Ok(value) => Some(Expression::NumberLiteral(value)),
             Err(_) => {
-                self.errors.push(format!(
-                    "Could not parse {} as number at line {}, column {}",
-                    self.current_token.literal,
-                    self.current_token.line,
-                    self.current_token.column
-                ));
+                self.error(diagnostic::INVALID_NUMBER, self.token_span(), format!("Could not parse {} as number", self.current_token.literal));
                 None
             }
         }
@@ -2051,18 +2260,12 @@ impl Parser {
         while !self.peek_token_is(TokenType::RightBrace) {
             self.next_token();
             
-            let key = self.parse_expression(Precedence::Lowest)?;
-            
-            if !self.expect_peek(TokenType::Colon) {
-                return None;
+            match self.parse_map_entry() {
+                Some(pair) => pairs.push(pair),
+                None if self.recover_in_list(&TokenType::RightBrace) => {},
+                None => return None,
             }
             
-            self.next_token();
-            
-            let value = self.parse_expression(Precedence::Lowest)?;
-            
-            pairs.push((key, value));
-            
             if !self.peek_token_is(TokenType::RightBrace) && !self.expect_peek(TokenType::Comma) {
                 return None;
             }
@@ -2075,6 +2278,19 @@ impl Parser {
         Some(Expression::MapLiteral { pairs })
     }
     
+    fn parse_map_entry(&mut self) -> Option<(Expression, Expression)> {
+        let key = self.parse_expression(Precedence::Lowest)?;
+        
+        if !self.expect_peek(TokenType::Colon) {
+            return None;
+        }
+        
+        self.next_token();
+        
+        let value = self.parse_expression(Precedence::Lowest)?;
+        Some((key, value))
+    }
+    
     fn parse_call_expression(&mut self, function: Expression) -> Option<Expression> {
         let arguments = self.parse_expression_list(TokenType::RightParen)?;
         
@@ -2155,10 +2371,7 @@ impl Parser {
             };
             
             if !is_library_token {
-                self.errors.push(format!(
-                    "Expected identifier after '.', got {:?} at line {}, column {}",
-                    self.current_token.token_type, self.current_token.line, self.current_token.column
-                ));
+                self.error(diagnostic::UNEXPECTED_TOKEN, self.token_span(), format!("Expected identifier after '.', got {:?}", self.current_token.token_type));
                 return None;
             }
         }
@@ -2176,7 +2389,7 @@ impl Parser {
     
     fn parse_namespace_expression(&mut self, left: Expression) -> Option<Expression> {
         // left should be the namespace identifier
-        let namespace = match left {
+        let namespace = match left.into_unlocated() {
             Expression::Identifier(name) => name,
             _ => return None,
         };
@@ -2229,12 +2442,18 @@ impl Parser {
         
         self.next_token();
         
-        list.push(self.parse_expression(Precedence::Lowest)?);
-        
-        while self.peek_token_is(TokenType::Comma) {
+        loop {
+            match self.parse_expression(Precedence::Lowest) {
+                Some(expression) => list.push(expression),
+                None if self.recover_in_list(&end) => {},
+                None => return None,
+            }
+            
+            if !self.peek_token_is(TokenType::Comma) {
+                break;
+            }
             self.next_token();
             self.next_token();
-            list.push(self.parse_expression(Precedence::Lowest)?);
         }
         
         if !self.expect_peek(end) {
@@ -2270,53 +2489,156 @@ impl Parser {
             _ => Precedence::Lowest,
         }
     }
-    // Parse class declaration (class Name { ... })
+    // Parse class declaration (class Name [extends Parent] { ... })
     fn parse_class_declaration(&mut self) -> Option<Statement> {
+        let (name, parent, body) = self.parse_class()?;
+        Some(Statement::ClassDeclaration { name, parent, body })
+    }
+    
+    // Parse the name, parent class and body of a class, from the 'class' keyword to the
+    // closing brace
+    fn parse_class(&mut self) -> Option<(String, Option<String>, Vec<Statement>)> {
         // Get the current token position for error reporting
-        let token_line = self.current_token.line;
-        let token_column = self.current_token.column;
+        let keyword = self.token_span();
         
         // Expect class name (identifier)
         if !self.expect_peek(TokenType::Identifier) {
-            self.errors.push(format!(
-                "Expected class name after 'class' keyword at line {}, column {}",
-                token_line, token_column
-            ));
+            self.error(diagnostic::UNEXPECTED_TOKEN, keyword, "Expected class name after 'class' keyword");
             return None;
         }
         
         let class_name = self.current_token.literal.clone();
         
+        // Optional parent class: class Name extends Parent
+        let mut parent = None;
+        if self.peek_token_is(TokenType::Identifier) && self.peek_token.literal == "extends" {
+            self.next_token();
+            if !self.expect_peek(TokenType::Identifier) {
+                self.error(diagnostic::UNEXPECTED_TOKEN, self.token_span(), "Expected parent class name after 'extends'");
+                return None;
+            }
+            parent = Some(self.current_token.literal.clone());
+        }
+        
         // Expect opening brace
         if !self.expect_peek(TokenType::LeftBrace) {
-            self.errors.push(format!(
-                "Expected '{{' after class name at line {}, column {}",
-                self.current_token.line, self.current_token.column
-            ));
+            self.error(diagnostic::UNEXPECTED_TOKEN, self.token_span(), "Expected '{' after class name");
             return None;
         }
         
-        // Parse class body
-        let body = self.parse_block_statement();
+        let body = self.parse_class_body()?;
+        Some((class_name, parent, body))
+    }
+    
+    // Parse the fields and methods of a class body, ending on its closing brace
+    fn parse_class_body(&mut self) -> Option<Vec<Statement>> {
+        let mut body = Vec::new();
         
-        Some(Statement::ClassDeclaration {
-            name: class_name,
-            body,
-        })
+        // Skip '{' token
+        self.next_token();
+        
+        while !self.current_token_is(TokenType::RightBrace) && !self.current_token_is(TokenType::EOF) {
+            let depth = self.brace_depth;
+            let member = match self.current_token.token_type {
+                TokenType::Comment | TokenType::Semicolon => None,
+                TokenType::Fun => self.parse_statement(),
+                TokenType::Num | TokenType::Str | TokenType::Bool | TokenType::Var |
+                TokenType::List | TokenType::Arr | TokenType::Map => {
+                    let start = self.token_span();
+                    self.parse_field_declaration().map(|field| Statement::Located {
+                        span: Span { end: self.current_token.end, ..start },
+                        statement: Box::new(field),
+                    })
+                },
+                _ => {
+                    self.error(diagnostic::UNEXPECTED_TOKEN, self.token_span(), format!("Expected a field or method in class body, got {:?}", self.current_token.token_type));
+                    None
+                }
+            };
+            match member {
+                Some(member) => body.push(member),
+                // Skip the broken member and carry on with the next one
+                None if self.panicking && self.synchronize(depth) => break,
+                None => {},
+            }
+            self.panicking = false;
+            self.next_token();
+        }
+        
+        if !self.current_token_is(TokenType::RightBrace) {
+            self.error(diagnostic::UNEXPECTED_TOKEN, self.token_span(), "Expected '}' to close class body");
+            return None;
+        }
+        
+        Some(body)
+    }
+    
+    // Parse a class field. The value is optional, and a type may follow the name as in
+    // `var stats: map<str, num> = {}`
+    fn parse_field_declaration(&mut self) -> Option<Statement> {
+        let mut var_type = self.current_token.literal.clone();
+        
+        if !self.expect_peek(TokenType::Identifier) {
+            return None;
+        }
+        
+        let name = self.current_token.literal.clone();
+        
+        if self.peek_token_is(TokenType::Colon) {
+            self.next_token();
+            self.next_token();
+            let annotation = self.current_token.literal.clone();
+            
+            // Skip type arguments, which are not checked
+            if self.peek_token_is(TokenType::Less) {
+                let mut depth = 0;
+                loop {
+                    self.next_token();
+                    match self.current_token.token_type {
+                        TokenType::Less => depth += 1,
+                        TokenType::Greater => {
+                            depth -= 1;
+                            if depth == 0 {
+                                break;
+                            }
+                        },
+                        TokenType::EOF => {
+                            self.error(diagnostic::UNEXPECTED_TOKEN, self.token_span(), format!("Unterminated type of field '{}'", name));
+                            return None;
+                        },
+                        _ => {},
+                    }
+                }
+            }
+            
+            // A `var` field with a known type is declared as that type
+            if var_type == "var" && matches!(annotation.as_str(), "num" | "str" | "bool" | "list" | "map") {
+                var_type = annotation;
+            }
+        }
+        
+        let mut value = None;
+        if self.peek_token_is(TokenType::Assign) {
+            self.next_token();
+            self.next_token();
+            value = Some(self.parse_expression(Precedence::Lowest)?);
+        }
+        
+        if self.peek_token_is(TokenType::Semicolon) {
+            self.next_token();
+        }
+        
+        Some(Statement::VariableDeclaration { var_type, name, value })
     }
     
     // Parse API declaration (api name = from("url"))
     fn parse_api_declaration(&mut self) -> Option<Statement> {
         // Get the current token position for error reporting
-        let token_line = self.current_token.line;
-        let token_column = self.current_token.column;
+        let keyword = self.token_span();
         
         // Expect API name (identifier)
         if !self.expect_peek(TokenType::Identifier) {
-            self.errors.push(format!(
-                "Expected API name after 'api' keyword at line {}, column {}",
-                token_line, token_column
-            ));
+            self.error(diagnostic::UNEXPECTED_TOKEN, keyword, "Expected API name after 'api' keyword");
             return None;
         }
         
@@ -2324,39 +2646,25 @@ impl Parser {
         
         // Expect assignment operator
         if !self.expect_peek(TokenType::Assign) {
-            self.errors.push(format!(
-                "Expected '=' after API name at line {}, column {}",
-                self.current_token.line, self.current_token.column
-            ));
+            self.error(diagnostic::UNEXPECTED_TOKEN, self.token_span(), "Expected '=' after API name");
             return None;
         }
         
         // Expect 'from' keyword
         if !self.expect_peek(TokenType::From) {
-            self.errors.push(format!(
-                "Expected 'from' after '=' in API declaration at line {}, column {}",
-                self.current_token.line, self.current_token.column
-            ));
+            self.error(diagnostic::UNEXPECTED_TOKEN, self.token_span(), "Expected 'from' after '=' in API declaration");
             return None;
         }
         
         // Expect opening parenthesis
         if !self.expect_peek(TokenType::LeftParen) {
-            self.errors.push(format!(
-                "Expected '(' after 'from' in API declaration at line {}, column {}",
-                self.current_token.line, self.current_token.column
-            ));
+            self.error(diagnostic::UNEXPECTED_TOKEN, self.token_span(), "Expected '(' after 'from' in API declaration");
             return None;
         }
         
         // Expect URL string
         if !self.expect_peek(TokenType::StringLiteral) {
-            self.errors.push(format!(
-                "Expected string literal in API declaration, got {:?} instead at line {}, column {}",
-                self.current_token.token_type,
-                self.current_token.line,
-                self.current_token.column
-            ));
+            self.error(diagnostic::UNEXPECTED_TOKEN, self.token_span(), format!("Expected string literal in API declaration, got {:?} instead", self.current_token.token_type));
             return None;
         }
         
@@ -2364,10 +2672,7 @@ impl Parser {
         
         // Expect closing parenthesis
         if !self.expect_peek(TokenType::RightParen) {
-            self.errors.push(format!(
-                "Expected ')' after URL in API declaration at line {}, column {}",
-                self.current_token.line, self.current_token.column
-            ));
+            self.error(diagnostic::UNEXPECTED_TOKEN, self.token_span(), "Expected ')' after URL in API declaration");
             return None;
         }
         
@@ -2385,15 +2690,11 @@ impl Parser {
     // Parse API call (call api_name { ... })
     fn parse_api_call(&mut self) -> Option<Statement> {
         // Get the current token position for error reporting
-        let token_line = self.current_token.line;
-        let token_column = self.current_token.column;
+        let keyword = self.token_span();
         
         // Expect API name (identifier)
         if !self.expect_peek(TokenType::Identifier) {
-            self.errors.push(format!(
-                "Expected API name after 'call' keyword at line {}, column {}",
-                token_line, token_column
-            ));
+            self.error(diagnostic::UNEXPECTED_TOKEN, keyword, "Expected API name after 'call' keyword");
             return None;
         }
         
@@ -2401,10 +2702,7 @@ impl Parser {
         
         // Expect opening brace
         if !self.expect_peek(TokenType::LeftBrace) {
-            self.errors.push(format!(
-                "Expected '{{' after API name in call statement at line {}, column {}",
-                self.current_token.line, self.current_token.column
-            ));
+            self.error(diagnostic::UNEXPECTED_TOKEN, self.token_span(), "Expected '{' after API name in call statement");
             return None;
         }
         
@@ -2420,15 +2718,11 @@ impl Parser {
     // Parse connect statement (connect name = from("url") { options })
     fn parse_connect_statement(&mut self) -> Option<Statement> {
         // Get the current token position for error reporting
-        let token_line = self.current_token.line;
-        let token_column = self.current_token.column;
+        let keyword = self.token_span();
         
         // Expect connection name (identifier)
         if !self.expect_peek(TokenType::Identifier) {
-            self.errors.push(format!(
-                "Expected connection name after 'connect' keyword at line {}, column {}",
-                token_line, token_column
-            ));
+            self.error(diagnostic::UNEXPECTED_TOKEN, keyword, "Expected connection name after 'connect' keyword");
             return None;
         }
         
@@ -2436,41 +2730,25 @@ impl Parser {
         
         // Expect assignment operator
         if !self.expect_peek(TokenType::Assign) {
-            self.errors.push(format!(
-                "Expected '=' after connection name at line {}, column {}",
-                self.current_token.line, self.current_token.column
-            ));
+            self.error(diagnostic::UNEXPECTED_TOKEN, self.token_span(), "Expected '=' after connection name");
             return None;
         }
         
         // Expect 'from' keyword
         if !self.expect_peek(TokenType::From) {
-            self.errors.push(format!(
-                "Expected 'from' after '=' in connect statement, got {:?} instead at line {}, column {}",
-                self.current_token.token_type,
-                self.current_token.line,
-                self.current_token.column
-            ));
+            self.error(diagnostic::UNEXPECTED_TOKEN, self.token_span(), format!("Expected 'from' after '=' in connect statement, got {:?} instead", self.current_token.token_type));
             return None;
         }
         
         // Expect opening parenthesis
         if !self.expect_peek(TokenType::LeftParen) {
-            self.errors.push(format!(
-                "Expected '(' after 'from' in connect statement at line {}, column {}",
-                self.current_token.line, self.current_token.column
-            ));
+            self.error(diagnostic::UNEXPECTED_TOKEN, self.token_span(), "Expected '(' after 'from' in connect statement");
             return None;
         }
         
         // Expect URL string
         if !self.expect_peek(TokenType::StringLiteral) {
-            self.errors.push(format!(
-                "Expected string literal in connect statement, got {:?} instead at line {}, column {}",
-                self.current_token.token_type,
-                self.current_token.line,
-                self.current_token.column
-            ));
+            self.error(diagnostic::UNEXPECTED_TOKEN, self.token_span(), format!("Expected string literal in connect statement, got {:?} instead", self.current_token.token_type));
             return None;
         }
         
@@ -2478,10 +2756,7 @@ impl Parser {
         
         // Expect closing parenthesis
         if !self.expect_peek(TokenType::RightParen) {
-            self.errors.push(format!(
-                "Expected ')' after URL in connect statement at line {}, column {}",
-                self.current_token.line, self.current_token.column
-            ));
+            self.error(diagnostic::UNEXPECTED_TOKEN, self.token_span(), "Expected ')' after URL in connect statement");
             return None;
         }
         
@@ -2497,10 +2772,7 @@ impl Parser {
                 loop {
                     // Expect option name (identifier)
                     if !self.current_token_is(TokenType::Identifier) {
-                        self.errors.push(format!(
-                            "Expected option name in connect configuration at line {}, column {}",
-                            self.current_token.line, self.current_token.column
-                        ));
+                        self.error(diagnostic::UNEXPECTED_TOKEN, self.token_span(), "Expected option name in connect configuration");
                         return None;
                     }
                     
@@ -2511,12 +2783,7 @@ impl Parser {
                     let option_value = if let Some(expr) = self.parse_expression(Precedence::Lowest) {
                         expr
                     } else {
-                        self.errors.push(format!(
-                            "Expected expression for option '{}' at line {}, column {}",
-                            option_name,
-                            self.current_token.line,
-                            self.current_token.column
-                        ));
+                        self.error(diagnostic::UNEXPECTED_TOKEN, self.token_span(), format!("Expected expression for option '{}'", option_name));
                         return None;
                     };
                     
@@ -2534,10 +2801,7 @@ impl Parser {
                     
                     // If we haven't reached the end, there should be more options
                     if self.current_token_is(TokenType::EOF) {
-                        self.errors.push(format!(
-                            "Unexpected end of file in connect configuration at line {}, column {}",
-                            self.current_token.line, self.current_token.column
-                        ));
+                        self.error(diagnostic::UNEXPECTED_TOKEN, self.token_span(), "Unexpected end of file in connect configuration");
                         return None;
                     }
                 }
@@ -2559,15 +2823,11 @@ impl Parser {
     // Parse import statement (import {name} from(./path/to/file))
     fn parse_import_statement(&mut self) -> Option<Statement> {
         // Get the current token position for error reporting
-        let token_line = self.current_token.line;
-        let token_column = self.current_token.column;
+        let keyword = self.token_span();
         
         // Check for { to start import list
         if !self.expect_peek(TokenType::LeftBrace) {
-            self.errors.push(format!(
-                "Expected '{{' after 'import' keyword at line {}, column {}",
-                token_line, token_column
-            ));
+            self.error(diagnostic::UNEXPECTED_TOKEN, keyword, "Expected '{' after 'import' keyword");
             return None;
         }
         
@@ -2578,10 +2838,7 @@ impl Parser {
         
         // Handle empty import list
         if self.current_token_is(TokenType::RightBrace) {
-            self.errors.push(format!(
-                "Empty import list at line {}, column {}",
-                self.current_token.line, self.current_token.column
-            ));
+            self.error(diagnostic::UNEXPECTED_TOKEN, self.token_span(), "Empty import list");
             return None;
         }
         
@@ -2590,12 +2847,7 @@ impl Parser {
             if self.current_token_is(TokenType::Identifier) {
                 imports.push(self.current_token.literal.clone());
             } else {
-                self.errors.push(format!(
-                    "Expected identifier in import list, got {:?} instead at line {}, column {}",
-                    self.current_token.token_type,
-                    self.current_token.line,
-                    self.current_token.column
-                ));
+                self.error(diagnostic::UNEXPECTED_TOKEN, self.token_span(), format!("Expected identifier in import list, got {:?} instead", self.current_token.token_type));
                 return None;
             }
             
@@ -2606,31 +2858,20 @@ impl Parser {
             if self.current_token_is(TokenType::Comma) {
                 self.next_token(); // Skip comma and continue
             } else if !self.current_token_is(TokenType::RightBrace) {
-                self.errors.push(format!(
-                    "Expected ',' or '}}' after import name, got {:?} instead at line {}, column {}",
-                    self.current_token.token_type,
-                    self.current_token.line,
-                    self.current_token.column
-                ));
+                self.error(diagnostic::UNEXPECTED_TOKEN, self.token_span(), format!("Expected ',' or '}}' after import name, got {:?} instead", self.current_token.token_type));
                 return None;
             }
         }
         
         // Check for from keyword
         if !self.expect_peek(TokenType::From) {
-            self.errors.push(format!(
-                "Expected 'from' after import list at line {}, column {}",
-                self.current_token.line, self.current_token.column
-            ));
+            self.error(diagnostic::UNEXPECTED_TOKEN, self.token_span(), "Expected 'from' after import list");
             return None;
         }
         
         // Check for opening parenthesis
         if !self.expect_peek(TokenType::LeftParen) {
-            self.errors.push(format!(
-                "Expected '(' after 'from' in import statement at line {}, column {}",
-                self.current_token.line, self.current_token.column
-            ));
+            self.error(diagnostic::UNEXPECTED_TOKEN, self.token_span(), "Expected '(' after 'from' in import statement");
             return None;
         }
         
@@ -2659,21 +2900,13 @@ impl Parser {
             
             path_str
         } else {
-            self.errors.push(format!(
-                "Expected string literal or path in import statement, got {:?} instead at line {}, column {}",
-                self.current_token.token_type,
-                self.current_token.line,
-                self.current_token.column
-            ));
+            self.error(diagnostic::UNEXPECTED_TOKEN, self.token_span(), format!("Expected string literal or path in import statement, got {:?} instead", self.current_token.token_type));
             return None;
         };
         
         // Check for closing parenthesis
         if !self.expect_peek(TokenType::RightParen) {
-            self.errors.push(format!(
-                "Expected ')' after path in import statement at line {}, column {}",
-                self.current_token.line, self.current_token.column
-            ));
+            self.error(diagnostic::UNEXPECTED_TOKEN, self.token_span(), "Expected ')' after path in import statement");
             return None;
         }
         
@@ -2691,8 +2924,7 @@ impl Parser {
     // Parse library import statement (lib name)
     fn parse_lib_statement(&mut self) -> Option<Statement> {
         // Get the current token position for error reporting
-        let token_line = self.current_token.line;
-        let token_column = self.current_token.column;
+        let keyword = self.token_span();
         
         // Move to the next token
         self.next_token();
@@ -2721,10 +2953,7 @@ impl Parser {
         };
         
         if !is_valid_library {
-            self.errors.push(format!(
-                "Expected library name after 'lib' keyword at line {}, column {}",
-                token_line, token_column
-            ));
+            self.error(diagnostic::UNEXPECTED_TOKEN, keyword, "Expected library name after 'lib' keyword");
             return None;
         }
         
@@ -2750,10 +2979,7 @@ impl Parser {
         
         // Expect opening parenthesis
         if !self.expect_peek(TokenType::LeftParen) {
-            self.errors.push(format!(
-                "Expected '(' after 'load' keyword at line {}, column {}",
-                self.current_token.line, self.current_token.column
-            ));
+            self.error(diagnostic::UNEXPECTED_TOKEN, self.token_span(), "Expected '(' after 'load' keyword");
             return None;
         }
         
@@ -2764,29 +2990,20 @@ impl Parser {
         let cycles = match self.parse_expression(Precedence::Lowest) {
             Some(expr) => expr,
             None => {
-                self.errors.push(format!(
-                    "Expected number after 'load(' at line {}, column {}",
-                    self.current_token.line, self.current_token.column
-                ));
+                self.error(diagnostic::UNEXPECTED_TOKEN, self.token_span(), "Expected number after 'load('");
                 return None;
             }
         };
         
         // Expect closing parenthesis
         if !self.expect_peek(TokenType::RightParen) {
-            self.errors.push(format!(
-                "Expected ')' after load count at line {}, column {}",
-                self.current_token.line, self.current_token.column
-            ));
+            self.error(diagnostic::UNEXPECTED_TOKEN, self.token_span(), "Expected ')' after load count");
             return None;
         }
         
         // Expect opening brace for block
         if !self.expect_peek(TokenType::LeftBrace) {
-            self.errors.push(format!(
-                "Expected '{{' after 'load()' at line {}, column {}",
-                self.current_token.line, self.current_token.column
-            ));
+            self.error(diagnostic::UNEXPECTED_TOKEN, self.token_span(), "Expected '{' after 'load()'");
             return None;
         }
         
@@ -2795,11 +3012,12 @@ impl Parser {
         
         // Validate that all statements in the block are 'show' statements
         for stmt in &block {
-            if !matches!(stmt, Statement::ShowStatement { .. }) {
-                self.errors.push(format!(
-                    "Only 'show' statements are allowed inside 'load' blocks at line {}, column {}",
-                    self.current_token.line, self.current_token.column
-                ));
+            if !matches!(stmt.unlocated(), Statement::ShowStatement { .. }) {
+                let span = match stmt {
+                    Statement::Located { span, .. } => *span,
+                    _ => self.token_span(),
+                };
+                self.error(diagnostic::INVALID_LOAD_BODY, span, "Only 'show' statements are allowed inside 'load' blocks");
                 break;
             }
         }
@@ -2814,8 +3032,7 @@ impl Parser {
         self.next_token(); // Move past 'is' to the identifier
 
         if !self.current_token_is(TokenType::Identifier) {
-            self.errors.push(format!("Expected identifier after 'is' at line {}, column {}", 
-                self.current_token.line, self.current_token.column));
+            self.error(diagnostic::UNEXPECTED_TOKEN, self.token_span(), "Expected identifier after 'is'");
             return None;
         }
         
@@ -2866,7 +3083,7 @@ impl Parser {
     
     fn parse_when_statement(&mut self) -> Option<Statement> {
         // 'when' is a pattern matching statement, similar to a switch/case
-        // Example: when x { 1 => { ... }, 2 => { ... }, _ => { ... } }
+        // Example: when x { 1 => { ... }, 2..5 => { ... }, n if n > 9 => { ... }, _ => { ... } }
         
         self.next_token(); // Move past 'when' to the expression
         
@@ -2876,22 +3093,150 @@ impl Parser {
             return None;
         }
         
-        // TODO: Implement pattern matching statements
-        // For now, we'll just parse a block and return it directly
-        let block = self.parse_block_statement();
+        // Skip '{' token
+        self.next_token();
         
-        // Return a simple block statement until pattern matching is implemented
-        Some(Statement::BlockStatement {
-            statements: block,
-        })
+        let mut arms = Vec::new();
+        
+        while !self.current_token_is(TokenType::RightBrace) && !self.current_token_is(TokenType::EOF) {
+            if self.current_token_is(TokenType::Comment) {
+                self.next_token();
+                continue;
+            }
+            
+            let pattern = self.parse_pattern()?;
+            
+            // Optional guard: pattern if condition => { ... }
+            let mut guard = None;
+            if self.peek_token_is(TokenType::If) {
+                self.next_token();
+                self.next_token();
+                guard = Some(self.parse_expression(Precedence::Lowest)?);
+            }
+            
+            if !self.expect_peek(TokenType::FatArrow) || !self.expect_peek(TokenType::LeftBrace) {
+                return None;
+            }
+            
+            let body = self.parse_block_statement();
+            arms.push(WhenArm { pattern, guard, body });
+            
+            // Skip comma if present
+            if self.peek_token_is(TokenType::Comma) {
+                self.next_token();
+            }
+            
+            // Move to the next arm
+            self.next_token();
+        }
+        
+        if !self.current_token_is(TokenType::RightBrace) {
+            self.error(diagnostic::UNEXPECTED_TOKEN, self.token_span(), "Expected '}' to close 'when'");
+            return None;
+        }
+        
+        Some(Statement::WhenStatement { value, arms })
+    }
+    
+    // Parse a single when pattern, leaving the current token on its last token
+    fn parse_pattern(&mut self) -> Option<Pattern> {
+        match self.current_token.token_type {
+            TokenType::Identifier if self.current_token.literal == "_" => Some(Pattern::Wildcard),
+            TokenType::Identifier => {
+                let name = self.current_token.literal.clone();
+                
+                // Enum variants are written Enum.VARIANT or Enum[VARIANT]
+                if self.peek_token_is(TokenType::Dot) {
+                    self.next_token();
+                    if !self.expect_peek(TokenType::Identifier) {
+                        return None;
+                    }
+                    let variant = self.current_token.literal.clone();
+                    return Some(Pattern::EnumVariant { enum_name: name, variant });
+                }
+                if self.peek_token_is(TokenType::LeftBracket) {
+                    self.next_token();
+                    if !self.expect_peek(TokenType::Identifier) {
+                        return None;
+                    }
+                    let variant = self.current_token.literal.clone();
+                    if !self.expect_peek(TokenType::RightBracket) {
+                        return None;
+                    }
+                    return Some(Pattern::EnumVariant { enum_name: name, variant });
+                }
+                
+                Some(Pattern::Binding(name))
+            },
+            TokenType::LeftBracket => {
+                let mut elements = Vec::new();
+                let mut rest = None;
+                
+                if self.peek_token_is(TokenType::RightBracket) {
+                    self.next_token();
+                    return Some(Pattern::Array { elements, rest });
+                }
+                
+                loop {
+                    self.next_token();
+                    
+                    // A trailing ..name collects the remaining elements
+                    if self.current_token_is(TokenType::DotDot) {
+                        let mut rest_pattern = Pattern::Wildcard;
+                        if self.peek_token_is(TokenType::Identifier) {
+                            self.next_token();
+                            if self.current_token.literal != "_" {
+                                rest_pattern = Pattern::Binding(self.current_token.literal.clone());
+                            }
+                        }
+                        rest = Some(Box::new(rest_pattern));
+                        if !self.expect_peek(TokenType::RightBracket) {
+                            return None;
+                        }
+                        break;
+                    }
+                    
+                    elements.push(self.parse_pattern()?);
+                    
+                    if self.peek_token_is(TokenType::Comma) {
+                        self.next_token();
+                    } else if !self.expect_peek(TokenType::RightBracket) {
+                        return None;
+                    } else {
+                        break;
+                    }
+                }
+                
+                Some(Pattern::Array { elements, rest })
+            },
+            TokenType::NumberLiteral | TokenType::StringLiteral | TokenType::True |
+            TokenType::False | TokenType::Null | TokenType::Minus => {
+                let start = self.parse_expression(Precedence::Prefix)?;
+                
+                if self.peek_token_is(TokenType::DotDot) || self.peek_token_is(TokenType::DotDotEqual) {
+                    self.next_token();
+                    let inclusive = self.current_token_is(TokenType::DotDotEqual);
+                    self.next_token();
+                    let end = self.parse_expression(Precedence::Prefix)?;
+                    return Some(Pattern::Range { start, end, inclusive });
+                }
+                
+                Some(Pattern::Literal(start))
+            },
+            _ => {
+                self.error(diagnostic::UNEXPECTED_TOKEN, self.token_span(), format!("Expected a pattern in 'when' but found {:?}", self.current_token.token_type));
+                None
+            }
+        }
     }
     
     fn parse_else_statement(&mut self) -> Option<Statement> {
         // 'else' should only appear after an 'if' statement
         // This is a syntax error if it appears standalone
         
-        self.errors.push(format!("Unexpected 'else' statement without matching 'if' at line {}, column {}",
-            self.current_token.line, self.current_token.column));
+        self.report(Diagnostic::error(diagnostic::ELSE_WITHOUT_IF, "Unexpected 'else' statement without matching 'if'")
+            .with_primary(self.token_span(), "")
+            .with_note("'else' must directly follow the closing brace of an 'if' block"));
         None
     }
     
@@ -2902,10 +3247,7 @@ impl Parser {
         
         // Expect identifier (constant name)
         if !self.current_token_is(TokenType::Identifier) {
-            self.errors.push(format!(
-                "Expected identifier after 'const' keyword at line {}, column {}",
-                self.current_token.line, self.current_token.column
-            ));
+            self.error(diagnostic::UNEXPECTED_TOKEN, self.token_span(), "Expected identifier after 'const' keyword");
             return None;
         }
         
@@ -2940,10 +3282,7 @@ impl Parser {
         
         // Expect identifier (enum name)
         if !self.current_token_is(TokenType::Identifier) {
-            self.errors.push(format!(
-                "Expected identifier after 'enum' keyword at line {}, column {}",
-                self.current_token.line, self.current_token.column
-            ));
+            self.error(diagnostic::UNEXPECTED_TOKEN, self.token_span(), "Expected identifier after 'enum' keyword");
             return None;
         }
         
@@ -2964,10 +3303,7 @@ impl Parser {
         while !self.current_token_is(TokenType::RightBrace) && !self.current_token_is(TokenType::EOF) {
             // Expect identifier (variant name)
             if !self.current_token_is(TokenType::Identifier) {
-                self.errors.push(format!(
-                    "Expected identifier for enum variant at line {}, column {}",
-                    self.current_token.line, self.current_token.column
-                ));
+                self.error(diagnostic::UNEXPECTED_TOKEN, self.token_span(), "Expected identifier for enum variant");
                 return None;
             }
             
@@ -3009,10 +3345,7 @@ impl Parser {
         
         // Expect 'fun' keyword
         if !self.current_token_is(TokenType::Fun) {
-            self.errors.push(format!(
-                "Expected 'fun' keyword after 'inline' at line {}, column {}",
-                self.current_token.line, self.current_token.column
-            ));
+            self.error(diagnostic::UNEXPECTED_TOKEN, self.token_span(), "Expected 'fun' keyword after 'inline'");
             return None;
         }
         
@@ -3021,10 +3354,7 @@ impl Parser {
         
         // Expect identifier (function name)
         if !self.current_token_is(TokenType::Identifier) {
-            self.errors.push(format!(
-                "Expected function name after 'inline fun' at line {}, column {}",
-                self.current_token.line, self.current_token.column
-            ));
+            self.error(diagnostic::UNEXPECTED_TOKEN, self.token_span(), "Expected function name after 'inline fun'");
             return None;
         }
         
@@ -3053,46 +3383,16 @@ impl Parser {
         })
     }
     
-    // Parse final class declaration (final class Name { ... })
+    // Parse final class declaration (final class Name [extends Parent] { ... })
     fn parse_final_class_declaration(&mut self) -> Option<Statement> {
-        // Skip 'final' token
-        self.next_token();
-        
         // Expect 'class' keyword
-        if !self.current_token_is(TokenType::Class) {
-            self.errors.push(format!(
-                "Expected 'class' keyword after 'final' at line {}, column {}",
-                self.current_token.line, self.current_token.column
-            ));
-            return None;
-        }
-        
-        // Skip 'class' token
-        self.next_token();
-        
-        // Expect identifier (class name)
-        if !self.current_token_is(TokenType::Identifier) {
-            self.errors.push(format!(
-                "Expected class name after 'final class' at line {}, column {}",
-                self.current_token.line, self.current_token.column
-            ));
-            return None;
-        }
-        
-        let name = self.current_token.literal.clone();
-        
-        // Expect opening brace
-        if !self.expect_peek(TokenType::LeftBrace) {
+        if !self.expect_peek(TokenType::Class) {
+            self.error(diagnostic::UNEXPECTED_TOKEN, self.token_span(), "Expected 'class' keyword after 'final'");
             return None;
         }
         
-        // Parse class body
-        let body = self.parse_block_statement();
-        
-        Some(Statement::FinalClassDeclaration {
-            name,
-            body,
-        })
+        let (name, parent, body) = self.parse_class()?;
+        Some(Statement::FinalClassDeclaration { name, parent, body })
     }
     
     // Parse volatile variable declaration (volatile let/hold/etc name = value;)
@@ -3106,10 +3406,7 @@ impl Parser {
                 self.current_token.literal.clone()
             },
             _ => {
-                self.errors.push(format!(
-                    "Expected variable type keyword after 'volatile' at line {}, column {}",
-                    self.current_token.line, self.current_token.column
-                ));
+                self.error(diagnostic::UNEXPECTED_TOKEN, self.token_span(), "Expected variable type keyword after 'volatile'");
                 return None;
             }
         };
@@ -3119,10 +3416,7 @@ impl Parser {
         
         // Expect identifier (variable name)
         if !self.current_token_is(TokenType::Identifier) {
-            self.errors.push(format!(
-                "Expected variable name after 'volatile {}' at line {}, column {}",
-                var_type, self.current_token.line, self.current_token.column
-            ));
+            self.error(diagnostic::UNEXPECTED_TOKEN, self.token_span(), format!("Expected variable name after 'volatile {}'", var_type));
             return None;
         }
         
@@ -3167,12 +3461,12 @@ mod tests {
         assert_eq!(parser.get_errors().len(), 0, "Parser errors: {:?}", parser.get_errors());
         assert_eq!(program.statements.len(), 1);
         
-        match &program.statements[0] {
+        match program.statements[0].unlocated() {
             Statement::VariableDeclaration { var_type, name, value } => {
                 assert_eq!(var_type, "let");
                 assert_eq!(name, "x");
                 
-                match value {
+                match value.as_ref().map(Expression::unlocated) {
                     Some(Expression::NumberLiteral(val)) => assert_eq!(*val, 5.0),
                     _ => panic!("Expected NumberLiteral, got {:?}", value),
                 }
@@ -3192,24 +3486,24 @@ mod tests {
         assert_eq!(parser.get_errors().len(), 0, "Parser errors: {:?}", parser.get_errors());
         assert_eq!(program.statements.len(), 1);
         
-        match &program.statements[0] {
+        match program.statements[0].unlocated() {
             Statement::FunctionDeclaration { name, parameters, body } => {
                 assert_eq!(name, "add");
                 assert_eq!(parameters, &vec!["x".to_string(), "y".to_string()]);
                 assert_eq!(body.len(), 1);
                 
-                match &body[0] {
+                match body[0].unlocated() {
                     Statement::ReturnStatement { value } => {
-                        match value {
+                        match value.as_ref().map(Expression::unlocated) {
                             Some(Expression::InfixExpression { left, operator, right }) => {
-                                match **left {
+                                match left.unlocated() {
                                     Expression::Identifier(ref id) => assert_eq!(id, "x"),
                                     _ => panic!("Expected Identifier, got {:?}", left),
                                 }
                                 
                                 assert_eq!(operator, "+");
                                 
-                                match **right {
+                                match right.unlocated() {
                                     Expression::Identifier(ref id) => assert_eq!(id, "y"),
                                     _ => panic!("Expected Identifier, got {:?}", right),
                                 }
@@ -3223,4 +3517,123 @@ mod tests {
             _ => panic!("Expected FunctionDeclaration, got {:?}", program.statements[0]),
         }
     }
-}
\ No newline at end of file
+    
+    #[test]
+    fn test_statement_positions() {
+        let input = "num x = 5;\n  show x;";
+        let lexer = Lexer::new(input.to_string());
+        let mut parser = Parser::new(lexer);
+        
+        let program = parser.parse_program();
+        
+        assert_eq!(parser.get_errors().len(), 0, "Parser errors: {:?}", parser.get_errors());
+        let spans: Vec<Span> = program.statements.iter()
+            .map(|stmt| match stmt {
+                Statement::Located { span, .. } => *span,
+                _ => panic!("Expected a located statement, got {:?}", stmt),
+            })
+            .collect();
+        assert_eq!(spans, vec![
+            Span { file: 0, start: 0, end: 10, line: 1, column: 1 },
+            Span { file: 0, start: 13, end: 20, line: 2, column: 3 },
+        ]);
+    }
+    
+    #[test]
+    fn test_expression_spans() {
+        let input = "show \"é\" + f(x * 2);";
+        let mut parser = Parser::new(Lexer::new(input.to_string()));
+        parser.set_file(3);
+        
+        let program = parser.parse_program();
+        
+        assert_eq!(parser.get_errors().len(), 0, "Parser errors: {:?}", parser.get_errors());
+        let value = match program.statements[0].unlocated() {
+            Statement::ShowStatement { value, .. } => value,
+            other => panic!("Expected ShowStatement, got {:?}", other),
+        };
+        let span_text = |expr: &Expression| match expr {
+            Expression::Located { span, .. } => {
+                assert_eq!(span.file, 3);
+                (&input[span.start..span.end], span.column)
+            },
+            other => panic!("Expected a located expression, got {:?}", other),
+        };
+        
+        assert_eq!(span_text(value), ("\"é\" + f(x * 2)", 6));
+        let Expression::InfixExpression { left, right, .. } = value.unlocated() else {
+            panic!("Expected InfixExpression, got {:?}", value);
+        };
+        assert_eq!(span_text(left), ("\"é\"", 6));
+        assert_eq!(span_text(right), ("f(x * 2)", 12));
+        let Expression::CallExpression { arguments, .. } = right.unlocated() else {
+            panic!("Expected CallExpression, got {:?}", right);
+        };
+        assert_eq!(span_text(&arguments[0]), ("x * 2", 14));
+    }
+    
+    #[test]
+    fn test_error_diagnostics() {
+        let input = "str s = 42;\nshow 1 & 2;";
+        let mut parser = Parser::new(Lexer::new(input.to_string()));
+        parser.parse_program();
+        
+        let errors = parser.get_errors();
+        let codes: Vec<&str> = errors.iter().map(|e| e.code).collect();
+        assert_eq!(codes, [diagnostic::TYPE_MISMATCH, diagnostic::UNEXPECTED_CHARACTER]);
+        
+        let primary = |error: &Diagnostic| {
+            let span = error.primary.as_ref().expect("error without a span").span;
+            &input[span.start..span.end]
+        };
+        assert_eq!(primary(&errors[0]), "42");
+        assert_eq!(&input[errors[0].labels[0].span.start..errors[0].labels[0].span.end], "str");
+        assert_eq!(primary(&errors[1]), "&");
+        assert_eq!(errors[1].suggestions[0].replacement, "&&");
+    }
+    
+    // Malformed programs and the errors a single parse should report for them, as
+    // (line, column, code): every real mistake once, and nothing that is fallout from another
+    #[test]
+    fn test_error_recovery() {
+        use crate::diagnostic::{EXPECTED_EXPRESSION as EXPR, UNEXPECTED_TOKEN as TOKEN};
+        
+        type ErrorAt = (usize, usize, &'static str);
+        let corpus: &[(&str, &[ErrorAt])] = &[
+            // Broken statements at the top level
+            ("num x = ;\nshow x;\nnum y = 3 +;", &[(1, 9, EXPR), (3, 12, EXPR)]),
+            ("num arr = [1, 2];\nshow arr;", &[(1, 5, TOKEN)]),
+            ("show \"a {} b\";\nshow 1 & 2;\nshow @;", &[
+                (1, 10, diagnostic::INVALID_INTERPOLATION),
+                (2, 8, diagnostic::UNEXPECTED_CHARACTER),
+                (3, 6, diagnostic::UNEXPECTED_CHARACTER),
+            ]),
+            // Inside blocks, the rest of the block and the code after it are still checked
+            ("fun f(a, b) {\n    show a + ;\n    return a * b;\n}\nshow f(1, 2;\nshow \"done\";", &[(2, 14, EXPR), (5, 12, TOKEN)]),
+            ("fun g() {\n    num n = ;\n    show 1;\n    show @;\n}\nshow g(;", &[
+                (2, 13, EXPR),
+                (4, 10, diagnostic::UNEXPECTED_CHARACTER),
+                (6, 8, EXPR),
+            ]),
+            ("while (true {\n    show 2;\n}\nclass { }\nshow \"after\";", &[(1, 13, TOKEN), (4, 7, TOKEN)]),
+            ("fun h() {\n    show 1;\n", &[(3, 1, TOKEN)]),
+            // Argument lists and map literals go on after a broken element
+            ("show max(1, , 3, ]);", &[(1, 13, EXPR), (1, 18, EXPR)]),
+            ("var m = {\"a\": 1, \"b\" 2, \"c\": };\nshow m;", &[(1, 22, TOKEN), (1, 30, EXPR)]),
+            // Class members
+            ("class A {\n    static f() { return 1; }\n    fun g() { return ; }\n    static h() {}\n}", &[(2, 5, TOKEN), (4, 5, TOKEN)]),
+        ];
+        
+        for (source, expected) in corpus {
+            let mut parser = Parser::new(Lexer::new(source.to_string()));
+            parser.parse_program();
+            let found: Vec<(usize, usize, &str)> = parser.get_errors().iter()
+                .map(|error| {
+                    let span = error.primary.as_ref().expect("error without a span").span;
+                    (span.line, span.column, error.code)
+                })
+                .collect();
+            assert_eq!(&found, expected, "Errors for {:?}: {:#?}", source, parser.get_errors());
+        }
+    }
+}
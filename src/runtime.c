@@ -0,0 +1,963 @@
+/*
+ * Razen native runtime.
+ *
+ * Natively compiled Razen programs keep numbers and booleans in registers and
+ * call into this file for everything dynamic: strings, lists, maps, printing
+ * and mixed-type arithmetic. Values are boxed as a tagged RzValue and a NULL
+ * pointer is the Razen `null`. Containers have value semantics, matching the
+ * interpreter: updates return a modified copy. Memory is never freed; compiled
+ * programs are short-lived and the process exit reclaims everything.
+ *
+ * This file is embedded into the compiler and built alongside every program
+ * it links, so it must stay plain C99 with no dependencies beyond libc/libm.
+ */
+
+#if !defined(_WIN32)
+#define _POSIX_C_SOURCE 200809L
+#endif
+
+#include <math.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <time.h>
+
+#if defined(_WIN32)
+#include <windows.h>
+#else
+#include <unistd.h>
+#endif
+
+typedef enum {
+    RZ_INT,
+    RZ_FLOAT,
+    RZ_BOOL,
+    RZ_STRING,
+    RZ_ARRAY,
+    RZ_MAP
+} RzTag;
+
+typedef struct RzValue RzValue;
+
+typedef struct {
+    size_t len;
+    size_t cap;
+    RzValue **items;
+} RzArray;
+
+typedef struct {
+    const char *key;
+    RzValue *value;
+} RzEntry;
+
+/* Entries are kept in insertion order; `index` is an open-addressing table of entry positions + 1 */
+typedef struct {
+    size_t len;
+    size_t cap;
+    RzEntry *entries;
+    size_t index_cap;
+    size_t *index;
+} RzMap;
+
+struct RzValue {
+    RzTag tag;
+    union {
+        int64_t i;
+        double f;
+        int b;
+        const char *s;
+        RzArray *array;
+        RzMap *map;
+    } as;
+};
+
+/* ---------- Errors and allocation ---------- */
+
+static void rz_fail(const char *message) {
+    fflush(stdout);
+    printf("Execution error: Unhandled exception: %s\n", message);
+    exit(1);
+}
+
+static void *rz_alloc(size_t size) {
+    void *ptr = calloc(1, size ? size : 1);
+    if (!ptr) {
+        rz_fail("Out of memory");
+    }
+    return ptr;
+}
+
+static RzValue *rz_new(RzTag tag) {
+    RzValue *value = rz_alloc(sizeof(RzValue));
+    value->tag = tag;
+    return value;
+}
+
+/* ---------- Boxing ---------- */
+
+RzValue *rz_int(int64_t i) {
+    RzValue *value = rz_new(RZ_INT);
+    value->as.i = i;
+    return value;
+}
+
+RzValue *rz_float(double f) {
+    RzValue *value = rz_new(RZ_FLOAT);
+    value->as.f = f;
+    return value;
+}
+
+RzValue *rz_bool(int32_t b) {
+    RzValue *value = rz_new(RZ_BOOL);
+    value->as.b = b != 0;
+    return value;
+}
+
+/* The string is not copied; literals live for the whole program */
+RzValue *rz_string(const char *s) {
+    RzValue *value = rz_new(RZ_STRING);
+    value->as.s = s;
+    return value;
+}
+
+/* Build a number value, keeping integral numbers as ints like Value::number */
+static RzValue *rz_number(double f) {
+    if (f == floor(f) && fabs(f) < 9.2e18) {
+        return rz_int((int64_t)f);
+    }
+    return rz_float(f);
+}
+
+static const char *rz_type_name(const RzValue *value) {
+    if (!value) {
+        return "null";
+    }
+    switch (value->tag) {
+    case RZ_INT:
+    case RZ_FLOAT:
+        return "num";
+    case RZ_BOOL:
+        return "bool";
+    case RZ_STRING:
+        return "str";
+    case RZ_ARRAY:
+        return "list";
+    case RZ_MAP:
+        return "map";
+    }
+    return "null";
+}
+
+static int rz_is_number(const RzValue *value) {
+    return value && (value->tag == RZ_INT || value->tag == RZ_FLOAT);
+}
+
+static double rz_as_double(const RzValue *value) {
+    return value->tag == RZ_INT ? (double)value->as.i : value->as.f;
+}
+
+/* ---------- Strings ---------- */
+
+typedef struct {
+    char *data;
+    size_t len;
+    size_t cap;
+} RzBuffer;
+
+static void rz_buffer_append(RzBuffer *buffer, const char *s) {
+    size_t len = strlen(s);
+    if (buffer->len + len + 1 > buffer->cap) {
+        size_t cap = buffer->cap ? buffer->cap : 32;
+        while (buffer->len + len + 1 > cap) {
+            cap *= 2;
+        }
+        buffer->data = realloc(buffer->data, cap);
+        if (!buffer->data) {
+            rz_fail("Out of memory");
+        }
+        buffer->cap = cap;
+    }
+    memcpy(buffer->data + buffer->len, s, len + 1);
+    buffer->len += len;
+}
+
+/* Format a float the way Rust's Display does: shortest round-trip digits, never an exponent */
+static void rz_format_float(double f, char *out, size_t size) {
+    char scientific[40];
+    char digits[40];
+    int precision;
+    int exponent;
+    size_t n = 0;
+    size_t i;
+    const char *p;
+
+    if (isnan(f)) {
+        snprintf(out, size, "NaN");
+        return;
+    }
+    if (isinf(f)) {
+        snprintf(out, size, f < 0 ? "-inf" : "inf");
+        return;
+    }
+
+    for (precision = 0; precision < 17; precision++) {
+        snprintf(scientific, sizeof(scientific), "%.*e", precision, f);
+        if (strtod(scientific, NULL) == f) {
+            break;
+        }
+    }
+
+    /* Split "-d.ddde+XX" into its digits and exponent */
+    p = scientific;
+    if (*p == '-') {
+        p++;
+    }
+    while (*p && *p != 'e') {
+        if (*p != '.') {
+            digits[n++] = *p;
+        }
+        p++;
+    }
+    digits[n] = '\0';
+    exponent = atoi(p + 1);
+
+    /* Drop trailing zeros from the significant digits */
+    while (n > 1 && digits[n - 1] == '0') {
+        digits[--n] = '\0';
+    }
+
+    i = 0;
+    if (signbit(f) && i + 1 < size) {
+        out[i++] = '-';
+    }
+    if (exponent < 0) {
+        int zeros = -exponent - 1;
+        if (i + 2 < size) {
+            out[i++] = '0';
+            out[i++] = '.';
+        }
+        while (zeros-- > 0 && i + 1 < size) {
+            out[i++] = '0';
+        }
+        for (p = digits; *p && i + 1 < size; p++) {
+            out[i++] = *p;
+        }
+    } else {
+        int position;
+        for (position = 0; (size_t)position < n || position <= exponent; position++) {
+            if (position == exponent + 1 && i + 1 < size) {
+                out[i++] = '.';
+            }
+            if (i + 1 < size) {
+                out[i++] = (size_t)position < n ? digits[position] : '0';
+            }
+        }
+    }
+    out[i] = '\0';
+}
+
+static void rz_write_value(RzBuffer *buffer, const RzValue *value) {
+    char scratch[400];
+    size_t i;
+
+    if (!value) {
+        rz_buffer_append(buffer, "null");
+        return;
+    }
+    switch (value->tag) {
+    case RZ_INT:
+        snprintf(scratch, sizeof(scratch), "%lld", (long long)value->as.i);
+        rz_buffer_append(buffer, scratch);
+        break;
+    case RZ_FLOAT:
+        rz_format_float(value->as.f, scratch, sizeof(scratch));
+        rz_buffer_append(buffer, scratch);
+        break;
+    case RZ_BOOL:
+        rz_buffer_append(buffer, value->as.b ? "true" : "false");
+        break;
+    case RZ_STRING:
+        rz_buffer_append(buffer, value->as.s);
+        break;
+    case RZ_ARRAY:
+        rz_buffer_append(buffer, "[");
+        for (i = 0; i < value->as.array->len; i++) {
+            if (i > 0) {
+                rz_buffer_append(buffer, ", ");
+            }
+            rz_write_value(buffer, value->as.array->items[i]);
+        }
+        rz_buffer_append(buffer, "]");
+        break;
+    case RZ_MAP:
+        rz_buffer_append(buffer, "{");
+        for (i = 0; i < value->as.map->len; i++) {
+            if (i > 0) {
+                rz_buffer_append(buffer, ", ");
+            }
+            rz_buffer_append(buffer, value->as.map->entries[i].key);
+            rz_buffer_append(buffer, ": ");
+            rz_write_value(buffer, value->as.map->entries[i].value);
+        }
+        rz_buffer_append(buffer, "}");
+        break;
+    }
+}
+
+const char *rz_to_string(const RzValue *value) {
+    RzBuffer buffer = {0};
+    rz_buffer_append(&buffer, "");
+    rz_write_value(&buffer, value);
+    return buffer.data;
+}
+
+static size_t rz_utf8_length(const char *s) {
+    size_t count = 0;
+    for (; *s; s++) {
+        if (((unsigned char)*s & 0xC0) != 0x80) {
+            count++;
+        }
+    }
+    return count;
+}
+
+/* ---------- Lists ---------- */
+
+static RzArray *rz_array_alloc(size_t cap) {
+    RzArray *array = rz_alloc(sizeof(RzArray));
+    array->cap = cap ? cap : 4;
+    array->items = rz_alloc(array->cap * sizeof(RzValue *));
+    return array;
+}
+
+static void rz_array_append(RzArray *array, RzValue *item) {
+    if (array->len == array->cap) {
+        array->cap *= 2;
+        array->items = realloc(array->items, array->cap * sizeof(RzValue *));
+        if (!array->items) {
+            rz_fail("Out of memory");
+        }
+    }
+    array->items[array->len++] = item;
+}
+
+static RzArray *rz_array_copy(const RzArray *source) {
+    RzArray *array = rz_array_alloc(source->len);
+    memcpy(array->items, source->items, source->len * sizeof(RzValue *));
+    array->len = source->len;
+    return array;
+}
+
+RzValue *rz_array_new(int64_t capacity) {
+    RzValue *value = rz_new(RZ_ARRAY);
+    value->as.array = rz_array_alloc(capacity > 0 ? (size_t)capacity : 0);
+    return value;
+}
+
+/* Only used while building a literal; the list is not shared yet */
+void rz_array_push(RzValue *array, RzValue *item) {
+    rz_array_append(array->as.array, item);
+}
+
+/* ---------- Maps ---------- */
+
+static uint64_t rz_hash(const char *key) {
+    uint64_t hash = 1469598103934665603ULL;
+    for (; *key; key++) {
+        hash ^= (unsigned char)*key;
+        hash *= 1099511628211ULL;
+    }
+    return hash;
+}
+
+static RzMap *rz_map_alloc(size_t cap) {
+    RzMap *map = rz_alloc(sizeof(RzMap));
+    map->cap = cap ? cap : 4;
+    map->entries = rz_alloc(map->cap * sizeof(RzEntry));
+    map->index_cap = map->cap * 2;
+    map->index = rz_alloc(map->index_cap * sizeof(size_t));
+    return map;
+}
+
+static size_t rz_map_slot(const RzMap *map, const char *key) {
+    size_t slot = (size_t)(rz_hash(key) % map->index_cap);
+    while (map->index[slot] != 0 && strcmp(map->entries[map->index[slot] - 1].key, key) != 0) {
+        slot = (slot + 1) % map->index_cap;
+    }
+    return slot;
+}
+
+static void rz_map_reindex(RzMap *map) {
+    size_t i;
+    memset(map->index, 0, map->index_cap * sizeof(size_t));
+    for (i = 0; i < map->len; i++) {
+        map->index[rz_map_slot(map, map->entries[i].key)] = i + 1;
+    }
+}
+
+static RzValue **rz_map_find(const RzMap *map, const char *key) {
+    size_t slot = rz_map_slot(map, key);
+    return map->index[slot] ? &map->entries[map->index[slot] - 1].value : NULL;
+}
+
+static void rz_map_put(RzMap *map, const char *key, RzValue *item) {
+    RzValue **existing = rz_map_find(map, key);
+    if (existing) {
+        *existing = item;
+        return;
+    }
+    if (map->len == map->cap) {
+        map->cap *= 2;
+        map->entries = realloc(map->entries, map->cap * sizeof(RzEntry));
+        if (!map->entries) {
+            rz_fail("Out of memory");
+        }
+        map->index_cap = map->cap * 2;
+        free(map->index);
+        map->index = rz_alloc(map->index_cap * sizeof(size_t));
+        rz_map_reindex(map);
+    }
+    map->entries[map->len].key = key;
+    map->entries[map->len].value = item;
+    map->index[rz_map_slot(map, key)] = map->len + 1;
+    map->len++;
+}
+
+static RzMap *rz_map_copy(const RzMap *source) {
+    RzMap *map = rz_map_alloc(source->len);
+    memcpy(map->entries, source->entries, source->len * sizeof(RzEntry));
+    map->len = source->len;
+    rz_map_reindex(map);
+    return map;
+}
+
+RzValue *rz_map_new(void) {
+    RzValue *value = rz_new(RZ_MAP);
+    value->as.map = rz_map_alloc(0);
+    return value;
+}
+
+/* Only used while building a literal; the map is not shared yet */
+void rz_map_insert(RzValue *map, RzValue *key, RzValue *item) {
+    rz_map_put(map->as.map, rz_to_string(key), item);
+}
+
+/* ---------- Indexing ---------- */
+
+RzValue *rz_get_index(const RzValue *container, const RzValue *index) {
+    char message[200];
+
+    if (container && container->tag == RZ_MAP) {
+        RzValue **found = rz_map_find(container->as.map, rz_to_string(index));
+        return found ? *found : NULL;
+    }
+    if (container && (container->tag == RZ_ARRAY || container->tag == RZ_STRING)) {
+        int64_t i;
+        if (!rz_is_number(index)) {
+            return NULL;
+        }
+        i = index->tag == RZ_INT ? index->as.i : (int64_t)index->as.f;
+        if (container->tag == RZ_ARRAY) {
+            int64_t len = (int64_t)container->as.array->len;
+            if (i < 0) {
+                i += len;
+            }
+            return i >= 0 && i < len ? container->as.array->items[i] : NULL;
+        } else {
+            const char *s = container->as.s;
+            const char *start;
+            char *character;
+            if (i < 0) {
+                return NULL;
+            }
+            for (; *s && i > 0; i--) {
+                s++;
+                while (((unsigned char)*s & 0xC0) == 0x80) {
+                    s++;
+                }
+            }
+            if (!*s) {
+                return NULL;
+            }
+            start = s++;
+            while (((unsigned char)*s & 0xC0) == 0x80) {
+                s++;
+            }
+            character = rz_alloc((size_t)(s - start) + 1);
+            memcpy(character, start, (size_t)(s - start));
+            return rz_string(character);
+        }
+    }
+    snprintf(message, sizeof(message), "Type error: cannot index %s with %s", rz_type_name(container), rz_type_name(index));
+    rz_fail(message);
+    return NULL;
+}
+
+RzValue *rz_set_index(const RzValue *container, const RzValue *index, RzValue *item) {
+    char message[200];
+
+    if (container && container->tag == RZ_MAP) {
+        RzValue *copy = rz_new(RZ_MAP);
+        copy->as.map = rz_map_copy(container->as.map);
+        rz_map_put(copy->as.map, rz_to_string(index), item);
+        return copy;
+    }
+    if (container && container->tag == RZ_ARRAY && rz_is_number(index)) {
+        int64_t i = index->tag == RZ_INT ? index->as.i : (int64_t)index->as.f;
+        RzValue *copy;
+        /* A list grows by storing one past its end, never by jumping ahead */
+        if (i < 0 || (size_t)i > container->as.array->len) {
+            snprintf(message, sizeof(message), "List index %lld is out of range", (long long)i);
+            rz_fail(message);
+        }
+        copy = rz_new(RZ_ARRAY);
+        copy->as.array = rz_array_copy(container->as.array);
+        if ((size_t)i == copy->as.array->len) {
+            rz_array_append(copy->as.array, item);
+        } else {
+            copy->as.array->items[i] = item;
+        }
+        return copy;
+    }
+    snprintf(message, sizeof(message), "Type error: cannot assign into %s with a %s index", rz_type_name(container), rz_type_name(index));
+    rz_fail(message);
+    return NULL;
+}
+
+RzValue *rz_get_key(const RzValue *container, const RzValue *key) {
+    char message[200];
+    RzValue **found;
+
+    if (!container || container->tag != RZ_MAP) {
+        snprintf(message, sizeof(message), "Type error: cannot read key '%s' from %s", rz_to_string(key), rz_type_name(container));
+        rz_fail(message);
+    }
+    found = rz_map_find(container->as.map, rz_to_string(key));
+    if (!found) {
+        snprintf(message, sizeof(message), "Key '%s' not found in map", rz_to_string(key));
+        rz_fail(message);
+    }
+    return *found;
+}
+
+RzValue *rz_set_key(const RzValue *container, const RzValue *key, RzValue *item) {
+    char message[200];
+
+    if (!container || container->tag != RZ_MAP) {
+        snprintf(message, sizeof(message), "Type error: cannot set key '%s' on %s", rz_to_string(key), rz_type_name(container));
+        rz_fail(message);
+    }
+    return rz_set_index(container, key, item);
+}
+
+int64_t rz_len(const RzValue *value) {
+    char message[200];
+
+    if (value && value->tag == RZ_ARRAY) {
+        return (int64_t)value->as.array->len;
+    }
+    if (value && value->tag == RZ_MAP) {
+        return (int64_t)value->as.map->len;
+    }
+    if (value && value->tag == RZ_STRING) {
+        return (int64_t)rz_utf8_length(value->as.s);
+    }
+    snprintf(message, sizeof(message), "Type error: %s has no length", rz_type_name(value));
+    rz_fail(message);
+    return 0;
+}
+
+/* Integer value of a number, such as the exit code main returns. Floats are truncated and
+   saturate like Rust's `as`, and anything that is not a number is 0 */
+int64_t rz_as_int(const RzValue *value) {
+    if (value && value->tag == RZ_INT) {
+        return value->as.i;
+    }
+    if (value && value->tag == RZ_FLOAT) {
+        double f = value->as.f;
+        if (f != f) {
+            return 0;
+        }
+        if (f >= 9223372036854775807.0) {
+            return INT64_MAX;
+        }
+        if (f <= -9223372036854775808.0) {
+            return INT64_MIN;
+        }
+        return (int64_t)f;
+    }
+    return 0;
+}
+
+/* Stores into a variable declared num, str, bool, list or map check the value's kind; null always fits */
+RzValue *rz_check_kind(RzValue *value, const RzValue *kind, const RzValue *name) {
+    char message[300];
+
+    if (value && strcmp(rz_type_name(value), kind->as.s) != 0) {
+        snprintf(message, sizeof(message), "Type error: cannot store %s in '%s', which is declared %s", rz_type_name(value), name->as.s, kind->as.s);
+        rz_fail(message);
+    }
+    return value;
+}
+
+/* ---------- Arithmetic on boxed values ---------- */
+
+/* Integer arithmetic that overflows is done again on doubles, like in the interpreter. Each
+   helper returns nonzero on overflow and otherwise stores the result */
+#if defined(__GNUC__) || defined(__clang__)
+#define rz_add_overflow(a, b, out) __builtin_add_overflow(a, b, out)
+#define rz_sub_overflow(a, b, out) __builtin_sub_overflow(a, b, out)
+#define rz_mul_overflow(a, b, out) __builtin_mul_overflow(a, b, out)
+#else
+static int rz_add_overflow(int64_t a, int64_t b, int64_t *out) {
+    if ((b > 0 && a > INT64_MAX - b) || (b < 0 && a < INT64_MIN - b)) {
+        return 1;
+    }
+    *out = a + b;
+    return 0;
+}
+
+static int rz_sub_overflow(int64_t a, int64_t b, int64_t *out) {
+    if ((b < 0 && a > INT64_MAX + b) || (b > 0 && a < INT64_MIN + b)) {
+        return 1;
+    }
+    *out = a - b;
+    return 0;
+}
+
+static int rz_mul_overflow(int64_t a, int64_t b, int64_t *out) {
+    if (b > 0 ? (a > INT64_MAX / b || a < INT64_MIN / b)
+              : b == -1 ? a == INT64_MIN
+              : b < 0 && (a < INT64_MAX / b || a > INT64_MIN / b)) {
+        return 1;
+    }
+    *out = a * b;
+    return 0;
+}
+#endif
+
+static void rz_type_error(const char *op, const RzValue *a, const RzValue *b) {
+    char message[200];
+    snprintf(message, sizeof(message), "Type error: cannot %s %s and %s", op, rz_type_name(a), rz_type_name(b));
+    rz_fail(message);
+}
+
+RzValue *rz_add(const RzValue *a, const RzValue *b) {
+    if (rz_is_number(a) && rz_is_number(b)) {
+        int64_t sum;
+        if (a->tag == RZ_INT && b->tag == RZ_INT && !rz_add_overflow(a->as.i, b->as.i, &sum)) {
+            return rz_int(sum);
+        }
+        return rz_float(rz_as_double(a) + rz_as_double(b));
+    }
+    if ((a && a->tag == RZ_STRING) || (b && b->tag == RZ_STRING)) {
+        RzBuffer buffer = {0};
+        rz_buffer_append(&buffer, "");
+        rz_write_value(&buffer, a);
+        rz_write_value(&buffer, b);
+        return rz_string(buffer.data);
+    }
+    if (a && b && a->tag == RZ_ARRAY && b->tag == RZ_ARRAY) {
+        RzValue *joined = rz_new(RZ_ARRAY);
+        size_t i;
+        joined->as.array = rz_array_copy(a->as.array);
+        for (i = 0; i < b->as.array->len; i++) {
+            rz_array_append(joined->as.array, b->as.array->items[i]);
+        }
+        return joined;
+    }
+    rz_type_error("add", a, b);
+    return NULL;
+}
+
+RzValue *rz_sub(const RzValue *a, const RzValue *b) {
+    int64_t difference;
+
+    if (!rz_is_number(a) || !rz_is_number(b)) {
+        rz_type_error("subtract", a, b);
+    }
+    if (a->tag == RZ_INT && b->tag == RZ_INT && !rz_sub_overflow(a->as.i, b->as.i, &difference)) {
+        return rz_int(difference);
+    }
+    return rz_float(rz_as_double(a) - rz_as_double(b));
+}
+
+RzValue *rz_mul(const RzValue *a, const RzValue *b) {
+    int64_t product;
+
+    if (!rz_is_number(a) || !rz_is_number(b)) {
+        rz_type_error("multiply", a, b);
+    }
+    if (a->tag == RZ_INT && b->tag == RZ_INT && !rz_mul_overflow(a->as.i, b->as.i, &product)) {
+        return rz_int(product);
+    }
+    return rz_float(rz_as_double(a) * rz_as_double(b));
+}
+
+RzValue *rz_div(const RzValue *a, const RzValue *b) {
+    if (!rz_is_number(a) || !rz_is_number(b)) {
+        rz_type_error("divide", a, b);
+    }
+    if (rz_as_double(b) == 0.0) {
+        rz_fail("Division by zero");
+    }
+    /* INT64_MIN / -1 overflows, and becomes a float like in the interpreter */
+    if (a->tag == RZ_INT && b->tag == RZ_INT && !(a->as.i == INT64_MIN && b->as.i == -1) && a->as.i % b->as.i == 0) {
+        return rz_int(a->as.i / b->as.i);
+    }
+    return rz_float(rz_as_double(a) / rz_as_double(b));
+}
+
+RzValue *rz_mod(const RzValue *a, const RzValue *b) {
+    if (!rz_is_number(a) || !rz_is_number(b)) {
+        rz_type_error("take the modulo of", a, b);
+    }
+    if (rz_as_double(b) == 0.0) {
+        rz_fail("Modulo by zero");
+    }
+    if (a->tag == RZ_INT && b->tag == RZ_INT) {
+        /* Every number is divisible by -1, and INT64_MIN % -1 would overflow */
+        return rz_int(b->as.i == -1 ? 0 : a->as.i % b->as.i);
+    }
+    return rz_float(fmod(rz_as_double(a), rz_as_double(b)));
+}
+
+RzValue *rz_pow(const RzValue *a, const RzValue *b) {
+    if (!rz_is_number(a) || !rz_is_number(b)) {
+        rz_type_error("raise", a, b);
+    }
+    if (a->tag == RZ_INT && b->tag == RZ_INT && b->as.i >= 0) {
+        return rz_number(pow((double)a->as.i, (double)b->as.i));
+    }
+    return rz_float(pow(rz_as_double(a), rz_as_double(b)));
+}
+
+RzValue *rz_floor_div(const RzValue *a, const RzValue *b) {
+    if (!rz_is_number(a) || !rz_is_number(b)) {
+        rz_type_error("divide", a, b);
+    }
+    if (rz_as_double(b) == 0.0) {
+        rz_fail("Division by zero");
+    }
+    /* INT64_MIN // -1 overflows, and becomes a float like in the interpreter */
+    if (a->tag == RZ_INT && b->tag == RZ_INT && !(a->as.i == INT64_MIN && b->as.i == -1)) {
+        int64_t quotient = a->as.i / b->as.i;
+        if (a->as.i % b->as.i != 0 && ((a->as.i < 0) != (b->as.i < 0))) {
+            quotient--;
+        }
+        return rz_int(quotient);
+    }
+    return rz_number(floor(rz_as_double(a) / rz_as_double(b)));
+}
+
+RzValue *rz_neg(const RzValue *a) {
+    char message[200];
+
+    if (a && a->tag == RZ_INT) {
+        /* -INT64_MIN overflows */
+        return a->as.i == INT64_MIN ? rz_float(-(double)a->as.i) : rz_int(-a->as.i);
+    }
+    if (a && a->tag == RZ_FLOAT) {
+        return rz_float(-a->as.f);
+    }
+    snprintf(message, sizeof(message), "Type error: cannot negate %s", rz_type_name(a));
+    rz_fail(message);
+    return NULL;
+}
+
+/* ---------- Comparison and truthiness ---------- */
+
+int32_t rz_equal(const RzValue *a, const RzValue *b) {
+    size_t i;
+
+    if (!a || !b) {
+        return a == b;
+    }
+    if (rz_is_number(a) && rz_is_number(b)) {
+        if (a->tag == RZ_INT && b->tag == RZ_INT) {
+            return a->as.i == b->as.i;
+        }
+        return rz_as_double(a) == rz_as_double(b);
+    }
+    if (a->tag != b->tag) {
+        return 0;
+    }
+    switch (a->tag) {
+    case RZ_BOOL:
+        return a->as.b == b->as.b;
+    case RZ_STRING:
+        return strcmp(a->as.s, b->as.s) == 0;
+    case RZ_ARRAY:
+        if (a->as.array->len != b->as.array->len) {
+            return 0;
+        }
+        for (i = 0; i < a->as.array->len; i++) {
+            if (!rz_equal(a->as.array->items[i], b->as.array->items[i])) {
+                return 0;
+            }
+        }
+        return 1;
+    case RZ_MAP:
+        if (a->as.map->len != b->as.map->len) {
+            return 0;
+        }
+        for (i = 0; i < a->as.map->len; i++) {
+            RzValue **other = rz_map_find(b->as.map, a->as.map->entries[i].key);
+            if (!other || !rz_equal(a->as.map->entries[i].value, *other)) {
+                return 0;
+            }
+        }
+        return 1;
+    default:
+        return 0;
+    }
+}
+
+/* Returns -1, 0 or 1 */
+int32_t rz_compare(const RzValue *a, const RzValue *b) {
+    if (rz_is_number(a) && rz_is_number(b)) {
+        double x = rz_as_double(a);
+        double y = rz_as_double(b);
+        if (a->tag == RZ_INT && b->tag == RZ_INT) {
+            return (a->as.i > b->as.i) - (a->as.i < b->as.i);
+        }
+        return (x > y) - (x < y);
+    }
+    if (a && b && a->tag == RZ_STRING && b->tag == RZ_STRING) {
+        int order = strcmp(a->as.s, b->as.s);
+        return (order > 0) - (order < 0);
+    }
+    if (a && b && a->tag == RZ_BOOL && b->tag == RZ_BOOL) {
+        return (a->as.b > b->as.b) - (a->as.b < b->as.b);
+    }
+    rz_type_error("compare", a, b);
+    return 0;
+}
+
+int32_t rz_truthy(const RzValue *value) {
+    if (!value) {
+        return 0;
+    }
+    switch (value->tag) {
+    case RZ_INT:
+        return value->as.i != 0;
+    case RZ_FLOAT:
+        return value->as.f != 0.0;
+    case RZ_BOOL:
+        return value->as.b;
+    case RZ_STRING:
+        return value->as.s[0] != '\0';
+    case RZ_ARRAY:
+        return value->as.array->len != 0;
+    case RZ_MAP:
+        return value->as.map->len != 0;
+    }
+    return 0;
+}
+
+/* ---------- Pattern matching ---------- */
+
+/* A list pattern matches a list of exactly `length` items, or at least that many when it ends with a rest */
+RzValue *rz_match_array(const RzValue *value, const RzValue *length, const RzValue *has_rest) {
+    int64_t wanted = rz_is_number(length) ? (int64_t)rz_as_double(length) : 0;
+    size_t items;
+
+    if (!value || value->tag != RZ_ARRAY) {
+        return rz_bool(0);
+    }
+    items = value->as.array->len;
+    wanted = wanted < 0 ? 0 : wanted;
+    return rz_bool(rz_truthy(has_rest) ? items >= (size_t)wanted : items == (size_t)wanted);
+}
+
+/* The items of a list from `start` on, bound by a `..rest` pattern */
+RzValue *rz_array_rest(const RzValue *value, const RzValue *start) {
+    char message[200];
+    int64_t first = rz_is_number(start) ? (int64_t)rz_as_double(start) : 0;
+    RzValue *rest;
+    size_t i;
+
+    if (!value || value->tag != RZ_ARRAY) {
+        snprintf(message, sizeof(message), "Type error: cannot take the rest of %s", rz_type_name(value));
+        rz_fail(message);
+    }
+    rest = rz_array_new(0);
+    for (i = first < 0 ? 0 : (size_t)first; i < value->as.array->len; i++) {
+        rz_array_append(rest->as.array, value->as.array->items[i]);
+    }
+    return rest;
+}
+
+/* Range patterns only match values that can be ordered against their bounds */
+RzValue *rz_comparable(const RzValue *a, const RzValue *b) {
+    if (rz_is_number(a) && rz_is_number(b)) {
+        return rz_bool(!isnan(rz_as_double(a)) && !isnan(rz_as_double(b)));
+    }
+    return rz_bool(a && b && a->tag == b->tag && (a->tag == RZ_STRING || a->tag == RZ_BOOL));
+}
+
+/* ---------- Input and output ---------- */
+
+void rz_print(const RzValue *value) {
+    fputs(rz_to_string(value), stdout);
+}
+
+RzValue *rz_read_line(void) {
+    RzBuffer buffer = {0};
+    char chunk[256];
+
+    fflush(stdout);
+    rz_buffer_append(&buffer, "");
+    while (fgets(chunk, sizeof(chunk), stdin)) {
+        rz_buffer_append(&buffer, chunk);
+        if (buffer.len > 0 && buffer.data[buffer.len - 1] == '\n') {
+            break;
+        }
+    }
+    if (buffer.len > 0 && buffer.data[buffer.len - 1] == '\n') {
+        buffer.data[--buffer.len] = '\0';
+        if (buffer.len > 0 && buffer.data[buffer.len - 1] == '\r') {
+            buffer.data[--buffer.len] = '\0';
+        }
+    }
+    return rz_string(buffer.data);
+}
+
+void rz_sleep(const RzValue *seconds) {
+    double duration;
+
+    if (!rz_is_number(seconds)) {
+        return;
+    }
+    duration = rz_as_double(seconds);
+    if (duration <= 0) {
+        return;
+    }
+    fflush(stdout);
+#if defined(_WIN32)
+    Sleep((DWORD)(duration * 1000.0));
+#else
+    {
+        struct timespec request;
+        request.tv_sec = (time_t)duration;
+        request.tv_nsec = (long)((duration - (double)request.tv_sec) * 1e9);
+        nanosleep(&request, NULL);
+    }
+#endif
+}
+
+void rz_throw(const RzValue *value) {
+    rz_fail(rz_to_string(value));
+}
+
+/* Used by compiled code for errors detected inline, such as integer division by zero */
+void rz_panic(const char *message) {
+    rz_fail(message);
+}
+
+
+/* Compiled code buffers output through stdio; the JIT flushes it before handing control back */
+void rz_flush(void) {
+    fflush(stdout);
+}
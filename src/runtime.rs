@@ -0,0 +1,35 @@
+// The native runtime (runtime.c) is also linked into the compiler by build.rs so that
+// JIT-compiled code can call it without a separate link step
+
+// Declares the runtime functions and lists their addresses. Only the addresses are used
+// from Rust, so the declarations carry no signatures
+macro_rules! runtime_symbols {
+    ($($name:ident),* $(,)?) => {
+        extern "C" {
+            $(fn $name();)*
+        }
+
+        /// Every runtime function compiled code may call, with its address in this process
+        pub fn symbols() -> Vec<(&'static str, usize)> {
+            vec![$((stringify!($name), $name as unsafe extern "C" fn() as usize)),*]
+        }
+    };
+}
+
+runtime_symbols!(
+    rz_int, rz_float, rz_bool, rz_string,
+    rz_array_new, rz_array_push, rz_map_new, rz_map_insert,
+    rz_get_index, rz_set_index, rz_get_key, rz_set_key, rz_len, rz_as_int, rz_check_kind,
+    rz_add, rz_sub, rz_mul, rz_div, rz_mod, rz_pow, rz_floor_div, rz_neg,
+    rz_equal, rz_compare, rz_truthy, rz_match_array, rz_array_rest, rz_comparable,
+    rz_print, rz_read_line, rz_sleep, rz_throw, rz_panic,
+);
+
+extern "C" {
+    fn rz_flush();
+}
+
+/// Flush output the runtime has buffered through C stdio
+pub fn flush_output() {
+    unsafe { rz_flush() }
+}
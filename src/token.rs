@@ -175,6 +175,9 @@ pub enum TokenType {
     RightBracket,   // ]
     Comma,          // ,
     Dot,            // .
+    DotDot,         // ..
+    DotDotEqual,    // ..=
+    FatArrow,       // =>
     Semicolon,      // ;
     Colon,          // :
     ColonColon,     // ::
@@ -182,6 +185,9 @@ pub enum TokenType {
     // Literals
     Identifier,     // Variable names, function names, etc.
     StringLiteral,  // "hello"
+    StringStart,    // "Hello { - text before the first interpolation
+    StringMiddle,   // }, you have { - text between two interpolations
+    StringEnd,      // } items" - text after the last interpolation
     NumberLiteral,  // 123, 3.14
     
     // Comments
@@ -404,6 +410,9 @@ impl fmt::Display for TokenType {
             TokenType::RightBracket => write!(f, "]"),
             TokenType::Comma => write!(f, ","),
             TokenType::Dot => write!(f, "."),
+            TokenType::DotDot => write!(f, ".."),
+            TokenType::DotDotEqual => write!(f, "..="),
+            TokenType::FatArrow => write!(f, "=>"),
             TokenType::Semicolon => write!(f, ";"),
             TokenType::Colon => write!(f, ":"),
             TokenType::ColonColon => write!(f, "::"),
@@ -411,6 +420,9 @@ impl fmt::Display for TokenType {
             // Literals
             TokenType::Identifier => write!(f, "IDENTIFIER"),
             TokenType::StringLiteral => write!(f, "STRING"),
+            TokenType::StringStart => write!(f, "STRING_START"),
+            TokenType::StringMiddle => write!(f, "STRING_MIDDLE"),
+            TokenType::StringEnd => write!(f, "STRING_END"),
             TokenType::NumberLiteral => write!(f, "NUMBER"),
             
             // Comments
@@ -464,6 +476,8 @@ pub struct Token {
     pub literal: String,
     pub line: usize,
     pub column: usize,
+    pub start: usize, // byte offset of the token's first char in the source
+    pub end: usize,   // byte offset just past the token's last char
 }
 
 impl Token {
@@ -473,6 +487,8 @@ impl Token {
             literal: literal.into(),
             line,
             column,
+            start: 0,
+            end: 0,
         }
     }
 }
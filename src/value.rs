@@ -1,5 +1,7 @@
+use std::cmp::Ordering;
 use std::collections::HashMap;
 use std::fmt;
+use std::sync::{Arc, Mutex};
 
 /// Value represents any value that can be manipulated in Razen
 #[derive(Debug, Clone)]
@@ -10,32 +12,53 @@ pub enum Value {
     String(String),
     Array(Vec<Value>),
     Map(HashMap<String, Value>),
+    Function(Arc<Closure>),
+    Instance(Arc<Instance>),
     Null,
 }
 
-impl Value {
-    /// Convert a Value to a string representation
-    pub fn to_string(&self) -> String {
-        match self {
-            Value::Int(i) => i.to_string(),
-            Value::Float(f) => f.to_string(),
-            Value::Bool(b) => b.to_string(),
-            Value::String(s) => s.clone(),
-            Value::Array(arr) => {
-                let elements: Vec<String> = arr.iter().map(|v| v.to_string()).collect();
-                format!("[{}]", elements.join(", "))
-            }
-            Value::Map(map) => {
-                let entries: Vec<String> = map
-                    .iter()
-                    .map(|(k, v)| format!("{}: {}", k, v.to_string()))
-                    .collect();
-                format!("{{{}}}", entries.join(", "))
-            }
-            Value::Null => "null".to_string(),
+/// A function value: a user function or builtin called by name, and the variables of
+/// enclosing functions it captured, shared with them. Values stay `Send` for the thread builtins
+pub struct Closure {
+    pub name: String,
+    pub captures: Vec<Arc<Mutex<Value>>>,
+}
+
+// Captured variables may hold the closure itself, so they are not printed
+impl fmt::Debug for Closure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Closure({}, {} captures)", self.name, self.captures.len())
+    }
+}
+
+/// An object created by calling a class. Copies of the value share the object, so methods
+/// see and make the same changes to its fields
+pub struct Instance {
+    pub class: String,
+    pub fields: Mutex<HashMap<String, Value>>,
+}
+
+impl Instance {
+    pub fn new(class: &str) -> Self {
+        Instance {
+            class: class.to_string(),
+            fields: Mutex::new(HashMap::new()),
         }
     }
 
+    fn fields(&self) -> std::sync::MutexGuard<'_, HashMap<String, Value>> {
+        self.fields.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+// Fields may refer back to the object, so they are not printed
+impl fmt::Debug for Instance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Instance({})", self.class)
+    }
+}
+
+impl Value {
     /// Try to convert a Value to an i64
     pub fn as_int(&self) -> Result<i64, String> {
         match self {
@@ -96,11 +119,268 @@ impl Value {
             _ => Err(format!("Cannot convert {:?} to map", self)),
         }
     }
+
+    /// Build a number value, keeping integral numbers as Int
+    pub fn number(n: f64) -> Value {
+        if n.fract() == 0.0 && n.is_finite() && n.abs() < i64::MAX as f64 {
+            Value::Int(n as i64)
+        } else {
+            Value::Float(n)
+        }
+    }
+
+    /// Get the Razen name of the value's type
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Int(_) | Value::Float(_) => "num",
+            Value::String(_) => "str",
+            Value::Bool(_) => "bool",
+            Value::Array(_) => "list",
+            Value::Map(_) => "map",
+            Value::Function(_) => "function",
+            Value::Instance(_) => "object",
+            Value::Null => "null",
+        }
+    }
+
+    /// Check whether the value counts as true in a condition
+    pub fn is_truthy(&self) -> bool {
+        match self {
+            Value::Bool(b) => *b,
+            Value::Int(i) => *i != 0,
+            Value::Float(f) => *f != 0.0,
+            Value::String(s) => !s.is_empty(),
+            Value::Array(arr) => !arr.is_empty(),
+            Value::Map(map) => !map.is_empty(),
+            Value::Function(_) | Value::Instance(_) => true,
+            Value::Null => false,
+        }
+    }
+
+    fn is_number(&self) -> bool {
+        matches!(self, Value::Int(_) | Value::Float(_))
+    }
+
+    fn type_error(&self, op: &str, other: &Value) -> String {
+        format!("Type error: cannot {} {} and {}", op, self.type_name(), other.type_name())
+    }
+
+    /// Add two values (numbers add, strings and lists concatenate)
+    pub fn add(&self, other: &Value) -> Result<Value, String> {
+        match (self, other) {
+            (Value::Int(a), Value::Int(b)) => Ok(a.checked_add(*b)
+                .map(Value::Int)
+                .unwrap_or(Value::Float(*a as f64 + *b as f64))),
+            (a, b) if a.is_number() && b.is_number() => Ok(Value::Float(a.as_float()? + b.as_float()?)),
+            (Value::String(a), b) => Ok(Value::String(format!("{}{}", a, b))),
+            (a, Value::String(b)) => Ok(Value::String(format!("{}{}", a, b))),
+            (Value::Array(a), Value::Array(b)) => {
+                let mut joined = a.clone();
+                joined.extend(b.iter().cloned());
+                Ok(Value::Array(joined))
+            }
+            _ => Err(self.type_error("add", other)),
+        }
+    }
+
+    /// Subtract two numbers
+    pub fn subtract(&self, other: &Value) -> Result<Value, String> {
+        match (self, other) {
+            (Value::Int(a), Value::Int(b)) => Ok(a.checked_sub(*b)
+                .map(Value::Int)
+                .unwrap_or(Value::Float(*a as f64 - *b as f64))),
+            (a, b) if a.is_number() && b.is_number() => Ok(Value::Float(a.as_float()? - b.as_float()?)),
+            _ => Err(self.type_error("subtract", other)),
+        }
+    }
+
+    /// Multiply two numbers
+    pub fn multiply(&self, other: &Value) -> Result<Value, String> {
+        match (self, other) {
+            (Value::Int(a), Value::Int(b)) => Ok(a.checked_mul(*b)
+                .map(Value::Int)
+                .unwrap_or(Value::Float(*a as f64 * *b as f64))),
+            (a, b) if a.is_number() && b.is_number() => Ok(Value::Float(a.as_float()? * b.as_float()?)),
+            _ => Err(self.type_error("multiply", other)),
+        }
+    }
+
+    /// Divide two numbers, keeping exact integer results as Int
+    pub fn divide(&self, other: &Value) -> Result<Value, String> {
+        if !self.is_number() || !other.is_number() {
+            return Err(self.type_error("divide", other));
+        }
+        let divisor = other.as_float()?;
+        if divisor == 0.0 {
+            return Err("Division by zero".to_string());
+        }
+        match (self, other) {
+            // i64::MIN / -1 overflows, and falls back to a float like other arithmetic
+            (Value::Int(a), Value::Int(b)) if a.checked_rem(*b) == Some(0) => Ok(a.checked_div(*b)
+                .map(Value::Int)
+                .unwrap_or(Value::Float(*a as f64 / *b as f64))),
+            _ => Ok(Value::Float(self.as_float()? / divisor)),
+        }
+    }
+
+    /// Remainder of two numbers
+    pub fn modulo(&self, other: &Value) -> Result<Value, String> {
+        if !self.is_number() || !other.is_number() {
+            return Err(self.type_error("take the modulo of", other));
+        }
+        if other.as_float()? == 0.0 {
+            return Err("Modulo by zero".to_string());
+        }
+        match (self, other) {
+            // Only i64::MIN % -1 overflows, and any number is divisible by -1
+            (Value::Int(a), Value::Int(b)) => Ok(Value::Int(a.checked_rem(*b).unwrap_or(0))),
+            _ => Ok(Value::Float(self.as_float()? % other.as_float()?)),
+        }
+    }
+
+    /// Raise a number to a power
+    pub fn power(&self, other: &Value) -> Result<Value, String> {
+        match (self, other) {
+            (Value::Int(a), Value::Int(b)) if *b >= 0 && *b <= u32::MAX as i64 => Ok(a.checked_pow(*b as u32)
+                .map(Value::Int)
+                .unwrap_or(Value::Float((*a as f64).powf(*b as f64)))),
+            (a, b) if a.is_number() && b.is_number() => Ok(Value::Float(a.as_float()?.powf(b.as_float()?))),
+            _ => Err(self.type_error("raise", other)),
+        }
+    }
+
+    /// Divide two numbers and round the result down
+    pub fn floor_div(&self, other: &Value) -> Result<Value, String> {
+        if !self.is_number() || !other.is_number() {
+            return Err(self.type_error("divide", other));
+        }
+        if other.as_float()? == 0.0 {
+            return Err("Division by zero".to_string());
+        }
+        match (self, other) {
+            // i64::MIN // -1 overflows and falls through to float division
+            (Value::Int(a), Value::Int(b)) if a.checked_div(*b).is_some() => {
+                let quotient = a / b;
+                if a % b != 0 && ((*a < 0) != (*b < 0)) {
+                    Ok(Value::Int(quotient - 1))
+                } else {
+                    Ok(Value::Int(quotient))
+                }
+            }
+            _ => Ok(Value::number((self.as_float()? / other.as_float()?).floor())),
+        }
+    }
+
+    /// Negate a number
+    pub fn negate(&self) -> Result<Value, String> {
+        match self {
+            Value::Int(i) => Ok(i.checked_neg().map(Value::Int).unwrap_or(Value::Float(-(*i as f64)))),
+            Value::Float(f) => Ok(Value::Float(-f)),
+            _ => Err(format!("Type error: cannot negate {}", self.type_name())),
+        }
+    }
+
+    /// Order two values of compatible types
+    pub fn compare(&self, other: &Value) -> Result<Ordering, String> {
+        let ordering = match (self, other) {
+            (Value::Int(a), Value::Int(b)) => Some(a.cmp(b)),
+            (a, b) if a.is_number() && b.is_number() => a.as_float()?.partial_cmp(&b.as_float()?),
+            (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+            (Value::Bool(a), Value::Bool(b)) => Some(a.cmp(b)),
+            _ => None,
+        };
+        ordering.ok_or_else(|| self.type_error("compare", other))
+    }
+
+    /// Read an element from a list, map or string
+    pub fn get_index(&self, index: &Value) -> Result<Value, String> {
+        match (self, index) {
+            (Value::Array(arr), idx) if idx.is_number() => {
+                let i = idx.as_int()?;
+                let i = if i < 0 { arr.len() as i64 + i } else { i };
+                Ok(arr.get(i as usize).cloned().filter(|_| i >= 0).unwrap_or(Value::Null))
+            }
+            (Value::String(s), idx) if idx.is_number() => {
+                let i = idx.as_int()?;
+                Ok(usize::try_from(i).ok()
+                    .and_then(|i| s.chars().nth(i))
+                    .map(|c| Value::String(c.to_string()))
+                    .unwrap_or(Value::Null))
+            }
+            (Value::Map(map), key) => Ok(map.get(&key.to_string()).cloned().unwrap_or(Value::Null)),
+            (Value::Instance(instance), key) => instance.fields().get(&key.to_string()).cloned()
+                .ok_or_else(|| format!("{} object has no field '{}'", instance.class, key)),
+            // A non-numeric key is never present in a list or string
+            (Value::Array(_), _) | (Value::String(_), _) => Ok(Value::Null),
+            _ => Err(format!("Type error: cannot index {} with {}", self.type_name(), index.type_name())),
+        }
+    }
+
+    /// Return a copy of a list or map with one element replaced. An object's field is set in
+    /// place, and the object itself returned
+    pub fn set_index(&self, index: &Value, value: Value) -> Result<Value, String> {
+        match (self, index) {
+            (Value::Array(arr), idx) if idx.is_number() => {
+                // A list grows by storing one past its end, never by jumping ahead
+                let i = idx.as_int()?;
+                if i < 0 || i as usize > arr.len() {
+                    return Err(format!("List index {} is out of range", i));
+                }
+                let i = i as usize;
+                let mut arr = arr.clone();
+                if i == arr.len() {
+                    arr.push(value);
+                } else {
+                    arr[i] = value;
+                }
+                Ok(Value::Array(arr))
+            }
+            (Value::Map(map), key) => {
+                let mut map = map.clone();
+                map.insert(key.to_string(), value);
+                Ok(Value::Map(map))
+            }
+            (Value::Instance(instance), key) => {
+                instance.fields().insert(key.to_string(), value);
+                Ok(self.clone())
+            }
+            _ => Err(format!("Type error: cannot assign into {} with a {} index", self.type_name(), index.type_name())),
+        }
+    }
+
+    /// Number of elements in a list, map or string
+    pub fn len(&self) -> Result<usize, String> {
+        match self {
+            Value::Array(arr) => Ok(arr.len()),
+            Value::Map(map) => Ok(map.len()),
+            Value::String(s) => Ok(s.chars().count()),
+            _ => Err(format!("Type error: {} has no length", self.type_name())),
+        }
+    }
 }
 
 impl fmt::Display for Value {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.to_string())
+        match self {
+            Value::Int(i) => write!(f, "{}", i),
+            Value::Float(n) => write!(f, "{}", n),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::String(s) => f.write_str(s),
+            Value::Array(arr) => {
+                let elements: Vec<String> = arr.iter().map(|v| v.to_string()).collect();
+                write!(f, "[{}]", elements.join(", "))
+            }
+            Value::Map(map) => {
+                let entries: Vec<String> = map
+                    .iter()
+                    .map(|(k, v)| format!("{}: {}", k, v))
+                    .collect();
+                write!(f, "{{{}}}", entries.join(", "))
+            }
+            Value::Function(closure) => write!(f, "<function {}>", closure.name),
+            Value::Instance(instance) => write!(f, "<{} object>", instance.class),
+            Value::Null => f.write_str("null"),
+        }
     }
 }
 
@@ -115,6 +395,11 @@ impl PartialEq for Value {
             (Value::String(a), Value::String(b)) => a == b,
             (Value::Array(a), Value::Array(b)) => a == b,
             (Value::Map(a), Value::Map(b)) => a == b,
+            (Value::Function(a), Value::Function(b)) => {
+                a.name == b.name && a.captures.len() == b.captures.len() &&
+                    a.captures.iter().zip(&b.captures).all(|(a, b)| Arc::ptr_eq(a, b))
+            }
+            (Value::Instance(a), Value::Instance(b)) => Arc::ptr_eq(a, b),
             (Value::Null, Value::Null) => true,
             _ => false,
         }